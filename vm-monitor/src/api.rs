@@ -1,11 +1,21 @@
 use crate::auth;
-use crate::config::Configuration;
+use crate::config::{ApiEndpoint, ApiTransport, ConfigUpdate, Configuration, DropPolicy, MonitoringSettings, PayloadCompression, RetryPolicy, SignatureAlgorithm};
+use crate::deadletter::DeadLetterDir;
 use crate::errors::VmMonitorError;
-use crate::monitor::SystemMetrics;
+use crate::monitor::{CircuitState, CloudInstance, Inventory, SystemMetrics, Virtualization};
+use crate::sink::{Delivery, Sink, SinkFuture, SinkHealth};
+use crate::spool::Spool;
 use chrono::Utc;
-use reqwest::{Client, Method};
+use flate2::write::GzEncoder;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use rand::Rng;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 // Placeholder for API response if needed, e.g. registration returns specific data
 #[derive(Deserialize, Debug)]
@@ -19,99 +29,571 @@ struct RegistrationPayload<'a> {
     instance_id: &'a str,
     instance_name: &'a str,
     cloud_provider: &'a str,
-    agent_api_key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_api_key: Option<String>, // For Ed25519, the public key; none unless `api_auth` is HMAC
+    signature_algorithm: SignatureAlgorithm,
+    virtualization: Option<Virtualization>,
+    tags: &'a BTreeMap<String, String>,
+    cloud_instance: Option<CloudInstance>,
+}
+
+// A sample refused in a 207 (partial success) response to a metrics batch.
+#[derive(Deserialize, Debug)]
+pub struct RejectedMetric {
+    pub index: usize,
+    pub error: String,
+    #[serde(default)]
+    pub retryable: bool, // Otherwise the sample is dead-lettered
+    #[serde(skip, default = "multi_status")]
+    pub status: u16, // Recorded with the dead letter: 207, or 413 for a sample too large to send on its own
+}
+
+fn multi_status() -> u16 {
+    207
+}
+
+#[derive(Deserialize)]
+struct MetricsBatchResponse {
+    #[serde(default)]
+    rejected: Vec<RejectedMetric>, // Empty when the whole batch was accepted
 }
 
 #[derive(Serialize)]
 struct HeartbeatPayload<'a> {
     instance_id: &'a str,
+    #[serde(flatten)]
+    details: Option<HeartbeatDetails>,
+}
+
+// Sent with heartbeats unless `heartbeat_details` is off.
+#[derive(Serialize)]
+pub struct HeartbeatDetails {
+    pub agent_version: &'static str,
+    pub uptime_seconds: u64, // Of the agent, not the host
+    pub interval_seconds: u64,
+}
+
+// Something the API asks the agent to do: "snapshot", "flush_spool" or "diagnostics".
+#[derive(Deserialize, Debug, Clone)]
+pub struct AgentCommand {
+    pub id: String,
+    pub action: String,
+}
+
+#[derive(Serialize)]
+struct CommandResultPayload<'a> {
+    instance_id: String,
+    id: &'a str,
+    success: bool,
+    output: serde_json::Value, // Null unless the command produces a report
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct RotateKeyPayload<'a> {
+    instance_id: String,
+    new_api_key: &'a str,
+}
+
+pub(crate) struct RequestFailure {
+    pub(crate) error: VmMonitorError,
+    pub(crate) transient: bool, // Worth retrying: connection errors, timeouts, 5xx
+    pub(crate) retry_after: Option<Duration>, // Server-requested backoff on 429/503
+}
+
+impl From<VmMonitorError> for RequestFailure {
+    fn from(error: VmMonitorError) -> Self {
+        RequestFailure { error, transient: false, retry_after: None }
+    }
+}
+
+impl From<reqwest::Error> for RequestFailure {
+    fn from(error: reqwest::Error) -> Self {
+        RequestFailure {
+            transient: error.is_connect() || error.is_timeout() || error.is_request() || error.is_body(),
+            error: VmMonitorError::HttpError(error),
+            retry_after: None,
+        }
+    }
+}
+
+// The body as signed (JSON) and, when compressed, as sent.
+struct RequestBody {
+    json: String,
+    encoded: Option<(&'static str, Vec<u8>)>, // Content-Encoding and compressed bytes
+    batch_id: Option<Uuid>, // Sent as X-Batch-Id on every attempt
+}
+
+impl RequestBody {
+    fn new(json: String, compression: PayloadCompression) -> Result<Self, VmMonitorError> {
+        let encoded = match compression {
+            _ if json.is_empty() => None,
+            PayloadCompression::None => None,
+            PayloadCompression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(json.as_bytes())?;
+                Some(("gzip", encoder.finish()?))
+            }
+            PayloadCompression::Zstd => Some(("zstd", zstd::encode_all(json.as_bytes(), 3)?)),
+        };
+        Ok(RequestBody { json, encoded, batch_id: None })
+    }
+
+    // Derived from the content rather than random, so the same batch keeps its ID across
+    // retries, spool replays and restarts, and the backend can drop resubmissions.
+    fn with_batch_id(mut self, instance_id: &Uuid) -> Self {
+        self.batch_id = Some(Uuid::new_v5(instance_id, self.json.as_bytes()));
+        self
+    }
+}
+
+// `Retry-After` is either delay-seconds or an HTTP date. Rate limiters that don't send it
+// usually announce their window reset instead, as seconds or as a Unix timestamp.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok().map(str::trim);
+    if let Some(value) = header("retry-after") {
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+        return (date.with_timezone(&Utc) - Utc::now()).to_std().ok().or(Some(Duration::ZERO));
+    }
+    let reset: u64 = header("ratelimit-reset").or_else(|| header("x-ratelimit-reset"))?.parse().ok()?;
+    let now = Utc::now().timestamp() as u64;
+    Some(Duration::from_secs(if reset > 1_000_000_000 { reset.saturating_sub(now) } else { reset }))
+}
+
+fn retry_delay(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let exponential = policy.base_delay_ms.saturating_mul(1u64 << (attempt - 1).min(32));
+    let capped = exponential.min(policy.max_delay_ms) as f64;
+    let jitter = policy.jitter.clamp(0.0, 1.0) * rand::thread_rng().r#gen::<f64>();
+    Duration::from_millis((capped * (1.0 - jitter)) as u64)
+}
+
+pub(crate) fn http_client(settings: &MonitoringSettings) -> Result<Client, VmMonitorError> {
+    let (tls, timeouts) = (&settings.api_tls, &settings.api_timeouts);
+    let mut builder = Client::builder()
+        .pool_idle_timeout(timeouts.pool_idle())
+        .tcp_keepalive(timeouts.tcp_keepalive());
+    if let Some(request_timeout) = timeouts.request() {
+        builder = builder.timeout(request_timeout);
+    }
+    if let Some(connect_timeout) = timeouts.connect() {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(ca_file) = &tls.ca_file {
+        let pem = std::fs::read(ca_file)
+            .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read CA file {}: {}", ca_file, e)))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)?;
+        if certificates.is_empty() {
+            return Err(VmMonitorError::ConfigError(format!("No certificates found in CA file {}", ca_file)));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(identity) = tls.client_identity()? {
+        let identity = reqwest::Identity::from_pem(&[identity.cert, identity.key].join(&b'\n'))?;
+        builder = builder.use_rustls_tls().identity(identity); // A PEM identity is a rustls one
+    }
+    if tls.insecure_skip_verify {
+        log::warn!("insecure_skip_verify is set: the API's TLS certificate is NOT verified.");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(proxy) = &settings.api_proxy {
+        let proxy = reqwest::Proxy::all(proxy.as_str())
+            .map_err(|e| VmMonitorError::ConfigError(format!("Invalid api_proxy {}: {}", proxy, e)))?;
+        builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env())); // NO_PROXY still applies
+    }
+    Ok(builder.build()?)
+}
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+struct CircuitBreaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Instant,
 }
 
 pub struct ApiClient {
     http_client: Client,
     config: Configuration, // Store a copy or reference to the config
+    circuit: Mutex<CircuitBreaker>,
+    backoff_until: Mutex<Option<Instant>>, // Set from Retry-After and similar headers
+    recent_requests: Mutex<VecDeque<Instant>>, // Start times within the rate limit window, oldest first
+    clock: auth::ClockSkew,
+    auth: auth::Authenticator,
+    #[cfg(feature = "grpc")]
+    grpc: Option<crate::grpc::GrpcClient>, // Set when `api_transport` is "grpc"
 }
 
 impl ApiClient {
-    pub fn new(config: Configuration) -> Self {
-        ApiClient {
-            http_client: Client::builder()
-                .timeout(Duration::from_secs(30))
-                .build()
-                .unwrap_or_else(|e| {
-                    log::warn!("Failed to build custom HTTP client: {}. Using default.", e);
-                    Client::new()
-                }),
+    // Fails rather than fall back to a default client, which would drop the CA, client
+    // certificate, proxy and timeouts yet keep talking to the API.
+    pub fn new(config: Configuration) -> Result<Self, VmMonitorError> {
+        let http_client = http_client(&config.monitoring_settings)?;
+        #[cfg(feature = "grpc")]
+        let grpc = match config.monitoring_settings.api_transport {
+            ApiTransport::Grpc => {
+                let auth = auth::Authenticator::new(&config, http_client.clone());
+                Some(crate::grpc::GrpcClient::new(&config.api_url, &config.monitoring_settings, auth)?)
+            }
+            ApiTransport::Http => None,
+        };
+        #[cfg(not(feature = "grpc"))]
+        if config.monitoring_settings.api_transport == ApiTransport::Grpc {
+            log::warn!("The API transport is gRPC, but this build doesn't include the `grpc` feature. API requests will fail.");
+        }
+        Ok(ApiClient {
+            auth: auth::Authenticator::new(&config, http_client.clone()),
+            http_client,
             config,
+            circuit: Mutex::new(CircuitBreaker {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+            }),
+            backoff_until: Mutex::new(None),
+            recent_requests: Mutex::new(VecDeque::new()),
+            clock: auth::ClockSkew::default(),
+            #[cfg(feature = "grpc")]
+            grpc,
+        })
+    }
+
+    // Waits out a server-requested backoff if it's within the retry policy's maximum
+    // delay, and fails fast otherwise so callers can spool instead of blocking.
+    async fn wait_for_backoff(&self) -> Result<(), VmMonitorError> {
+        let until = *self.backoff_until.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(remaining) = until.and_then(|until| until.checked_duration_since(Instant::now())) else {
+            return Ok(());
+        };
+        if remaining > Duration::from_millis(self.config.monitoring_settings.api_retry.max_delay_ms) {
+            return Err(VmMonitorError::Throttled(remaining.as_secs() + 1));
+        }
+        tokio::time::sleep(remaining).await;
+        Ok(())
+    }
+
+    // Keeps requests within `api_max_requests_per_minute` over any 60s window, so a fleet
+    // draining spools after an outage can't flood the backend. Like a server-requested
+    // backoff, short waits are slept through and longer ones fail fast.
+    async fn acquire_request_slot(&self) -> Result<(), VmMonitorError> {
+        let limit = self.config.monitoring_settings.api_max_requests_per_minute as usize;
+        if limit == 0 {
+            return Ok(());
+        }
+        let wait = {
+            let mut recent = self.recent_requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            while recent.front().is_some_and(|start| now.saturating_duration_since(*start) >= RATE_LIMIT_WINDOW) {
+                recent.pop_front();
+            }
+            let wait = match recent.len().checked_sub(limit) {
+                Some(index) => RATE_LIMIT_WINDOW.saturating_sub(now.saturating_duration_since(recent[index])),
+                None => Duration::ZERO,
+            };
+            if wait > Duration::from_millis(self.config.monitoring_settings.api_retry.max_delay_ms) {
+                return Err(VmMonitorError::RateLimited(wait.as_secs() + 1));
+            }
+            recent.push_back(now + wait); // Reserved, so concurrent requests queue up behind it
+            wait
+        };
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+
+    fn back_off(&self, path: &str, delay: Duration) {
+        log::warn!("API asked to back off, pausing requests for {:?} (after {}).", delay, path);
+        *self.backoff_until.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now() + delay);
+    }
+
+    fn circuit(&self) -> MutexGuard<'_, CircuitBreaker> {
+        self.circuit.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn circuit_state(&self) -> (CircuitState, u32) {
+        let circuit = self.circuit();
+        (circuit.state, circuit.consecutive_failures)
+    }
+
+    // Fails fast while the circuit is open. Once the cool-down is over, a single health
+    // probe decides whether requests resume or the circuit stays open for another round.
+    async fn check_circuit(&self) -> Result<(), VmMonitorError> {
+        let open_for = Duration::from_secs(self.config.monitoring_settings.api_circuit_breaker.open_seconds);
+        {
+            let mut circuit = self.circuit();
+            match circuit.state {
+                CircuitState::Closed => return Ok(()),
+                CircuitState::Open if circuit.opened_at.elapsed() < open_for => {
+                    let remaining = open_for - circuit.opened_at.elapsed();
+                    return Err(VmMonitorError::CircuitOpen(remaining.as_secs() + 1));
+                }
+                _ => circuit.state = CircuitState::HalfOpen,
+            }
+        }
+
+        log::info!("API circuit half-open, probing the health endpoint...");
+        let probe = RequestBody::new(String::new(), PayloadCompression::None)?;
+        match self.send_request_once::<serde_json::Value>(Method::GET, ApiEndpoint::Health, &probe).await {
+            Ok(_) => {
+                self.record_success();
+                Ok(())
+            }
+            Err(failure @ RequestFailure { error: VmMonitorError::RateLimited(_), .. }) => Err(failure.error), // Probed later
+            Err(failure) => {
+                let mut circuit = self.circuit();
+                circuit.state = CircuitState::Open;
+                circuit.opened_at = Instant::now();
+                log::warn!(
+                    "API health probe failed: {}. Suspending requests for another {}s.",
+                    failure.error, open_for.as_secs()
+                );
+                Err(VmMonitorError::CircuitOpen(open_for.as_secs()))
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut circuit = self.circuit();
+        if circuit.state != CircuitState::Closed {
+            log::info!("API reachable again, resuming requests (circuit closed).");
+        }
+        circuit.state = CircuitState::Closed;
+        circuit.consecutive_failures = 0;
+    }
+
+    fn record_failure(&self) {
+        let settings = &self.config.monitoring_settings.api_circuit_breaker;
+        let mut circuit = self.circuit();
+        circuit.consecutive_failures += 1;
+        if settings.failure_threshold > 0
+            && circuit.consecutive_failures >= settings.failure_threshold
+            && circuit.state == CircuitState::Closed
+        {
+            circuit.state = CircuitState::Open;
+            circuit.opened_at = Instant::now();
+            log::warn!(
+                "API failed {} consecutive times, suspending requests for {}s (circuit open).",
+                circuit.consecutive_failures, settings.open_seconds
+            );
         }
     }
 
     async fn send_request<T: Serialize, R: for<'de> Deserialize<'de> + 'static>(
         &self,
         method: Method,
-        path: &str,
+        endpoint: ApiEndpoint,
         body: Option<&T>,
     ) -> Result<R, VmMonitorError> {
-        let url = format!("{}{}", self.config.api_url, path);
-        let timestamp = Utc::now().timestamp();
-        
-        let body_str = match body {
+        let body = RequestBody::new(match body {
             Some(b) => serde_json::to_string(b)?,
             None => "".to_string(),
+        }, PayloadCompression::None)?;
+        self.send_prepared_request(method, endpoint, body).await
+    }
+
+    async fn send_prepared_request<R: for<'de> Deserialize<'de> + 'static>(
+        &self,
+        method: Method,
+        endpoint: ApiEndpoint,
+        body: RequestBody,
+    ) -> Result<R, VmMonitorError> {
+        self.wait_for_backoff().await?;
+        self.check_circuit().await?;
+        match self.send_with_retries(method, endpoint, &body).await {
+            Ok(response) => {
+                self.record_success();
+                Ok(response)
+            }
+            Err(failure @ RequestFailure { error: VmMonitorError::RateLimited(_), .. }) => Err(failure.error), // Never sent
+            Err(failure) => {
+                if failure.transient {
+                    self.record_failure();
+                } else {
+                    self.record_success(); // The backend answered, it just rejected the request
+                }
+                Err(failure.error)
+            }
+        }
+    }
+
+    async fn send_with_retries<R: for<'de> Deserialize<'de> + 'static>(
+        &self,
+        method: Method,
+        endpoint: ApiEndpoint,
+        body: &RequestBody,
+    ) -> Result<R, RequestFailure> {
+        let policy = &self.config.monitoring_settings.api_retry;
+        let path = self.config.monitoring_settings.api_paths.path(endpoint);
+        let mut attempt = 1;
+        loop {
+            match self.send_request_once(method.clone(), endpoint, body).await {
+                Ok(response) => return Ok(response),
+                Err(failure) if failure.transient && attempt < policy.max_attempts => {
+                    let delay = match failure.retry_after {
+                        Some(retry_after) => {
+                            self.back_off(&path, retry_after);
+                            if retry_after > Duration::from_millis(policy.max_delay_ms) {
+                                return Err(failure); // Too long to wait inline
+                            }
+                            retry_after
+                        }
+                        None => retry_delay(policy, attempt),
+                    };
+                    log::warn!(
+                        "API request {} {} failed (attempt {}/{}): {}. Retrying in {:?}.",
+                        method, path, attempt, policy.max_attempts, failure.error, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(failure) => {
+                    if let Some(retry_after) = failure.retry_after {
+                        self.back_off(&path, retry_after);
+                    }
+                    return Err(failure);
+                }
+            }
+        }
+    }
+
+    // Signed per attempt, since the server rejects stale request timestamps.
+    async fn send_request_once<R: for<'de> Deserialize<'de> + 'static>(
+        &self,
+        method: Method,
+        endpoint: ApiEndpoint,
+        body: &RequestBody,
+    ) -> Result<R, RequestFailure> {
+        self.acquire_request_slot().await?;
+        if self.config.monitoring_settings.api_transport == ApiTransport::Grpc {
+            return self.send_grpc_once(endpoint, body).await;
+        }
+        let path = self.config.monitoring_settings.api_paths.path(endpoint);
+        let url = format!("{}{}", self.config.api_url, path);
+        let socket_path = self.config.api_socket_path();
+        let target = match socket_path {
+            Some(_) => format!("http://localhost{}", path),
+            None => url.clone(),
+        };
+        let has_body = method != Method::GET && !body.json.is_empty();
+        let payload: &[u8] = match &body.encoded {
+            _ if !has_body => &[],
+            Some((_, bytes)) => bytes,
+            None => body.json.as_bytes(),
         };
+        // The token endpoint being unreachable is like the API being unreachable.
+        let auth_headers = self
+            .auth
+            .headers(
+                &self.clock,
+                &auth::RequestParts { method: method.as_str(), url: &target, path: &path, body: &body.json, payload },
+            )
+            .await
+            .map_err(|error| RequestFailure { error, transient: true, retry_after: None })?;
 
-        let signature = auth::sign_request(
-            &self.config.api_key,
-            timestamp,
-            method.as_str(),
-            path,
-            &body_str,
-        )?;
-
-        let mut request_builder = self.http_client.request(method.clone(), &url)
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .header("X-Request-Timestamp", timestamp.to_string())
-            .header("X-Request-Signature", signature)
+        let mut request_builder = self.http_client.request(method.clone(), &target)
             .header("X-Instance-Id", self.config.instance_id.to_string());
+        for (name, value) in auth_headers {
+            request_builder = request_builder.header(name, value);
+        }
+        if let Some(batch_id) = body.batch_id {
+            request_builder = request_builder.header("X-Batch-Id", batch_id.to_string());
+        }
 
-        if method != Method::GET && !body_str.is_empty() {
-            request_builder = request_builder.header("Content-Type", "application/json").body(body_str);
+        if has_body {
+            request_builder = request_builder.header("Content-Type", "application/json");
+            request_builder = match &body.encoded {
+                Some((encoding, bytes)) => request_builder.header("Content-Encoding", *encoding).body(bytes.clone()),
+                None => request_builder.body(body.json.clone()),
+            };
         }
         
         log::debug!("Sending API request: {} {} to {}", method, path, url);
 
-        let response = request_builder.send().await?;
-
-        let status = response.status();
-        let response_text = response.text().await?; // Read text for logging before trying to parse JSON
+        let (status, headers, response_text) = match socket_path {
+            Some(socket_path) => self.send_unix_once(socket_path, request_builder).await?,
+            None => {
+                let response = request_builder.send().await?;
+                let (status, headers) = (response.status(), response.headers().clone());
+                (status, headers, response.text().await?) // Read text for logging before trying to parse JSON
+            }
+        };
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => parse_retry_after(&headers),
+            _ => None,
+        };
+        let clock_corrected = headers.get("date").and_then(|date| date.to_str().ok()).is_some_and(|date| self.clock.observe(date));
 
         if status.is_success() {
             if response_text.is_empty() && std::any::TypeId::of::<R>() == std::any::TypeId::of::<()>() {
-                serde_json::from_str(&response_text)
-                    .map_err(|e| VmMonitorError::JsonError(e))
+                Ok(serde_json::from_str(&response_text)
+                    .map_err(VmMonitorError::JsonError)?)
             } else if response_text.is_empty() {
                  Err(VmMonitorError::ApiError(format!(
                     "API request to {} {} succeeded with status {} but returned an empty non-JSON response.",
                     method, path, status
-                )))
+                )).into())
             } else {
-                serde_json::from_str(&response_text)
+                Ok(serde_json::from_str(&response_text)
                     .map_err(|e| VmMonitorError::ApiError(format!(
                         "Failed to parse successful API response from {} {}: {}. Response body: {}", method, path, e, response_text
-                    )))
+                    )))?)
             }
         } else {
             log::error!(
                 "API request to {} {} failed with status {}: {}",
                 method, path, status, response_text
             );
-            Err(VmMonitorError::ApiError(format!(
-                "API request failed: {} - {}",
-                status, response_text
-            )))
+            if status == StatusCode::PAYLOAD_TOO_LARGE {
+                return Err(VmMonitorError::PayloadTooLarge(response_text).into());
+            }
+            if status == StatusCode::BAD_REQUEST || status == StatusCode::UNPROCESSABLE_ENTITY {
+                return Err(VmMonitorError::ApiRejected(status.as_u16(), response_text).into());
+            }
+            // Likely a stale timestamp or an expired token, both fixed by the next attempt.
+            let stale_credentials = status == StatusCode::UNAUTHORIZED && (clock_corrected || self.auth.rejected().await);
+            Err(RequestFailure {
+                error: VmMonitorError::ApiError(format!(
+                    "API request failed: {} - {}",
+                    status, response_text
+                )),
+                // 4xx means the request itself is wrong and would fail again, except for these.
+                transient: status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || stale_credentials,
+                retry_after,
+            })
         }
     }
 
+    #[cfg(unix)]
+    async fn send_unix_once(&self, socket_path: &str, request: RequestBuilder) -> Result<(StatusCode, HeaderMap, String), RequestFailure> {
+        let response = crate::unix_socket::send(socket_path, request.build()?, &self.config.monitoring_settings.api_timeouts).await?;
+        Ok((response.status, response.headers, response.body))
+    }
+
+    #[cfg(not(unix))]
+    async fn send_unix_once(&self, _socket_path: &str, _request: RequestBuilder) -> Result<(StatusCode, HeaderMap, String), RequestFailure> {
+        Err(VmMonitorError::ConfigError("Unix socket API targets aren't supported on this platform".to_string()).into())
+    }
+
+    #[cfg(feature = "grpc")]
+    async fn send_grpc_once<R: for<'de> Deserialize<'de>>(&self, endpoint: ApiEndpoint, body: &RequestBody) -> Result<R, RequestFailure> {
+        let Some(grpc) = &self.grpc else {
+            return Err(VmMonitorError::ConfigError("The API transport isn't gRPC".to_string()).into());
+        };
+        let response = grpc.call(&self.config, endpoint, &body.json, body.batch_id).await?;
+        Ok(serde_json::from_value(response).map_err(VmMonitorError::JsonError)?)
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    async fn send_grpc_once<R>(&self, _endpoint: ApiEndpoint, _body: &RequestBody) -> Result<R, RequestFailure> {
+        Err(VmMonitorError::ConfigError("The gRPC transport needs a build with the `grpc` feature".to_string()).into())
+    }
+
     pub async fn register_instance(&self) -> Result<RegistrationResponse, VmMonitorError> {
         // Convert CloudProvider enum to string for the payload
         let cloud_provider_str = match &self.config.cloud_provider {
@@ -121,52 +603,358 @@ impl ApiClient {
             crate::config::CloudProvider::Unknown(s) => s.as_str(),
         };
 
+        let algorithm = self.config.monitoring_settings.signature_algorithm;
+        let agent_api_key = self.config.monitoring_settings.api_auth.uses_api_key()
+            .then(|| auth::verification_key(&self.config.api_key, algorithm))
+            .transpose()?;
         let payload = RegistrationPayload {
             instance_id: &self.config.instance_id.to_string(),
             instance_name: &self.config.instance_name,
             cloud_provider: cloud_provider_str,
-            agent_api_key: &self.config.api_key,
+            agent_api_key,
+            signature_algorithm: algorithm,
+            virtualization: crate::monitor::detect_virtualization(),
+            tags: &self.config.monitoring_settings.tags,
+            cloud_instance: crate::monitor::detect_cloud_instance(&self.config.cloud_provider).await,
         };
         // Assuming API endpoint for registration is /register
-        self.send_request(Method::POST, "/v1/agent/register", Some(&payload)).await
+        self.send_request(Method::POST, ApiEndpoint::Register, Some(&payload)).await
     }
 
-    pub async fn send_metrics_batch(&self, metrics: &[SystemMetrics]) -> Result<(), VmMonitorError> {
-        // API might expect a wrapper object like {"metrics": [...]}
-        // For now, assume it accepts a direct array of SystemMetrics
-        // Assuming API endpoint for metrics is /metrics
-        // The type R for send_request needs to be specified. If no response body, use `()` and handle.
-        // For now, let's make a dummy response struct for empty successful calls.
-
+    // Generic so batches replayed from the spool can be sent as plain JSON values.
+    // Batches over `max_batch_bytes` or rejected with 413 are split in half until they fit,
+    // sending the halves in order. If a later half fails, the caller keeps the whole batch,
+    // so the backend may see the earlier halves twice rather than losing any samples.
+    // Samples the API refused individually (207) are returned, indexed into `metrics`.
+    pub async fn send_metrics_batch<M: Serialize>(&self, metrics: &[M]) -> Result<Vec<RejectedMetric>, VmMonitorError> {
         #[derive(Serialize)]
-        struct MetricsBatch<'a> {
-            metrics: &'a [SystemMetrics],
+        struct MetricsBatch<'a, M> {
+            metrics: &'a [M],
         }
-        
-        let batch = MetricsBatch { metrics };
-        
-        #[derive(Deserialize)] 
-        struct EmptyResponse {}
 
-        let _: EmptyResponse = self.send_request(Method::POST, "/v1/agent/metrics", Some(&batch)).await?;
+        let max_bytes = self.config.monitoring_settings.max_batch_bytes;
+        let mut rejected = Vec::new();
+        let mut pending = vec![(0, metrics)]; // Stack of (offset, part), next part to send on top
+        while let Some((offset, part)) = pending.pop() {
+            let json = serde_json::to_string(&MetricsBatch { metrics: part })?;
+            let splittable = part.len() > 1;
+            let oversized = max_bytes > 0 && splittable && json.len() as u64 > max_bytes;
+            if !oversized {
+                let compression = match self.config.monitoring_settings.api_transport {
+                    ApiTransport::Http => self.config.monitoring_settings.compression,
+                    ApiTransport::Grpc => PayloadCompression::None, // gRPC compresses the protobuf message itself
+                };
+                let body = RequestBody::new(json, compression)?
+                    .with_batch_id(&self.config.instance_id);
+                match self.send_prepared_request::<MetricsBatchResponse>(Method::POST, ApiEndpoint::Metrics, body).await {
+                    Ok(response) => {
+                        rejected.extend(
+                            response.rejected.into_iter()
+                                .filter(|item| item.index < part.len())
+                                .map(|item| RejectedMetric { index: offset + item.index, ..item }),
+                        );
+                        continue;
+                    }
+                    Err(VmMonitorError::PayloadTooLarge(_)) if splittable => {
+                        log::warn!("API rejected a batch of {} metrics as too large, splitting it.", part.len());
+                    }
+                    // A single sample can't be split, and resending it would hold up everything behind it.
+                    Err(VmMonitorError::PayloadTooLarge(error)) => {
+                        rejected.push(RejectedMetric { index: offset, error, retryable: false, status: 413 });
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let (first, second) = part.split_at(part.len() / 2);
+            pending.push((offset + first.len(), second));
+            pending.push((offset, first));
+        }
+        // An index the API listed twice counts once.
+        rejected.sort_by_key(|item| item.index);
+        rejected.dedup_by_key(|item| item.index);
+        Ok(rejected)
+    }
+
+    pub async fn send_inventory(&self, inventory: &Inventory) -> Result<(), VmMonitorError> {
+        #[derive(Deserialize)] struct EmptyResponse {}
+        let _: EmptyResponse = self.send_request(Method::POST, ApiEndpoint::Inventory, Some(inventory)).await?;
         Ok(())
     }
 
-    pub async fn send_heartbeat(&self) -> Result<(), VmMonitorError> {
+    // The API may answer with settings changes, as `{"config": {...}}`.
+    pub async fn send_heartbeat(&self, details: Option<HeartbeatDetails>) -> Result<Option<ConfigUpdate>, VmMonitorError> {
         let payload = HeartbeatPayload {
             instance_id: &self.config.instance_id.to_string(),
+            details,
         };
-        // Assuming API endpoint for heartbeat is /heartbeat
-        #[derive(Deserialize)] struct EmptyResponse {}
-        let _: EmptyResponse = self.send_request(Method::POST, "/v1/agent/heartbeat", Some(&payload)).await?;
-        Ok(())
+        #[derive(Deserialize)] struct HeartbeatResponse { #[serde(default)] config: Option<ConfigUpdate> }
+        let response: HeartbeatResponse = self.send_request(Method::POST, ApiEndpoint::Heartbeat, Some(&payload)).await?;
+        Ok(response.config)
     }
 
     // A simple ping for status check
     pub async fn check_api_status(&self) -> Result<(), VmMonitorError> {
         #[derive(Deserialize)] struct PingResponse { _message: Option<String> } // Or more specific health check response
         // Assuming a GET endpoint like /health or /ping
-        let _: PingResponse = self.send_request(Method::GET, "/v1/health", Option::<&()>::None).await?;
+        let _: PingResponse = self.send_request(Method::GET, ApiEndpoint::Health, Option::<&()>::None).await?;
+        Ok(())
+    }
+
+    pub async fn poll_commands(&self) -> Result<Vec<AgentCommand>, VmMonitorError> {
+        #[derive(Deserialize)] struct CommandsResponse { #[serde(default)] commands: Vec<AgentCommand> }
+        let response: CommandsResponse = self.send_request(Method::GET, ApiEndpoint::Commands, Option::<&()>::None).await?;
+        Ok(response.commands)
+    }
+
+    pub async fn send_command_result(&self, id: &str, result: &Result<serde_json::Value, String>) -> Result<(), VmMonitorError> {
+        let payload = CommandResultPayload {
+            instance_id: self.config.instance_id.to_string(),
+            id,
+            success: result.is_ok(),
+            output: result.as_ref().cloned().unwrap_or_default(),
+            error: result.as_ref().err().map(String::as_str),
+        };
+        #[derive(Deserialize)] struct EmptyResponse {}
+        let _: EmptyResponse = self.send_request(Method::POST, ApiEndpoint::CommandResult, Some(&payload)).await?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Signed with the current key, which the API keeps accepting until the new one is used.
+    // Safe to repeat with the same key, e.g. when the response was lost. An Ed25519 key is
+    // sent as its public key.
+    pub async fn rotate_key(&self, new_api_key: &str) -> Result<(), VmMonitorError> {
+        let new_api_key = &auth::verification_key(new_api_key, self.config.monitoring_settings.signature_algorithm)?;
+        let payload = RotateKeyPayload { instance_id: self.config.instance_id.to_string(), new_api_key };
+        #[derive(Deserialize)] struct EmptyResponse {}
+        let _: EmptyResponse = self.send_request(Method::POST, ApiEndpoint::RotateKey, Some(&payload)).await?;
+        Ok(())
+    }
+}
+
+// Batches replayed from the spool per collection cycle, so a long backlog can't stall collection.
+const MAX_SPOOL_BATCHES_PER_CYCLE: usize = 30;
+
+fn dead_letter<M: Serialize>(dead_letters: &DeadLetterDir, metrics: &[M], status: u16, error: &str) {
+    match dead_letters.write(metrics, status, error) {
+        Ok(Some(path)) => log::error!(
+            "API rejected a batch of {} metrics ({}): {}. Saved to {}.",
+            metrics.len(), status, error, path.display()
+        ),
+        Ok(None) => log::error!("API rejected a batch of {} metrics ({}), dropping it: {}", metrics.len(), status, error),
+        Err(e) => log::error!("API rejected a batch of {} metrics ({}) and saving it failed: {}", metrics.len(), status, e),
+    }
+}
+
+// Dead-letters the samples a 207 response refused for good, returning the indices of those
+// worth retrying. Indices outside `metrics` or already seen are skipped.
+fn dead_letter_rejected<M: Serialize>(dead_letters: &DeadLetterDir, metrics: &[M], rejected: &[RejectedMetric]) -> HashSet<usize> {
+    let mut retry = HashSet::new();
+    let mut seen = HashSet::new();
+    for item in rejected {
+        let Some(metric) = metrics.get(item.index..=item.index) else { continue };
+        if !seen.insert(item.index) {
+            continue;
+        }
+        if item.retryable {
+            retry.insert(item.index);
+        } else {
+            dead_letter(dead_letters, metric, item.status, &item.error);
+        }
+    }
+    if !retry.is_empty() {
+        log::warn!("API asked to retry {} metrics from the batch later.", retry.len());
+    }
+    retry
+}
+
+async fn drain_spool(client: &ApiClient, spool: &mut Spool, dead_letters: &DeadLetterDir) {
+    for _ in 0..MAX_SPOOL_BATCHES_PER_CYCLE {
+        let batch = match spool.next_batch() {
+            Ok(Some(batch)) => batch,
+            Ok(None) => {
+                log::info!("Metrics spool drained.");
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to read metrics spool: {}", e);
+                return;
+            }
+        };
+        match client.send_metrics_batch(&batch).await {
+            Ok(rejected) => {
+                let retry = dead_letter_rejected(dead_letters, &batch, &rejected);
+                if !retry.is_empty() {
+                    let retry_batch: Vec<&serde_json::Value> =
+                        batch.iter().enumerate().filter(|(index, _)| retry.contains(index)).map(|(_, metric)| metric).collect();
+                    if let Err(e) = spool.append(&retry_batch) {
+                        log::error!("Failed to respool {} metrics, dropping them: {}", retry_batch.len(), e);
+                    }
+                }
+            }
+            // Skipped, or it would block everything queued behind it.
+            Err(VmMonitorError::ApiRejected(status, error)) => dead_letter(dead_letters, &batch, status, &error),
+            // Already logged when the circuit opened or the backoff started, or rate limited for now
+            Err(VmMonitorError::CircuitOpen(_) | VmMonitorError::Throttled(_) | VmMonitorError::RateLimited(_)) => return,
+            Err(e) => {
+                log::warn!("Failed to resend spooled metrics ({} bytes pending): {}", spool.size_bytes(), e);
+                return;
+            }
+        }
+        if let Err(e) = spool.commit() {
+            log::error!("Failed to record spool progress: {}", e);
+            return;
+        }
+    }
+}
+
+// The agent API as a metrics sink. Batches it can't take are spooled, or kept in memory
+// when the spool is off or failing, and resent in order; batches it rejects are dead-lettered.
+pub struct ApiSink {
+    client: Arc<ApiClient>, // Shared with the command poll
+    spool: Option<Spool>,
+    dead_letters: DeadLetterDir,
+    unsent: Vec<serde_json::Value>, // Samples held in memory, including those a 207 asked to retry
+}
+
+impl ApiSink {
+    pub fn new(client: Arc<ApiClient>, spool: Option<Spool>, dead_letters: DeadLetterDir) -> Self {
+        ApiSink { client, spool, dead_letters, unsent: Vec::new() }
+    }
+
+    // Samples as JSON, for the metrics stream's fallback while it's down.
+    #[cfg(feature = "websocket")]
+    pub async fn send_samples(&mut self, samples: Vec<serde_json::Value>) -> Result<(), VmMonitorError> {
+        self.unsent.extend(samples);
+        self.send_or_spool().await
+    }
+
+    // While older batches are still spooled, new ones queue behind them to keep their order.
+    async fn send_or_spool(&mut self) -> Result<(), VmMonitorError> {
+        let mut result = Ok(());
+        if self.spool.as_ref().is_none_or(|spool| spool.is_empty()) {
+            match self.client.send_metrics_batch(&self.unsent).await {
+                Ok(rejected) => {
+                    log::info!("Successfully sent batch of {} metrics.", self.unsent.len().saturating_sub(rejected.len()));
+                    // Samples refused as retryable stay in memory for the next batch.
+                    let retry = dead_letter_rejected(&self.dead_letters, &self.unsent, &rejected);
+                    let mut index = 0;
+                    self.unsent.retain(|_| {
+                        let keep = retry.contains(&index);
+                        index += 1;
+                        keep
+                    });
+                    return Ok(());
+                }
+                Err(VmMonitorError::ApiRejected(status, error)) => {
+                    dead_letter(&self.dead_letters, &self.unsent, status, &error);
+                    self.unsent.clear();
+                    return Ok(());
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        let settings = &self.client.config.monitoring_settings;
+        let Some(spool) = &mut self.spool else {
+            enforce_buffer_limit(&mut self.unsent, settings); // Kept in memory for the next attempt
+            return result;
+        };
+        match spool.append(&self.unsent) {
+            Ok(()) => {
+                log::info!("Spooled batch of {} metrics for later delivery.", self.unsent.len());
+                self.unsent.clear();
+            }
+            Err(e) => {
+                log::error!("Failed to spool metrics batch: {}", e);
+                enforce_buffer_limit(&mut self.unsent, settings);
+            }
+        }
+        result
+    }
+}
+
+// Bounds unsent metrics held in memory to `max_buffered_batches` batches.
+pub(crate) fn enforce_buffer_limit<M>(unsent: &mut Vec<M>, settings: &MonitoringSettings) {
+    let limit = settings.max_buffered_batches.max(1) * settings.batch_size.max(1);
+    let excess = unsent.len().saturating_sub(limit);
+    if excess == 0 {
+        return;
+    }
+    match settings.buffer_drop_policy {
+        DropPolicy::DropOldest => {
+            unsent.drain(..excess);
+        }
+        DropPolicy::DropNewest => unsent.truncate(limit),
+    }
+    log::warn!(
+        "Metrics buffer full ({} samples), dropped {} sample(s) ({:?}).",
+        limit, excess, settings.buffer_drop_policy
+    );
+}
+
+impl Sink for ApiSink {
+    fn name(&self) -> &'static str {
+        "api"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for sample in metrics {
+                // Round-tripped through text rather than `to_value`, which would widen f32 fields
+                // and so change the batch's bytes, and its X-Batch-Id, from a spool replay's.
+                self.unsent.push(serde_json::from_str(&serde_json::to_string(sample)?)?);
+            }
+            self.send_or_spool().await
+        })
+    }
+
+    // Replays the spool, so a backlog drains every cycle rather than once per batch. Heartbeats
+    // to the API run on their own timer in `start`.
+    fn send_heartbeat(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            if let Some(spool) = self.spool.as_mut().filter(|spool| !spool.is_empty()) {
+                drain_spool(&self.client, spool, &self.dead_letters).await;
+            }
+            Ok(())
+        })
+    }
+
+    fn health(&self) -> SinkHealth {
+        let (circuit_state, consecutive_failures) = self.client.circuit_state();
+        SinkHealth {
+            circuit_state,
+            consecutive_failures,
+            backlog_bytes: self.spool.as_ref().map_or(0, |spool| spool.size_bytes()),
+        }
+    }
+
+    fn drain_backlog(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            if !self.unsent.is_empty() {
+                self.send_or_spool().await?;
+            }
+            while let Some(spool) = self.spool.as_mut().filter(|spool| !spool.is_empty()) {
+                let pending = spool.size_bytes();
+                drain_spool(&self.client, spool, &self.dead_letters).await;
+                if !spool.is_empty() && spool.size_bytes() >= pending {
+                    return Err(VmMonitorError::ApiError(format!("Spool stopped draining with {} bytes left", pending)));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            if self.unsent.is_empty() {
+                return Ok(());
+            }
+            log::info!("Sending remaining {} metrics before shutdown...", self.unsent.len());
+            self.send_or_spool().await
+        })
+    }
+}
@@ -1,35 +1,227 @@
+use crate::aws::Aws;
+use crate::azure::ManagedIdentity;
+use crate::config::{ApiAuth, AwsSigv4Settings, Configuration, SignatureAlgorithm};
 use crate::errors::VmMonitorError;
+use crate::gcp::IdentityTokens;
+use crate::oauth2::TokenSource;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use hmac::digest::KeyInit;
 use hmac::{Hmac, Mac};
 use rand::RngCore;
-use sha2::Sha256;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sha2::{Sha256, Sha512};
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::OnceCell;
 
 type HmacSha256 = Hmac<Sha256>;
+type HmacSha512 = Hmac<Sha512>;
 
-pub fn generate_api_key() -> String {
+// `Date` has second precision and the response took some time to arrive, so smaller
+// changes are noise.
+const SKEW_NOTICE_SECS: i64 = 5;
+
+// How far the API's clock is ahead of ours, learned from the `Date` header of its responses.
+// Request timestamps are taken on the API's clock, so a VM whose clock drifted still signs
+// requests inside the server's timestamp window.
+#[derive(Default)]
+pub struct ClockSkew(AtomicI64); // Seconds
+
+impl ClockSkew {
+    /// The current time on the API's clock, as a Unix timestamp.
+    pub fn timestamp(&self) -> i64 {
+        self.now().timestamp()
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + chrono::TimeDelta::seconds(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Updates the skew from a response's `Date` header. Returns true when it changed
+    /// enough that a request signed before may have been rejected for its timestamp.
+    pub fn observe(&self, date: &str) -> bool {
+        let Ok(server_time) = DateTime::parse_from_rfc2822(date.trim()) else {
+            return false;
+        };
+        let skew = server_time.timestamp() - Utc::now().timestamp();
+        let previous = self.0.swap(skew, Ordering::Relaxed);
+        if (skew - previous).abs() < SKEW_NOTICE_SECS {
+            return false;
+        }
+        if skew.abs() >= SKEW_NOTICE_SECS {
+            let direction = if skew > 0 { "behind" } else { "ahead of" };
+            log::warn!("The local clock is {}s {} the API's, signing requests with the API's time.", skew.abs(), direction);
+        } else {
+            log::info!("The local clock is back in sync with the API's.");
+        }
+        true
+    }
+}
+
+// The parts of an API request that authentication covers. `path` is the HTTP path, or the
+// RPC's full name over gRPC.
+pub struct RequestParts<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub path: &'a str,
+    pub body: &'a str, // As signed; over gRPC, the base64 of the encoded message
+    pub payload: &'a [u8], // The body as sent, after compression
+}
+
+// Signs with the instance role's credentials; the region is looked up on first use.
+struct SigV4 {
+    aws: Aws,
+    settings: AwsSigv4Settings,
+    region: OnceCell<String>,
+}
+
+impl SigV4 {
+    async fn headers(&self, clock: &ClockSkew, request: &RequestParts<'_>) -> Result<Vec<(&'static str, String)>, VmMonitorError> {
+        let url = reqwest::Url::parse(request.url)
+            .map_err(|e| VmMonitorError::ConfigError(format!("Can't sign a request to {}: {}", request.url, e)))?;
+        let region = self.region.get_or_try_init(|| self.aws.region(self.settings.region.as_deref())).await?;
+        let credentials = self.aws.credentials().await?;
+        Ok(credentials.sign(clock.now(), region, &self.settings.service, request.method, &url, &[], request.payload))
+    }
+}
+
+enum Scheme {
+    Hmac,
+    OAuth2(Box<TokenSource>),
+    AwsSigv4(Box<SigV4>),
+    GcpIdentity(Box<IdentityTokens>),
+    AzureManagedIdentity { identity: Box<ManagedIdentity>, resource: String },
+}
+
+// Produces the authentication headers for API requests, per `api_auth`.
+pub struct Authenticator {
+    api_key: String,
+    algorithm: SignatureAlgorithm,
+    scheme: Scheme,
+}
+
+impl Authenticator {
+    /// `client` fetches tokens, for schemes that need them.
+    pub fn new(config: &Configuration, client: reqwest::Client) -> Self {
+        let scheme = match &config.monitoring_settings.api_auth {
+            ApiAuth::Hmac => Scheme::Hmac,
+            ApiAuth::Oauth2(settings) => Scheme::OAuth2(Box::new(TokenSource::new(client, settings.clone()))),
+            ApiAuth::AwsSigv4(settings) => {
+                Scheme::AwsSigv4(Box::new(SigV4 { aws: Aws::new(), settings: settings.clone(), region: OnceCell::new() }))
+            }
+            ApiAuth::GcpIdentity(settings) => {
+                let audience = settings.audience.as_deref().unwrap_or(&config.api_url);
+                Scheme::GcpIdentity(Box::new(IdentityTokens::new(&settings.service_account, audience)))
+            }
+            ApiAuth::AzureManagedIdentity(settings) => Scheme::AzureManagedIdentity {
+                identity: Box::new(ManagedIdentity::new(settings.client_id.clone())),
+                resource: settings.resource.clone(),
+            },
+        };
+        Authenticator { api_key: config.api_key.clone(), algorithm: config.monitoring_settings.signature_algorithm, scheme }
+    }
+
+    pub async fn headers(&self, clock: &ClockSkew, request: &RequestParts<'_>) -> Result<Vec<(&'static str, String)>, VmMonitorError> {
+        match &self.scheme {
+            Scheme::Hmac => {
+                let timestamp = clock.timestamp();
+                let nonce = generate_nonce();
+                let signature =
+                    sign_request(&self.api_key, self.algorithm, timestamp, &nonce, request.method, request.path, request.body)?;
+                // Only the signature: the key never leaves the agent, so a captured request can't be re-signed.
+                Ok(vec![
+                    ("x-request-timestamp", timestamp.to_string()),
+                    ("x-request-nonce", nonce),
+                    ("x-request-signature", signature),
+                ])
+            }
+            Scheme::OAuth2(tokens) => Ok(vec![("authorization", format!("Bearer {}", tokens.token().await?))]),
+            Scheme::AwsSigv4(signer) => signer.headers(clock, request).await,
+            Scheme::GcpIdentity(tokens) => Ok(vec![("authorization", format!("Bearer {}", tokens.token().await?))]),
+            Scheme::AzureManagedIdentity { identity, resource } => {
+                Ok(vec![("authorization", format!("Bearer {}", identity.token(resource).await?))])
+            }
+        }
+    }
+
+    /// Called when the API answered 401. Returns whether a retry may succeed, because
+    /// a cached credential was dropped and will be fetched anew.
+    pub async fn rejected(&self) -> bool {
+        match &self.scheme {
+            Scheme::Hmac | Scheme::AwsSigv4(_) => false,
+            Scheme::OAuth2(tokens) => tokens.invalidate().await,
+            Scheme::GcpIdentity(tokens) => tokens.invalidate(),
+            Scheme::AzureManagedIdentity { identity, resource } => identity.invalidate(resource),
+        }
+    }
+}
+
+pub fn generate_api_key(algorithm: SignatureAlgorithm) -> Result<String, VmMonitorError> {
+    if algorithm == SignatureAlgorithm::Ed25519 {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| VmMonitorError::AuthError("Failed to generate an Ed25519 key".to_string()))?;
+        return Ok(STANDARD.encode(pkcs8.as_ref()));
+    }
     let mut key_bytes = [0u8; 32]; // 256 bits
     rand::thread_rng().fill_bytes(&mut key_bytes);
-    STANDARD.encode(key_bytes) // Use the STANDARD engine to encode
+    Ok(STANDARD.encode(key_bytes)) // Use the STANDARD engine to encode
+}
+
+fn ed25519_key_pair(api_key: &str) -> Result<Ed25519KeyPair, VmMonitorError> {
+    let pkcs8 = STANDARD
+        .decode(api_key.trim())
+        .map_err(|e| VmMonitorError::AuthError(format!("The API key isn't an Ed25519 private key: {}", e)))?;
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+        .map_err(|e| VmMonitorError::AuthError(format!("The API key isn't an Ed25519 private key: {}", e)))
+}
+
+/// What the API stores to verify signatures: the key itself for HMAC, the base64 of the raw
+/// public key for Ed25519.
+pub fn verification_key(api_key: &str, algorithm: SignatureAlgorithm) -> Result<String, VmMonitorError> {
+    match algorithm {
+        SignatureAlgorithm::HmacSha256 | SignatureAlgorithm::HmacSha512 => Ok(api_key.to_string()),
+        SignatureAlgorithm::Ed25519 => Ok(STANDARD.encode(ed25519_key_pair(api_key)?.public_key().as_ref())),
+    }
+}
+
+fn hmac_signature<M: Mac + KeyInit>(api_secret_key: &str, message: &str) -> Result<Vec<u8>, VmMonitorError> {
+    let mut mac = <M as Mac>::new_from_slice(api_secret_key.as_bytes())
+        .map_err(|e| VmMonitorError::AuthError(format!("Failed to initialize HMAC: {}", e)))?;
+    mac.update(message.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// Unique per attempt, so the API can refuse a captured request replayed within the
+// timestamp window.
+pub fn generate_nonce() -> String {
+    let mut nonce_bytes = [0u8; 16]; // 128 bits
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    STANDARD.encode(nonce_bytes)
 }
 
 pub fn sign_request(
     api_secret_key: &str,
+    algorithm: SignatureAlgorithm,
     timestamp: i64,
+    nonce: &str,
     http_method: &str,
     request_path: &str,
     request_body: &str, // JSON string of the body
 ) -> Result<String, VmMonitorError> {
     let message = format!(
-        "{}\n{}\n{}\n{}",
+        "{}\n{}\n{}\n{}\n{}",
         timestamp,
+        nonce,
         http_method.to_uppercase(),
         request_path,
         request_body
     );
 
-    let mut mac = HmacSha256::new_from_slice(api_secret_key.as_bytes())
-        .map_err(|e| VmMonitorError::AuthError(format!("Failed to initialize HMAC: {}", e)))?;
-    mac.update(message.as_bytes());
-    let signature_bytes = mac.finalize().into_bytes();
+    let signature_bytes = match algorithm {
+        SignatureAlgorithm::HmacSha256 => hmac_signature::<HmacSha256>(api_secret_key, &message)?,
+        SignatureAlgorithm::HmacSha512 => hmac_signature::<HmacSha512>(api_secret_key, &message)?,
+        SignatureAlgorithm::Ed25519 => ed25519_key_pair(api_secret_key)?.sign(message.as_bytes()).as_ref().to_vec(),
+    };
     Ok(STANDARD.encode(signature_bytes)) // Use the STANDARD engine to encode
 }
\ No newline at end of file
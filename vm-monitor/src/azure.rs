@@ -0,0 +1,163 @@
+// Azure managed identity and instance metadata, for calling Azure APIs as the VM itself.
+// Tokens come from the instance metadata service (IMDS) and are cached per resource until
+// shortly before they expire.
+use crate::errors::VmMonitorError;
+use crate::monitor::CloudInstance;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+const REFRESH_BEFORE_EXPIRY_SECONDS: u64 = 5 * 60;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_on: String, // Unix time, as a string
+}
+
+#[derive(Clone)]
+struct Token {
+    access_token: String,
+    expires_on: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Compute {
+    pub location: String,
+    pub resource_id: String,
+    #[serde(default)]
+    vm_size: String,
+    #[serde(default)]
+    zone: String, // Empty unless the VM was placed in a zone
+    #[serde(default)]
+    vm_id: String,
+    #[serde(default)]
+    storage_profile: StorageProfile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct StorageProfile {
+    #[serde(default)]
+    image_reference: ImageReference,
+}
+
+// A custom or gallery image has an `id`; a marketplace image is named by the other four.
+#[derive(Deserialize, Default)]
+struct ImageReference {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    publisher: String,
+    #[serde(default)]
+    offer: String,
+    #[serde(default)]
+    sku: String,
+    #[serde(default)]
+    version: String,
+}
+
+impl Compute {
+    pub fn cloud_instance(&self) -> CloudInstance {
+        let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+        let image = &self.storage_profile.image_reference;
+        let urn = [&image.publisher, &image.offer, &image.sku, &image.version].map(String::as_str).join(":");
+        CloudInstance {
+            instance_type: non_empty(&self.vm_size),
+            region: non_empty(&self.location),
+            availability_zone: non_empty(&self.zone),
+            instance_id: non_empty(&self.vm_id),
+            image: non_empty(&image.id).or_else(|| non_empty(&image.publisher).map(|_| urn)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct InstanceMetadata {
+    compute: Compute,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+// `IMDS_ENDPOINT` moves the metadata service, as on Azure Arc.
+pub struct ManagedIdentity {
+    client: Client,
+    metadata_endpoint: String,
+    client_id: Option<String>, // Of a user-assigned identity
+    tokens: Mutex<HashMap<String, Token>>, // By resource
+}
+
+impl ManagedIdentity {
+    pub fn new(client_id: Option<String>) -> Self {
+        ManagedIdentity {
+            client: Client::builder().timeout(METADATA_TIMEOUT).build().unwrap_or_else(|_| Client::new()),
+            metadata_endpoint: std::env::var("IMDS_ENDPOINT")
+                .unwrap_or_else(|_| DEFAULT_METADATA_ENDPOINT.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            client_id,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An access token for `resource`, e.g. `https://monitoring.azure.com/`.
+    pub async fn token(&self, resource: &str) -> Result<String, VmMonitorError> {
+        if let Some(token) = self.tokens.lock().unwrap_or_else(|e| e.into_inner()).get(resource)
+            && token.expires_on > now() + REFRESH_BEFORE_EXPIRY_SECONDS
+        {
+            return Ok(token.access_token.clone());
+        }
+        let mut query = vec![("api-version", "2018-02-01"), ("resource", resource)];
+        if let Some(client_id) = &self.client_id {
+            query.push(("client_id", client_id));
+        }
+        let response = self
+            .client
+            .get(format!("{}/metadata/identity/oauth2/token", self.metadata_endpoint))
+            .query(&query)
+            .header("Metadata", "true")
+            .send()
+            .await
+            .map_err(|e| VmMonitorError::AuthError(format!("Managed identity unavailable: {}", e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(VmMonitorError::AuthError(format!("Managed identity token request returned {}: {}", status, text)));
+        }
+        let response: TokenResponse = response.json().await?;
+        let token = Token {
+            access_token: response.access_token,
+            expires_on: response.expires_on.parse().unwrap_or_default(),
+        };
+        log::debug!("Got a managed identity token for {}, valid for {}s", resource, token.expires_on.saturating_sub(now()));
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner()).insert(resource.to_string(), token.clone());
+        Ok(token.access_token)
+    }
+
+    /// Drops the cached token for `resource` after it was rejected. Returns whether there was one.
+    pub fn invalidate(&self, resource: &str) -> bool {
+        self.tokens.lock().unwrap_or_else(|e| e.into_inner()).remove(resource).is_some()
+    }
+
+    /// The VM's region, resource ID, size and image.
+    pub async fn compute(&self) -> Result<Compute, VmMonitorError> {
+        let metadata: InstanceMetadata = self
+            .client
+            .get(format!("{}/metadata/instance", self.metadata_endpoint))
+            .query(&[("api-version", "2021-02-01")])
+            .header("Metadata", "true")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(metadata.compute)
+    }
+}
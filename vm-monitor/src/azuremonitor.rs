@@ -0,0 +1,138 @@
+// Pushes every batch to Azure Monitor as custom metrics, authenticated with the VM's managed
+// identity, so they show up next to the platform's own VM metrics. Azure Monitor stores
+// metrics per minute, so samples are pre-aggregated into min/max/sum/count per minute.
+use crate::azure::ManagedIdentity;
+use crate::config::AzureMonitorSettings;
+use crate::errors::VmMonitorError;
+use crate::gauges::gauges;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Delivery, Sink, SinkFuture};
+use chrono::{DateTime, DurationRound, SecondsFormat, TimeDelta, Utc};
+use reqwest::Client;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const TOKEN_RESOURCE: &str = "https://monitoring.azure.com/";
+
+struct Aggregate {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+// One request body per minute and metric, with a series per combination of dimension values.
+type Series = BTreeMap<Vec<String>, Aggregate>;
+
+fn aggregate(metrics: &[SystemMetrics]) -> BTreeMap<(DateTime<Utc>, String, Vec<&'static str>), Series> {
+    let mut metric_series: BTreeMap<_, Series> = BTreeMap::new();
+    for sample in metrics {
+        let minute = sample.timestamp.duration_trunc(TimeDelta::minutes(1)).unwrap_or(sample.timestamp);
+        for gauge in gauges(sample) {
+            let (name, value) = (gauge.path("/"), gauge.value);
+            let (dim_names, dim_values): (Vec<&'static str>, Vec<String>) = gauge.dimension.into_iter().unzip();
+            let series = metric_series.entry((minute, name, dim_names)).or_default();
+            let aggregate = series.entry(dim_values).or_insert(Aggregate { min: value, max: value, sum: 0.0, count: 0 });
+            aggregate.min = aggregate.min.min(value);
+            aggregate.max = aggregate.max.max(value);
+            aggregate.sum += value;
+            aggregate.count += 1;
+        }
+    }
+    metric_series
+}
+
+// Where to publish, resolved on the first batch since it may need the metadata service.
+struct Target {
+    url: String,
+}
+
+pub struct AzureMonitorSink {
+    client: Client,
+    identity: ManagedIdentity,
+    settings: AzureMonitorSettings,
+    target: Option<Target>,
+}
+
+impl AzureMonitorSink {
+    pub fn new(settings: &AzureMonitorSettings) -> Result<Self, VmMonitorError> {
+        Ok(AzureMonitorSink {
+            client: Client::builder().timeout(Duration::from_secs(settings.timeout_seconds)).build()?,
+            identity: ManagedIdentity::new(settings.client_id.clone()),
+            settings: settings.clone(),
+            target: None,
+        })
+    }
+
+    async fn resolve_target(&self) -> Result<Target, VmMonitorError> {
+        let (region, resource_id) = match (&self.settings.region, &self.settings.resource_id) {
+            (Some(region), Some(resource_id)) => (region.clone(), resource_id.clone()),
+            (region, resource_id) => {
+                let compute = self.identity.compute().await.map_err(|e| {
+                    VmMonitorError::ConfigError(format!("Azure Monitor needs region and resource_id off Azure: {}", e))
+                })?;
+                (region.clone().unwrap_or(compute.location), resource_id.clone().unwrap_or(compute.resource_id))
+            }
+        };
+        log::info!("Publishing to Azure Monitor in {} for {}", region, resource_id);
+        let endpoint = self.settings.endpoint.clone().unwrap_or_else(|| format!("https://{}.monitoring.azure.com", region));
+        let url = format!("{}{}/metrics", endpoint.trim_end_matches('/'), resource_id);
+        Ok(Target { url })
+    }
+
+    async fn post(&self, target: &Target, body: &serde_json::Value) -> Result<(), VmMonitorError> {
+        let token = self.identity.token(TOKEN_RESOURCE).await?;
+        let response = self.client.post(&target.url).bearer_auth(token).json(body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(VmMonitorError::ApiError(format!("Azure Monitor returned {}: {}", status, text)));
+        }
+        Ok(())
+    }
+}
+
+impl Sink for AzureMonitorSink {
+    fn name(&self) -> &'static str {
+        "azure_monitor"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            if self.target.is_none() {
+                self.target = Some(self.resolve_target().await?);
+            }
+            let target = self.target.as_ref().expect("resolved above");
+            for ((minute, name, dim_names), series) in aggregate(metrics) {
+                let series: Vec<_> = series
+                    .into_iter()
+                    .map(|(dim_values, aggregate)| {
+                        json!({
+                            "dimValues": dim_values,
+                            "min": aggregate.min,
+                            "max": aggregate.max,
+                            "sum": aggregate.sum,
+                            "count": aggregate.count,
+                        })
+                    })
+                    .collect();
+                let body = json!({
+                    "time": minute.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    "data": {"baseData": {
+                        "metric": name,
+                        "namespace": self.settings.namespace,
+                        "dimNames": dim_names,
+                        "series": series,
+                    }},
+                });
+                self.post(target, &body).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,160 @@
+// Publishes every batch to Amazon CloudWatch through PutMetricData, signed with SigV4 using
+// the instance role's credentials. Metrics are named as for StatsD, with what StatsD puts in
+// the path (mount point, interface, container...) as dimensions instead.
+use crate::aws::{Aws, uri_encode};
+use crate::config::{CloudWatchSettings, Configuration};
+use crate::errors::VmMonitorError;
+use crate::gauges::gauges;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Delivery, Sink, SinkFuture};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::{Client, Url};
+use std::time::Duration;
+
+const MAX_DATUMS_PER_REQUEST: usize = 1000; // PutMetricData's limit
+const CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+
+struct Datum {
+    name: &'static str,
+    unit: &'static str,
+    value: f64,
+    dimension: Option<(String, String)>, // Besides InstanceId
+    timestamp: DateTime<Utc>,
+}
+
+fn datums(metrics: &SystemMetrics) -> Vec<Datum> {
+    gauges(metrics)
+        .into_iter()
+        .map(|gauge| Datum {
+            name: gauge.cloudwatch_name,
+            unit: gauge.unit,
+            value: gauge.value,
+            dimension: gauge.dimension.map(|(name, value)| (dimension_name(name), value)),
+            timestamp: metrics.timestamp,
+        })
+        .collect()
+}
+
+// "mount_point" becomes "MountPoint".
+fn dimension_name(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+        })
+        .collect()
+}
+
+// The query-protocol form body of one PutMetricData request.
+fn form_body(namespace: &str, instance_id: &str, datums: &[Datum]) -> String {
+    let mut fields = vec![
+        ("Action".to_string(), "PutMetricData".to_string()),
+        ("Version".to_string(), "2010-08-01".to_string()),
+        ("Namespace".to_string(), namespace.to_string()),
+    ];
+    for (index, datum) in datums.iter().enumerate() {
+        let member = format!("MetricData.member.{}", index + 1);
+        fields.push((format!("{}.MetricName", member), datum.name.to_string()));
+        fields.push((format!("{}.Value", member), datum.value.to_string()));
+        fields.push((format!("{}.Unit", member), datum.unit.to_string()));
+        fields.push((format!("{}.Timestamp", member), datum.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)));
+        let dimensions = std::iter::once(("InstanceId", instance_id)).chain(datum.dimension.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        for (position, (name, value)) in dimensions.enumerate() {
+            fields.push((format!("{}.Dimensions.member.{}.Name", member, position + 1), name.to_string()));
+            fields.push((format!("{}.Dimensions.member.{}.Value", member, position + 1), value.to_string()));
+        }
+    }
+    fields.iter().map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value))).collect::<Vec<_>>().join("&")
+}
+
+// Where to publish, resolved on the first batch since it may need the metadata service.
+struct Target {
+    region: String,
+    url: Url,
+    instance_id: String,
+}
+
+pub struct CloudWatchSink {
+    client: Client,
+    aws: Aws,
+    settings: CloudWatchSettings,
+    fallback_instance_id: String, // The agent's own ID, off EC2
+    target: Option<Target>,
+}
+
+impl CloudWatchSink {
+    pub fn new(config: &Configuration, settings: &CloudWatchSettings) -> Result<Self, VmMonitorError> {
+        Ok(CloudWatchSink {
+            client: Client::builder().timeout(Duration::from_secs(settings.timeout_seconds)).build()?,
+            aws: Aws::new(),
+            settings: settings.clone(),
+            fallback_instance_id: config.instance_id.to_string(),
+            target: None,
+        })
+    }
+
+    async fn resolve_target(&self) -> Result<Target, VmMonitorError> {
+        let region = self.aws.region(self.settings.region.as_deref()).await?;
+        let endpoint = self.settings.endpoint.clone().unwrap_or_else(|| format!("https://monitoring.{}.amazonaws.com/", region));
+        let url = Url::parse(&endpoint)
+            .map_err(|e| VmMonitorError::ConfigError(format!("Invalid CloudWatch endpoint {:?}: {}", endpoint, e)))?;
+        let instance_id = match self.aws.metadata("meta-data/instance-id").await {
+            Ok(instance_id) => instance_id,
+            Err(e) => {
+                log::debug!("Not on EC2 ({}), using the agent's instance ID as the CloudWatch dimension", e);
+                self.fallback_instance_id.clone()
+            }
+        };
+        log::info!("Publishing to CloudWatch in {} as instance {}", region, instance_id);
+        Ok(Target { region, url, instance_id })
+    }
+
+    async fn put_metric_data(&self, target: &Target, datums: &[Datum]) -> Result<(), VmMonitorError> {
+        let body = form_body(&self.settings.namespace, &target.instance_id, datums);
+        let credentials = self.aws.credentials().await?;
+        let signature = credentials.sign(
+            Utc::now(),
+            &target.region,
+            "monitoring",
+            "POST",
+            &target.url,
+            &[("content-type", CONTENT_TYPE)],
+            body.as_bytes(),
+        );
+        let mut request = self.client.post(target.url.clone()).header("Content-Type", CONTENT_TYPE);
+        for (name, value) in signature {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(VmMonitorError::ApiError(format!("CloudWatch returned {}: {}", status, text)));
+        }
+        Ok(())
+    }
+}
+
+impl Sink for CloudWatchSink {
+    fn name(&self) -> &'static str {
+        "cloudwatch"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            if self.target.is_none() {
+                self.target = Some(self.resolve_target().await?);
+            }
+            let target = self.target.as_ref().expect("resolved above");
+            let datums: Vec<Datum> = metrics.iter().flat_map(datums).collect();
+            for chunk in datums.chunks(MAX_DATUMS_PER_REQUEST) {
+                self.put_metric_data(target, chunk).await?;
+            }
+            Ok(())
+        })
+    }
+}
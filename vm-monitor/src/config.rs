@@ -1,21 +1,40 @@
 use crate::errors::VmMonitorError;
+use crate::secrets;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 #[cfg(all(unix, feature = "unix_perms"))]
 use nix::sys::stat::{fchmod, Mode};
 #[cfg(all(unix, feature = "unix_perms"))]
-use std::os::unix::io::AsRawFd;
+use std::os::unix::{fs::PermissionsExt, io::AsRawFd};
 
 
-const CONFIG_FILE_NAME: &str = "vm-monitor.json";
+const CONFIG_FILE_STEM: &str = "vm-monitor";
+const CONFIG_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"]; // A new config is JSON
 const APP_NAME: &str = "vm-monitor";
+const KEY_FILE_NAME: &str = "api_key"; // Next to the system-wide config
+const CONFIG_PATH_ENV: &str = "VM_MONITOR_CONFIG";
+// Override the config file, for secrets injected by Kubernetes or Nomad.
+pub const API_KEY_ENV: &str = "VM_MONITOR_API_KEY";
+pub const API_URL_ENV: &str = "VM_MONITOR_API_URL";
+// Any other VM_MONITOR_ variable overrides the setting it names; see `apply_env_overrides`.
+const ENV_PREFIX: &str = "VM_MONITOR_";
+// System-wide config directory and file name, layered under each user's config. On macOS and
+// FreeBSD it's where root daemons kept their config before there were layers.
+#[cfg(target_os = "macos")]
+const SYSTEM_CONFIG: (&str, &str) = ("/Library/Application Support/vm-monitor", CONFIG_FILE_STEM);
+#[cfg(target_os = "freebsd")]
+const SYSTEM_CONFIG: (&str, &str) = ("/usr/local/etc/vm-monitor", CONFIG_FILE_STEM);
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "freebsd"))))]
+const SYSTEM_CONFIG: (&str, &str) = ("/etc/vm-monitor", "config");
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CloudProvider {
     AWS,
     GCP,
@@ -23,10 +42,904 @@ pub enum CloudProvider {
     Unknown(String), // Store reason if known
 }
 
+// A command run on a schedule whose JSON stdout (e.g. {"queue_depth": 12}) is
+// reported as custom metrics named "<name>.<key>".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CustomCollectorConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_custom_collector_interval")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_custom_collector_timeout")]
+    pub timeout_seconds: u64,
+}
+
+// A synthetic HTTP(S) probe run from the VM.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpCheckConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_http_check_method")]
+    pub method: String, // GET or HEAD
+    #[serde(default = "default_http_check_interval")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_http_check_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_http_check_method() -> String {
+    "GET".to_string()
+}
+
+fn default_http_check_interval() -> u64 {
+    60
+}
+
+fn default_http_check_timeout() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PingProtocol {
+    Icmp,
+    Tcp,
+}
+
+// Content-Encoding of metrics batches. Signatures always cover the uncompressed JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+// Wire format for talking to the API. gRPC uses `api_url` as its endpoint and needs a
+// build with the `grpc` feature.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiTransport {
+    #[default]
+    Http,
+    Grpc,
+}
+
+// How `hmac` mode signs requests, chosen at `init`, since it decides what kind of key is
+// generated and registered. With Ed25519, `api_key` is the agent's private key (PKCS#8,
+// base64) and the API only ever sees the public key, so its database can't be used to forge
+// requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureAlgorithm {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+    Ed25519,
+}
+
+// How API requests are authenticated, over HTTP, gRPC and the metrics stream alike.
+// e.g. {"mode": "oauth2", "token_url": "...", "client_id": "...", "client_secret": "..."}
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ApiAuth {
+    #[default]
+    Hmac, // The agent's API key, with a signature of each request
+    Oauth2(OAuth2Settings),
+    AwsSigv4(AwsSigv4Settings),
+    GcpIdentity(GcpIdentitySettings),
+    AzureManagedIdentity(AzureManagedIdentitySettings),
+}
+
+impl ApiAuth {
+    // Only HMAC needs a per-VM key; the other modes authenticate with the VM's identity or a
+    // shared client, so there's no key to generate, register or keep.
+    pub fn uses_api_key(&self) -> bool {
+        matches!(self, ApiAuth::Hmac)
+    }
+}
+
+// The client credentials grant, for an API behind an OAuth2 gateway. The client
+// authenticates to the token endpoint with HTTP Basic auth.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OAuth2Settings {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scope: Option<String>, // Space-separated
+    #[serde(default)]
+    pub audience: Option<String>, // For identity providers that require one, e.g. Auth0
+}
+
+// AWS Signature Version 4 with the instance role's credentials, for an API behind API Gateway
+// with IAM authorization. HTTP and the metrics stream only.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AwsSigv4Settings {
+    #[serde(default)]
+    pub region: Option<String>, // Defaults to AWS_REGION, then the instance's region
+    #[serde(default = "default_sigv4_service")]
+    pub service: String,
+}
+
+fn default_sigv4_service() -> String {
+    "execute-api".to_string()
+}
+
+// A Google-signed identity token of the VM's service account, for an API on Cloud Run or
+// behind IAP, which verifies it without any shared secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GcpIdentitySettings {
+    #[serde(default)]
+    pub audience: Option<String>, // Defaults to `api_url`; for IAP, the OAuth client ID
+    #[serde(default = "default_service_account")]
+    pub service_account: String,
+}
+
+fn default_service_account() -> String {
+    "default".to_string()
+}
+
+// An Entra ID (AAD) access token of the VM's managed identity, for an API registered as an
+// Entra ID application.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AzureManagedIdentitySettings {
+    pub resource: String, // The API's application ID URI, e.g. "api://vm-monitor"
+    #[serde(default)]
+    pub client_id: Option<String>, // Of a user-assigned identity; the system-assigned one otherwise
+}
+
+// TLS for the API connection: HTTP, gRPC and the metrics stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ApiTlsSettings {
+    #[serde(default)]
+    pub ca_file: Option<String>, // PEM bundle, trusted in addition to the built-in roots
+    #[serde(default)]
+    pub insecure_skip_verify: bool, // INSECURE: accepts any server certificate; for testing only
+    #[serde(default)]
+    pub client_cert_file: Option<String>, // PEM, for APIs that require mutual TLS
+    #[serde(default)]
+    pub client_key_file: Option<String>,
+    #[serde(skip)]
+    pub vault_client_identity: Option<ClientIdentity>, // Fetched from Vault at startup, used instead of the files
+}
+
+// A client certificate chain and its key, as PEM.
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity").field("cert", &String::from_utf8_lossy(&self.cert)).finish_non_exhaustive()
+    }
+}
+
+impl ApiTlsSettings {
+    // Read when mutual TLS is configured.
+    pub fn client_identity(&self) -> Result<Option<ClientIdentity>, VmMonitorError> {
+        if let Some(identity) = &self.vault_client_identity {
+            return Ok(Some(identity.clone()));
+        }
+        let read = |path: &str| std::fs::read(path).map_err(|e| VmMonitorError::ConfigError(format!("Failed to read {}: {}", path, e)));
+        match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert), Some(key)) => Ok(Some(ClientIdentity { cert: read(cert)?, key: read(key)? })),
+            (None, None) => Ok(None),
+            _ => Err(VmMonitorError::ConfigError("api_tls client_cert_file and client_key_file must be set together".to_string())),
+        }
+    }
+}
+
+// Which samples go when the in-memory buffer of unsent metrics is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    #[default]
+    DropOldest, // Keep the most recent data
+    DropNewest, // Keep a contiguous history from the start of the outage
+}
+
+// A host checked for reachability on every collection cycle.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingTarget {
+    pub host: String,
+    pub protocol: PingProtocol,
+    #[serde(default)]
+    pub port: Option<u16>, // Required for TCP
+    #[serde(default = "default_ping_timeout")]
+    pub timeout_seconds: u64,
+}
+
+// A name resolved on every collection cycle, through the system resolver unless
+// `server` is set, in which case an A query is sent directly to that nameserver.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsCheck {
+    pub name: String,
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+// A log file tailed between samples, reporting how many new lines match each pattern.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogWatchConfig {
+    pub path: String,
+    pub patterns: Vec<String>, // Regexes, e.g. ["ERROR", "(?i)timed? ?out"]
+}
+
+// An OpenTelemetry collector that receives every sample over OTLP/HTTP, alongside the API.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OtlpSettings {
+    pub endpoint: String, // e.g. "http://localhost:4318"; "/v1/metrics" is appended
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>, // e.g. {"Authorization": "Bearer ..."}
+    #[serde(default = "default_otlp_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_otlp_timeout() -> u64 {
+    10
+}
+
+// A StatsD server or relay (e.g. Telegraf's statsd input) that receives every sample as gauges.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsdSettings {
+    pub address: String, // host:port, usually port 8125
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String, // Prepended to every metric name, "" for none
+}
+
+fn default_statsd_prefix() -> String {
+    "vm_monitor".to_string()
+}
+
+// A Kafka topic receiving every metrics batch. Needs a build with the `kafka` feature.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KafkaSettings {
+    pub brokers: Vec<String>, // Bootstrap brokers as host:port, plaintext only
+    pub topic: String, // Must already exist
+    #[serde(default = "default_kafka_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_kafka_timeout() -> u64 {
+    10
+}
+
+// An MQTT broker receiving every sample as JSON. Needs a build with the `mqtt` feature.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttSettings {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>, // Defaults to 8883 with TLS, 1883 without
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String, // "{instance_id}" and "{instance_name}" are filled in
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8, // 0, 1 or 2
+    #[serde(default)]
+    pub client_id: Option<String>, // Defaults to "vm-monitor-<instance_id>"
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub ca_file: Option<String>, // PEM; the system trust store is used when unset
+    #[serde(default)]
+    pub client_cert_file: Option<String>, // PEM, for brokers that authenticate clients by certificate
+    #[serde(default)]
+    pub client_key_file: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive_seconds: u64,
+}
+
+fn default_mqtt_topic() -> String {
+    "vm-monitor/{instance_id}/metrics".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_keep_alive() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    #[default]
+    Unix, // The local syslog daemon's socket
+    Udp,
+    Tcp,
+}
+
+// Syslog (RFC 5424) output of a summary of every sample and of the agent's own warnings and errors.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyslogSettings {
+    #[serde(default)]
+    pub transport: SyslogTransport,
+    #[serde(default)]
+    pub address: Option<String>, // host:port for UDP and TCP; socket path for unix, defaulting to /dev/log (/var/run/syslog on macOS)
+    #[serde(default = "default_syslog_facility")]
+    pub facility: String, // e.g. "daemon", "user", "local0"
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+    #[serde(default = "default_syslog_events_level")]
+    pub events_level: String, // Agent log records at this level and above are forwarded: "error", "warn", "info"
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".to_string()
+}
+
+fn default_syslog_app_name() -> String {
+    "vm-monitor".to_string()
+}
+
+fn default_syslog_events_level() -> String {
+    "warn".to_string()
+}
+
+// A local file receiving every sample as one JSON line, rotated by size and age.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileSinkSettings {
+    pub path: String, // e.g. "/var/log/vm-monitor/metrics.jsonl"
+    #[serde(default = "default_file_sink_max_bytes")]
+    pub max_bytes: u64, // Rotated before growing past this (0 disables size-based rotation)
+    #[serde(default)]
+    pub rotate_seconds: u64, // Rotated once this old, e.g. 86400 for daily files (0 disables)
+    #[serde(default = "default_file_sink_max_files")]
+    pub max_files: usize, // Oldest rotated files are removed beyond this (0 keeps all)
+    #[serde(default)]
+    pub compress: bool, // gzip rotated files
+}
+
+fn default_file_sink_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_file_sink_max_files() -> usize {
+    10
+}
+
+// Amazon CloudWatch, receiving every batch through PutMetricData. Credentials come from
+// the AWS_* environment variables or the EC2 instance role.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudWatchSettings {
+    #[serde(default = "default_cloudwatch_namespace")]
+    pub namespace: String,
+    #[serde(default)]
+    pub region: Option<String>, // Defaults to AWS_REGION, then the instance's own region
+    #[serde(default)]
+    pub endpoint: Option<String>, // e.g. a VPC endpoint; defaults to https://monitoring.<region>.amazonaws.com
+    #[serde(default = "default_cloudwatch_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_cloudwatch_namespace() -> String {
+    "VmMonitor".to_string()
+}
+
+fn default_cloudwatch_timeout() -> u64 {
+    10
+}
+
+// Azure Monitor custom metrics, authenticated with the VM's managed identity. The region and
+// resource ID default to the VM's own, from the instance metadata service.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AzureMonitorSettings {
+    #[serde(default = "default_azure_monitor_namespace")]
+    pub namespace: String,
+    #[serde(default)]
+    pub region: Option<String>, // e.g. "westeurope"
+    #[serde(default)]
+    pub resource_id: Option<String>, // "/subscriptions/.../resourceGroups/.../providers/Microsoft.Compute/virtualMachines/..."
+    #[serde(default)]
+    pub client_id: Option<String>, // Of a user-assigned identity; the system-assigned one otherwise
+    #[serde(default)]
+    pub endpoint: Option<String>, // Defaults to https://<region>.monitoring.azure.com
+    #[serde(default = "default_azure_monitor_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_azure_monitor_namespace() -> String {
+    "VmMonitor".to_string()
+}
+
+fn default_azure_monitor_timeout() -> u64 {
+    10
+}
+
+// The New Relic Metric API. `endpoint` is the US one by default; EU accounts use
+// https://metric-api.eu.newrelic.com/metric/v1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewRelicSettings {
+    pub license_key: String,
+    #[serde(default = "default_newrelic_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_newrelic_prefix")]
+    pub prefix: String, // Metric names become e.g. "vm_monitor.cpu.usage_percent"
+    #[serde(default = "default_newrelic_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_newrelic_endpoint() -> String {
+    "https://metric-api.newrelic.com/metric/v1".to_string()
+}
+
+fn default_newrelic_prefix() -> String {
+    "vm_monitor".to_string()
+}
+
+fn default_newrelic_timeout() -> u64 {
+    10
+}
+
+// An Elasticsearch or OpenSearch cluster, receiving every sample as a document through the
+// bulk API. Authenticates with `api_key` if set, else `username`/`password` if set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ElasticsearchSettings {
+    pub url: String, // e.g. "https://es.internal:9200"
+    #[serde(default = "default_elasticsearch_index")]
+    pub index: String, // strftime pattern, expanded with the sample's UTC timestamp
+    #[serde(default)]
+    pub api_key: Option<String>, // Base64 "id:api_key", as Elasticsearch returns it
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_elasticsearch_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_elasticsearch_index() -> String {
+    "vm-monitor-%Y.%m.%d".to_string()
+}
+
+fn default_elasticsearch_timeout() -> u64 {
+    30
+}
+
+// One place samples are sent, from the `sinks` list. Sinks of the same type can be listed more
+// than once, e.g. two OTLP collectors; a disabled one stays in the file but isn't set up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SinkConfig {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    #[serde(default = "default_sink_enabled")]
+    pub enabled: bool,
+}
+
+fn default_sink_enabled() -> bool {
+    true
+}
+
+// The sink's `type` and, alongside it, its own settings: endpoint, credentials and the rest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkKind {
+    Api, // The agent's own API; saved with `url` and `api_key`, which are `Configuration::api_url` and `api_key`
+    Prometheus { listen: String }, // e.g. "0.0.0.0:9900" to serve /metrics for scraping, overridden by `start --listen`
+    Otlp(OtlpSettings),
+    Statsd(StatsdSettings),
+    Kafka(KafkaSettings),
+    Mqtt(MqttSettings),
+    Syslog(SyslogSettings),
+    File(FileSinkSettings),
+    Cloudwatch(CloudWatchSettings),
+    AzureMonitor(AzureMonitorSettings),
+    Newrelic(NewRelicSettings),
+    Elasticsearch(ElasticsearchSettings),
+}
+
+impl SinkConfig {
+    pub fn new(kind: SinkKind) -> Self {
+        SinkConfig { kind, enabled: true }
+    }
+
+    // `sinks` after the API, for `init`, unless they already list it.
+    pub fn with_api(sinks: Vec<SinkConfig>) -> Vec<SinkConfig> {
+        if sinks.iter().any(|sink| matches!(sink.kind, SinkKind::Api)) {
+            return sinks;
+        }
+        default_sinks().into_iter().chain(sinks).collect()
+    }
+
+    // Its `type` in the config, for listing sinks.
+    pub fn type_name(&self) -> &'static str {
+        match self.kind {
+            SinkKind::Api => "api",
+            SinkKind::Prometheus { .. } => "prometheus",
+            SinkKind::Otlp(_) => "otlp",
+            SinkKind::Statsd(_) => "statsd",
+            SinkKind::Kafka(_) => "kafka",
+            SinkKind::Mqtt(_) => "mqtt",
+            SinkKind::Syslog(_) => "syslog",
+            SinkKind::File(_) => "file",
+            SinkKind::Cloudwatch(_) => "cloudwatch",
+            SinkKind::AzureMonitor(_) => "azure_monitor",
+            SinkKind::Newrelic(_) => "newrelic",
+            SinkKind::Elasticsearch(_) => "elasticsearch",
+        }
+    }
+
+    // Where it sends to, for listing sinks.
+    pub fn target(&self) -> String {
+        match &self.kind {
+            SinkKind::Api => "api_url".to_string(),
+            SinkKind::Prometheus { listen } => listen.clone(),
+            SinkKind::Otlp(settings) => settings.endpoint.clone(),
+            SinkKind::Statsd(settings) => settings.address.clone(),
+            SinkKind::Kafka(settings) => format!("{} ({})", settings.brokers.join(","), settings.topic),
+            SinkKind::Mqtt(settings) => format!("{} ({})", settings.host, settings.topic),
+            SinkKind::Syslog(settings) => settings.address.clone().unwrap_or_else(|| "local syslog".to_string()),
+            SinkKind::File(settings) => settings.path.clone(),
+            SinkKind::Cloudwatch(settings) => format!("namespace {}", settings.namespace),
+            SinkKind::AzureMonitor(settings) => format!("namespace {}", settings.namespace),
+            SinkKind::Newrelic(settings) => settings.endpoint.clone(),
+            SinkKind::Elasticsearch(settings) => settings.url.clone(),
+        }
+    }
+}
+
+// What a config without a `sinks` list sends to: the API alone, as before there was one.
+fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::new(SinkKind::Api)]
+}
+
+// Retries of API requests that failed transiently (connection errors, timeouts, 5xx).
+// Attempt n waits `base_delay_ms * 2^(n-1)`, capped at `max_delay_ms` and reduced by up
+// to `jitter` (0.0 to 1.0) of itself so agents restarted together don't retry in lockstep.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, // Including the first one; 1 disables retries
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: 0.5,
+        }
+    }
+}
+
+// After `failure_threshold` consecutive requests fail transiently (retries included), API
+// calls fail fast for `open_seconds`, then the health endpoint is probed before resuming.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    pub failure_threshold: u32, // 0 disables the breaker
+    pub open_seconds: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        CircuitBreakerSettings {
+            failure_threshold: 3,
+            open_seconds: 60,
+        }
+    }
+}
+
+// Timeouts of API connections, in seconds. `request_seconds` bounds a whole request,
+// connecting included. 0 disables a timeout, or TCP keepalive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ApiTimeouts {
+    pub connect_seconds: u64,
+    pub request_seconds: u64,
+    pub pool_idle_seconds: u64, // Idle pooled connections are closed after this
+    pub tcp_keepalive_seconds: u64,
+}
+
+impl Default for ApiTimeouts {
+    fn default() -> Self {
+        ApiTimeouts {
+            connect_seconds: 30,
+            request_seconds: 30,
+            pool_idle_seconds: 90,
+            tcp_keepalive_seconds: 0,
+        }
+    }
+}
+
+impl ApiTimeouts {
+    fn duration(seconds: u64) -> Option<std::time::Duration> {
+        (seconds > 0).then(|| std::time::Duration::from_secs(seconds))
+    }
+
+    pub fn connect(&self) -> Option<std::time::Duration> {
+        Self::duration(self.connect_seconds)
+    }
+
+    pub fn request(&self) -> Option<std::time::Duration> {
+        Self::duration(self.request_seconds)
+    }
+
+    pub fn pool_idle(&self) -> Option<std::time::Duration> {
+        Self::duration(self.pool_idle_seconds)
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<std::time::Duration> {
+        Self::duration(self.tcp_keepalive_seconds)
+    }
+}
+
+// The API's endpoints. An endpoint's HTTP path is `prefix` followed by its own path, so a
+// gateway can be targeted by changing the prefix alone, or single endpoints moved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiEndpoint {
+    Register,
+    Metrics,
+    Heartbeat,
+    Inventory,
+    Health,
+    Commands, // Polled for commands to the agent
+    CommandResult,
+    RotateKey, // Registers a new API key, signed with the current one
+    #[cfg(feature = "websocket")]
+    Stream, // The metrics stream
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ApiPaths {
+    pub prefix: String,
+    pub register: String,
+    pub metrics: String,
+    pub heartbeat: String,
+    pub inventory: String,
+    pub health: String,
+    pub commands: String,
+    pub command_result: String,
+    pub rotate_key: String,
+    pub stream: String,
+}
+
+impl Default for ApiPaths {
+    fn default() -> Self {
+        ApiPaths {
+            prefix: "/v1".to_string(),
+            register: "/agent/register".to_string(),
+            metrics: "/agent/metrics".to_string(),
+            heartbeat: "/agent/heartbeat".to_string(),
+            inventory: "/agent/inventory".to_string(),
+            health: "/health".to_string(),
+            commands: "/agent/commands".to_string(),
+            command_result: "/agent/commands/result".to_string(),
+            rotate_key: "/agent/rotate-key".to_string(),
+            stream: "/agent/stream".to_string(),
+        }
+    }
+}
+
+impl ApiPaths {
+    pub fn path(&self, endpoint: ApiEndpoint) -> String {
+        let path = match endpoint {
+            ApiEndpoint::Register => &self.register,
+            ApiEndpoint::Metrics => &self.metrics,
+            ApiEndpoint::Heartbeat => &self.heartbeat,
+            ApiEndpoint::Inventory => &self.inventory,
+            ApiEndpoint::Health => &self.health,
+            ApiEndpoint::Commands => &self.commands,
+            ApiEndpoint::CommandResult => &self.command_result,
+            ApiEndpoint::RotateKey => &self.rotate_key,
+            #[cfg(feature = "websocket")]
+            ApiEndpoint::Stream => &self.stream,
+        };
+        format!("{}/{}", self.prefix.trim_end_matches('/'), path.trim_start_matches('/'))
+    }
+}
+
+fn default_ping_timeout() -> u64 {
+    2
+}
+
+fn default_custom_collector_interval() -> u64 {
+    60
+}
+
+fn default_custom_collector_timeout() -> u64 {
+    10
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MonitoringSettings {
     pub interval_seconds: u64,
     pub batch_size: usize,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>, // Attached to the registration and every sample, e.g. {"env": "prod"}
+    #[serde(default = "default_top_processes")]
+    pub top_processes: usize, // Number of processes reported per ranking (0 disables)
+    #[serde(default = "default_top_users")]
+    pub top_users: usize, // Number of users reported by aggregate CPU usage (0 disables)
+    #[serde(default)]
+    pub collect_docker: bool,
+    #[serde(default = "default_docker_socket")]
+    pub docker_socket: String,
+    #[serde(default)]
+    pub collect_power: bool, // Battery and power draw, for laptops and edge gateways
+    #[serde(default)]
+    pub collect_smart: bool, // Requires smartctl (smartmontools >= 7.0) and usually root
+    // Switched off for a minimal agent reporting little more than CPU and memory
+    #[serde(default = "default_collect")]
+    pub collect_per_core: bool, // Per-core CPU usage
+    #[serde(default = "default_collect")]
+    pub collect_disks: bool, // Usage and I/O per disk
+    #[serde(default = "default_collect")]
+    pub collect_network: bool, // Traffic per interface, TCP and conntrack counts
+    #[serde(default = "default_collect")]
+    pub collect_processes: bool, // Process count, top processes and users, and the process watchlist
+    #[serde(default = "default_collect")]
+    pub collect_kernel: bool, // Context switches, interrupts, file descriptors and entropy
+    #[serde(default = "default_collect")]
+    pub collect_gpu: bool, // With the `gpu` feature
+    #[serde(default)]
+    pub watch_services: Vec<String>, // systemd units (Windows service names on Windows), e.g. ["nginx", "postgresql"]
+    #[serde(default)]
+    pub watch_processes: Vec<String>, // Regexes matched against process name and command line
+    #[serde(default = "default_listening_ports_interval")]
+    pub listening_ports_interval_seconds: u64, // How often the socket inventory is attached (0 disables)
+    #[serde(default = "default_disk_exclude_fs_types")]
+    pub disk_exclude_fs_types: Vec<String>,
+    #[serde(default)]
+    pub disk_exclude_mounts: Vec<String>, // Excludes the mount point and everything below it
+    #[serde(default)]
+    pub network_include: Vec<String>, // Regexes; when non-empty only matching interfaces are reported
+    #[serde(default)]
+    pub network_exclude: Vec<String>, // Regexes, e.g. ["^lo$", "^veth", "^docker0$"]
+    #[serde(default)]
+    pub custom_collectors: Vec<CustomCollectorConfig>,
+    #[serde(default)]
+    pub textfile_directory: Option<String>, // Drop-box for .prom/.json files from cron jobs and scripts
+    #[serde(default = "default_ntp_server")]
+    pub ntp_server: String,
+    #[serde(default = "default_clock_check_interval")]
+    pub clock_check_interval_seconds: u64, // How often clock offset is measured (0 disables)
+    #[serde(default)]
+    pub certificate_files: Vec<String>, // PEM or DER files, e.g. ["/etc/nginx/tls/site.crt"]
+    #[serde(default)]
+    pub certificate_endpoints: Vec<String>, // TLS endpoints as host:port, e.g. ["localhost:443"]
+    #[serde(default = "default_certificate_check_interval")]
+    pub certificate_check_interval_seconds: u64,
+    #[serde(default)]
+    pub http_checks: Vec<HttpCheckConfig>,
+    #[serde(default)]
+    pub ping_targets: Vec<PingTarget>, // e.g. [{"host": "10.0.0.5", "protocol": "tcp", "port": 5432}]
+    #[serde(default)]
+    pub dns_checks: Vec<DnsCheck>, // e.g. [{"name": "db.internal"}, {"name": "example.com", "server": "169.254.169.253"}]
+    #[serde(default)]
+    pub log_watches: Vec<LogWatchConfig>,
+    #[serde(default)]
+    pub spool_directory: Option<String>, // Unsent batches; defaults to "spool" next to the config file
+    #[serde(default = "default_spool_max_bytes")]
+    pub spool_max_bytes: u64, // Oldest batches are dropped beyond this (0 keeps unsent metrics in memory only)
+    #[serde(default = "default_max_buffered_batches")]
+    pub max_buffered_batches: usize, // In-memory bound on unsent metrics, used when the spool is off or failing
+    #[serde(default)]
+    pub buffer_drop_policy: DropPolicy,
+    #[serde(default)]
+    pub dead_letter_directory: Option<String>, // Batches rejected with 400/422; defaults to "dead-letter" next to the config file
+    #[serde(default = "default_dead_letter_max_files")]
+    pub dead_letter_max_files: usize, // Oldest files are removed beyond this (0 drops rejected batches)
+    #[serde(default = "default_max_batch_bytes")]
+    pub max_batch_bytes: u64, // Larger batches (before compression) are split before sending (0 only splits on HTTP 413)
+    #[serde(default)]
+    pub compression: PayloadCompression, // "none", "gzip" or "zstd"; the backend must accept the encoding
+    #[serde(default)]
+    pub api_transport: ApiTransport, // "http" (JSON) or "grpc" (protobuf)
+    #[serde(default)]
+    pub api_auth: ApiAuth,
+    #[serde(default)]
+    pub signature_algorithm: SignatureAlgorithm, // "hmac_sha256", "hmac_sha512" or "ed25519"
+    #[serde(default)]
+    pub api_tls: ApiTlsSettings,
+    #[serde(default)]
+    pub api_proxy: Option<String>, // e.g. "http://proxy.internal:3128", credentials in the URL; unset uses HTTPS_PROXY and the like
+    #[serde(default)]
+    pub stream_metrics: bool, // Stream samples over a persistent WebSocket instead of sending batches (`websocket` feature)
+    #[serde(default)]
+    pub api_retry: RetryPolicy,
+    #[serde(default)]
+    pub api_circuit_breaker: CircuitBreakerSettings,
+    #[serde(default)]
+    pub api_timeouts: ApiTimeouts,
+    #[serde(default)]
+    pub api_max_requests_per_minute: u32, // Across all API requests, retries and health probes included; 0 is unlimited
+    #[serde(default)]
+    pub api_paths: ApiPaths,
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval_seconds: u64, // 0 disables heartbeats, for APIs without the endpoint
+    #[serde(default = "default_heartbeat_details")]
+    pub heartbeat_details: bool, // Send the agent's version, uptime and interval; off sends just the instance ID
+    #[serde(default = "default_remote_config")]
+    pub remote_config: bool, // Apply settings changes sent with heartbeat responses
+    #[serde(default)]
+    pub command_poll_seconds: u64, // How often to ask the API for commands; 0 disables
+    #[serde(default = "default_sink_timeout")]
+    pub sink_timeout_seconds: u64, // How long each sink gets to send, heartbeat or flush before it's given up on; 0 waits
+}
+
+fn default_sink_timeout() -> u64 {
+    60
+}
+
+fn default_top_processes() -> usize {
+    5
+}
+
+fn default_listening_ports_interval() -> u64 {
+    60 * 60
+}
+
+fn default_disk_exclude_fs_types() -> Vec<String> {
+    ["tmpfs", "overlay", "squashfs"].iter().map(|fs| fs.to_string()).collect()
+}
+
+fn default_ntp_server() -> String {
+    "pool.ntp.org".to_string()
+}
+
+fn default_clock_check_interval() -> u64 {
+    60 * 60
+}
+
+fn default_certificate_check_interval() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_dead_letter_max_files() -> usize {
+    100
+}
+
+// nginx's default client_max_body_size
+fn default_max_batch_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_collect() -> bool {
+    true
+}
+
+fn default_heartbeat_interval() -> u64 {
+    300
+}
+
+fn default_heartbeat_details() -> bool {
+    true
+}
+
+fn default_remote_config() -> bool {
+    true
+}
+
+fn default_max_buffered_batches() -> usize {
+    5
+}
+
+fn default_spool_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_top_users() -> usize {
+    5
+}
+
+fn default_docker_socket() -> String {
+    "/var/run/docker.sock".to_string()
 }
 
 impl Default for MonitoringSettings {
@@ -34,43 +947,915 @@ impl Default for MonitoringSettings {
         MonitoringSettings {
             interval_seconds: 60,
             batch_size: 10,
+            tags: BTreeMap::new(),
+            top_processes: default_top_processes(),
+            top_users: default_top_users(),
+            collect_docker: false,
+            docker_socket: default_docker_socket(),
+            collect_power: false,
+            collect_smart: false,
+            collect_per_core: true,
+            collect_disks: true,
+            collect_network: true,
+            collect_processes: true,
+            collect_kernel: true,
+            collect_gpu: true,
+            watch_services: Vec::new(),
+            watch_processes: Vec::new(),
+            listening_ports_interval_seconds: default_listening_ports_interval(),
+            disk_exclude_fs_types: default_disk_exclude_fs_types(),
+            disk_exclude_mounts: Vec::new(),
+            network_include: Vec::new(),
+            network_exclude: Vec::new(),
+            custom_collectors: Vec::new(),
+            textfile_directory: None,
+            ntp_server: default_ntp_server(),
+            clock_check_interval_seconds: default_clock_check_interval(),
+            certificate_files: Vec::new(),
+            certificate_endpoints: Vec::new(),
+            certificate_check_interval_seconds: default_certificate_check_interval(),
+            http_checks: Vec::new(),
+            ping_targets: Vec::new(),
+            dns_checks: Vec::new(),
+            log_watches: Vec::new(),
+            spool_directory: None,
+            spool_max_bytes: default_spool_max_bytes(),
+            max_buffered_batches: default_max_buffered_batches(),
+            buffer_drop_policy: DropPolicy::default(),
+            dead_letter_directory: None,
+            dead_letter_max_files: default_dead_letter_max_files(),
+            max_batch_bytes: default_max_batch_bytes(),
+            compression: PayloadCompression::default(),
+            api_transport: ApiTransport::default(),
+            api_auth: ApiAuth::default(),
+            signature_algorithm: SignatureAlgorithm::default(),
+            api_tls: ApiTlsSettings::default(),
+            api_proxy: None,
+            stream_metrics: false,
+            api_retry: RetryPolicy::default(),
+            api_circuit_breaker: CircuitBreakerSettings::default(),
+            api_timeouts: ApiTimeouts::default(),
+            api_max_requests_per_minute: 0,
+            api_paths: ApiPaths::default(),
+            heartbeat_interval_seconds: default_heartbeat_interval(),
+            heartbeat_details: default_heartbeat_details(),
+            remote_config: default_remote_config(),
+            command_poll_seconds: 0,
+            sink_timeout_seconds: default_sink_timeout(),
+        }
+    }
+}
+
+// Settings the API can change by answering a heartbeat with `{"config": {...}}`. Settings
+// left out keep their value.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    pub interval_seconds: Option<u64>,
+    pub batch_size: Option<usize>,
+    pub top_processes: Option<usize>,
+    pub top_users: Option<usize>,
+    pub collect_docker: Option<bool>,
+    pub collect_power: Option<bool>,
+    pub collect_smart: Option<bool>,
+    pub collect_per_core: Option<bool>,
+    pub collect_disks: Option<bool>,
+    pub collect_network: Option<bool>,
+    pub collect_processes: Option<bool>,
+    pub collect_kernel: Option<bool>,
+    pub collect_gpu: Option<bool>,
+}
+
+impl ConfigUpdate {
+    /// Applies the update, returning a description of each setting it changed.
+    pub fn apply(&self, settings: &mut MonitoringSettings) -> Vec<String> {
+        fn set<T: PartialEq + Copy + std::fmt::Debug>(changes: &mut Vec<String>, name: &str, setting: &mut T, value: Option<T>) {
+            if let Some(value) = value.filter(|value| value != setting) {
+                changes.push(format!("{}: {:?} -> {:?}", name, setting, value));
+                *setting = value;
+            }
         }
+        let mut changes = Vec::new();
+        let interval = self.interval_seconds.filter(|&interval| interval > 0); // 0 is invalid for both
+        set(&mut changes, "interval_seconds", &mut settings.interval_seconds, interval);
+        set(&mut changes, "batch_size", &mut settings.batch_size, self.batch_size.filter(|&size| size > 0));
+        set(&mut changes, "top_processes", &mut settings.top_processes, self.top_processes);
+        set(&mut changes, "top_users", &mut settings.top_users, self.top_users);
+        set(&mut changes, "collect_docker", &mut settings.collect_docker, self.collect_docker);
+        set(&mut changes, "collect_power", &mut settings.collect_power, self.collect_power);
+        set(&mut changes, "collect_smart", &mut settings.collect_smart, self.collect_smart);
+        set(&mut changes, "collect_per_core", &mut settings.collect_per_core, self.collect_per_core);
+        set(&mut changes, "collect_disks", &mut settings.collect_disks, self.collect_disks);
+        set(&mut changes, "collect_network", &mut settings.collect_network, self.collect_network);
+        set(&mut changes, "collect_processes", &mut settings.collect_processes, self.collect_processes);
+        set(&mut changes, "collect_kernel", &mut settings.collect_kernel, self.collect_kernel);
+        set(&mut changes, "collect_gpu", &mut settings.collect_gpu, self.collect_gpu);
+        changes
     }
 }
 
+// Where the API key is kept. With the keyring, the config file's `api_key` only names the
+// keyring entry, as "keyring:<account>"; with Vault, the secret, as "vault:<path>#<field>";
+// with a key file, the file, as "file:<path>".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ApiKeyStore {
+    #[default]
+    ConfigFile,
+    Keyring(String), // The account under the "vm-monitor" service
+    Environment(String), // VM_MONITOR_API_KEY, with the file's own `api_key` (often none) kept on save
+    Vault(String), // The reference, kept on save; the key is only ever in memory
+    KeyFile(String), // Its path; the system-wide config's key always goes to one, see `save_config`
+}
+
+// HashiCorp Vault, for secrets fetched at startup and kept in memory only.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultSettings {
+    pub address: String, // e.g. "https://vault.example.com:8200"
+    pub auth: VaultAuth,
+    #[serde(default)]
+    pub auth_mount: Option<String>, // Where the auth method is mounted; its name by default
+    #[serde(default)]
+    pub namespace: Option<String>, // Vault Enterprise
+    #[serde(default)]
+    pub ca_file: Option<String>, // PEM bundle, trusted in addition to the built-in roots
+    #[serde(default)]
+    pub client_certificate: Option<String>, // "<path>", a secret with `certificate` and `private_key` fields for mutual TLS to the API
+    #[serde(default = "default_vault_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_vault_timeout() -> u64 {
+    10
+}
+
+// How the agent logs in to Vault. The cloud methods prove the VM's identity with its
+// instance role, service account or managed identity, so no secret is stored at all.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum VaultAuth {
+    Approle {
+        role_id: String,
+        #[serde(default)]
+        secret_id: Option<String>,
+        #[serde(default)]
+        secret_id_file: Option<String>, // e.g. delivered by the provisioning system and deleted after use
+    },
+    Aws {
+        role: String,
+        #[serde(default)]
+        server_id: Option<String>, // The X-Vault-AWS-IAM-Server-ID the auth method requires, if any
+    },
+    Gcp {
+        role: String,
+        #[serde(default = "default_service_account")]
+        service_account: String,
+    },
+    Azure {
+        role: String,
+        #[serde(default = "default_azure_vault_resource")]
+        resource: String,
+        #[serde(default)]
+        client_id: Option<String>, // Of a user-assigned identity
+    },
+}
+
+fn default_azure_vault_resource() -> String {
+    "https://management.azure.com/".to_string()
+}
+
+// The environment variable's value, unless unset or empty.
+pub fn env_override(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// A setting overridden by a VM_MONITOR_ variable.
+#[derive(Debug, Clone)]
+pub struct EnvOverride {
+    pub variable: String,
+    pointer: String, // The setting's JSON pointer, e.g. /monitoring_settings/api_retry/max_attempts
+    value: serde_json::Value,
+    // Written back on save unless the setting changed since: the file's value at `file_pointer`, the
+    // setting or the section it created
+    file_pointer: String,
+    file_value: Option<serde_json::Value>,
+}
+
+// The variable's value as the setting's type: a string setting takes it as is, a list also takes
+// comma-separated strings, and anything else is parsed as JSON, falling back to a string (for enums).
+fn env_value(current: Option<&serde_json::Value>, raw: &str) -> serde_json::Value {
+    use serde_json::Value;
+    match current {
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        Some(Value::Array(_)) if !raw.trim_start().starts_with('[') => {
+            Value::Array(raw.split(',').map(str::trim).filter(|item| !item.is_empty()).map(|item| Value::String(item.to_string())).collect())
+        }
+        _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string())),
+    }
+}
+
+// Sets the value at `pointer`, making objects of whatever's missing or null on the way.
+fn set_pointer(root: &mut serde_json::Value, pointer: &str, value: serde_json::Value) {
+    let mut node = root;
+    for key in pointer.split('/').skip(1) {
+        if let Some(index) = key.parse::<usize>().ok().filter(|&index| node.as_array().is_some_and(|items| index < items.len())) {
+            node = &mut node[index];
+            continue;
+        }
+        if !node.is_object() {
+            *node = serde_json::Value::Object(serde_json::Map::new());
+        }
+        node = node.as_object_mut().expect("just made an object").entry(key).or_insert(serde_json::Value::Null);
+    }
+    *node = value;
+}
+
+fn remove_pointer(root: &mut serde_json::Value, pointer: &str) {
+    if let Some((parent, key)) = pointer.rsplit_once('/')
+        && let Some(object) = root.pointer_mut(parent).and_then(|parent| parent.as_object_mut())
+    {
+        object.remove(key);
+    }
+}
+
+// The JSON pointer of a setting named by its keys, where `monitoring_settings` (or just
+// `monitoring`) can be left out: ["interval_seconds"] is /monitoring_settings/interval_seconds.
+fn setting_pointer(config: &serde_json::Value, keys: &[&str]) -> String {
+    if let [name @ ("api_url" | "api_key")] = keys
+        && let Some(api) = api_sink_number(config)
+    {
+        return format!("/sinks/{}/{}", api, if *name == "api_url" { "url" } else { "api_key" });
+    }
+    let keys = match keys {
+        ["monitoring" | "monitoring_settings", rest @ ..] => [&["monitoring_settings"], rest].concat(),
+        [first, ..] if config["monitoring_settings"].get(first).is_some() => [&["monitoring_settings"], keys].concat(),
+        _ => keys.to_vec(),
+    };
+    keys.iter().map(|key| format!("/{}", key)).collect()
+}
+
+// Where `api_url` and `api_key` are kept in the saved config.
+fn api_sink_number(config: &serde_json::Value) -> Option<usize> {
+    config["sinks"].as_array()?.iter().position(|sink| sink["type"] == "api")
+}
+
+// VM_MONITOR_INTERVAL_SECONDS, VM_MONITOR_API_RETRY__MAX_ATTEMPTS or VM_MONITOR_INSTANCE_NAME: the
+// setting's name in upper case, with `__` between nested keys and `monitoring_settings` left out.
+// For images that bake in one config and tell environments apart at boot.
+fn apply_env_overrides(config: &mut Configuration) -> Result<(), VmMonitorError> {
+    let reserved = [CONFIG_PATH_ENV, API_KEY_ENV, API_URL_ENV]; // Handled on their own
+    let mut variables: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, value)| name.starts_with(ENV_PREFIX) && !reserved.contains(&name.as_str()) && !value.is_empty())
+        .collect();
+    if variables.is_empty() {
+        return Ok(());
+    }
+    variables.sort(); // So VM_MONITOR_API_RETRY comes before VM_MONITOR_API_RETRY__MAX_ATTEMPTS
+
+    let mut value = serde_json::to_value(&*config)?;
+    let mut overrides = Vec::new();
+    for (variable, raw) in variables {
+        let keys = variable[ENV_PREFIX.len()..].to_lowercase();
+        let pointer = setting_pointer(&value, &keys.split("__").collect::<Vec<_>>());
+
+        let file_pointer = pointer
+            .match_indices('/')
+            .skip(1)
+            .map(|(end, _)| &pointer[..end])
+            .chain([pointer.as_str()])
+            .find(|prefix| value.pointer(prefix).is_none_or(serde_json::Value::is_null))
+            .unwrap_or(&pointer)
+            .to_string();
+        let file_value = value.pointer(&file_pointer).cloned();
+        let new_value = env_value(value.pointer(&pointer), &raw);
+        set_pointer(&mut value, &pointer, new_value);
+        let updated: Configuration = serde_json::from_value(value.clone())
+            .map_err(|e| VmMonitorError::ConfigError(format!("Invalid {}: {}", variable, e)))?;
+        // What the setting became, having gone through its type; nothing if it isn't a setting.
+        value = serde_json::to_value(&updated)?;
+        let Some(applied) = value.pointer(&pointer).cloned() else {
+            return Err(VmMonitorError::ConfigError(format!("{} doesn't name a setting", variable)));
+        };
+        log::debug!("{} overrides {}", variable, &pointer[1..]);
+        overrides.push(EnvOverride { variable, pointer, value: applied, file_pointer, file_value });
+    }
+    *config = serde_json::from_value(value)?;
+    config.env_overrides = overrides;
+    Ok(())
+}
+
+// Saved as `ConfigurationFile`, where `api_url` and the API key (or where it's kept) are the
+// `api` sink's settings; here they're fields of their own, as everything that talks to the API uses them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "ConfigurationFile", into = "ConfigurationFile")]
 pub struct Configuration {
-    pub instance_id: Uuid,
+    pub schema_version: u32, // CONFIG_SCHEMA_VERSION once loaded; 0 in files from before it existed
+    pub instance_id: Uuid, // Nil in a config from `init --offline` until `start` assigns one
     pub instance_name: String,
     pub api_url: String,
-    pub api_key: String,
+    pub file_api_url: Option<String>, // The file's own `api_url` when VM_MONITOR_API_URL overrides it, kept on save
+    pub api_key: String, // Always the key itself once loaded, wherever it's stored
+    pub api_key_store: ApiKeyStore,
+    pub vault: Option<VaultSettings>,
     pub cloud_provider: CloudProvider,
     pub monitoring_settings: MonitoringSettings,
+    pub sinks: Vec<SinkConfig>,
     pub initialized_at: DateTime<Utc>,
+    pub registration_pending: bool, // Set by `init --offline` until `start` has registered the instance
+    pub env_overrides: Vec<EnvOverride>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConfigurationFile {
+    #[serde(default)]
+    schema_version: u32,
+    #[serde(default, skip_serializing_if = "Uuid::is_nil")]
+    instance_id: Uuid,
+    instance_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vault: Option<VaultSettings>,
+    cloud_provider: CloudProvider,
+    monitoring_settings: MonitoringSettings,
+    #[serde(default = "default_sink_values")]
+    sinks: Vec<serde_json::Value>, // The first `api` entry also has `url` and `api_key`
+    initialized_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    registration_pending: bool,
+}
+
+fn default_sink_values() -> Vec<serde_json::Value> {
+    vec![serde_json::json!({"type": "api"})]
+}
+
+impl TryFrom<ConfigurationFile> for Configuration {
+    type Error = String;
+
+    fn try_from(file: ConfigurationFile) -> Result<Self, String> {
+        let (mut api_url, mut api_key) = (None, None);
+        let mut sinks = Vec::with_capacity(file.sinks.len());
+        for (number, mut sink) in file.sinks.into_iter().enumerate() {
+            if let Some(entry) = sink.as_object_mut().filter(|entry| entry.get("type").and_then(serde_json::Value::as_str) == Some("api")) {
+                let url = entry.remove("url").and_then(|url| url.as_str().map(str::to_string));
+                let key = entry.remove("api_key").and_then(|key| key.as_str().map(str::to_string));
+                if api_url.is_none() {
+                    (api_url, api_key) = (url, key);
+                }
+            }
+            sinks.push(serde_json::from_value(sink).map_err(|e| format!("sinks.{}: {}", number, e))?);
+        }
+        Ok(Configuration {
+            schema_version: file.schema_version,
+            instance_id: file.instance_id,
+            instance_name: file.instance_name,
+            api_url: api_url.unwrap_or_default(),
+            file_api_url: None,
+            api_key: api_key.unwrap_or_default(),
+            api_key_store: ApiKeyStore::default(),
+            vault: file.vault,
+            cloud_provider: file.cloud_provider,
+            monitoring_settings: file.monitoring_settings,
+            sinks,
+            initialized_at: file.initialized_at,
+            registration_pending: file.registration_pending,
+            env_overrides: Vec::new(),
+        })
+    }
+}
+
+impl From<Configuration> for ConfigurationFile {
+    fn from(config: Configuration) -> Self {
+        let mut api = Some((config.api_url, config.api_key));
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|sink| {
+                let mut value = serde_json::to_value(sink).unwrap_or_default();
+                if matches!(sink.kind, SinkKind::Api)
+                    && let Some((url, api_key)) = api.take()
+                {
+                    // After the type, ahead of `enabled`
+                    let mut entry = serde_json::Map::new();
+                    entry.insert("type".to_string(), "api".into());
+                    entry.insert("url".to_string(), url.into());
+                    if !api_key.is_empty() {
+                        entry.insert("api_key".to_string(), api_key.into());
+                    }
+                    entry.extend(value.as_object_mut().map(std::mem::take).unwrap_or_default().into_iter().filter(|(key, _)| key != "type"));
+                    value = entry.into();
+                }
+                value
+            })
+            .collect();
+        ConfigurationFile {
+            schema_version: config.schema_version,
+            instance_id: config.instance_id,
+            instance_name: config.instance_name,
+            vault: config.vault,
+            cloud_provider: config.cloud_provider,
+            monitoring_settings: config.monitoring_settings,
+            sinks,
+            initialized_at: config.initialized_at,
+            registration_pending: config.registration_pending,
+        }
+    }
+}
+
+// Settings `start` reads once, setting up the sinks and the API client, so a reload can't change
+// them. Neither can `sinks`.
+const STARTUP_SETTINGS: [&str; 21] = [
+    "spool_directory",
+    "spool_max_bytes",
+    "max_buffered_batches",
+    "buffer_drop_policy",
+    "dead_letter_directory",
+    "dead_letter_max_files",
+    "max_batch_bytes",
+    "compression",
+    "api_transport",
+    "api_auth",
+    "signature_algorithm",
+    "api_tls",
+    "api_proxy",
+    "stream_metrics",
+    "api_retry",
+    "api_circuit_breaker",
+    "api_timeouts",
+    "api_max_requests_per_minute",
+    "api_paths",
+    "command_poll_seconds",
+    "sink_timeout_seconds",
+];
+
+impl Configuration {
+    // Set when the API is reached through a local relay, with an `api_url` of `unix:///path/to.sock`.
+    pub fn api_socket_path(&self) -> Option<&str> {
+        self.api_url.strip_prefix("unix://")
+    }
+
+    /// What's wrong with values that deserialize fine but the agent can't run with, one line each.
+    pub fn problems(&self) -> Vec<String> {
+        let settings = &self.monitoring_settings;
+        let mut problems = Vec::new();
+        if settings.interval_seconds == 0 {
+            problems.push("monitoring_settings.interval_seconds must be above 0".to_string());
+        }
+        if settings.batch_size == 0 {
+            problems.push("monitoring_settings.batch_size must be above 0".to_string());
+        }
+        if let Some(proxy) = &settings.api_proxy
+            && let Err(e) = reqwest::Proxy::all(proxy.as_str())
+        {
+            problems.push(format!("monitoring_settings.api_proxy {} doesn't parse: {}", proxy, e));
+        }
+        let mut labels: BTreeMap<String, &str> = BTreeMap::new();
+        for key in settings.tags.keys() {
+            match crate::prometheus::label_name(key) {
+                None => problems.push(format!("monitoring_settings.tags key {:?} is empty or starts with __, as Prometheus labels can't", key)),
+                Some(label) => {
+                    if let Some(other) = labels.insert(label.clone(), key) {
+                        problems.push(format!("monitoring_settings.tags keys {:?} and {:?} are both the Prometheus label {}", other, key, label));
+                    }
+                }
+            }
+        }
+        match self.sinks.iter().filter(|sink| matches!(sink.kind, SinkKind::Api)).count() {
+            0 => problems.push("sinks has no api entry, which holds api_url; disable it instead of removing it".to_string()),
+            1 => {}
+            _ => problems.push("sinks lists the api sink more than once".to_string()),
+        }
+        match self.api_socket_path() {
+            Some("") => problems.push("api_url has no socket path after unix://".to_string()),
+            Some(_) => {}
+            None => match reqwest::Url::parse(&self.api_url) {
+                Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+                    problems.push(format!("api_url {} must be http(s):// or unix://", self.api_url))
+                }
+                Ok(url) if !url.has_host() => problems.push(format!("api_url {} has no host", self.api_url)),
+                Ok(_) => {}
+                Err(e) => problems.push(format!("api_url {} doesn't parse: {}", self.api_url, e)),
+            },
+        }
+        problems
+    }
+
+    /// Takes what a running agent can change from a reloaded config. Returns the names of the
+    /// settings that changed, and of those that differ but only take effect after a restart.
+    pub fn reload(&mut self, reloaded: Configuration) -> Result<(Vec<String>, Vec<String>), VmMonitorError> {
+        let problems = reloaded.problems();
+        if !problems.is_empty() {
+            return Err(VmMonitorError::ConfigError(problems.join("; ")));
+        }
+        let current = serde_json::to_value(&self.monitoring_settings)?;
+        let mut settings = serde_json::to_value(&reloaded.monitoring_settings)?;
+        let (mut changed, mut restart) = (Vec::new(), Vec::new());
+        if let (Some(current), Some(settings)) = (current.as_object(), settings.as_object_mut()) {
+            for (name, value) in settings.iter_mut() {
+                match current.get(name) {
+                    Some(running) if running == value => {}
+                    Some(running) if STARTUP_SETTINGS.contains(&name.as_str()) => {
+                        restart.push(name.clone());
+                        *value = running.clone();
+                    }
+                    _ => changed.push(name.clone()),
+                }
+            }
+        }
+        if serde_json::to_value(&reloaded.sinks)? != serde_json::to_value(&self.sinks)? {
+            restart.push("sinks".to_string());
+        }
+        if reloaded.api_url != self.api_url {
+            restart.push("api_url".to_string());
+        }
+        if reloaded.instance_name != self.instance_name {
+            restart.push("instance_name".to_string());
+        }
+        if !matches!(reloaded.api_key_store, ApiKeyStore::Vault(_)) && reloaded.api_key != self.api_key {
+            restart.push("api_key".to_string());
+        }
+
+        let mut settings: MonitoringSettings = serde_json::from_value(settings)?;
+        settings.api_tls.vault_client_identity = self.monitoring_settings.api_tls.vault_client_identity.take();
+        self.monitoring_settings = settings;
+        self.env_overrides = reloaded.env_overrides; // For saving the reloaded file's own values
+        Ok((changed, restart))
+    }
+}
+
+// Bumped with each change to the config's structure that needs a migration below; fields added
+// with a default don't.
+pub const CONFIG_SCHEMA_VERSION: u32 = 3;
+
+// Each upgrades the config from the version at its index to the next, on the file's values
+// before they're deserialized, so an upgraded agent reads the config an older one wrote.
+type Migration = fn(&mut serde_json::Value);
+const MIGRATIONS: [Migration; CONFIG_SCHEMA_VERSION as usize] = [
+    |_| {}, // 0: From before `schema_version`, when fields were only ever added with defaults
+    sinks_list, // 1: Each sink had its own field in `monitoring_settings`
+    api_in_sinks, // 2: `api_url` and `api_key` were top-level fields
+];
+
+// `send_to_api`, `prometheus_listen` and the sink sections in `monitoring_settings` become
+// entries in `sinks`, the API's first (disabled if it was turned off) and the others in the
+// order `start` used to set them up.
+fn sinks_list(config: &mut serde_json::Value) {
+    let Some(settings) = config.get_mut("monitoring_settings").and_then(serde_json::Value::as_object_mut) else {
+        return;
+    };
+    let send_to_api = settings.remove("send_to_api").and_then(|value| value.as_bool()).unwrap_or(true);
+    let mut sinks = vec![serde_json::json!({"type": "api", "enabled": send_to_api})];
+    if let Some(listen) = settings.remove("prometheus_listen").filter(|listen| !listen.is_null()) {
+        sinks.push(serde_json::json!({"type": "prometheus", "listen": listen}));
+    }
+    let sections = [
+        ("otlp", "otlp"),
+        ("statsd", "statsd"),
+        ("syslog", "syslog"),
+        ("cloudwatch", "cloudwatch"),
+        ("azure_monitor", "azure_monitor"),
+        ("newrelic", "newrelic"),
+        ("elasticsearch", "elasticsearch"),
+        ("file_sink", "file"),
+        ("mqtt", "mqtt"),
+        ("kafka", "kafka"),
+    ];
+    for (section, sink_type) in sections {
+        if let Some(serde_json::Value::Object(mut sink)) = settings.remove(section) {
+            sink.insert("type".to_string(), sink_type.into());
+            sinks.push(sink.into());
+        }
+    }
+    config["sinks"] = sinks.into();
+}
+
+// `api_url` and `api_key` become the settings of the `api` entry in `sinks`, which is added,
+// disabled, when the API was only used for registration and heartbeats.
+fn api_in_sinks(config: &mut serde_json::Value) {
+    let Some(fields) = config.as_object_mut() else {
+        return;
+    };
+    let mut moved = serde_json::Map::new();
+    for (from, to) in [("api_url", "url"), ("api_key", "api_key")] {
+        if let Some(value) = fields.remove(from) {
+            moved.insert(to.to_string(), value);
+        }
+    }
+    if moved.is_empty() {
+        return;
+    }
+    let sinks = fields.entry("sinks").or_insert_with(|| serde_json::json!([{"type": "api"}]));
+    let Some(sinks) = sinks.as_array_mut() else {
+        return;
+    };
+    let is_api = |sink: &serde_json::Value| sink.get("type").and_then(serde_json::Value::as_str) == Some("api");
+    if !sinks.iter().any(is_api) {
+        sinks.insert(0, serde_json::json!({"type": "api", "enabled": false}));
+    }
+    if let Some(api) = sinks.iter_mut().find(|sink| is_api(sink)).and_then(serde_json::Value::as_object_mut) {
+        api.extend(moved);
+    }
+}
+
+// The config in `dir`, in whichever of the supported formats it exists.
+fn config_file_in(dir: PathBuf, stem: &str) -> PathBuf {
+    let mut existing = CONFIG_EXTENSIONS
+        .iter()
+        .map(|extension| dir.join(format!("{}.{}", stem, extension)))
+        .filter(|path| path.exists());
+    let Some(path) = existing.next() else {
+        return dir.join(format!("{}.{}", stem, CONFIG_EXTENSIONS[0]));
+    };
+    if let Some(ignored) = existing.next() {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| log::warn!("Using config file {}, ignoring {}", path.display(), ignored.display()));
+    }
+    path
+}
+
+// Picked by the file's extension; anything else is JSON, as it always was.
+#[derive(Clone, Copy, PartialEq)]
+enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    fn parse(self, contents: &str, path: &Path) -> Result<Configuration, VmMonitorError> {
+        let invalid = |e: &dyn std::fmt::Display| VmMonitorError::ConfigError(format!("Invalid config file {}: {}", path.display(), e));
+        let (value, migrated) = self.parse_value(contents, path)?;
+        if !migrated && self == ConfigFormat::Json {
+            return Ok(serde_json::from_str(contents)?); // For errors with the line and column
+        } else if !migrated && self == ConfigFormat::Toml {
+            return toml::from_str(contents).map_err(|e| invalid(&e));
+        }
+        serde_json::from_value(value).map_err(|e| invalid(&e))
+    }
+
+    // The file's values, upgraded to the current schema version; also whether they needed that.
+    fn parse_value(self, contents: &str, path: &Path) -> Result<(serde_json::Value, bool), VmMonitorError> {
+        let invalid = |e: &dyn std::fmt::Display| VmMonitorError::ConfigError(format!("Invalid config file {}: {}", path.display(), e));
+        let mut value: serde_json::Value = match self {
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| invalid(&e))?,
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| invalid(&e))?,
+            // YAML always goes through JSON, so enums are maps as in a JSON config and not `!Tag value`
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| invalid(&e))?,
+        };
+        let version = value.get("schema_version").and_then(serde_json::Value::as_u64).unwrap_or(0);
+        if version > u64::from(CONFIG_SCHEMA_VERSION) {
+            return Err(invalid(&format!(
+                "it's config schema version {}, from a newer vm-monitor that this one (up to version {}) can't read",
+                version, CONFIG_SCHEMA_VERSION
+            )));
+        }
+        if version == u64::from(CONFIG_SCHEMA_VERSION) {
+            return Ok((value, false));
+        }
+        for migration in &MIGRATIONS[version as usize..] {
+            migration(&mut value);
+        }
+        value["schema_version"] = CONFIG_SCHEMA_VERSION.into();
+        log::info!(
+            "Upgraded {} from config schema version {} to {}; it's saved that way the next time settings are saved.",
+            path.display(),
+            version,
+            CONFIG_SCHEMA_VERSION
+        );
+        Ok((value, true))
+    }
+
+    // A TOML file is updated in place, keeping its comments and layout wherever the values are
+    // unchanged. YAML is rewritten from scratch.
+    fn render(self, config: &serde_json::Value, existing: Option<&str>) -> Result<String, VmMonitorError> {
+        let unrepresentable = |e: &dyn std::fmt::Display| VmMonitorError::ConfigError(format!("Can't write the config: {}", e));
+        match self {
+            ConfigFormat::Json => Ok(serde_json::to_string_pretty(config)?),
+            ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| unrepresentable(&e)),
+            ConfigFormat::Toml => {
+                let rendered = toml::to_string_pretty(&without_nulls(config.clone())).map_err(|e| unrepresentable(&e))?;
+                let Some(mut document) = existing.and_then(|existing| existing.parse::<toml_edit::DocumentMut>().ok()) else {
+                    return Ok(rendered);
+                };
+                let updated = rendered.parse::<toml_edit::DocumentMut>().map_err(|e| unrepresentable(&e))?;
+                merge_toml(document.as_table_mut(), updated.as_table());
+                Ok(document.to_string())
+            }
+        }
+    }
+}
+
+// What an item means, whatever way it's written: inline or not, quoted keys, one line or several.
+fn toml_meaning(item: &toml_edit::Item) -> Option<toml::Table> {
+    let mut document = toml_edit::DocumentMut::new();
+    document.insert("item", item.clone());
+    toml::from_str(&document.to_string()).ok()
+}
+
+fn merge_toml(existing: &mut toml_edit::Table, updated: &toml_edit::Table) {
+    existing.retain(|key, _| updated.contains_key(key));
+    for (key, item) in updated.iter() {
+        let Some(old) = existing.get_mut(key) else {
+            existing.insert(key, item.clone());
+            continue;
+        };
+        if toml_meaning(old).is_some_and(|meaning| Some(meaning) == toml_meaning(item)) {
+            continue;
+        }
+        match (old, item) {
+            (toml_edit::Item::Table(old), toml_edit::Item::Table(new)) => merge_toml(old, new),
+            (toml_edit::Item::Value(old), toml_edit::Item::Value(new)) => {
+                let decor = old.decor().clone();
+                *old = new.clone();
+                *old.decor_mut() = decor;
+            }
+            (old, new) => *old = new.clone(),
+        }
+    }
+}
+
+static CONFIG_PATH_FLAG: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Uses `path` as the config file from now on, as given with `--config`.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH_FLAG.set(path);
+}
+
+// `--config`, then VM_MONITOR_CONFIG, override the location, e.g. from a launchd plist or systemd unit.
+pub fn get_config_path() -> Result<PathBuf, VmMonitorError> {
+    if let Some(path) = CONFIG_PATH_FLAG.get() {
+        return Ok(path.clone());
+    }
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let user_path = dirs::config_dir().map(|path| config_file_in(path.join(APP_NAME), CONFIG_FILE_STEM));
+    #[cfg(unix)]
+    {
+        // A systemd, LaunchDaemon or rc.d service runs as root, whose per-user config dir is an
+        // odd place for system config. Use the system-wide config as its own instead, unless a
+        // config already exists in the per-user one.
+        let running_as_root = nix::unistd::geteuid().is_root();
+        if running_as_root && !user_path.as_ref().is_some_and(|path| path.exists()) {
+            return get_system_config_path().ok_or_else(|| VmMonitorError::ConfigError("No system config directory".to_string()));
+        }
+    }
+    user_path.ok_or_else(|| VmMonitorError::ConfigError("Could not find config directory".to_string()))
+}
+
+/// The system-wide config, under every user's: `/etc/vm-monitor/config.json` on Linux.
+pub fn get_system_config_path() -> Option<PathBuf> {
+    #[cfg(unix)]
+    return Some(config_file_in(PathBuf::from(SYSTEM_CONFIG.0), SYSTEM_CONFIG.1));
+    #[cfg(windows)]
+    return std::env::var_os("ProgramData").map(|dir| config_file_in(PathBuf::from(dir).join(APP_NAME), "config"));
+    #[cfg(not(any(unix, windows)))]
+    None
 }
 
-fn get_config_path() -> Result<PathBuf, VmMonitorError> {
-    dirs::config_dir()
-        .ok_or_else(|| VmMonitorError::ConfigError("Could not find config directory".to_string()))
-        .map(|path| path.join(APP_NAME).join(CONFIG_FILE_NAME))
+/// The system config when there's one under `path`, which it isn't itself.
+pub fn system_layer_under(path: &Path) -> Option<PathBuf> {
+    get_system_config_path().filter(|system| system != path && system.exists())
 }
 
+// Objects are merged key by key; anything else in `layer` replaces what's under it.
+fn merge_layer(value: &mut serde_json::Value, layer: serde_json::Value) {
+    match (value, layer) {
+        (serde_json::Value::Object(map), serde_json::Value::Object(layer)) => {
+            for (key, layer) in layer {
+                match map.get_mut(&key) {
+                    Some(value) => merge_layer(value, layer),
+                    None => {
+                        map.insert(key, layer);
+                    }
+                }
+            }
+        }
+        (value, layer) => *value = layer,
+    }
+}
+
+// The reverse of `merge_layer`: leaves out of `value` what `layer` already says, so later
+// changes to the layer still apply.
+fn strip_layer(value: &mut serde_json::Value, layer: &serde_json::Value) {
+    let (serde_json::Value::Object(map), serde_json::Value::Object(layer)) = (value, layer) else {
+        return;
+    };
+    map.retain(|key, value| match layer.get(key) {
+        Some(under) if under == value => false,
+        Some(under) => {
+            strip_layer(value, under);
+            !value.as_object().is_some_and(serde_json::Map::is_empty)
+        }
+        None => true,
+    });
+}
+
+// TOML has no null; a setting that's unset is left out instead.
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map.into_iter().filter(|(_, value)| !value.is_null()).map(|(key, value)| (key, without_nulls(value))).collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(without_nulls).collect(),
+        value => value,
+    }
+}
+
+// Agent state directories default to siblings of the config file.
+fn state_dir(configured: &Option<String>, default_name: &str) -> Result<PathBuf, VmMonitorError> {
+    if let Some(dir) = configured {
+        return Ok(PathBuf::from(dir));
+    }
+    let config_path = get_config_path()?;
+    let config_dir = config_path
+        .parent()
+        .ok_or_else(|| VmMonitorError::ConfigError("Config path has no parent directory".to_string()))?;
+    Ok(config_dir.join(default_name))
+}
+
+pub fn get_spool_dir(settings: &MonitoringSettings) -> Result<PathBuf, VmMonitorError> {
+    state_dir(&settings.spool_directory, "spool")
+}
+
+pub fn get_dead_letter_dir(settings: &MonitoringSettings) -> Result<PathBuf, VmMonitorError> {
+    state_dir(&settings.dead_letter_directory, "dead-letter")
+}
+
+// Written to a temporary file that then replaces the config, so a crash midway can't leave
+// a truncated config (and, after `rotate-key`, lose the only copy of the API key).
 pub fn save_config(config: &Configuration) -> Result<PathBuf, VmMonitorError> {
     let path = get_config_path()?;
     if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent)?;
     }
+    let format = ConfigFormat::of(&path);
+    let existing = match format {
+        ConfigFormat::Toml => std::fs::read_to_string(&path).ok(),
+        ConfigFormat::Json | ConfigFormat::Yaml => None,
+    };
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    let temp_path = path.with_extension(format!("{}.tmp", extension));
+    // Users read the system-wide config to layer theirs over it, so its key goes to a file
+    // only root reads instead.
+    let system_config = get_system_config_path().is_some_and(|system| system == path);
+    let mut config = match &config.api_key_store {
+        ApiKeyStore::ConfigFile if system_config && !config.api_key.is_empty() && !secrets::is_reference(&config.api_key) => {
+            with_key_file(config, &path.with_file_name(KEY_FILE_NAME).to_string_lossy())?
+        }
+        ApiKeyStore::ConfigFile => config.clone(),
+        ApiKeyStore::Keyring(account) => {
+            if !config.api_key.is_empty() {
+                secrets::keyring_store(account, &config.api_key)?;
+            }
+            Configuration { api_key: format!("{}{}", secrets::KEYRING_PREFIX, account), ..config.clone() }
+        }
+        ApiKeyStore::Environment(file_api_key) => Configuration { api_key: file_api_key.clone(), ..config.clone() },
+        ApiKeyStore::Vault(reference) => Configuration { api_key: reference.clone(), ..config.clone() },
+        ApiKeyStore::KeyFile(key_path) => with_key_file(config, key_path)?,
+    };
+    if let Some(file_api_url) = config.file_api_url.take() {
+        config.api_url = file_api_url;
+    }
+    let mut value = serde_json::to_value(&config)?;
+    for env_override in config.env_overrides.iter().rev() {
+        if value.pointer(&env_override.pointer) != Some(&env_override.value) {
+            continue; // Changed since, e.g. by the API's remote config
+        }
+        match &env_override.file_value {
+            Some(file_value) => set_pointer(&mut value, &env_override.file_pointer, file_value.clone()),
+            None => remove_pointer(&mut value, &env_override.file_pointer),
+        }
+    }
+    if let Some(system_path) = system_layer_under(&path) {
+        let mut layer = read_layer(&system_path)?;
+        if let Some(layer) = layer.as_object_mut() {
+            layer.remove("schema_version"); // Each file records its own
+        }
+        strip_layer(&mut value, &layer);
+    }
 
     let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(&path)?;
+        .open(&temp_path)?;
     
     #[cfg(all(unix, feature = "unix_perms"))]
     {
-        fchmod(file.as_raw_fd(), Mode::S_IRUSR | Mode::S_IWUSR)?; // 600 permissions
-        log::debug!("Set config file permissions to 600 (Unix).");
+        // Without its key, the system-wide config keeps the mode it was given, and is readable
+        // by everyone when new. A user's config stays their own.
+        let mode = match std::fs::metadata(&path) {
+            Ok(metadata) if system_config => Mode::from_bits_truncate(metadata.permissions().mode() as nix::libc::mode_t),
+            Err(_) if system_config => Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IROTH,
+            _ => Mode::S_IRUSR | Mode::S_IWUSR,
+        };
+        fchmod(file.as_raw_fd(), mode).map_err(std::io::Error::from)?;
+        log::debug!("Set config file permissions to {:o} (Unix).", mode.bits());
     }
     #[cfg(not(all(unix, feature = "unix_perms")))]
     {
@@ -79,36 +1864,197 @@ pub fn save_config(config: &Configuration) -> Result<PathBuf, VmMonitorError> {
 
 
     let mut writer = std::io::BufWriter::new(file);
-    serde_json::to_writer_pretty(&mut writer, config)?;
+    writer.write_all(format.render(&value, existing.as_deref())?.as_bytes())?;
     writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(&temp_path, &path)?;
     Ok(path)
 }
 
-pub fn load_config() -> Result<Configuration, VmMonitorError> {
+/// Where a key that isn't kept elsewhere goes: the config file, or for the system-wide
+/// config, a key file next to it.
+pub fn file_key_store() -> Result<ApiKeyStore, VmMonitorError> {
     let path = get_config_path()?;
-    if !path.exists() {
-        return Err(VmMonitorError::ConfigError(format!(
-            "Configuration file not found at {}. Please run 'init' command.",
-            path.display()
-        )));
+    Ok(match get_system_config_path().is_some_and(|system| system == path) {
+        true => ApiKeyStore::KeyFile(path.with_file_name(KEY_FILE_NAME).to_string_lossy().into_owned()),
+        false => ApiKeyStore::ConfigFile,
+    })
+}
+
+// Writes the key to `key_path`, leaving the config only its path.
+fn with_key_file(config: &Configuration, key_path: &str) -> Result<Configuration, VmMonitorError> {
+    if config.api_key.is_empty() {
+        return Ok(config.clone()); // None yet, e.g. until the first start
     }
+    secrets::key_file_store(key_path, &config.api_key)?;
+    Ok(Configuration { api_key: format!("{}{}", secrets::KEY_FILE_PREFIX, key_path), ..config.clone() })
+}
+
+fn read_contents(path: &Path) -> Result<String, std::io::Error> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let config: Configuration = serde_json::from_str(&contents)?;
+    Ok(contents)
+}
+
+fn read_layer(path: &Path) -> Result<serde_json::Value, VmMonitorError> {
+    Ok(ConfigFormat::of(path).parse_value(&read_contents(path)?, path)?.0)
+}
+
+// The config as the files have it, the user's over the system's, without environment
+// overrides or secrets fetched.
+fn read_config_file() -> Result<Configuration, VmMonitorError> {
+    let path = get_config_path()?;
+    // A system config users aren't allowed to read is left out rather than locking them out.
+    let system_layer = match system_layer_under(&path).map(|system_path| (read_contents(&system_path), system_path)) {
+        Some((Err(e), system_path)) if path.exists() => {
+            log::warn!("Can't read {} ({}), using {} alone.", system_path.display(), e, path.display());
+            None
+        }
+        Some((contents, system_path)) => {
+            let (layer, _) = ConfigFormat::of(&system_path).parse_value(&contents?, &system_path)?;
+            Some((layer, system_path))
+        }
+        None => None,
+    };
+    let Some((mut value, system_path)) = system_layer else {
+        if !path.exists() {
+            return Err(VmMonitorError::ConfigError(format!(
+                "Configuration file not found at {}. Please run 'init' command.",
+                path.display()
+            )));
+        }
+        return ConfigFormat::of(&path).parse(&read_contents(&path)?, &path);
+    };
+    if path.exists() {
+        merge_layer(&mut value, read_layer(&path)?);
+    }
+    serde_json::from_value(value).map_err(|e| {
+        VmMonitorError::ConfigError(format!("Invalid config in {} over {}: {}", path.display(), system_path.display(), e))
+    })
+}
+
+pub fn load_config() -> Result<Configuration, VmMonitorError> {
+    let mut config = read_config_file()?;
+    apply_env_overrides(&mut config)?;
+    if let Some(api_url) = env_override(API_URL_ENV) {
+        config.file_api_url = Some(std::mem::replace(&mut config.api_url, api_url));
+    }
+    if let Some(api_key) = env_override(API_KEY_ENV) {
+        let file_api_key = std::mem::replace(&mut config.api_key, api_key);
+        config.api_key_store = ApiKeyStore::Environment(file_api_key);
+    } else if let Some(account) = config.api_key.strip_prefix(secrets::KEYRING_PREFIX) {
+        let account = account.to_string();
+        config.api_key = if account.is_empty() { String::new() } else { secrets::keyring_load(&account)? };
+        config.api_key_store = ApiKeyStore::Keyring(account);
+    } else if let Some(key_path) = config.api_key.strip_prefix(secrets::KEY_FILE_PREFIX) {
+        let key_path = key_path.to_string();
+        config.api_key = secrets::key_file_load(&key_path)?;
+        config.api_key_store = ApiKeyStore::KeyFile(key_path);
+    } else if config.api_key.starts_with(secrets::VAULT_PREFIX) {
+        // Fetched by `load_config_with_secrets`, for the commands that talk to the API.
+        config.api_key_store = ApiKeyStore::Vault(std::mem::take(&mut config.api_key));
+    } else if config.api_key.is_empty() && !config.registration_pending && config.monitoring_settings.api_auth.uses_api_key() {
+        return Err(VmMonitorError::ConfigError(format!("No API key in the config file, and {} isn't set", API_KEY_ENV)));
+    }
     Ok(config)
 }
 
+/// A setting's value in the config file, named with dots, e.g. "monitoring.interval_seconds".
+pub fn get_setting(name: &str) -> Result<serde_json::Value, VmMonitorError> {
+    let value = serde_json::to_value(read_config_file()?)?;
+    let pointer = setting_pointer(&value, &name.split('.').collect::<Vec<_>>());
+    value.pointer(&pointer).cloned().ok_or_else(|| VmMonitorError::ConfigError(format!("{} isn't set", name)))
+}
+
+/// Changes a setting in the config file, taking the value as an environment override would.
+/// Returns the old and new values; the file is only rewritten when they differ.
+pub fn set_setting(name: &str, raw: &str) -> Result<(serde_json::Value, serde_json::Value), VmMonitorError> {
+    let mut value = serde_json::to_value(read_config_file()?)?;
+    let pointer = setting_pointer(&value, &name.split('.').collect::<Vec<_>>());
+    let old = value.pointer(&pointer).cloned().unwrap_or_default();
+    let new_value = env_value(value.pointer(&pointer), raw);
+    set_pointer(&mut value, &pointer, new_value);
+    let updated: Configuration =
+        serde_json::from_value(value).map_err(|e| VmMonitorError::ConfigError(format!("Invalid value for {}: {}", name, e)))?;
+    let Some(new) = serde_json::to_value(&updated)?.pointer(&pointer).cloned() else {
+        return Err(VmMonitorError::ConfigError(format!("{} isn't a setting", name)));
+    };
+    let problems = updated.problems();
+    if !problems.is_empty() {
+        return Err(VmMonitorError::ConfigError(problems.join("; ")));
+    }
+    if new != old {
+        save_config(&updated)?;
+    }
+    Ok((old, new))
+}
+
+/// Changes the config file's `sinks` list with `change`, saving it unless that fails.
+pub fn update_sinks<T>(change: impl FnOnce(&mut Vec<SinkConfig>) -> Result<T, VmMonitorError>) -> Result<T, VmMonitorError> {
+    let mut config = read_config_file()?;
+    let changed = change(&mut config.sinks)?;
+    let problems = config.problems();
+    if !problems.is_empty() {
+        return Err(VmMonitorError::ConfigError(problems.join("; ")));
+    }
+    save_config(&config)?;
+    Ok(changed)
+}
+
+/// A sink given as JSON on the command line, e.g. `{"type": "statsd", "address": "localhost:8125"}`.
+pub fn parse_sink(raw: &str) -> Result<SinkConfig, VmMonitorError> {
+    serde_json::from_str(raw).map_err(|e| VmMonitorError::ConfigError(format!("Invalid sink {}: {}", raw, e)))
+}
+
+// The config with what's kept in Vault fetched, for talking to the API.
+pub async fn load_config_with_secrets() -> Result<Configuration, VmMonitorError> {
+    let mut config = load_config()?;
+    crate::vault::resolve(&mut config).await?;
+    Ok(config)
+}
+
+#[cfg(windows)]
+fn system_vendor() -> Option<String> {
+    // "    SystemManufacturer    REG_SZ    Amazon EC2"
+    let output = std::process::Command::new("reg")
+        .args(["query", r"HKLM\HARDWARE\DESCRIPTION\System\BIOS", "/v", "SystemManufacturer"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.split_once("REG_SZ"))
+        .map(|(_, vendor)| vendor.trim().to_string())
+}
+
+#[cfg(not(windows))]
+fn system_vendor() -> Option<String> {
+    std::fs::read_to_string("/sys/class/dmi/id/sys_vendor").ok().map(|vendor| vendor.trim().to_string())
+}
+
 // Basic cloud provider detection
 pub async fn detect_cloud_provider() -> CloudProvider {
     // AWS: Check for /sys/hypervisor/uuid starting with "ec2"
-    if let Ok(uuid_content) = std::fs::read_to_string("/sys/hypervisor/uuid") {
-        if uuid_content.starts_with("ec2") {
-            log::info!("AWS detected via /sys/hypervisor/uuid");
+    if let Ok(uuid_content) = std::fs::read_to_string("/sys/hypervisor/uuid")
+        && uuid_content.starts_with("ec2")
+    {
+        log::info!("AWS detected via /sys/hypervisor/uuid");
+        return CloudProvider::AWS;
+    }
+
+    // Firmware vendor strings work on Nitro instances and on Windows, where there is no /sys/hypervisor.
+    // Azure VMs report "Microsoft Corporation" like any Hyper-V guest, so Azure is left to its metadata server.
+    if let Some(vendor) = system_vendor() {
+        if vendor.starts_with("Amazon EC2") {
+            log::info!("AWS detected via system vendor '{}'", vendor);
             return CloudProvider::AWS;
         }
+        if vendor.starts_with("Google") {
+            log::info!("GCP detected via system vendor '{}'", vendor);
+            return CloudProvider::GCP;
+        }
     }
-    
+
     let client = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(2))
         .build()
@@ -138,4 +2084,72 @@ pub async fn detect_cloud_provider() -> CloudProvider {
     
     log::info!("No specific cloud provider detected, defaulting to Unknown.");
     CloudProvider::Unknown("Not AWS, GCP, or Azure, or metadata services unreachable/unresponsive".to_string())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As `init` wrote it before `schema_version`, with every sink in `monitoring_settings`.
+    fn unversioned_config(send_to_api: bool) -> String {
+        serde_json::json!({
+            "instance_id": "11111111-1111-1111-1111-111111111111",
+            "instance_name": "web-1",
+            "api_url": "https://api.example.com",
+            "api_key": "abcdefghijkl",
+            "cloud_provider": {"Unknown": "x"},
+            "monitoring_settings": {
+                "interval_seconds": 30,
+                "batch_size": 10,
+                "send_to_api": send_to_api,
+                "prometheus_listen": "0.0.0.0:9900",
+                "statsd": {"address": "127.0.0.1:8125", "prefix": "vm"},
+                "file_sink": {"path": "/var/log/vm-monitor/metrics.jsonl"}
+            },
+            "initialized_at": "2026-01-01T00:00:00Z"
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn migrates_an_unversioned_config() {
+        let path = Path::new("vm-monitor.json");
+        let (value, migrated) = ConfigFormat::Json.parse_value(&unversioned_config(true), path).unwrap();
+        assert!(migrated);
+        assert_eq!(value["schema_version"], CONFIG_SCHEMA_VERSION);
+        assert_eq!(
+            value["sinks"],
+            serde_json::json!([
+                {"type": "api", "enabled": true, "url": "https://api.example.com", "api_key": "abcdefghijkl"},
+                {"type": "prometheus", "listen": "0.0.0.0:9900"},
+                {"type": "statsd", "address": "127.0.0.1:8125", "prefix": "vm"},
+                {"type": "file", "path": "/var/log/vm-monitor/metrics.jsonl"},
+            ])
+        );
+        assert!(value.get("api_url").is_none() && value.get("api_key").is_none());
+        for section in ["send_to_api", "prometheus_listen", "statsd", "file_sink"] {
+            assert!(value["monitoring_settings"].get(section).is_none(), "{} is still in monitoring_settings", section);
+        }
+
+        let config = ConfigFormat::Json.parse(&unversioned_config(true), path).unwrap();
+        assert_eq!(config.schema_version, CONFIG_SCHEMA_VERSION);
+        assert_eq!(config.api_url, "https://api.example.com");
+        assert_eq!(config.api_key, "abcdefghijkl");
+        assert_eq!(config.monitoring_settings.interval_seconds, 30);
+        assert_eq!(config.sinks.len(), 4);
+        assert!(config.sinks.iter().all(|sink| sink.enabled));
+        assert!(matches!(config.sinks[0].kind, SinkKind::Api));
+        assert!(matches!(&config.sinks[1].kind, SinkKind::Prometheus { listen } if listen == "0.0.0.0:9900"));
+        assert!(matches!(&config.sinks[2].kind, SinkKind::Statsd(statsd) if statsd.prefix == "vm"));
+        assert!(matches!(&config.sinks[3].kind, SinkKind::File(file) if file.max_bytes == default_file_sink_max_bytes()));
+    }
+
+    #[test]
+    fn keeps_the_api_settings_when_sending_to_it_was_off() {
+        let path = Path::new("vm-monitor.json");
+        let config = ConfigFormat::Json.parse(&unversioned_config(false), path).unwrap();
+        assert_eq!(config.api_url, "https://api.example.com");
+        assert_eq!(config.api_key, "abcdefghijkl");
+        assert!(matches!(config.sinks[0].kind, SinkKind::Api));
+        assert!(!config.sinks[0].enabled);
+    }
+}
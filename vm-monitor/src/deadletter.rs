@@ -0,0 +1,62 @@
+// Metrics batches the API rejected as invalid (400/422). Retrying won't help, so each one is
+// written to its own JSON file with the rejection attached, for debugging schema mismatches
+// and replaying the data once the agent or backend is fixed.
+use crate::errors::VmMonitorError;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+struct DeadLetter<'a, M> {
+    rejected_at: DateTime<Utc>,
+    status: u16,
+    error: &'a str,
+    metrics: &'a [M],
+}
+
+pub struct DeadLetterDir {
+    dir: PathBuf,
+    max_files: usize,
+}
+
+impl DeadLetterDir {
+    pub fn new(dir: PathBuf, max_files: usize) -> Self {
+        DeadLetterDir { dir, max_files }
+    }
+
+    /// Stores a rejected batch and returns its path, or `None` when dead-lettering is disabled.
+    pub fn write<M: Serialize>(&self, metrics: &[M], status: u16, error: &str) -> Result<Option<PathBuf>, VmMonitorError> {
+        if self.max_files == 0 {
+            return Ok(None);
+        }
+        fs::create_dir_all(&self.dir)?;
+        let rejected_at = Utc::now();
+        // Timestamped names sort chronologically, which pruning relies on.
+        let path = self.dir.join(format!("{}.json", rejected_at.format("%Y%m%dT%H%M%S%.6fZ")));
+        let letter = DeadLetter { rejected_at, status, error, metrics };
+        fs::write(&path, serde_json::to_vec_pretty(&letter)?)?;
+        self.prune();
+        Ok(Some(path))
+    }
+
+    fn prune(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut files: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        if files.len() <= self.max_files {
+            return;
+        }
+        files.sort();
+        for path in &files[..files.len() - self.max_files] {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("Failed to remove old dead-letter file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
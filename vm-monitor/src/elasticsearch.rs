@@ -0,0 +1,93 @@
+// Indexes every sample into Elasticsearch or OpenSearch through the bulk API, one document per
+// sample in the shape the API receives plus an `@timestamp`, into a date-based index so old
+// data can be dropped a whole index at a time.
+use crate::config::ElasticsearchSettings;
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Delivery, Sink, SinkFuture};
+use chrono::format::{Item, StrftimeItems};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    #[serde(default)]
+    items: Vec<Value>,
+}
+
+pub struct ElasticsearchSink {
+    client: Client,
+    url: String,
+    settings: ElasticsearchSettings,
+}
+
+impl ElasticsearchSink {
+    pub fn new(settings: &ElasticsearchSettings) -> Result<Self, VmMonitorError> {
+        if StrftimeItems::new(&settings.index).any(|item| item == Item::Error) {
+            return Err(VmMonitorError::ConfigError(format!("Invalid Elasticsearch index pattern {:?}", settings.index)));
+        }
+        Ok(ElasticsearchSink {
+            client: Client::builder().timeout(Duration::from_secs(settings.timeout_seconds)).build()?,
+            url: format!("{}/_bulk", settings.url.trim_end_matches('/')),
+            settings: settings.clone(),
+        })
+    }
+
+    // `create` rather than `index`, so the target may also be a data stream.
+    fn body(&self, metrics: &[SystemMetrics]) -> Result<String, VmMonitorError> {
+        let mut body = String::new();
+        for sample in metrics {
+            let index = sample.timestamp.format(&self.settings.index).to_string();
+            let mut document = serde_json::to_value(sample)?;
+            document["@timestamp"] = json!(sample.timestamp);
+            body.push_str(&json!({"create": {"_index": index}}).to_string());
+            body.push('\n');
+            body.push_str(&document.to_string());
+            body.push('\n');
+        }
+        Ok(body)
+    }
+}
+
+impl Sink for ElasticsearchSink {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let mut request = self.client.post(&self.url).header("Content-Type", "application/x-ndjson").body(self.body(metrics)?);
+            if let Some(api_key) = &self.settings.api_key {
+                request = request.header("Authorization", format!("ApiKey {}", api_key));
+            } else if let Some(username) = &self.settings.username {
+                request = request.basic_auth(username, self.settings.password.as_ref());
+            }
+            let response = request.send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                let text = response.text().await.unwrap_or_default();
+                return Err(VmMonitorError::ApiError(format!("Elasticsearch returned {}: {}", status, text)));
+            }
+            // Documents are accepted or rejected one by one, so a 200 can still carry failures.
+            let response: BulkResponse = response.json().await?;
+            if response.errors {
+                let failed: Vec<&Value> =
+                    response.items.iter().map(|item| &item["create"]["error"]).filter(|error| !error.is_null()).collect();
+                return Err(VmMonitorError::ApiError(format!(
+                    "Elasticsearch rejected {} of {} documents, e.g.: {}",
+                    failed.len(),
+                    metrics.len(),
+                    failed.first().map(|error| error.to_string()).unwrap_or_default()
+                )));
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,7 +1,7 @@
 use thiserror::Error;
 
 #[derive(Error, Debug)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum VmMonitorError {
     #[error("Configuration error: {0}")]
     ConfigError(String),
@@ -13,8 +13,24 @@ pub enum VmMonitorError {
     JsonError(#[from] serde_json::Error),
     #[error("API communication error: {0}")]
     ApiError(String),
+    #[error("API rejected the request as invalid ({0}): {1}")]
+    ApiRejected(u16, String), // 400 and 422, which retrying won't fix
+    #[error("API rejected the request body as too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("API requests suspended for {0}s after repeated failures")]
+    CircuitOpen(u64),
+    #[error("API asked the agent to back off for another {0}s")]
+    Throttled(u64),
+    #[error("Outbound API rate limit reached, next request allowed in {0}s")]
+    RateLimited(u64),
+    #[error("Metrics sink error: {0}")]
+    SinkError(String),
     #[error("Authentication error: {0}")]
     AuthError(String),
+    #[error("OS keyring error: {0}")]
+    KeyringError(String),
+    #[error("Vault error: {0}")]
+    VaultError(String),
     #[error("HTTP request error: {0}")]
     HttpError(#[from] reqwest::Error),
     #[error("Invalid input: {0}")]
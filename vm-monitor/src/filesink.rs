@@ -0,0 +1,130 @@
+// Appends every sample as one JSON line to a local file, for air-gapped machines and for
+// seeing exactly what the agent would have sent. The file is rotated by size and age:
+// rotated files get a timestamp suffix and are gzipped and pruned in the background.
+use crate::config::FileSinkSettings;
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub struct FileSink {
+    settings: FileSinkSettings,
+    path: PathBuf,
+    file: Option<File>, // Opened on first write, and again after a rotation
+    size: u64,
+    opened_at: SystemTime,
+}
+
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, flate2::Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+// Rotated files are "<name>.<timestamp>[.gz]", so sorting by name sorts them by age.
+fn prune(path: &Path, max_files: usize) -> io::Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+    let mut rotated: Vec<PathBuf> = fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir })?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path())
+        .collect();
+    rotated.sort();
+    let excess = rotated.len().saturating_sub(max_files);
+    for old in &rotated[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+impl FileSink {
+    pub fn new(settings: &FileSinkSettings) -> Self {
+        FileSink {
+            settings: settings.clone(),
+            path: PathBuf::from(&settings.path),
+            file: None,
+            size: 0,
+            opened_at: SystemTime::now(),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            let metadata = file.metadata()?;
+            self.size = metadata.len();
+            // An existing file keeps its age across restarts where the platform records it.
+            self.opened_at = metadata.created().unwrap_or_else(|_| SystemTime::now());
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().expect("opened above"))
+    }
+
+    fn rotation_due(&self, line_len: u64) -> bool {
+        let max_age = Duration::from_secs(self.settings.rotate_seconds);
+        let too_big = self.settings.max_bytes > 0 && self.size + line_len > self.settings.max_bytes;
+        let too_old = !max_age.is_zero() && self.opened_at.elapsed().is_ok_and(|age| age >= max_age);
+        self.size > 0 && (too_big || too_old)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+        let rotated = PathBuf::from(rotated);
+        fs::rename(&self.path, &rotated)?;
+        log::info!("Rotated metrics file to {}", rotated.display());
+
+        let compress = self.settings.compress;
+        let (path, max_files) = (self.path.clone(), self.settings.max_files);
+        tokio::task::spawn_blocking(move || {
+            if compress && let Err(e) = gzip(&rotated) {
+                log::warn!("Failed to compress {}: {}", rotated.display(), e);
+            }
+            if max_files > 0 && let Err(e) = prune(&path, max_files) {
+                log::warn!("Failed to remove old metrics files next to {}: {}", path.display(), e);
+            }
+        });
+        Ok(())
+    }
+
+    fn append(&mut self, metrics: &SystemMetrics) -> Result<(), VmMonitorError> {
+        let mut line = serde_json::to_vec(metrics)?;
+        line.push(b'\n');
+        self.open()?;
+        if self.rotation_due(line.len() as u64) {
+            self.rotate()?;
+        }
+        self.open()?.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        let result = metrics.iter().try_for_each(|sample| self.append(sample));
+        if result.is_err() {
+            self.file = None; // Reopened on the next sample, e.g. after the file was removed
+        }
+        Box::pin(async { result })
+    }
+}
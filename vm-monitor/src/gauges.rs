@@ -0,0 +1,94 @@
+// A sample flattened into gauges, for the sinks that send each value as a metric of its own,
+// so they all cover the same ones. Each sink spells the name its own way from `group`, `name`
+// and the dimension, except CloudWatch, which keeps the names it always had.
+use crate::monitor::SystemMetrics;
+
+pub struct Gauge {
+    pub group: &'static str,           // e.g. "cpu"
+    pub name: &'static str,            // e.g. "usage_percent", or empty for custom metrics
+    pub cloudwatch_name: &'static str, // e.g. "CpuUsage"
+    pub unit: &'static str,            // As CloudWatch names it: "Percent", "Bytes", "Bytes/Second", "Count", "Milliseconds" or "None"
+    pub dimension: Option<(&'static str, String)>, // e.g. ("mount_point", "/")
+    pub value: f64,
+}
+
+impl Gauge {
+    // e.g. "cpu.usage_percent" for "."
+    pub fn path(&self, separator: &str) -> String {
+        if self.name.is_empty() { self.group.to_string() } else { format!("{}{}{}", self.group, separator, self.name) }
+    }
+}
+
+fn flag(value: bool) -> Option<f64> {
+    Some(if value { 1.0 } else { 0.0 })
+}
+
+pub fn gauges(metrics: &SystemMetrics) -> Vec<Gauge> {
+    let mut gauges = Vec::new();
+    let mut gauge = |group, name, cloudwatch_name, unit, dimension: Option<(&'static str, String)>, value: Option<f64>| {
+        if let Some(value) = value.filter(|value| value.is_finite()) {
+            gauges.push(Gauge { group, name, cloudwatch_name, unit, dimension, value });
+        }
+    };
+
+    let cpu = &metrics.cpu_metrics;
+    gauge("cpu", "usage_percent", "CpuUsage", "Percent", None, Some(cpu.usage_percent as f64));
+    if let Some(times) = &cpu.time_breakdown {
+        gauge("cpu", "user_percent", "CpuUser", "Percent", None, Some(times.user_percent as f64));
+        gauge("cpu", "system_percent", "CpuSystem", "Percent", None, Some(times.system_percent as f64));
+        gauge("cpu", "iowait_percent", "CpuIowait", "Percent", None, Some(times.iowait_percent as f64));
+        gauge("cpu", "steal_percent", "CpuSteal", "Percent", None, Some(times.steal_percent as f64));
+    }
+    let memory = &metrics.memory_metrics;
+    gauge("memory", "total_bytes", "MemoryTotal", "Bytes", None, Some(memory.total_memory as f64));
+    gauge("memory", "used_bytes", "MemoryUsed", "Bytes", None, Some(memory.used_memory as f64));
+    gauge("memory", "available_bytes", "MemoryAvailable", "Bytes", None, Some(memory.available_memory as f64));
+    gauge("swap", "total_bytes", "SwapTotal", "Bytes", None, Some(memory.total_swap as f64));
+    gauge("swap", "used_bytes", "SwapUsed", "Bytes", None, Some(memory.used_swap as f64));
+
+    for disk in &metrics.disk_metrics {
+        let mount = || Some(("mount_point", disk.mount_point.clone()));
+        gauge("disk", "total_bytes", "DiskTotal", "Bytes", mount(), Some(disk.total_space as f64));
+        gauge("disk", "available_bytes", "DiskAvailable", "Bytes", mount(), Some(disk.available_space as f64));
+        gauge("disk", "read_bytes_per_sec", "DiskReadBytes", "Bytes/Second", mount(), disk.read_bytes_per_sec);
+        gauge("disk", "write_bytes_per_sec", "DiskWriteBytes", "Bytes/Second", mount(), disk.write_bytes_per_sec);
+    }
+    for network in &metrics.network_metrics {
+        let interface = || Some(("interface", network.interface_name.clone()));
+        gauge("network", "received_bytes_per_sec", "NetworkReceivedBytes", "Bytes/Second", interface(), network.received_bytes_per_sec);
+        gauge("network", "transmitted_bytes_per_sec", "NetworkTransmittedBytes", "Bytes/Second", interface(), network.transmitted_bytes_per_sec);
+    }
+    if let Some(tcp) = &metrics.tcp_metrics {
+        gauge("tcp", "established", "TcpEstablished", "Count", None, Some(tcp.established as f64));
+        gauge("tcp", "time_wait", "TcpTimeWait", "Count", None, Some(tcp.time_wait as f64));
+        gauge("tcp", "close_wait", "TcpCloseWait", "Count", None, Some(tcp.close_wait as f64));
+    }
+    gauge("processes", "total", "Processes", "Count", None, Some(metrics.process_metrics.total_processes as f64));
+
+    for container in &metrics.container_metrics {
+        let name = || Some(("container", container.name.clone()));
+        gauge("container", "cpu_usage_percent", "ContainerCpuUsage", "Percent", name(), container.cpu_usage_percent);
+        gauge("container", "memory_usage_bytes", "ContainerMemoryUsage", "Bytes", name(), container.memory_usage.map(|bytes| bytes as f64));
+    }
+    for service in &metrics.service_metrics {
+        gauge("service", "healthy", "ServiceHealthy", "None", Some(("service", service.name.clone())), flag(service.healthy));
+    }
+    for check in &metrics.http_checks {
+        let name = || Some(("check", check.name.clone()));
+        gauge("http_check", "latency_ms", "HttpCheckLatency", "Milliseconds", name(), check.latency_ms);
+        gauge("http_check", "status_code", "HttpCheckStatusCode", "None", name(), check.status_code.map(f64::from));
+    }
+    for ping in &metrics.ping_results {
+        let host = || Some(("host", ping.host.clone()));
+        gauge("ping", "reachable", "PingReachable", "None", host(), flag(ping.reachable));
+        gauge("ping", "rtt_ms", "PingRtt", "Milliseconds", host(), ping.rtt_ms);
+    }
+    for (name, value) in &metrics.custom_metrics {
+        gauge("custom", "", "Custom", "None", Some(("metric", name.clone())), Some(*value));
+    }
+    if let Some(agent) = &metrics.agent_metrics {
+        gauge("agent", "api_consecutive_failures", "AgentApiConsecutiveFailures", "Count", None, Some(agent.api_consecutive_failures as f64));
+        gauge("agent", "spooled_bytes", "AgentSpooledBytes", "Bytes", None, Some(agent.spooled_bytes as f64));
+    }
+    gauges
+}
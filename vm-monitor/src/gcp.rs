@@ -0,0 +1,110 @@
+// Google identity tokens for the VM's service account, from the GCE metadata server. They are
+// JWTs signed by Google for a given audience, which Cloud Run and IAP verify themselves, and
+// are cached until shortly before they expire. The server also describes the instance.
+use crate::errors::VmMonitorError;
+use crate::monitor::CloudInstance;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+const REFRESH_BEFORE_EXPIRY_SECONDS: u64 = 5 * 60;
+
+#[derive(Deserialize)]
+struct Claims {
+    exp: u64,
+}
+
+struct Token {
+    jwt: String,
+    expires_at: u64, // 0 when the token couldn't be decoded; it's then fetched for every request
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+// The token's own expiry; no need to verify the signature of a token we're only passing on.
+fn expiry(jwt: &str) -> Option<u64> {
+    let payload = URL_SAFE_NO_PAD.decode(jwt.split('.').nth(1)?.trim_end_matches('=')).ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok().map(|claims| claims.exp)
+}
+
+// `GCE_METADATA_HOST` moves the metadata server, as for the Google Cloud client libraries.
+fn metadata_url(path: &str) -> String {
+    let host = std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_string());
+    format!("http://{}/computeMetadata/v1/{}", host, path)
+}
+
+/// The instance's machine type, zone, ID and boot image.
+pub async fn instance() -> Result<CloudInstance, VmMonitorError> {
+    let client = Client::builder().timeout(METADATA_TIMEOUT).build().unwrap_or_else(|_| Client::new());
+    let read = |path: &'static str| {
+        let request = client.get(metadata_url(&format!("instance/{}", path))).header("Metadata-Flavor", "Google");
+        async move { Ok::<_, VmMonitorError>(request.send().await?.error_for_status()?.text().await?.trim().to_string()) }
+    };
+    // The machine type and zone come as "projects/<number>/machineTypes/e2-medium" and "projects/<number>/zones/us-central1-a".
+    let last_segment = |path: String| path.rsplit('/').next().unwrap_or_default().to_string();
+    let zone = read("zone").await.ok().map(last_segment);
+    Ok(CloudInstance {
+        instance_type: Some(last_segment(read("machine-type").await?)),
+        region: zone.as_deref().and_then(|zone| zone.rsplit_once('-')).map(|(region, _)| region.to_string()),
+        availability_zone: zone,
+        instance_id: read("id").await.ok(),
+        image: read("image").await.ok().filter(|image| !image.is_empty()),
+    })
+}
+
+pub struct IdentityTokens {
+    client: Client,
+    url: String,
+    audience: String,
+    token: Mutex<Option<Token>>,
+}
+
+impl IdentityTokens {
+    /// Tokens for `service_account` ("default" for the VM's own), issued for `audience`.
+    pub fn new(service_account: &str, audience: &str) -> Self {
+        IdentityTokens {
+            client: Client::builder().timeout(METADATA_TIMEOUT).build().unwrap_or_else(|_| Client::new()),
+            url: metadata_url(&format!("instance/service-accounts/{}/identity", service_account)),
+            audience: audience.to_string(),
+            token: Mutex::new(None),
+        }
+    }
+
+    pub async fn token(&self) -> Result<String, VmMonitorError> {
+        if let Some(token) = self.token.lock().unwrap_or_else(|e| e.into_inner()).as_ref()
+            && token.expires_at > now() + REFRESH_BEFORE_EXPIRY_SECONDS
+        {
+            return Ok(token.jwt.clone());
+        }
+        // `format=full` adds the project and instance to the claims, for backends that check them.
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("audience", self.audience.as_str()), ("format", "full")])
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .map_err(|e| VmMonitorError::AuthError(format!("GCE metadata server unavailable: {}", e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(VmMonitorError::AuthError(format!("Identity token request returned {}: {}", status, text)));
+        }
+        let jwt = response.text().await?.trim().to_string();
+        let expires_at = expiry(&jwt).unwrap_or_default();
+        log::debug!("Got an identity token for {}, valid for {}s", self.audience, expires_at.saturating_sub(now()));
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(Token { jwt: jwt.clone(), expires_at });
+        Ok(jwt)
+    }
+
+    /// Drops the cached token after the API rejected it. Returns whether there was one.
+    pub fn invalidate(&self) -> bool {
+        self.token.lock().unwrap_or_else(|e| e.into_inner()).take().is_some()
+    }
+}
@@ -0,0 +1,970 @@
+// gRPC transport for the API (`api_transport: "grpc"`), for ingestion services that are
+// gRPC-native. `ApiClient` keeps its retries, circuit breaker and spooling and only swaps
+// the wire format: each request's JSON body is converted to the protobuf message of the
+// matching RPC in proto/vm_monitor/v1/agent.proto, and the reply back to JSON.
+// The prost types below are written by hand so building doesn't need protoc.
+use crate::api::RequestFailure;
+use crate::auth;
+use crate::config::{ApiAuth, ApiEndpoint, Configuration, MonitoringSettings, PayloadCompression};
+use crate::errors::VmMonitorError;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tonic::codec::{CompressionEncoding, ProstCodec};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Request, Status};
+use uuid::Uuid;
+
+const REGISTER: &str = "/vm_monitor.v1.AgentIngest/Register";
+const SEND_METRICS: &str = "/vm_monitor.v1.AgentIngest/SendMetrics";
+const HEARTBEAT: &str = "/vm_monitor.v1.AgentIngest/Heartbeat";
+const SEND_INVENTORY: &str = "/vm_monitor.v1.AgentIngest/SendInventory";
+const HEALTH: &str = "/vm_monitor.v1.AgentIngest/Health";
+const POLL_COMMANDS: &str = "/vm_monitor.v1.AgentIngest/PollCommands";
+const REPORT_COMMAND_RESULT: &str = "/vm_monitor.v1.AgentIngest/ReportCommandResult";
+const ROTATE_KEY: &str = "/vm_monitor.v1.AgentIngest/RotateKey";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegisterRequest {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+    #[prost(string, tag = "2")]
+    pub instance_name: String,
+    #[prost(string, tag = "3")]
+    pub cloud_provider: String,
+    #[prost(string, tag = "4")]
+    pub agent_api_key: String,
+    #[prost(string, optional, tag = "5")]
+    pub virtualization: Option<String>,
+    #[prost(string, tag = "6")]
+    pub signature_algorithm: String,
+    #[prost(btree_map = "string, string", tag = "7")]
+    pub tags: BTreeMap<String, String>,
+    #[prost(message, optional, tag = "8")]
+    pub cloud_instance: Option<CloudInstance>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CloudInstance {
+    #[prost(string, optional, tag = "1")]
+    pub instance_type: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub region: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub availability_zone: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub instance_id: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub image: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RegisterResponse {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HeartbeatRequest {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+    #[prost(string, optional, tag = "2")]
+    pub agent_version: Option<String>,
+    #[prost(uint64, optional, tag = "3")]
+    pub uptime_seconds: Option<u64>,
+    #[prost(uint64, optional, tag = "4")]
+    pub interval_seconds: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HeartbeatResponse {
+    #[prost(string, tag = "1")]
+    pub config_json: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InventoryRequest {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+    #[prost(string, tag = "2")]
+    pub inventory_json: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InventoryResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HealthRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HealthResponse {
+    #[prost(string, tag = "1")]
+    pub status: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CommandsRequest {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CommandsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub commands: Vec<Command>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Command {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub action: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CommandResult {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+    #[prost(string, tag = "2")]
+    pub id: String,
+    #[prost(bool, tag = "3")]
+    pub success: bool,
+    #[prost(string, tag = "4")]
+    pub output_json: String,
+    #[prost(string, optional, tag = "5")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CommandResultResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RotateKeyRequest {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+    #[prost(string, tag = "2")]
+    pub new_api_key: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RotateKeyResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricsBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub metrics: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct MetricsBatchResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub rejected: Vec<RejectedMetric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RejectedMetric {
+    #[prost(uint32, tag = "1")]
+    pub index: u32,
+    #[prost(string, tag = "2")]
+    pub error: String,
+    #[prost(bool, tag = "3")]
+    pub retryable: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(int64, tag = "1")]
+    pub timestamp_unix_nanos: i64,
+    #[prost(string, tag = "2")]
+    pub instance_id: String,
+    #[prost(message, optional, tag = "3")]
+    pub cpu: Option<Cpu>,
+    #[prost(message, optional, tag = "4")]
+    pub memory: Option<Memory>,
+    #[prost(message, repeated, tag = "5")]
+    pub disks: Vec<Disk>,
+    #[prost(message, repeated, tag = "6")]
+    pub network_interfaces: Vec<NetworkInterface>,
+    #[prost(message, optional, tag = "7")]
+    pub tcp: Option<Tcp>,
+    #[prost(message, optional, tag = "8")]
+    pub processes: Option<Processes>,
+    #[prost(message, repeated, tag = "9")]
+    pub containers: Vec<Container>,
+    #[prost(message, repeated, tag = "10")]
+    pub services: Vec<Service>,
+    #[prost(message, repeated, tag = "11")]
+    pub http_checks: Vec<HttpCheck>,
+    #[prost(message, repeated, tag = "12")]
+    pub pings: Vec<Ping>,
+    #[prost(btree_map = "string, double", tag = "13")]
+    pub custom_metrics: BTreeMap<String, f64>,
+    #[prost(message, optional, tag = "14")]
+    pub agent: Option<Agent>,
+    #[prost(message, optional, tag = "15")]
+    pub system_info: Option<SystemInfo>,
+    #[prost(string, tag = "16")]
+    pub extensions_json: String,
+    #[prost(btree_map = "string, string", tag = "17")]
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Cpu {
+    #[prost(double, tag = "1")]
+    pub usage_percent: f64,
+    #[prost(uint32, tag = "2")]
+    pub core_count: u32,
+    #[prost(double, repeated, tag = "3")]
+    pub per_core_usage: Vec<f64>,
+    #[prost(double, optional, tag = "4")]
+    pub limit_cores: Option<f64>,
+    #[prost(message, optional, tag = "5")]
+    pub time_breakdown: Option<CpuTimes>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CpuTimes {
+    #[prost(double, tag = "1")]
+    pub user_percent: f64,
+    #[prost(double, tag = "2")]
+    pub nice_percent: f64,
+    #[prost(double, tag = "3")]
+    pub system_percent: f64,
+    #[prost(double, tag = "4")]
+    pub idle_percent: f64,
+    #[prost(double, tag = "5")]
+    pub iowait_percent: f64,
+    #[prost(double, tag = "6")]
+    pub irq_percent: f64,
+    #[prost(double, tag = "7")]
+    pub softirq_percent: f64,
+    #[prost(double, tag = "8")]
+    pub steal_percent: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Memory {
+    #[prost(uint64, tag = "1")]
+    pub total_memory: u64,
+    #[prost(uint64, tag = "2")]
+    pub used_memory: u64,
+    #[prost(uint64, tag = "3")]
+    pub available_memory: u64,
+    #[prost(uint64, tag = "4")]
+    pub total_swap: u64,
+    #[prost(uint64, tag = "5")]
+    pub used_swap: u64,
+    #[prost(uint64, optional, tag = "6")]
+    pub limit_bytes: Option<u64>,
+    #[prost(double, optional, tag = "7")]
+    pub swap_in_pages_per_sec: Option<f64>,
+    #[prost(double, optional, tag = "8")]
+    pub swap_out_pages_per_sec: Option<f64>,
+    #[prost(double, optional, tag = "9")]
+    pub major_faults_per_sec: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Disk {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub mount_point: String,
+    #[prost(string, tag = "3")]
+    pub filesystem: String,
+    #[prost(uint64, tag = "4")]
+    pub total_space: u64,
+    #[prost(uint64, tag = "5")]
+    pub available_space: u64,
+    #[prost(uint64, tag = "6")]
+    pub total_read_bytes: u64,
+    #[prost(uint64, tag = "7")]
+    pub total_written_bytes: u64,
+    #[prost(double, optional, tag = "8")]
+    pub read_bytes_per_sec: Option<f64>,
+    #[prost(double, optional, tag = "9")]
+    pub write_bytes_per_sec: Option<f64>,
+    #[prost(double, optional, tag = "10")]
+    pub read_latency_ms: Option<f64>,
+    #[prost(double, optional, tag = "11")]
+    pub write_latency_ms: Option<f64>,
+    #[prost(double, optional, tag = "12")]
+    pub avg_queue_depth: Option<f64>,
+    #[prost(uint64, optional, tag = "13")]
+    pub io_in_flight: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NetworkInterface {
+    #[prost(string, tag = "1")]
+    pub interface_name: String,
+    #[prost(uint64, tag = "2")]
+    pub received_bytes_total: u64,
+    #[prost(uint64, tag = "3")]
+    pub transmitted_bytes_total: u64,
+    #[prost(double, optional, tag = "4")]
+    pub received_bytes_per_sec: Option<f64>,
+    #[prost(double, optional, tag = "5")]
+    pub transmitted_bytes_per_sec: Option<f64>,
+    #[prost(uint64, optional, tag = "6")]
+    pub received_packets: Option<u64>,
+    #[prost(uint64, optional, tag = "7")]
+    pub transmitted_packets: Option<u64>,
+    #[prost(uint64, optional, tag = "8")]
+    pub receive_errors: Option<u64>,
+    #[prost(uint64, optional, tag = "9")]
+    pub transmit_errors: Option<u64>,
+    #[prost(uint64, optional, tag = "10")]
+    pub receive_drops: Option<u64>,
+    #[prost(uint64, optional, tag = "11")]
+    pub transmit_drops: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Tcp {
+    #[prost(uint64, tag = "1")]
+    pub total: u64,
+    #[prost(uint64, tag = "2")]
+    pub established: u64,
+    #[prost(uint64, tag = "3")]
+    pub syn_sent: u64,
+    #[prost(uint64, tag = "4")]
+    pub syn_recv: u64,
+    #[prost(uint64, tag = "5")]
+    pub fin_wait1: u64,
+    #[prost(uint64, tag = "6")]
+    pub fin_wait2: u64,
+    #[prost(uint64, tag = "7")]
+    pub time_wait: u64,
+    #[prost(uint64, tag = "8")]
+    pub close: u64,
+    #[prost(uint64, tag = "9")]
+    pub close_wait: u64,
+    #[prost(uint64, tag = "10")]
+    pub last_ack: u64,
+    #[prost(uint64, tag = "11")]
+    pub listen: u64,
+    #[prost(uint64, tag = "12")]
+    pub closing: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Processes {
+    #[prost(uint64, tag = "1")]
+    pub total_processes: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub top_by_cpu: Vec<Process>,
+    #[prost(message, repeated, tag = "3")]
+    pub top_by_memory: Vec<Process>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Process {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub cmdline: String,
+    #[prost(double, tag = "4")]
+    pub cpu_usage_percent: f64,
+    #[prost(uint64, tag = "5")]
+    pub rss_bytes: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Container {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub image: String,
+    #[prost(string, tag = "4")]
+    pub state: String,
+    #[prost(double, optional, tag = "5")]
+    pub cpu_usage_percent: Option<f64>,
+    #[prost(uint64, optional, tag = "6")]
+    pub memory_usage: Option<u64>,
+    #[prost(uint64, optional, tag = "7")]
+    pub memory_limit: Option<u64>,
+    #[prost(uint64, tag = "8")]
+    pub network_rx_bytes: u64,
+    #[prost(uint64, tag = "9")]
+    pub network_tx_bytes: u64,
+    #[prost(uint64, tag = "10")]
+    pub restart_count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Service {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub load_state: String,
+    #[prost(string, tag = "3")]
+    pub active_state: String,
+    #[prost(string, tag = "4")]
+    pub sub_state: String,
+    #[prost(uint64, optional, tag = "5")]
+    pub restart_count: Option<u64>,
+    #[prost(bool, tag = "6")]
+    pub healthy: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HttpCheck {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub url: String,
+    #[prost(uint32, optional, tag = "3")]
+    pub status_code: Option<u32>,
+    #[prost(double, optional, tag = "4")]
+    pub latency_ms: Option<f64>,
+    #[prost(double, optional, tag = "5")]
+    pub tls_days_remaining: Option<f64>,
+    #[prost(string, optional, tag = "6")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Ping {
+    #[prost(string, tag = "1")]
+    pub host: String,
+    #[prost(string, tag = "2")]
+    pub protocol: String,
+    #[prost(uint32, optional, tag = "3")]
+    pub port: Option<u32>,
+    #[prost(bool, tag = "4")]
+    pub reachable: bool,
+    #[prost(double, optional, tag = "5")]
+    pub rtt_ms: Option<f64>,
+    #[prost(string, optional, tag = "6")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Agent {
+    #[prost(string, tag = "1")]
+    pub api_circuit_state: String,
+    #[prost(uint32, tag = "2")]
+    pub api_consecutive_failures: u32,
+    #[prost(uint64, tag = "3")]
+    pub spooled_bytes: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SystemInfo {
+    #[prost(string, tag = "1")]
+    pub hostname: String,
+    #[prost(string, tag = "2")]
+    pub os_name: String,
+    #[prost(string, tag = "3")]
+    pub os_version: String,
+    #[prost(string, tag = "4")]
+    pub kernel_version: String,
+    #[prost(uint64, tag = "5")]
+    pub uptime: u64,
+    #[prost(double, optional, tag = "6")]
+    pub clock_offset_ms: Option<f64>,
+    #[prost(message, optional, tag = "7")]
+    pub cloud_instance: Option<CloudInstance>,
+}
+
+// Takes typed fields out of a JSON object as they're converted, so whatever remains of
+// a sample afterwards is exactly what goes into `extensions_json`.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Fields<'_> {
+    fn take(&mut self, key: &str) -> Value {
+        self.0.remove(key).unwrap_or(Value::Null)
+    }
+
+    fn string(&mut self, key: &str) -> String {
+        self.opt_string(key).unwrap_or_default()
+    }
+
+    fn opt_string(&mut self, key: &str) -> Option<String> {
+        match self.take(key) {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn string_map(&mut self, key: &str) -> BTreeMap<String, String> {
+        match self.take(key) {
+            Value::Object(map) => map.into_iter().filter_map(|(key, value)| Some((key, value.as_str()?.to_string()))).collect(),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    fn f64(&mut self, key: &str) -> f64 {
+        self.opt_f64(key).unwrap_or_default()
+    }
+
+    fn opt_f64(&mut self, key: &str) -> Option<f64> {
+        self.take(key).as_f64()
+    }
+
+    fn u64(&mut self, key: &str) -> u64 {
+        self.opt_u64(key).unwrap_or_default()
+    }
+
+    fn opt_u64(&mut self, key: &str) -> Option<u64> {
+        self.take(key).as_u64()
+    }
+
+    fn opt_u32(&mut self, key: &str) -> Option<u32> {
+        self.opt_u64(key).and_then(|value| u32::try_from(value).ok())
+    }
+
+    fn bool(&mut self, key: &str) -> bool {
+        self.take(key).as_bool().unwrap_or_default()
+    }
+
+    // Converts a nested object, dropping it from the parent once nothing is left of it.
+    fn object<T>(&mut self, key: &str, convert: impl FnOnce(&mut Fields) -> T) -> Option<T> {
+        match self.0.get_mut(key) {
+            Some(Value::Object(map)) => {
+                let converted = convert(&mut Fields(map));
+                if map.is_empty() {
+                    self.0.remove(key);
+                }
+                Some(converted)
+            }
+            Some(Value::Null) => {
+                self.0.remove(key);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Converts a list of objects. Leftovers keep their positions, so they line up with the
+    // typed entries when merged back.
+    fn list<T>(&mut self, key: &str, mut convert: impl FnMut(&mut Fields) -> T) -> Vec<T> {
+        let Some(Value::Array(items)) = self.0.get_mut(key) else {
+            return Vec::new();
+        };
+        let converted = items.iter_mut().filter_map(Value::as_object_mut).map(|map| convert(&mut Fields(map))).collect();
+        if items.iter().all(|item| item.as_object().is_some_and(Map::is_empty)) {
+            self.0.remove(key);
+        }
+        converted
+    }
+}
+
+fn process(f: &mut Fields) -> Process {
+    Process {
+        pid: f.opt_u32("pid").unwrap_or_default(),
+        name: f.string("name"),
+        cmdline: f.string("cmdline"),
+        cpu_usage_percent: f.f64("cpu_usage_percent"),
+        rss_bytes: f.u64("rss_bytes"),
+    }
+}
+
+// A field left out of `extensions_json` reads as null anyway. List items stay, as their
+// positions line them up with the typed entries.
+fn prune_nulls(map: &mut Map<String, Value>) {
+    map.retain(|_, value| !value.is_null());
+    for value in map.values_mut() {
+        match value {
+            Value::Object(inner) => prune_nulls(inner),
+            Value::Array(items) => items.iter_mut().filter_map(Value::as_object_mut).for_each(prune_nulls),
+            _ => {}
+        }
+    }
+}
+
+fn cloud_instance(f: &mut Fields) -> CloudInstance {
+    CloudInstance {
+        instance_type: f.opt_string("instance_type"),
+        region: f.opt_string("region"),
+        availability_zone: f.opt_string("availability_zone"),
+        instance_id: f.opt_string("instance_id"),
+        image: f.opt_string("image"),
+    }
+}
+
+fn sample(mut json: Value) -> Result<Sample, VmMonitorError> {
+    let Some(map) = json.as_object_mut() else {
+        return Err(VmMonitorError::ApiError("Metrics sample isn't a JSON object".to_string()));
+    };
+    let mut f = Fields(map);
+    let timestamp = f.opt_string("timestamp").and_then(|timestamp| DateTime::parse_from_rfc3339(&timestamp).ok());
+    let mut sample = Sample {
+        timestamp_unix_nanos: timestamp.and_then(|timestamp| timestamp.with_timezone(&Utc).timestamp_nanos_opt()).unwrap_or_default(),
+        instance_id: f.string("instance_id"),
+        cpu: f.object("cpu_metrics", |f| Cpu {
+            usage_percent: f.f64("usage_percent"),
+            core_count: f.opt_u32("core_count").unwrap_or_default(),
+            per_core_usage: f.take("per_core_usage").as_array().into_iter().flatten().filter_map(Value::as_f64).collect(),
+            limit_cores: f.opt_f64("limit_cores"),
+            time_breakdown: f.object("time_breakdown", |f| CpuTimes {
+                user_percent: f.f64("user_percent"),
+                nice_percent: f.f64("nice_percent"),
+                system_percent: f.f64("system_percent"),
+                idle_percent: f.f64("idle_percent"),
+                iowait_percent: f.f64("iowait_percent"),
+                irq_percent: f.f64("irq_percent"),
+                softirq_percent: f.f64("softirq_percent"),
+                steal_percent: f.f64("steal_percent"),
+            }),
+        }),
+        memory: f.object("memory_metrics", |f| Memory {
+            total_memory: f.u64("total_memory"),
+            used_memory: f.u64("used_memory"),
+            available_memory: f.u64("available_memory"),
+            total_swap: f.u64("total_swap"),
+            used_swap: f.u64("used_swap"),
+            limit_bytes: f.opt_u64("limit_bytes"),
+            swap_in_pages_per_sec: f.opt_f64("swap_in_pages_per_sec"),
+            swap_out_pages_per_sec: f.opt_f64("swap_out_pages_per_sec"),
+            major_faults_per_sec: f.opt_f64("major_faults_per_sec"),
+        }),
+        disks: f.list("disk_metrics", |f| Disk {
+            name: f.string("name"),
+            mount_point: f.string("mount_point"),
+            filesystem: f.string("filesystem"),
+            total_space: f.u64("total_space"),
+            available_space: f.u64("available_space"),
+            total_read_bytes: f.u64("total_read_bytes"),
+            total_written_bytes: f.u64("total_written_bytes"),
+            read_bytes_per_sec: f.opt_f64("read_bytes_per_sec"),
+            write_bytes_per_sec: f.opt_f64("write_bytes_per_sec"),
+            read_latency_ms: f.opt_f64("read_latency_ms"),
+            write_latency_ms: f.opt_f64("write_latency_ms"),
+            avg_queue_depth: f.opt_f64("avg_queue_depth"),
+            io_in_flight: f.opt_u64("io_in_flight"),
+        }),
+        network_interfaces: f.list("network_metrics", |f| NetworkInterface {
+            interface_name: f.string("interface_name"),
+            received_bytes_total: f.u64("received_bytes_total"),
+            transmitted_bytes_total: f.u64("transmitted_bytes_total"),
+            received_bytes_per_sec: f.opt_f64("received_bytes_per_sec"),
+            transmitted_bytes_per_sec: f.opt_f64("transmitted_bytes_per_sec"),
+            received_packets: f.opt_u64("received_packets"),
+            transmitted_packets: f.opt_u64("transmitted_packets"),
+            receive_errors: f.opt_u64("receive_errors"),
+            transmit_errors: f.opt_u64("transmit_errors"),
+            receive_drops: f.opt_u64("receive_drops"),
+            transmit_drops: f.opt_u64("transmit_drops"),
+        }),
+        tcp: f.object("tcp_metrics", |f| Tcp {
+            total: f.u64("total"),
+            established: f.u64("established"),
+            syn_sent: f.u64("syn_sent"),
+            syn_recv: f.u64("syn_recv"),
+            fin_wait1: f.u64("fin_wait1"),
+            fin_wait2: f.u64("fin_wait2"),
+            time_wait: f.u64("time_wait"),
+            close: f.u64("close"),
+            close_wait: f.u64("close_wait"),
+            last_ack: f.u64("last_ack"),
+            listen: f.u64("listen"),
+            closing: f.u64("closing"),
+        }),
+        processes: f.object("process_metrics", |f| Processes {
+            total_processes: f.u64("total_processes"),
+            top_by_cpu: f.list("top_by_cpu", process),
+            top_by_memory: f.list("top_by_memory", process),
+        }),
+        containers: f.list("container_metrics", |f| Container {
+            id: f.string("id"),
+            name: f.string("name"),
+            image: f.string("image"),
+            state: f.string("state"),
+            cpu_usage_percent: f.opt_f64("cpu_usage_percent"),
+            memory_usage: f.opt_u64("memory_usage"),
+            memory_limit: f.opt_u64("memory_limit"),
+            network_rx_bytes: f.u64("network_rx_bytes"),
+            network_tx_bytes: f.u64("network_tx_bytes"),
+            restart_count: f.u64("restart_count"),
+        }),
+        services: f.list("service_metrics", |f| Service {
+            name: f.string("name"),
+            load_state: f.string("load_state"),
+            active_state: f.string("active_state"),
+            sub_state: f.string("sub_state"),
+            restart_count: f.opt_u64("restart_count"),
+            healthy: f.bool("healthy"),
+        }),
+        http_checks: f.list("http_checks", |f| HttpCheck {
+            name: f.string("name"),
+            url: f.string("url"),
+            status_code: f.opt_u32("status_code"),
+            latency_ms: f.opt_f64("latency_ms"),
+            tls_days_remaining: f.opt_f64("tls_days_remaining"),
+            error: f.opt_string("error"),
+        }),
+        pings: f.list("ping_results", |f| Ping {
+            host: f.string("host"),
+            protocol: f.string("protocol"),
+            port: f.opt_u32("port"),
+            reachable: f.bool("reachable"),
+            rtt_ms: f.opt_f64("rtt_ms"),
+            error: f.opt_string("error"),
+        }),
+        custom_metrics: match f.take("custom_metrics") {
+            Value::Object(metrics) => metrics.into_iter().filter_map(|(name, value)| Some((name, value.as_f64()?))).collect(),
+            _ => BTreeMap::new(),
+        },
+        agent: f.object("agent_metrics", |f| Agent {
+            api_circuit_state: f.string("api_circuit_state"),
+            api_consecutive_failures: f.opt_u32("api_consecutive_failures").unwrap_or_default(),
+            spooled_bytes: f.u64("spooled_bytes"),
+        }),
+        system_info: f.object("system_info", |f| SystemInfo {
+            hostname: f.string("hostname"),
+            os_name: f.string("os_name"),
+            os_version: f.string("os_version"),
+            kernel_version: f.string("kernel_version"),
+            uptime: f.u64("uptime"),
+            clock_offset_ms: f.opt_f64("clock_offset_ms"),
+            cloud_instance: f.object("cloud_instance", cloud_instance),
+        }),
+        extensions_json: String::new(),
+        tags: f.string_map("tags"),
+    };
+    prune_nulls(map);
+    if !map.is_empty() {
+        sample.extensions_json = serde_json::to_string(map)?;
+    }
+    Ok(sample)
+}
+
+fn failure(rpc: &str, status: Status, stale_credentials: bool) -> RequestFailure {
+    log::error!("gRPC call {} failed: {:?} - {}", rpc, status.code(), status.message());
+    let retry_after = status
+        .metadata()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+    let message = status.message().to_string();
+    let (error, transient) = match status.code() {
+        // Reported as HTTP 400, so dead letters look the same for both transports.
+        Code::InvalidArgument => (VmMonitorError::ApiRejected(400, message), false),
+        Code::ResourceExhausted if retry_after.is_none() => (VmMonitorError::PayloadTooLarge(message), false),
+        code => (
+            VmMonitorError::ApiError(format!("gRPC call failed: {:?} - {}", code, message)),
+            matches!(
+                code,
+                Code::Unavailable
+                    | Code::DeadlineExceeded
+                    | Code::Aborted
+                    | Code::Internal
+                    | Code::Unknown
+                    | Code::Cancelled
+                    | Code::ResourceExhausted
+            ) || (code == Code::Unauthenticated && stale_credentials), // A stale timestamp or expired token, fixed by now
+        ),
+    };
+    RequestFailure { error, transient, retry_after }
+}
+
+fn metadata(value: String) -> Result<MetadataValue<tonic::metadata::Ascii>, VmMonitorError> {
+    MetadataValue::try_from(value).map_err(|e| VmMonitorError::ApiError(format!("Invalid gRPC metadata value: {}", e)))
+}
+
+pub struct GrpcClient {
+    channel: Channel, // Connects on first use and reconnects by itself
+    clock: auth::ClockSkew,
+    auth: auth::Authenticator,
+}
+
+impl GrpcClient {
+    pub fn new(api_url: &str, settings: &MonitoringSettings, auth: auth::Authenticator) -> Result<Self, VmMonitorError> {
+        let invalid = |e: &dyn std::fmt::Display| VmMonitorError::ConfigError(format!("Invalid gRPC endpoint {}: {}", api_url, e));
+        if api_url.starts_with("unix://") {
+            return Err(invalid(&"Unix sockets are only supported with the HTTP transport"));
+        }
+        if let ApiAuth::AwsSigv4(_) = settings.api_auth {
+            return Err(invalid(&"AWS SigV4 signing is only supported with the HTTP transport"));
+        }
+        let (tls, timeouts) = (&settings.api_tls, &settings.api_timeouts);
+        let mut endpoint = Endpoint::from_shared(api_url.to_string())
+            .map_err(|e| invalid(&e))?
+            .tcp_keepalive(timeouts.tcp_keepalive());
+        if let Some(request_timeout) = timeouts.request() {
+            endpoint = endpoint.timeout(request_timeout);
+        }
+        if let Some(connect_timeout) = timeouts.connect() {
+            endpoint = endpoint.connect_timeout(connect_timeout);
+        }
+        if api_url.starts_with("https://") {
+            let mut tls_config = ClientTlsConfig::new().with_webpki_roots();
+            if let Some(ca_file) = &tls.ca_file {
+                let pem = std::fs::read(ca_file)
+                    .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read CA file {}: {}", ca_file, e)))?;
+                tls_config = tls_config.ca_certificate(Certificate::from_pem(pem));
+            }
+            if let Some(identity) = tls.client_identity()? {
+                tls_config = tls_config.identity(Identity::from_pem(identity.cert, identity.key));
+            }
+            if tls.insecure_skip_verify {
+                log::warn!("insecure_skip_verify isn't supported over gRPC, the API's TLS certificate is still verified.");
+            }
+            endpoint = endpoint.tls_config(tls_config).map_err(|e| invalid(&e))?;
+        }
+        Ok(GrpcClient { channel: endpoint.connect_lazy(), clock: auth::ClockSkew::default(), auth })
+    }
+
+    fn observe_date(&self, metadata: &MetadataMap) -> bool {
+        metadata.get("date").and_then(|date| date.to_str().ok()).is_some_and(|date| self.clock.observe(date))
+    }
+
+    async fn unary<Req, Resp>(
+        &self,
+        config: &Configuration,
+        rpc: &'static str,
+        message: Req,
+        batch_id: Option<Uuid>,
+    ) -> Result<Resp, RequestFailure>
+    where
+        Req: Message + Send + Sync + 'static,
+        Resp: Message + Default + Send + Sync + 'static,
+    {
+        let payload = message.encode_to_vec();
+        let body = STANDARD.encode(&payload);
+        let url = format!("{}{}", config.api_url, rpc);
+        let auth_headers = self
+            .auth
+            .headers(&self.clock, &auth::RequestParts { method: "POST", url: &url, path: rpc, body: &body, payload: &payload })
+            .await
+            .map_err(|error| RequestFailure { error, transient: true, retry_after: None })?;
+        let mut request = Request::new(message);
+        let headers = request.metadata_mut();
+        for (name, value) in auth_headers {
+            headers.insert(name, metadata(value)?);
+        }
+        headers.insert("x-instance-id", metadata(config.instance_id.to_string())?);
+        if let Some(batch_id) = batch_id {
+            headers.insert("x-batch-id", metadata(batch_id.to_string())?);
+        }
+
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc = match config.monitoring_settings.compression {
+            PayloadCompression::None => grpc,
+            PayloadCompression::Gzip => grpc.send_compressed(CompressionEncoding::Gzip),
+            PayloadCompression::Zstd => grpc.send_compressed(CompressionEncoding::Zstd),
+        };
+        log::debug!("Sending gRPC request {} to {}", rpc, config.api_url);
+        grpc.ready().await.map_err(|e| RequestFailure {
+            error: VmMonitorError::ApiError(format!("gRPC connection to {} failed: {}", config.api_url, e)),
+            transient: true,
+            retry_after: None,
+        })?;
+        let response = match grpc.unary(request, PathAndQuery::from_static(rpc), ProstCodec::<Req, Resp>::default()).await {
+            Ok(response) => response,
+            Err(status) => {
+                let clock_corrected = self.observe_date(status.metadata());
+                let stale_credentials = clock_corrected || (status.code() == Code::Unauthenticated && self.auth.rejected().await);
+                return Err(failure(rpc, status, stale_credentials));
+            }
+        };
+        self.observe_date(response.metadata());
+        Ok(response.into_inner())
+    }
+
+    /// Sends an API request given by its endpoint and JSON body as the matching RPC,
+    /// returning the reply as the JSON the HTTP API would have answered with.
+    pub async fn call(
+        &self,
+        config: &Configuration,
+        endpoint: ApiEndpoint,
+        body: &str,
+        batch_id: Option<Uuid>,
+    ) -> Result<Value, RequestFailure> {
+        let mut json: Value = if body.is_empty() { Value::Null } else { serde_json::from_str(body).map_err(VmMonitorError::from)? };
+        let mut empty = Map::new();
+        let mut f = Fields(json.as_object_mut().unwrap_or(&mut empty));
+        match endpoint {
+            ApiEndpoint::Register => {
+                let request = RegisterRequest {
+                    instance_id: f.string("instance_id"),
+                    instance_name: f.string("instance_name"),
+                    cloud_provider: f.string("cloud_provider"),
+                    agent_api_key: f.string("agent_api_key"),
+                    virtualization: f.opt_string("virtualization"),
+                    signature_algorithm: f.string("signature_algorithm"),
+                    tags: f.string_map("tags"),
+                    cloud_instance: f.object("cloud_instance", cloud_instance),
+                };
+                let response: RegisterResponse = self.unary(config, REGISTER, request, batch_id).await?;
+                Ok(json!({ "message": response.message }))
+            }
+            ApiEndpoint::Metrics => {
+                let samples = match f.take("metrics") {
+                    Value::Array(samples) => samples.into_iter().map(sample).collect::<Result<_, _>>()?,
+                    _ => Vec::new(),
+                };
+                let response: MetricsBatchResponse = self.unary(config, SEND_METRICS, MetricsBatch { metrics: samples }, batch_id).await?;
+                let rejected: Vec<Value> = response
+                    .rejected
+                    .into_iter()
+                    .map(|item| json!({ "index": item.index, "error": item.error, "retryable": item.retryable }))
+                    .collect();
+                Ok(json!({ "rejected": rejected }))
+            }
+            ApiEndpoint::Heartbeat => {
+                let request = HeartbeatRequest {
+                    instance_id: f.string("instance_id"),
+                    agent_version: f.opt_string("agent_version"),
+                    uptime_seconds: f.opt_u64("uptime_seconds"),
+                    interval_seconds: f.opt_u64("interval_seconds"),
+                };
+                let response: HeartbeatResponse = self.unary(config, HEARTBEAT, request, batch_id).await?;
+                match response.config_json.as_str() {
+                    "" => Ok(json!({})),
+                    settings => Ok(json!({ "config": serde_json::from_str::<Value>(settings).map_err(VmMonitorError::from)? })),
+                }
+            }
+            ApiEndpoint::Inventory => {
+                let request = InventoryRequest { instance_id: config.instance_id.to_string(), inventory_json: body.to_string() };
+                let _: InventoryResponse = self.unary(config, SEND_INVENTORY, request, batch_id).await?;
+                Ok(json!({}))
+            }
+            ApiEndpoint::Health => {
+                let response: HealthResponse = self.unary(config, HEALTH, HealthRequest {}, batch_id).await?;
+                Ok(json!({ "status": response.status }))
+            }
+            ApiEndpoint::Commands => {
+                let request = CommandsRequest { instance_id: config.instance_id.to_string() };
+                let response: CommandsResponse = self.unary(config, POLL_COMMANDS, request, batch_id).await?;
+                let commands: Vec<Value> =
+                    response.commands.into_iter().map(|command| json!({ "id": command.id, "action": command.action })).collect();
+                Ok(json!({ "commands": commands }))
+            }
+            ApiEndpoint::CommandResult => {
+                let request = CommandResult {
+                    instance_id: f.string("instance_id"),
+                    id: f.string("id"),
+                    success: f.bool("success"),
+                    output_json: match f.take("output") {
+                        Value::Null => String::new(),
+                        output => output.to_string(),
+                    },
+                    error: f.opt_string("error"),
+                };
+                let _: CommandResultResponse = self.unary(config, REPORT_COMMAND_RESULT, request, batch_id).await?;
+                Ok(json!({}))
+            }
+            ApiEndpoint::RotateKey => {
+                let request = RotateKeyRequest { instance_id: f.string("instance_id"), new_api_key: f.string("new_api_key") };
+                let _: RotateKeyResponse = self.unary(config, ROTATE_KEY, request, batch_id).await?;
+                Ok(json!({}))
+            }
+            #[cfg(feature = "websocket")]
+            ApiEndpoint::Stream => Err(VmMonitorError::ApiError("The metrics stream has no gRPC equivalent".to_string()).into()),
+        }
+    }
+}
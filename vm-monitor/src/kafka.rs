@@ -0,0 +1,150 @@
+// Publishes metrics batches to a Kafka topic, for data platforms that ingest through Kafka
+// rather than the HTTP API. Each record holds one batch in the API's `{"metrics": [...]}`
+// shape, keyed by instance ID so an agent's batches stay ordered within one partition.
+use crate::config::{Configuration, KafkaSettings};
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Delivery, Sink, SinkFuture};
+use rskafka::BackoffConfig;
+use rskafka::chrono::Utc;
+use rskafka::client::ClientBuilder;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::record::Record;
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+// Kafka's default partitioner hashes keys with murmur2, so records land on the same
+// partition a Java or librdkafka producer would pick for this instance.
+fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    let mut hash = SEED ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).wrapping_mul(M);
+        k ^= k >> 24;
+        hash = hash.wrapping_mul(M) ^ k.wrapping_mul(M);
+    }
+    if tail.len() >= 3 {
+        hash ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        hash ^= (tail[1] as u32) << 8;
+    }
+    if let Some(&first) = tail.first() {
+        hash = (hash ^ first as u32).wrapping_mul(M);
+    }
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(M);
+    hash ^ (hash >> 15)
+}
+
+fn kafka_error(e: impl std::fmt::Display) -> VmMonitorError {
+    VmMonitorError::SinkError(format!("Kafka: {}", e))
+}
+
+pub struct KafkaSink {
+    settings: KafkaSettings,
+    partition: Option<PartitionClient>, // Connected on first publish, and again after a failure
+    key: Vec<u8>,
+    batch_size: usize,
+    max_pending: usize, // Samples kept while the brokers are unreachable
+    pending: VecDeque<serde_json::Value>,
+}
+
+impl KafkaSink {
+    pub fn new(config: &Configuration, settings: &KafkaSettings) -> Self {
+        let batch_size = config.monitoring_settings.batch_size.max(1);
+        KafkaSink {
+            settings: settings.clone(),
+            partition: None,
+            key: config.instance_id.to_string().into_bytes(),
+            batch_size,
+            max_pending: batch_size * config.monitoring_settings.max_buffered_batches.max(1),
+            pending: VecDeque::new(),
+        }
+    }
+
+    async fn connect(&self) -> Result<PartitionClient, VmMonitorError> {
+        // Without a deadline rskafka retries forever, which would stall the collection loop.
+        let backoff = BackoffConfig {
+            deadline: Some(Duration::from_secs(self.settings.timeout_seconds)),
+            ..BackoffConfig::default()
+        };
+        let client = ClientBuilder::new(self.settings.brokers.clone())
+            .client_id("vm-monitor")
+            .backoff_config(backoff)
+            .build()
+            .await
+            .map_err(kafka_error)?;
+        let topic = &self.settings.topic;
+        let partitions = client
+            .list_topics()
+            .await
+            .map_err(kafka_error)?
+            .into_iter()
+            .find(|candidate| candidate.name == *topic)
+            .map(|candidate| candidate.partitions.len())
+            .filter(|count| *count > 0)
+            .ok_or_else(|| kafka_error(format!("topic {} doesn't exist", topic)))?;
+
+        let partition = ((murmur2(&self.key) & 0x7fff_ffff) as usize % partitions) as i32;
+        log::info!("Publishing to partition {} of {} on Kafka topic {}", partition, partitions, topic);
+        client
+            .partition_client(topic.clone(), partition, UnknownTopicHandling::Error)
+            .await
+            .map_err(kafka_error)
+    }
+
+    // Publishes everything pending, batch by batch. Unpublished samples stay queued.
+    async fn publish_pending(&mut self) -> Result<(), VmMonitorError> {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.batch_size);
+            let metrics: Vec<&serde_json::Value> = self.pending.iter().take(count).collect();
+            let record = Record {
+                key: Some(self.key.clone()),
+                value: Some(serde_json::to_vec(&json!({ "metrics": metrics }))?),
+                headers: BTreeMap::from([("content-type".to_string(), b"application/json".to_vec())]),
+                timestamp: Utc::now(),
+            };
+            let partition = match self.partition.take() {
+                Some(partition) => partition,
+                None => self.connect().await?,
+            };
+            partition.produce(vec![record], Compression::Gzip).await.map_err(kafka_error)?;
+            self.partition = Some(partition); // Kept only while it works, so leader changes reconnect
+            self.pending.drain(..count);
+        }
+        Ok(())
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for sample in metrics {
+                self.pending.push_back(serde_json::to_value(sample)?);
+            }
+            let excess = self.pending.len().saturating_sub(self.max_pending);
+            if excess > 0 {
+                log::warn!("Kafka is unreachable, dropping the {} oldest unpublished samples.", excess);
+                self.pending.drain(..excess);
+            }
+            self.publish_pending().await
+        })
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(self.publish_pending())
+    }
+}
@@ -1,15 +1,48 @@
 mod api;
 mod auth;
+mod aws;
+mod azure;
+mod azuremonitor;
+mod cloudwatch;
 mod config;
+mod deadletter;
+mod elasticsearch;
 mod errors;
+mod filesink;
+mod gauges;
+mod gcp;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "kafka")]
+mod kafka;
 mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod newrelic;
+mod oauth2;
+mod otlp;
+mod prometheus;
 mod recommend;
+mod secrets;
+mod sink;
+mod spool;
+mod statsd;
+mod stdout;
+mod syslog;
+#[cfg(unix)]
+mod unix_socket;
+mod vault;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 use crate::api::ApiClient;
+use crate::errors::VmMonitorError;
 use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use sysinfo::System;
 use tokio::time::Instant;
+use sysinfo::System;
 use uuid::Uuid;
 use cli_table::{print_stdout, Table, WithTitle};
 
@@ -17,6 +50,8 @@ use cli_table::{print_stdout, Table, WithTitle};
 #[clap(name = "vm-monitor", version = "0.1.0", author = "Farhan")]
 #[clap(about = "Monitors VM resources and sends data to a remote API.")]
 struct Cli {
+    #[clap(long, global = true, value_name = "PATH", help = "Config file to use (defaults to VM_MONITOR_CONFIG, then the user's config directory)")]
+    config: Option<PathBuf>,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -24,23 +59,40 @@ struct Cli {
 #[derive(Parser, Debug)]
 enum Commands {
     /// Initialize the agent with API endpoint and instance name
-    Init {
-        #[clap(long, help = "Remote API base URL")]
-        api_url: String,
-        #[clap(long, help = "User-defined name for this VM instance")]
-        name: String,
-        #[clap(long, help = "Monitoring interval in seconds", default_value_t = 60)]
-        interval: u64,
-        #[clap(long, help = "Number of metrics to batch before sending", default_value_t = 10)]
-        batch_size: usize,
-    },
+    Init(Box<InitArgs>),
     /// Start monitoring and sending data (runs as a daemon-like foreground process)
     Start {
         #[clap(long, help = "Override monitoring interval in seconds from config")]
         interval: Option<u64>,
+        #[clap(long, help = "Serve the latest metrics for Prometheus at http://<address>/metrics, e.g. 0.0.0.0:9900")]
+        listen: Option<String>,
+        #[clap(long, help = "Print every sample as one JSON line to stdout")]
+        stdout: bool,
+        #[clap(long, help = "Don't send metrics to the API, e.g. with --stdout")]
+        no_api: bool,
     },
     /// Show current system status and configuration
     Status,
+    /// Collect the hardware/software inventory and send it to the API
+    Inventory {
+        #[clap(long, help = "Print the inventory as JSON instead of sending it")]
+        dry_run: bool,
+    },
+    /// Replace the API key with a new one, registered with the API using the current key
+    RotateKey {
+        #[clap(long, help = "Keep the new key in the OS keyring instead of the config file (needs the `keyring` feature)")]
+        keyring: bool,
+    },
+    /// Send the registration again, e.g. after the API's database was restored or the VM was cloned from an image
+    ReRegister {
+        #[clap(long, help = "Mint a new instance ID and API key first, for a VM cloned from another agent's image")]
+        new_identity: bool,
+    },
+    /// Read, change or validate settings in the config file
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
     Recommend {
         #[clap(long, help = "Collect usage data for this many seconds before recommending", default_value_t = 60)]
         duration: u64,
@@ -50,26 +102,169 @@ enum Commands {
     },
 }
 
-async fn handle_init(
-    api_url: String,
-    instance_name: String,
+#[derive(clap::Args, Debug)]
+struct InitArgs {
+    #[clap(long, help = "Remote API base URL (defaults to VM_MONITOR_API_URL)")]
+    api_url: Option<String>,
+    #[clap(long, help = "User-defined name for this VM instance")]
+    name: String,
+    #[clap(long, help = "Monitoring interval in seconds", default_value_t = 60)]
     interval: u64,
+    #[clap(long, help = "Number of metrics to batch before sending", default_value_t = 10)]
     batch_size: usize,
-) -> anyhow::Result<()> {
+    #[clap(long, help = "Talk to the API over gRPC instead of JSON over HTTP (needs the `grpc` feature)")]
+    grpc: bool,
+    #[clap(long, help = "Keep the API key in the OS keyring instead of the config file (needs the `keyring` feature)")]
+    keyring: bool,
+    #[clap(long, value_enum, default_value_t, help = "How requests are signed; ed25519 registers only a public key")]
+    signature_algorithm: config::SignatureAlgorithm,
+    #[clap(long = "auth", value_name = "JSON", help = r#"How the agent authenticates to the API, e.g. '{"mode": "gcp_identity"}'; no API key is generated unless it's hmac (the default)"#)]
+    api_auth: Option<String>,
+    #[clap(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag, help = "Tag for grouping agents, sent with the registration and every sample; repeatable")]
+    tags: Vec<(String, String)>,
+    #[clap(long, help = "Write the config without contacting the API; `start` registers the instance instead")]
+    offline: bool,
+    #[clap(long, help = "Overwrite an existing config")]
+    force: bool,
+    #[clap(long, value_name = "URL", help = "Proxy for API requests, e.g. http://proxy.internal:3128 (defaults to HTTPS_PROXY and the like)")]
+    proxy: Option<String>,
+    #[clap(long, value_name = "FILE", help = "PEM bundle to trust for the API's certificate, in addition to the built-in roots")]
+    ca_cert: Option<PathBuf>,
+    #[clap(long, value_name = "FILE", requires = "client_key", help = "PEM client certificate, for APIs that require mutual TLS")]
+    client_cert: Option<PathBuf>,
+    #[clap(long, value_name = "FILE", requires = "client_cert", help = "PEM key for --client-cert")]
+    client_key: Option<PathBuf>,
+    #[clap(long = "sink", value_name = "JSON", value_parser = parse_sink, help = r#"Sink to send samples to besides the API, e.g. '{"type": "statsd", "address": "localhost:8125"}'; repeatable"#)]
+    sinks: Vec<config::SinkConfig>,
+}
+
+// Files given to `init` as they're found from anywhere, since the agent may run from another directory.
+fn absolute_path(path: PathBuf) -> anyhow::Result<String> {
+    let absolute = std::fs::canonicalize(&path).map_err(|e| anyhow::anyhow!("Can't read {}: {}", path.display(), e))?;
+    Ok(absolute.to_string_lossy().into_owned())
+}
+
+fn parse_sink(raw: &str) -> Result<config::SinkConfig, String> {
+    config::parse_sink(raw).map_err(|e| e.to_string())
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.trim().to_string())),
+        _ => Err(format!("expected KEY=VALUE, e.g. env=prod, got {:?}", tag)),
+    }
+}
+
+#[derive(Parser, Debug)]
+enum ConfigAction {
+    /// Print a setting, e.g. `config get monitoring.interval_seconds`
+    Get {
+        #[clap(help = "Setting name, with dots between nested keys")]
+        name: String,
+    },
+    /// Change a setting, e.g. `config set monitoring.interval_seconds 30`; the file is left alone if it already has the value
+    Set {
+        #[clap(help = "Setting name, with dots between nested keys")]
+        name: String,
+        #[clap(help = "New value; lists also take comma-separated items, and sections take JSON")]
+        value: String,
+    },
+    /// Check the config before starting the agent, exiting non-zero if anything is wrong
+    Validate {
+        #[clap(long, help = "Also check that the API answers on its health endpoint")]
+        ping: bool,
+    },
+    /// List, add, remove, enable or disable the sinks samples are sent to
+    Sink {
+        #[clap(subcommand)]
+        action: SinkAction,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum SinkAction {
+    /// Print the configured sinks with their numbers
+    List,
+    /// Add a sink, e.g. `config sink add '{"type": "otlp", "endpoint": "http://localhost:4318"}'`
+    Add {
+        #[clap(help = "The sink's type and settings as JSON")]
+        sink: String,
+    },
+    /// Remove a sink by its number in `config sink list`
+    Remove { number: usize },
+    /// Turn a sink back on
+    Enable { number: usize },
+    /// Stop sending to a sink, keeping its settings
+    Disable { number: usize },
+}
+
+async fn handle_init(args: InitArgs) -> anyhow::Result<()> {
+    let InitArgs {
+        api_url,
+        name: instance_name,
+        interval,
+        batch_size,
+        grpc,
+        keyring,
+        signature_algorithm,
+        api_auth,
+        tags,
+        offline,
+        force,
+        proxy,
+        ca_cert,
+        client_cert,
+        client_key,
+        sinks,
+    } = args;
     log::info!(
         "Initializing new VmMonitor agent for instance: {}",
         instance_name
     );
 
-    if config::load_config().is_ok() {
-        log::warn!("Existing configuration found. Re-initializing will overwrite it.");
-        // Add a prompt here in a real app: "Overwrite? [y/N]"
+    let existing = config::get_config_path()?;
+    if existing.exists() {
+        if !force {
+            anyhow::bail!("A configuration already exists at {}. Pass --force to overwrite it.", existing.display());
+        }
+        log::warn!("Overwriting the existing configuration at {}.", existing.display());
     }
-    
-    let instance_id = Uuid::new_v4();
-    let api_key = auth::generate_api_key();
-    log::info!("Generated Instance ID: {}", instance_id);
-    log::debug!("Generated API Key: {}", api_key); // Log only in debug, not for user display of full key.
+
+    let api_url = api_url
+        .or_else(|| config::env_override(config::API_URL_ENV))
+        .ok_or_else(|| anyhow::anyhow!("Pass --api-url or set {}", config::API_URL_ENV))?;
+    // An offline config is baked into images, so each VM made from one gets its own ID and key on
+    // its first start instead of all sharing these.
+    let instance_id = if offline { Uuid::nil() } else { Uuid::new_v4() };
+    if !offline {
+        log::info!("Generated Instance ID: {}", instance_id);
+    }
+    let api_auth: config::ApiAuth = match api_auth {
+        Some(raw) => serde_json::from_str(&raw).map_err(|e| anyhow::anyhow!("Invalid --auth: {}", e))?,
+        None => config::ApiAuth::default(),
+    };
+    // A key injected through the environment is registered as is, and never written to disk.
+    let (api_key, api_key_store) = match config::env_override(config::API_KEY_ENV) {
+        _ if !api_auth.uses_api_key() => {
+            if keyring {
+                log::warn!("Ignoring --keyring, there's no API key with this --auth.");
+            }
+            (String::new(), config::ApiKeyStore::ConfigFile)
+        }
+        Some(api_key) => {
+            log::info!("Using the API key from {}, it won't be saved in the config file.", config::API_KEY_ENV);
+            if keyring {
+                log::warn!("Ignoring --keyring, the API key comes from {}.", config::API_KEY_ENV);
+            }
+            (api_key, config::ApiKeyStore::Environment(String::new()))
+        }
+        None => {
+            let api_key = if offline { String::new() } else { auth::generate_api_key(signature_algorithm)? };
+            log::debug!("Generated API Key: {}", api_key); // Log only in debug, not for user display of full key.
+            let store = if keyring { config::ApiKeyStore::Keyring(api_key_account(instance_id)) } else { config::file_key_store()? };
+            (api_key, store)
+        }
+    };
 
     log::info!("Detecting cloud provider...");
     let cloud_provider = config::detect_cloud_provider().await;
@@ -78,59 +273,161 @@ async fn handle_init(
     let monitoring_settings = config::MonitoringSettings {
         interval_seconds: interval,
         batch_size,
+        tags: tags.into_iter().collect(),
+        api_transport: if grpc { config::ApiTransport::Grpc } else { config::ApiTransport::Http },
+        signature_algorithm,
+        api_auth,
+        api_tls: config::ApiTlsSettings {
+            ca_file: ca_cert.map(absolute_path).transpose()?,
+            client_cert_file: client_cert.map(absolute_path).transpose()?,
+            client_key_file: client_key.map(absolute_path).transpose()?,
+            ..Default::default()
+        },
+        api_proxy: proxy,
+        ..Default::default()
     };
+    // Fails on a bad proxy URL or unreadable certificate files now rather than when the agent starts
+    api::http_client(&monitoring_settings)?;
 
-    let new_config = config::Configuration {
+    let mut new_config = config::Configuration {
+        schema_version: config::CONFIG_SCHEMA_VERSION,
         instance_id,
         instance_name: instance_name.clone(),
         api_url: api_url.clone(),
+        file_api_url: None,
         api_key: api_key.clone(),
+        api_key_store,
+        vault: None,
         cloud_provider,
         monitoring_settings,
+        sinks: config::SinkConfig::with_api(sinks),
         initialized_at: chrono::Utc::now(),
+        registration_pending: offline,
+        env_overrides: Vec::new(),
     };
+    let problems = new_config.problems();
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("; "));
+    }
 
-    // Attempt to register with the remote API
-    // This requires the API to be available. For testing, this might be mocked.
-    log::info!("Registering instance with API at {}...", api_url);
-    let api_client = ApiClient::new(new_config.clone()); // Clone config for API client
-    match api_client.register_instance().await {
-        Ok(response) => {
-            log::info!(
-                "Instance registered successfully with API: {}",
-                response.message
-            );
-        }
-        Err(e) => {
-            // Log full error for diagnostics, return user-friendly error
-            log::error!("Failed to register instance with API: {:?}", e);
-            return Err(anyhow::anyhow!(
-                "Failed to register with remote API. Please check API URL and network. Error: {}", e
-            ));
-        }
+    if offline {
+        log::info!("Offline, the instance will be registered with {} when the agent starts.", api_url);
+    } else if let Err(e) = register_instance(&ApiClient::new(new_config.clone())?, &new_config).await {
+        // Log full error for diagnostics, return user-friendly error
+        log::error!("Failed to register instance with API: {:?}", e);
+        return Err(anyhow::anyhow!(
+            "Failed to register with remote API. Please check API URL and network, or pass --offline to register on the first start. Error: {}", e
+        ));
     }
 
-    let config_path = config::save_config(&new_config)?;
+    let config_path = save_config_or_keep_key_in_file(&mut new_config)?;
     log::info!("Configuration saved to: {}", config_path.display());
 
-    println!("VmMonitor Agent initialized successfully!");
-    println!("Instance ID: {}", instance_id);
+    if offline {
+        println!("VmMonitor Agent initialized offline, it registers with the API on the first start.");
+        println!("Instance ID: assigned on the first start");
+    } else {
+        println!("VmMonitor Agent initialized successfully!");
+        println!("Instance ID: {}", instance_id);
+    }
     println!("Instance Name: {}", instance_name);
     println!("API URL: {}", api_url);
-    println!("API Key: {}... (stored in config)", &api_key[..8.min(api_key.len())]); // Show only a prefix
+    if !api_key.is_empty() {
+        println!("API Key: {}... (stored in {})", &api_key[..8.min(api_key.len())], key_location(&new_config.api_key_store)); // Show only a prefix
+    }
     println!("Config file: {}", config_path.display());
 
     Ok(())
 }
 
-async fn handle_start(cli_interval: Option<u64>) -> anyhow::Result<()> {
-    let config = config::load_config().map_err(|e| {
+// Sets up one of the configured sinks other than the API, which `start` sets up itself.
+async fn add_sink(sinks: &mut sink::Sinks, config: &config::Configuration, kind: &config::SinkKind, cli_listen: bool) -> anyhow::Result<()> {
+    match kind {
+        config::SinkKind::Api => {}
+        config::SinkKind::Prometheus { .. } if cli_listen => {} // `start --listen` takes its place
+        config::SinkKind::Prometheus { listen } => add_prometheus_sink(sinks, listen).await?,
+        config::SinkKind::Otlp(settings) => {
+            log::info!("Exporting metrics over OTLP to {}", settings.endpoint);
+            sinks.add(Box::new(otlp::OtlpExporter::new(config, settings)?));
+        }
+        config::SinkKind::Statsd(settings) => {
+            let sink = statsd::StatsdSink::connect(settings)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to set up StatsD sink for {}: {}", settings.address, e))?;
+            log::info!("Sending metrics to StatsD at {}", settings.address);
+            sinks.add(Box::new(sink));
+        }
+        config::SinkKind::Syslog(settings) => {
+            let events_level: log::Level = settings
+                .events_level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid syslog events_level {:?}", settings.events_level))?;
+            let writer = std::sync::Arc::new(
+                syslog::SyslogWriter::new(settings).map_err(|e| anyhow::anyhow!("Failed to set up syslog output: {}", e))?,
+            );
+            syslog::forward_events(writer.clone(), events_level);
+            sinks.add(Box::new(writer));
+        }
+        config::SinkKind::Cloudwatch(settings) => {
+            log::info!("Publishing metrics to CloudWatch namespace {}", settings.namespace);
+            sinks.add(Box::new(cloudwatch::CloudWatchSink::new(config, settings)?));
+        }
+        config::SinkKind::AzureMonitor(settings) => {
+            log::info!("Publishing metrics to Azure Monitor namespace {}", settings.namespace);
+            sinks.add(Box::new(azuremonitor::AzureMonitorSink::new(settings)?));
+        }
+        config::SinkKind::Newrelic(settings) => {
+            log::info!("Sending metrics to New Relic at {}", settings.endpoint);
+            sinks.add(Box::new(newrelic::NewRelicSink::new(config, settings)?));
+        }
+        config::SinkKind::Elasticsearch(settings) => {
+            log::info!("Indexing metrics into Elasticsearch at {}", settings.url);
+            sinks.add(Box::new(elasticsearch::ElasticsearchSink::new(settings)?));
+        }
+        config::SinkKind::File(settings) => {
+            log::info!("Writing metrics to {}", settings.path);
+            sinks.add(Box::new(filesink::FileSink::new(settings)));
+        }
+        #[cfg(feature = "mqtt")]
+        config::SinkKind::Mqtt(settings) => {
+            let sink = mqtt::MqttSink::new(config, settings).map_err(|e| anyhow::anyhow!("Failed to set up MQTT sink: {}", e))?;
+            sinks.add(Box::new(sink));
+        }
+        #[cfg(not(feature = "mqtt"))]
+        config::SinkKind::Mqtt(_) => {
+            log::warn!("MQTT is configured, but this build doesn't include the `mqtt` feature. Not publishing to MQTT.");
+        }
+        #[cfg(feature = "kafka")]
+        config::SinkKind::Kafka(settings) => sinks.add(Box::new(kafka::KafkaSink::new(config, settings))),
+        #[cfg(not(feature = "kafka"))]
+        config::SinkKind::Kafka(_) => {
+            log::warn!("Kafka is configured, but this build doesn't include the `kafka` feature. Not publishing to Kafka.");
+        }
+    }
+    Ok(())
+}
+
+async fn add_prometheus_sink(sinks: &mut sink::Sinks, address: &str) -> anyhow::Result<()> {
+    let exposition = prometheus::start_server(address)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to serve Prometheus metrics on {}: {}", address, e))?;
+    sinks.add(Box::new(exposition));
+    Ok(())
+}
+
+async fn handle_start(cli_interval: Option<u64>, cli_listen: Option<String>, stdout: bool, no_api: bool) -> anyhow::Result<()> {
+    let mut config = config::load_config_with_secrets().await.map_err(|e| {
         anyhow::anyhow!("Failed to load configuration: {}. Please run 'init' first.", e)
     })?;
-    
-    let api_client = ApiClient::new(config.clone());
+    let problems = config.problems();
+    if !problems.is_empty() {
+        anyhow::bail!("Can't start with this configuration: {}. See `config validate`.", problems.join("; "));
+    }
+    if config.registration_pending {
+        assign_identity(&mut config).map_err(|e| anyhow::anyhow!("Failed to assign this VM an instance ID: {}", e))?;
+    }
 
-    let monitoring_interval_secs = cli_interval.unwrap_or(config.monitoring_settings.interval_seconds);
+    let mut monitoring_interval_secs = cli_interval.unwrap_or(config.monitoring_settings.interval_seconds);
     let batch_size = config.monitoring_settings.batch_size;
 
     log::info!(
@@ -142,62 +439,172 @@ async fn handle_start(cli_interval: Option<u64>) -> anyhow::Result<()> {
         monitoring_interval_secs,
         batch_size
     );
-    println!(
-        "VM Monitor agent started. Interval: {}s, Batch Size: {}. Press Ctrl+C to stop.",
-        monitoring_interval_secs,
-        batch_size
-    );
+    if !stdout {
+        println!(
+            "VM Monitor agent started. Interval: {}s, Batch Size: {}. Press Ctrl+C to stop.",
+            monitoring_interval_secs,
+            batch_size
+        );
+    }
 
 
-    let mut sys = System::new_all(); // Initialize sysinfo system
-    let mut metrics_buffer: Vec<monitor::SystemMetrics> = Vec::new();
-    let mut last_heartbeat_time = Instant::now();
-    let heartbeat_interval = Duration::from_secs(5 * 60); // 5 minutes
+    let mut collector = monitor::MetricsCollector::new(config.instance_id, config.monitoring_settings.clone());
+    collector.set_cloud_instance(monitor::detect_cloud_instance(&config.cloud_provider).await);
+    let mut collection = Collection::new(collector);
+    let mut sinks = sink::Sinks::new(batch_size, config.monitoring_settings.sink_timeout_seconds);
+    if stdout {
+        sinks.add(Box::<stdout::StdoutSink>::default());
+    }
+    let stream = config.monitoring_settings.stream_metrics && cfg!(feature = "websocket");
+    if config.monitoring_settings.stream_metrics && !stream {
+        log::warn!("Streaming is configured, but this build doesn't include the `websocket` feature. Sending batches instead.");
+    }
+    let direct = stream || config.monitoring_settings.api_transport == config::ApiTransport::Grpc;
+    if config.monitoring_settings.api_proxy.is_some() && direct {
+        log::warn!("api_proxy only applies to HTTP requests; gRPC and the metrics stream connect to the API directly.");
+    }
+    let command_poll = Duration::from_secs(config.monitoring_settings.command_poll_seconds);
+    let api_enabled = !no_api && config.sinks.iter().any(|sink| sink.enabled && matches!(sink.kind, config::SinkKind::Api));
+    let api_client = api_enabled
+        .then(|| ApiClient::new(config.clone()).map(Arc::new))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to set up the API client: {}", e))?;
+    let mut heartbeat_client = None; // With the API sink, streaming or not
+    if let Some(client) = &api_client {
+        let dead_letters = deadletter::DeadLetterDir::new(
+            config::get_dead_letter_dir(&config.monitoring_settings)?,
+            config.monitoring_settings.dead_letter_max_files,
+        );
+        let spool = if config.monitoring_settings.spool_max_bytes > 0 {
+            let spool_dir = config::get_spool_dir(&config.monitoring_settings)?;
+            let spool = spool::Spool::open(spool_dir.clone(), config.monitoring_settings.spool_max_bytes)
+                .map_err(|e| anyhow::anyhow!("Failed to open metrics spool at {}: {}", spool_dir.display(), e))?;
+            Some(spool)
+        } else {
+            None
+        };
+        let api_sink = api::ApiSink::new(client.clone(), spool, dead_letters);
+        #[cfg(feature = "websocket")]
+        if stream {
+            sinks.add(Box::new(websocket::WebSocketSink::new(config.clone(), api_sink)?));
+        } else {
+            sinks.add(Box::new(api_sink));
+        }
+        #[cfg(not(feature = "websocket"))]
+        sinks.add(Box::new(api_sink));
+        heartbeat_client = Some(client.clone());
+    }
+    if let Some(address) = &cli_listen {
+        add_prometheus_sink(&mut sinks, address).await?;
+    }
+    for sink in config.sinks.iter().filter(|sink| sink.enabled) {
+        add_sink(&mut sinks, &config, &sink.kind, cli_listen.is_some()).await?;
+    }
+    if sinks.is_empty() {
+        log::warn!("No sinks configured, metrics will be collected but not sent anywhere.");
+    }
+    if config.registration_pending && !api_enabled {
+        log::info!("Not sending to the API, so the instance stays unregistered.");
+    }
+    let mut registration = match &api_client {
+        Some(client) if config.registration_pending => Some(client.clone()),
+        None if config.registration_pending && api_enabled => Some(Arc::new(ApiClient::new(config.clone())?)),
+        _ => None,
+    };
+    if let Some(client) = &registration
+        && complete_registration(client, &mut config).await
+    {
+        registration = None;
+    }
+    // Listen once for the whole loop: a fresh `ctrl_c()` per iteration would miss a signal
+    // that arrives while a cycle is busy sending or retrying.
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    let mut hangup = Hangup::listen();
+    let config_path = config::get_config_path().ok();
+    let mut config_modified = modified_time(&config_path);
 
+    let command_client = api_client.filter(|_| !command_poll.is_zero());
+    if command_client.is_some() {
+        log::info!("Polling the API for commands every {}s", command_poll.as_secs());
+    }
+    // Deadlines rather than fresh sleeps, so a command poll doesn't push back collection.
+    let started = Instant::now();
+    let mut next_collection = started + Duration::from_secs(monitoring_interval_secs);
+    let mut next_command_poll = started + command_poll;
+    let mut next_heartbeat = started + Duration::from_secs(config.monitoring_settings.heartbeat_interval_seconds);
     loop {
         tokio::select! {
-            _ = tokio::time::sleep(Duration::from_secs(monitoring_interval_secs)) => {
-                log::debug!("Collecting metrics...");
-                let current_metrics = monitor::collect_metrics(config.instance_id, &mut sys);
-                metrics_buffer.push(current_metrics);
-                log::info!("Collected metrics. Buffer size: {}", metrics_buffer.len());
-
-                if metrics_buffer.len() >= batch_size {
-                    log::info!("Batch limit reached ({} items). Sending metrics...", metrics_buffer.len());
-                    match api_client.send_metrics_batch(&metrics_buffer).await {
-                        Ok(_) => {
-                            log::info!("Successfully sent batch of {} metrics.", metrics_buffer.len());
-                            metrics_buffer.clear();
-                        }
-                        Err(e) => {
-                            log::error!("Failed to send metrics batch: {}", e);
-                            // Strategy for unsent metrics: For MVP, clear to avoid OOM.
-                            // A more robust solution might involve a persistent queue or retry logic.
-                            if metrics_buffer.len() > batch_size * 5 { // Avoid unbounded growth
-                                log::warn!("Metrics buffer too large, clearing {} items to prevent OOM.", metrics_buffer.len());
-                                metrics_buffer.clear();
-                            }
-                        }
+            _ = tokio::time::sleep_until(next_collection), if !collection.is_running() => {
+                if modified_time(&config_path) != config_modified {
+                    log::info!("The config file changed, reloading it.");
+                    config_modified = modified_time(&config_path);
+                    if let Some(interval) = reload_config(&mut config, &mut collection, &mut sinks) {
+                        monitoring_interval_secs = cli_interval.unwrap_or(interval);
+                        next_heartbeat = next_heartbeat.min(Instant::now() + Duration::from_secs(config.monitoring_settings.heartbeat_interval_seconds));
                     }
                 }
-
-                // Heartbeat logic
-                if last_heartbeat_time.elapsed() >= heartbeat_interval {
-                    log::info!("Sending heartbeat...");
-                    match api_client.send_heartbeat().await {
-                        Ok(_) => {
-                            log::info!("Heartbeat sent successfully.");
-                            last_heartbeat_time = Instant::now(); // Reset timer only on success
-                        }
-                        Err(e) => {
-                            log::error!("Failed to send heartbeat: {}", e);
-                            // Don't reset timer, will retry next cycle implicitly (or specific retry logic)
+                if let Some(client) = &registration
+                    && complete_registration(client, &mut config).await
+                {
+                    registration = None;
+                    config_modified = modified_time(&config_path); // Saved just now, nothing to reload
+                }
+                collection.start();
+                next_collection = Instant::now() + Duration::from_secs(monitoring_interval_secs);
+            }
+            metrics = collection.finished(), if collection.is_running() => {
+                if let Some(metrics) = metrics {
+                    record_sample(metrics, &mut sinks).await;
+                }
+                sinks.heartbeat().await;
+                // The interval counts from the end of a slow sample, not its start
+                next_collection = Instant::now() + Duration::from_secs(monitoring_interval_secs);
+            }
+            _ = tokio::time::sleep_until(next_heartbeat), if heartbeat_client.is_some() && registration.is_none() && config.monitoring_settings.heartbeat_interval_seconds > 0 => {
+                let every = Duration::from_secs(config.monitoring_settings.heartbeat_interval_seconds);
+                let Some(client) = &heartbeat_client else { continue };
+                let agent = AgentState { config: &config, interval_secs: monitoring_interval_secs, started };
+                match send_heartbeat(client, agent).await {
+                    Ok(update) => {
+                        next_heartbeat = Instant::now() + every;
+                        if let Some(update) = update {
+                            if !config.monitoring_settings.remote_config {
+                                log::debug!("Ignoring settings from the API, remote_config is off.");
+                            } else if apply_config_update(&mut config, &update) {
+                                monitoring_interval_secs = cli_interval.unwrap_or(config.monitoring_settings.interval_seconds);
+                                sinks.set_batch_size(config.monitoring_settings.batch_size);
+                                collection.update_settings(config.monitoring_settings.clone());
+                                config_modified = modified_time(&config_path); // Saved just now, nothing to reload
+                                next_collection = next_collection.min(Instant::now() + Duration::from_secs(monitoring_interval_secs));
+                            }
                         }
                     }
+                    Err(e) => {
+                        sink::report("api", "sending heartbeat to", Err(e));
+                        // Retried with the next collection rather than a whole heartbeat interval later
+                        next_heartbeat = Instant::now() + every.min(Duration::from_secs(monitoring_interval_secs));
+                    }
                 }
             }
+            _ = hangup.recv() => {
+                log::info!("SIGHUP received, reloading the config.");
+                config_modified = modified_time(&config_path);
+                if let Some(interval) = reload_config(&mut config, &mut collection, &mut sinks) {
+                    monitoring_interval_secs = cli_interval.unwrap_or(interval);
+                    next_collection = next_collection.min(Instant::now() + Duration::from_secs(monitoring_interval_secs));
+                    next_heartbeat = next_heartbeat.min(Instant::now() + Duration::from_secs(config.monitoring_settings.heartbeat_interval_seconds));
+                }
+            }
+            _ = tokio::time::sleep_until(next_command_poll), if command_client.is_some() => {
+                if let Some(client) = &command_client {
+                    let agent = AgentState { config: &config, interval_secs: monitoring_interval_secs, started };
+                    run_commands(client, &mut collection, &mut sinks, agent).await;
+                }
+                next_command_poll = Instant::now() + command_poll;
+            }
             // Handle shutdown signal (Ctrl+C)
-            result = tokio::signal::ctrl_c() => {
+            result = &mut shutdown => {
                 match result {
                     Ok(()) => {
                         log::info!("Shutdown signal received.");
@@ -207,14 +614,7 @@ async fn handle_start(cli_interval: Option<u64>) -> anyhow::Result<()> {
                     }
                 }
                 
-                if !metrics_buffer.is_empty() {
-                    log::info!("Sending remaining {} metrics before shutdown...", metrics_buffer.len());
-                    if let Err(e) = api_client.send_metrics_batch(&metrics_buffer).await {
-                        log::error!("Failed to send final metrics batch: {}", e);
-                    } else {
-                        log::info!("Final metrics batch sent successfully.");
-                    }
-                }
+                sinks.flush().await;
                 log::info!("VmMonitor agent shutting down.");
                 break; // Exit loop
             }
@@ -223,30 +623,582 @@ async fn handle_start(cli_interval: Option<u64>) -> anyhow::Result<()> {
     Ok(())
 }
 
+// SIGHUP, as sent by `systemctl reload` or `kill -HUP`. Elsewhere it never arrives, and an
+// edit to the config file is only noticed by its modification time.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn listen() -> Self {
+        #[cfg(unix)]
+        let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .inspect_err(|e| log::warn!("Not reloading the config on SIGHUP: {}", e))
+            .ok();
+        Hangup {
+            #[cfg(unix)]
+            signal,
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+// The collector, sampled on a blocking thread: probes and custom collectors wait on the
+// network and on child processes, and the loop has to keep answering signals and heartbeats.
+struct Collection {
+    collector: Arc<std::sync::Mutex<monitor::MetricsCollector>>,
+    settings: Option<config::MonitoringSettings>, // Applied before the next sample
+    running: Option<tokio::task::JoinHandle<monitor::SystemMetrics>>,
+}
+
+impl Collection {
+    fn new(collector: monitor::MetricsCollector) -> Self {
+        Collection { collector: Arc::new(std::sync::Mutex::new(collector)), settings: None, running: None }
+    }
+
+    fn update_settings(&mut self, settings: config::MonitoringSettings) {
+        self.settings = Some(settings);
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    fn start(&mut self) {
+        if self.running.is_some() {
+            return;
+        }
+        log::debug!("Collecting metrics...");
+        let collector = self.collector.clone();
+        let settings = self.settings.take();
+        self.running = Some(tokio::task::spawn_blocking(move || {
+            let mut collector = collector.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(settings) = settings {
+                collector.update_settings(settings);
+            }
+            collector.collect()
+        }));
+    }
+
+    // Waits for the sample that's being collected, None if the collection panicked.
+    async fn finished(&mut self) -> Option<monitor::SystemMetrics> {
+        let result = self.running.as_mut()?.await;
+        self.running = None;
+        result.inspect_err(|e| log::error!("Collecting metrics failed: {}", e)).ok()
+    }
+
+    // A sample now, or the one already being collected.
+    async fn sample(&mut self) -> Option<monitor::SystemMetrics> {
+        self.start();
+        self.finished().await
+    }
+}
+
+fn modified_time(path: &Option<PathBuf>) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path.as_ref()?).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Rereads the config file. What's collected, the interval and the batch size change from the
+// next sample, keeping buffered metrics and without registering again; sinks and the API client
+// keep their settings until a restart. Returns the interval, when the reload worked.
+fn reload_config(config: &mut config::Configuration, collection: &mut Collection, sinks: &mut sink::Sinks) -> Option<u64> {
+    let reloaded = match config::load_config() {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            log::error!("Failed to reload the config, keeping the current one: {}", e);
+            return None;
+        }
+    };
+    let (changed, restart) = match config.reload(reloaded) {
+        Ok(changes) => changes,
+        Err(e) => {
+            log::error!("Failed to reload the config, keeping the current one: {}", e);
+            return None;
+        }
+    };
+    if !restart.is_empty() {
+        log::warn!("Restart the agent to apply the changes to {}", restart.join(", "));
+    }
+    if changed.is_empty() {
+        log::info!("No settings changed.");
+    } else {
+        log::info!("Reloaded settings: {}", changed.join(", "));
+        sinks.set_batch_size(config.monitoring_settings.batch_size);
+        collection.update_settings(config.monitoring_settings.clone());
+    }
+    Some(config.monitoring_settings.interval_seconds)
+}
+
+// Adds the agent's own view of API delivery to a sample and hands it to the sinks.
+async fn record_sample(mut current_metrics: monitor::SystemMetrics, sinks: &mut sink::Sinks) {
+    let api = sinks.health("api").unwrap_or_default();
+    current_metrics.agent_metrics = Some(monitor::AgentMetrics {
+        api_circuit_state: api.circuit_state,
+        api_consecutive_failures: api.consecutive_failures,
+        spooled_bytes: api.backlog_bytes,
+    });
+    sinks.record(current_metrics).await;
+}
+
+// What a "diagnostics" command reports about the running agent.
+struct AgentState<'a> {
+    config: &'a config::Configuration,
+    interval_secs: u64, // Effective, `start --interval` included
+    started: Instant,
+}
+
+impl AgentState<'_> {
+    fn diagnostics(&self, sinks: &sink::Sinks) -> serde_json::Value {
+        let sinks: serde_json::Map<String, serde_json::Value> = sinks
+            .healths()
+            .into_iter()
+            .map(|(name, health)| (name.to_string(), serde_json::to_value(health).unwrap_or_default()))
+            .collect();
+        serde_json::json!({
+            "agent_version": env!("CARGO_PKG_VERSION"),
+            "uptime_seconds": self.started.elapsed().as_secs(),
+            "interval_seconds": self.interval_secs,
+            "batch_size": self.config.monitoring_settings.batch_size,
+            "api_transport": self.config.monitoring_settings.api_transport,
+            "sinks": sinks,
+        })
+    }
+}
+
+// Runs the commands the API has queued for this agent, reporting each one's outcome back.
+async fn run_commands(client: &ApiClient, collection: &mut Collection, sinks: &mut sink::Sinks, agent: AgentState<'_>) {
+    let commands = match client.poll_commands().await {
+        Ok(commands) => commands,
+        // Already logged when the circuit opened or the backoff started, or rate limited for now
+        Err(e @ (errors::VmMonitorError::CircuitOpen(_) | errors::VmMonitorError::Throttled(_) | errors::VmMonitorError::RateLimited(_))) => {
+            log::debug!("Not polling the API for commands: {}", e);
+            return;
+        }
+        Err(e) => {
+            log::warn!("Failed to poll the API for commands: {}", e);
+            return;
+        }
+    };
+    for command in commands {
+        log::info!("Running command {} ({}) from the API.", command.action, command.id);
+        let result = match command.action.as_str() {
+            "snapshot" => match collection.sample().await {
+                Some(metrics) => {
+                    record_sample(metrics, sinks).await;
+                    sinks.send_pending().await;
+                    Ok(serde_json::Value::Null)
+                }
+                None => Err("Collecting metrics failed".to_string()),
+            },
+            "flush_spool" => match sinks.drain_backlogs().await {
+                errors if errors.is_empty() => Ok(serde_json::Value::Null),
+                errors => Err(errors.join("; ")),
+            },
+            "diagnostics" => Ok(agent.diagnostics(sinks)),
+            action => Err(format!("Unknown command {:?}", action)),
+        };
+        if let Err(e) = &result {
+            log::warn!("Command {} ({}) failed: {}", command.action, command.id, e);
+        }
+        if let Err(e) = client.send_command_result(&command.id, &result).await {
+            log::warn!("Failed to report the result of command {} to the API: {}", command.id, e);
+        }
+    }
+}
+
+// Returns the settings changes the API answered with, if any.
+async fn send_heartbeat(client: &ApiClient, agent: AgentState<'_>) -> Result<Option<config::ConfigUpdate>, VmMonitorError> {
+    let details = agent.config.monitoring_settings.heartbeat_details.then(|| api::HeartbeatDetails {
+        agent_version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: agent.started.elapsed().as_secs(),
+        interval_seconds: agent.interval_secs,
+    });
+    log::info!("Sending heartbeat...");
+    let update = client.send_heartbeat(details).await?;
+    log::info!("Heartbeat sent successfully.");
+    Ok(update)
+}
+
+// Applies settings sent by the API and saves them to the config file, reloaded first so
+// edits made since the agent started aren't lost. Returns whether anything changed.
+fn apply_config_update(config: &mut config::Configuration, update: &config::ConfigUpdate) -> bool {
+    let changes = update.apply(&mut config.monitoring_settings);
+    if changes.is_empty() {
+        return false;
+    }
+    log::info!("Applying settings from the API: {}", changes.join(", "));
+    let saved = config::load_config().and_then(|mut saved| {
+        update.apply(&mut saved.monitoring_settings);
+        config::save_config(&saved)
+    });
+    if let Err(e) = saved {
+        log::error!("Failed to save settings from the API: {}", e);
+    }
+    true
+}
+
+// Strings print bare, for scripts; anything else as JSON.
+fn print_setting(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value.clone(),
+        value => serde_json::to_string_pretty(value).unwrap_or_default(),
+    }
+}
+
+async fn handle_config(action: ConfigAction) -> anyhow::Result<()> {
+    match action {
+        ConfigAction::Validate { ping } => validate_config(ping).await?,
+        ConfigAction::Sink { action } => handle_sink(action)?,
+        ConfigAction::Get { name } => println!("{}", print_setting(&config::get_setting(&name)?)),
+        ConfigAction::Set { name, value } => match config::set_setting(&name, &value)? {
+            (old, new) if old == new => println!("{} is already {}", name, print_setting(&new)),
+            (old, new) => println!("{}: {} -> {}", name, print_setting(&old), print_setting(&new)),
+        },
+    }
+    Ok(())
+}
+
+fn handle_sink(action: SinkAction) -> anyhow::Result<()> {
+    let (number, enabled) = match action {
+        SinkAction::List => {
+            let config = config::load_config()?;
+            if config.sinks.is_empty() {
+                println!("No sinks configured.");
+            }
+            for (number, sink) in config.sinks.iter().enumerate() {
+                let state = if sink.enabled { "" } else { " (disabled)" };
+                let target = if matches!(sink.kind, config::SinkKind::Api) { config.api_url.clone() } else { sink.target() };
+                println!("{}: {} {}{}", number, sink.type_name(), target, state);
+            }
+            return Ok(());
+        }
+        SinkAction::Add { sink } => {
+            let sink = config::parse_sink(&sink)?;
+            let description = format!("{} {}", sink.type_name(), sink.target());
+            let number = config::update_sinks(|sinks| {
+                sinks.push(sink);
+                Ok(sinks.len() - 1)
+            })?;
+            println!("Added sink {}: {}", number, description);
+            return Ok(());
+        }
+        SinkAction::Remove { number } => {
+            let removed = config::update_sinks(|sinks| Ok(sinks.remove(sink_index(sinks, number)?)))?;
+            println!("Removed sink {}: {} {}", number, removed.type_name(), removed.target());
+            return Ok(());
+        }
+        SinkAction::Enable { number } => (number, true),
+        SinkAction::Disable { number } => (number, false),
+    };
+    let sink = config::update_sinks(|sinks| {
+        let index = sink_index(sinks, number)?;
+        let sink = &mut sinks[index];
+        sink.enabled = enabled;
+        Ok(sink.clone())
+    })?;
+    let state = if enabled { "enabled" } else { "disabled" };
+    println!("Sink {} ({} {}) is {}; restart the agent for it to take effect.", number, sink.type_name(), sink.target(), state);
+    Ok(())
+}
+
+fn sink_index(sinks: &[config::SinkConfig], number: usize) -> Result<usize, VmMonitorError> {
+    if number >= sinks.len() {
+        return Err(VmMonitorError::ConfigError(format!("There's no sink {}, see `config sink list`", number)));
+    }
+    Ok(number)
+}
+
+async fn validate_config(ping: bool) -> anyhow::Result<()> {
+    let path = config::get_config_path()?;
+    let loaded = if ping { config::load_config_with_secrets().await } else { config::load_config() };
+    let config = loaded.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let mut problems = config.problems();
+    if ping && problems.is_empty() {
+        match ApiClient::new(config) {
+            Ok(client) => match client.check_api_status().await {
+                Ok(()) => println!("API health check passed."),
+                Err(e) => problems.push(format!("API health check failed: {}", e)),
+            },
+            Err(e) => problems.push(format!("Can't set up the API client: {}", e)),
+        }
+    }
+    if problems.is_empty() {
+        println!("{} is valid.", path.display());
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    anyhow::bail!("{} problem(s) in {}", problems.len(), path.display())
+}
+
+async fn handle_inventory(dry_run: bool) -> anyhow::Result<()> {
+    let config = config::load_config_with_secrets().await.map_err(|e| {
+        anyhow::anyhow!("Failed to load configuration: {}. Please run 'init' first.", e)
+    })?;
+    let inventory = monitor::collect_inventory(config.instance_id);
+
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&inventory)?);
+        return Ok(());
+    }
+
+    ApiClient::new(config)?
+        .send_inventory(&inventory)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send inventory: {}", e))?;
+    println!(
+        "Inventory sent: {} ({} logical CPUs, {} disks, {} network interfaces).",
+        inventory.cpu.brand,
+        inventory.cpu.logical_cores,
+        inventory.disks.len(),
+        inventory.network_interfaces.len()
+    );
+    Ok(())
+}
+
+fn key_location(store: &config::ApiKeyStore) -> &'static str {
+    match store {
+        config::ApiKeyStore::ConfigFile => "config",
+        config::ApiKeyStore::Keyring(_) => "OS keyring",
+        config::ApiKeyStore::Environment(_) => config::API_KEY_ENV,
+        config::ApiKeyStore::Vault(_) => "Vault",
+        config::ApiKeyStore::KeyFile(_) => "root-only key file",
+    }
+}
+
+async fn register_instance(api_client: &ApiClient, config: &config::Configuration) -> Result<(), VmMonitorError> {
+    log::info!("Registering instance with API at {}...", config.api_url);
+    let response = api_client.register_instance().await?;
+    log::info!(
+        "Instance registered successfully with API: {}",
+        response.message
+    );
+    // Static details go out once here instead of with every metrics batch.
+    let inventory = monitor::collect_inventory(config.instance_id);
+    if let Err(e) = api_client.send_inventory(&inventory).await {
+        log::warn!("Failed to send inventory: {}. Run 'inventory' to retry.", e);
+    }
+    Ok(())
+}
+
+// The keyring entry holding an instance's key; none yet for an instance without an ID.
+fn api_key_account(instance_id: Uuid) -> String {
+    if instance_id.is_nil() { String::new() } else { instance_id.to_string() }
+}
+
+// For a config written by `init --offline`, on the VM's first start: its own instance ID and,
+// unless one comes from the environment or Vault, API key. Saved before anything uses them.
+fn assign_identity(config: &mut config::Configuration) -> Result<(), VmMonitorError> {
+    if !config.instance_id.is_nil() {
+        return Ok(());
+    }
+    config.instance_id = Uuid::new_v4();
+    if let config::ApiKeyStore::Keyring(account) = &mut config.api_key_store {
+        *account = api_key_account(config.instance_id);
+    }
+    if config.api_key.is_empty() && config.monitoring_settings.api_auth.uses_api_key() {
+        config.api_key = auth::generate_api_key(config.monitoring_settings.signature_algorithm)?;
+    }
+    save_config_or_keep_key_in_file(config)?;
+    log::info!("Assigned instance ID {} to this VM.", config.instance_id);
+    Ok(())
+}
+
+// For a config written by `init --offline`: registers, then records that in the config.
+async fn complete_registration(api_client: &ApiClient, config: &mut config::Configuration) -> bool {
+    if let Err(e) = register_instance(api_client, config).await {
+        log::warn!("Failed to register instance with API: {}. Retrying next cycle.", e);
+        return false;
+    }
+    config.registration_pending = false;
+    if let Err(e) = config::save_config(config) {
+        log::warn!("Registered, but failed to save the config: {}. Registering again on the next start.", e);
+    }
+    true
+}
+
+// For a key about to move into the keyring. Headless servers often have no keyring running,
+// and the key then stays in the config file as before.
+fn save_config_or_keep_key_in_file(config: &mut config::Configuration) -> Result<PathBuf, VmMonitorError> {
+    match config::save_config(config) {
+        Err(VmMonitorError::KeyringError(e)) => {
+            log::warn!("Couldn't store the API key in the OS keyring ({}), keeping it in the config file instead.", e);
+            config.api_key_store = config::file_key_store()?;
+            config::save_config(config)
+        }
+        result => result,
+    }
+}
+
+// The API learns the new key from a request signed with the old one, and keeps accepting
+// both until the new one is used, so a failure at any point leaves a working key in place.
+async fn handle_rotate_key(keyring: bool) -> anyhow::Result<()> {
+    let mut config = config::load_config().map_err(|e| {
+        anyhow::anyhow!("Failed to load configuration: {}. Please run 'init' first.", e)
+    })?;
+    if !config.monitoring_settings.api_auth.uses_api_key() {
+        anyhow::bail!("There's no API key to rotate, `api_auth` in the config isn't hmac.");
+    }
+    if config.registration_pending {
+        anyhow::bail!("The instance isn't registered yet; `start` registers it, then its key can be rotated.");
+    }
+    if matches!(config.api_key_store, config::ApiKeyStore::Environment(_)) {
+        anyhow::bail!("The API key comes from {}, rotate it where that's set instead.", config::API_KEY_ENV);
+    }
+    if matches!(config.api_key_store, config::ApiKeyStore::Vault(_)) {
+        anyhow::bail!("The API key is kept in Vault, rotate it there instead.");
+    }
+    let new_api_key = auth::generate_api_key(config.monitoring_settings.signature_algorithm)?;
+
+    log::info!("Registering a new API key with {}...", config.api_url);
+    ApiClient::new(config.clone())?
+        .rotate_key(&new_api_key)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to register the new API key, the current one stays in use: {}", e))?;
+
+    config.api_key = new_api_key;
+    let saved = if keyring && config.api_key_store == config::ApiKeyStore::ConfigFile {
+        config.api_key_store = config::ApiKeyStore::Keyring(config.instance_id.to_string());
+        save_config_or_keep_key_in_file(&mut config)
+    } else {
+        config::save_config(&config)
+    };
+    let config_path = saved.map_err(|e| {
+        anyhow::anyhow!("The API accepted the new key, but saving it failed; the current one stays in use. Run 'rotate-key' again: {}", e)
+    })?;
+
+    println!("API key rotated.");
+    println!("API Key: {}... (stored in {})", &config.api_key[..8.min(config.api_key.len())], key_location(&config.api_key_store));
+    println!("Config file: {}", config_path.display());
+    println!("Restart a running agent for it to use the new key.");
+    Ok(())
+}
+
+// The new identity is only saved once the API has accepted it, so a failure leaves the agent as it was.
+async fn handle_re_register(new_identity: bool) -> anyhow::Result<()> {
+    let mut config = config::load_config_with_secrets().await.map_err(|e| {
+        anyhow::anyhow!("Failed to load configuration: {}. Please run 'init' first.", e)
+    })?;
+    let previous_account = match &config.api_key_store {
+        config::ApiKeyStore::Keyring(account) => Some(account.clone()),
+        _ => None,
+    };
+    if new_identity || config.instance_id.is_nil() {
+        let previous = config.instance_id;
+        config.instance_id = Uuid::new_v4();
+        match &config.api_key_store {
+            _ if !config.monitoring_settings.api_auth.uses_api_key() => {}
+            config::ApiKeyStore::ConfigFile | config::ApiKeyStore::Keyring(_) | config::ApiKeyStore::KeyFile(_) => {
+                config.api_key = auth::generate_api_key(config.monitoring_settings.signature_algorithm)?;
+            }
+            store => log::info!("Keeping the API key from {}, only the instance ID is new.", key_location(store)),
+        }
+        if let config::ApiKeyStore::Keyring(account) = &mut config.api_key_store {
+            *account = api_key_account(config.instance_id);
+        }
+        log::info!("Replacing instance ID {} with {}", previous, config.instance_id);
+    }
+
+    register_instance(&ApiClient::new(config.clone())?, &config)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to register with the API, the config is unchanged: {}", e))?;
+    config.registration_pending = false;
+    let config_path = save_config_or_keep_key_in_file(&mut config).map_err(|e| {
+        anyhow::anyhow!("Registered, but saving the config failed; run 're-register' again: {}", e)
+    })?;
+    if let (Some(previous), config::ApiKeyStore::Keyring(account)) = (previous_account, &config.api_key_store)
+        && !previous.is_empty()
+        && previous != *account
+        && let Err(e) = secrets::keyring_delete(&previous)
+    {
+        log::warn!("{}; remove it from the keyring by hand.", e);
+    }
+
+    println!("Instance registered with {}.", config.api_url);
+    println!("Instance ID: {}", config.instance_id);
+    if new_identity {
+        if !config.api_key.is_empty() {
+            println!("API Key: {}... (stored in {})", &config.api_key[..8.min(config.api_key.len())], key_location(&config.api_key_store));
+        }
+        println!("Config file: {}", config_path.display());
+        println!("Restart a running agent for it to use the new identity.");
+    }
+    Ok(())
+}
+
 async fn handle_status() -> anyhow::Result<()> {
     println!("VM Monitor Agent Status:\n");
 
-    match config::load_config() {
+    match config::load_config_with_secrets().await {
         Ok(config) => {
             println!("Configuration loaded:");
-            println!("  Instance ID: {}", config.instance_id);
+            if let Ok(path) = config::get_config_path() {
+                match config::system_layer_under(&path) {
+                    Some(system_path) => println!("  Config File: {} (over {})", path.display(), system_path.display()),
+                    None => println!("  Config File: {}", path.display()),
+                }
+            }
+            if config.instance_id.is_nil() {
+                println!("  Instance ID: assigned on the first start");
+            } else {
+                println!("  Instance ID: {}", config.instance_id);
+            }
             println!("  Instance Name: {}", config.instance_name);
-            println!("  API URL: {}", config.api_url);
-            println!(
-                "  API Key: {}... (masked)",
-                &config.api_key[..8.min(config.api_key.len())]
-            );
+            match &config.file_api_url {
+                Some(_) => println!("  API URL: {} (from {})", config.api_url, config::API_URL_ENV),
+                None => println!("  API URL: {}", config.api_url),
+            }
+            println!("  API Transport: {:?}", config.monitoring_settings.api_transport);
+            println!("  Signature Algorithm: {:?}", config.monitoring_settings.signature_algorithm);
+            if config.monitoring_settings.api_auth.uses_api_key() {
+                println!(
+                    "  API Key: {}... (masked, in {})",
+                    &config.api_key[..8.min(config.api_key.len())],
+                    key_location(&config.api_key_store)
+                );
+            } else {
+                println!("  API Key: none, `api_auth` isn't hmac");
+            }
             println!("  Cloud Provider: {:?}", config.cloud_provider);
             println!(
                 "  Monitoring Interval: {}s",
                 config.monitoring_settings.interval_seconds
             );
             println!("  Batch Size: {}", config.monitoring_settings.batch_size);
+            println!("  Top Processes Reported: {}", config.monitoring_settings.top_processes);
+            if !config.monitoring_settings.tags.is_empty() {
+                let tags: Vec<String> = config.monitoring_settings.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+                println!("  Tags: {}", tags.join(", "));
+            }
+            let sinks: Vec<&str> = config.sinks.iter().filter(|sink| sink.enabled).map(|sink| sink.type_name()).collect();
+            println!("  Sinks: {}", if sinks.is_empty() { "None".to_string() } else { sinks.join(", ") });
             println!("  Initialized At: {}", config.initialized_at);
+            if !config.env_overrides.is_empty() {
+                let variables: Vec<&str> = config.env_overrides.iter().map(|env_override| env_override.variable.as_str()).collect();
+                println!("  Overridden By: {}", variables.join(", "));
+            }
+            if config.monitoring_settings.spool_max_bytes == 0 {
+                println!("  Unsent Metrics Spool: Disabled (max {} batches in memory)", config.monitoring_settings.max_buffered_batches);
+            } else if let Ok(spool_dir) = config::get_spool_dir(&config.monitoring_settings) {
+                match spool::Spool::open(spool_dir.clone(), config.monitoring_settings.spool_max_bytes) {
+                    Ok(spool) => println!("  Unsent Metrics Spool: {} bytes ({})", spool.size_bytes(), spool_dir.display()),
+                    Err(e) => println!("  Unsent Metrics Spool: Error - {}", e),
+                }
+            }
             
             // Check API connection status
-            let api_client = ApiClient::new(config.clone());
-            match api_client.check_api_status().await {
+            match async { ApiClient::new(config.clone())?.check_api_status().await }.await {
                 Ok(_) => println!("\nAPI Connection Status: Connected"),
                 Err(e) => println!("\nAPI Connection Status: Error - {}", e),
             }
@@ -260,31 +1212,238 @@ async fn handle_status() -> anyhow::Result<()> {
     }
 
     println!("\nCurrent System Metrics (real-time snapshot):");
-    let mut sys = System::new_all();
     // Use a dummy instance ID if config is not available, or get from config if it is.
     // For simplicity, if config fails, we might not have an instance_id for metrics.
-    // However, collect_metrics requires one. Let's use a placeholder if no config.
-    let instance_id_for_metrics = config::load_config().map(|c| c.instance_id).unwrap_or_else(|_| Uuid::nil());
-    let metrics = monitor::collect_metrics(instance_id_for_metrics, &mut sys);
+    // However, the collector requires one. Let's use a placeholder if no config.
+    let (instance_id_for_metrics, settings) = config::load_config()
+        .map(|c| (c.instance_id, c.monitoring_settings))
+        .unwrap_or_else(|_| (Uuid::nil(), config::MonitoringSettings::default()));
+    let mut collector = monitor::MetricsCollector::new(instance_id_for_metrics, settings);
+    let metrics = collector.collect();
     
     // Pretty print metrics (abbreviated for brevity)
     println!("  Timestamp: {}", metrics.timestamp);
     println!("  CPU Usage: {:.2}% ({} cores)", metrics.cpu_metrics.usage_percent, metrics.cpu_metrics.core_count);
+    if let Some(times) = &metrics.cpu_metrics.time_breakdown {
+        println!("    user {:.1}%, system {:.1}%, iowait {:.1}%, steal {:.1}%",
+            times.user_percent, times.system_percent, times.iowait_percent, times.steal_percent
+        );
+    }
     // Could add per-core if verbose: println!("    Per-core: {:?}", metrics.cpu_metrics.per_core_usage);
     println!("  Memory: {:.2} GB / {:.2} GB used ({:.2} GB available)", 
         metrics.memory_metrics.used_memory as f64 / (1024.0 * 1024.0 * 1024.0),
         metrics.memory_metrics.total_memory as f64 / (1024.0 * 1024.0 * 1024.0),
         metrics.memory_metrics.available_memory as f64 / (1024.0 * 1024.0 * 1024.0)
     );
+    if let Some(breakdown) = &metrics.memory_metrics.breakdown {
+        println!("    cached {:.2} GB, buffers {:.2} GB, shared {:.2} GB, slab {:.2} GB, dirty {:.2} MB",
+            breakdown.cached as f64 / (1024.0 * 1024.0 * 1024.0),
+            breakdown.buffers as f64 / (1024.0 * 1024.0 * 1024.0),
+            breakdown.shared as f64 / (1024.0 * 1024.0 * 1024.0),
+            breakdown.slab as f64 / (1024.0 * 1024.0 * 1024.0),
+            breakdown.dirty as f64 / (1024.0 * 1024.0)
+        );
+    }
+    if let Some(freebsd) = &metrics.memory_metrics.freebsd_breakdown {
+        println!("    wired {:.2} GB, active {:.2} GB, inactive {:.2} GB, laundry {:.2} GB, ZFS ARC {}",
+            freebsd.wired as f64 / (1024.0 * 1024.0 * 1024.0),
+            freebsd.active as f64 / (1024.0 * 1024.0 * 1024.0),
+            freebsd.inactive as f64 / (1024.0 * 1024.0 * 1024.0),
+            freebsd.laundry as f64 / (1024.0 * 1024.0 * 1024.0),
+            freebsd.zfs_arc.map_or("N/A".to_string(), |arc| format!("{:.2} GB", arc as f64 / (1024.0 * 1024.0 * 1024.0)))
+        );
+    }
+    for node in &metrics.memory_metrics.numa_nodes {
+        println!("    NUMA node {}: {:.2} GB / {:.2} GB used",
+            node.node,
+            node.used_memory as f64 / (1024.0 * 1024.0 * 1024.0),
+            node.total_memory as f64 / (1024.0 * 1024.0 * 1024.0)
+        );
+    }
     println!("  Swap: {:.2} GB / {:.2} GB used",
         metrics.memory_metrics.used_swap as f64 / (1024.0 * 1024.0 * 1024.0),
         metrics.memory_metrics.total_swap as f64 / (1024.0 * 1024.0 * 1024.0)
     );
     println!("  System Uptime: {} seconds", metrics.system_info.uptime);
+    if let Some(offset) = metrics.system_info.clock_offset_ms {
+        println!("  Clock Offset: {:.1} ms", offset);
+    }
+    println!("  Environment: {:?}", metrics.system_info.environment);
+    println!("  Virtualization: {}",
+        metrics.system_info.virtualization.map_or("N/A".to_string(), |v| format!("{:?}", v))
+    );
+    if let Some(k8s) = &metrics.system_info.kubernetes {
+        println!("  Kubernetes: {} (node: {}, namespace: {}, pod: {})",
+            k8s.role,
+            k8s.node_name.as_deref().unwrap_or("N/A"),
+            k8s.namespace.as_deref().unwrap_or("N/A"),
+            k8s.pod_name.as_deref().unwrap_or("N/A")
+        );
+    }
     // Further details for disks and network can be added.
     // For brevity, just show count of disks/networks.
     println!("  Disks Found: {}", metrics.disk_metrics.len());
     println!("  Network Interfaces Found: {}", metrics.network_metrics.len());
+    for gpu in &metrics.gpu_metrics {
+        println!("  GPU {}: {} - {}% utilization, {:.2} GB / {:.2} GB memory",
+            gpu.index,
+            gpu.name,
+            gpu.utilization_percent.map_or("N/A".to_string(), |u| u.to_string()),
+            gpu.memory_used.unwrap_or(0) as f64 / (1024.0 * 1024.0 * 1024.0),
+            gpu.memory_total.unwrap_or(0) as f64 / (1024.0 * 1024.0 * 1024.0)
+        );
+        for process in &gpu.processes {
+            println!("    {:>8} {:<24} {} {:.2} MB, {}% SM",
+                process.pid,
+                process.name.as_deref().unwrap_or("unknown"),
+                process.kind,
+                process.used_memory.unwrap_or(0) as f64 / (1024.0 * 1024.0),
+                process.sm_utilization_percent.map_or("N/A".to_string(), |u| u.to_string())
+            );
+        }
+    }
+    if let Some(tcp) = &metrics.tcp_metrics {
+        println!("  TCP Connections: {} established, {} time_wait, {} close_wait, {} syn_recv",
+            tcp.established, tcp.time_wait, tcp.close_wait, tcp.syn_recv
+        );
+    }
+    if let Some(sockets) = &metrics.listening_sockets {
+        println!("  Listening Sockets: {}", sockets.len());
+        for socket in sockets {
+            println!("    {:<5} {}:{} ({})",
+                socket.protocol,
+                socket.address,
+                socket.port,
+                socket.process_name.as_deref().unwrap_or("unknown")
+            );
+        }
+    }
+    if let Some(fd) = &metrics.fd_metrics {
+        println!("  File Descriptors: {} / {} system-wide ({:.2}%)",
+            fd.system_in_use,
+            fd.system_max,
+            fd.system_usage_percent
+        );
+    }
+    if let Some(conntrack) = &metrics.conntrack_metrics {
+        println!("  Conntrack Entries: {} / {} ({:.2}%)",
+            conntrack.entries,
+            conntrack.max_entries,
+            conntrack.usage_percent
+        );
+    }
+    if let Some(entropy) = metrics.entropy_available {
+        println!("  Entropy Available: {} bits", entropy);
+    }
+    if let Some(power) = &metrics.power_metrics {
+        for battery in &power.batteries {
+            println!("  Battery {}: {}% ({})",
+                battery.name,
+                battery.capacity_percent.map_or("N/A".to_string(), |c| c.to_string()),
+                battery.status
+            );
+        }
+    }
+    if !metrics.container_metrics.is_empty() {
+        println!("  Docker Containers: {}", metrics.container_metrics.len());
+    }
+    for watched in &metrics.watched_processes {
+        println!("  Process '{}': {} ({} running, {:.2} MB RSS)",
+            watched.pattern,
+            if watched.running { "running" } else { "NOT RUNNING" },
+            watched.process_count,
+            watched.rss_bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+    for service in &metrics.service_metrics {
+        println!("  Service {}: {} ({})", service.name, service.active_state, service.sub_state);
+    }
+    if let Some(windows) = &metrics.windows_metrics {
+        let format = |value: Option<f64>| value.map_or("N/A".to_string(), |v| format!("{:.2}", v));
+        println!("  Disk Queue Length: {}", format(windows.disk_queue_length));
+        println!("  Paging: {} pages/sec, {}% page file used",
+            format(windows.pages_per_sec),
+            format(windows.page_file_usage_percent)
+        );
+        println!("  Handles: {}", windows.handle_count.map_or("N/A".to_string(), |h| h.to_string()));
+    }
+    if let Some(macos) = &metrics.macos_metrics {
+        println!("  Memory Pressure: {} ({}% free)",
+            macos.memory_pressure.as_deref().unwrap_or("N/A"),
+            macos.memory_free_percent.map_or("N/A".to_string(), |p| p.to_string())
+        );
+        println!("  Thermal Warning Level: {}, CPU Speed Limit: {}%",
+            macos.thermal_warning_level.map_or("N/A".to_string(), |l| l.to_string()),
+            macos.cpu_speed_limit_percent.map_or("N/A".to_string(), |l| l.to_string())
+        );
+    }
+    for cert in &metrics.certificate_expiry {
+        match (cert.days_remaining, &cert.error) {
+            (Some(days), _) => println!("  Certificate {}: expires in {:.1} days", cert.source, days),
+            (None, Some(error)) => println!("  Certificate {}: {}", cert.source, error),
+            (None, None) => println!("  Certificate {}: unknown", cert.source),
+        }
+    }
+    for check in &metrics.http_checks {
+        match (check.status_code, &check.error) {
+            (Some(status), _) => println!("  HTTP Check {}: {} in {:.1} ms",
+                check.name,
+                status,
+                check.latency_ms.unwrap_or_default()
+            ),
+            (None, Some(error)) => println!("  HTTP Check {}: {}", check.name, error),
+            (None, None) => println!("  HTTP Check {}: no response", check.name),
+        }
+    }
+    for ping in &metrics.ping_results {
+        let target = match ping.port {
+            Some(port) => format!("{}:{}", ping.host, port),
+            None => ping.host.clone(),
+        };
+        match (ping.rtt_ms, &ping.error) {
+            (Some(rtt), _) => println!("  Ping {} ({:?}): {:.2} ms", target, ping.protocol, rtt),
+            (None, Some(error)) => println!("  Ping {} ({:?}): unreachable, {}", target, ping.protocol, error),
+            (None, None) => println!("  Ping {} ({:?}): unreachable", target, ping.protocol),
+        }
+    }
+    for check in &metrics.dns_checks {
+        let server = check.server.as_deref().unwrap_or("system resolver");
+        match (check.latency_ms, &check.error) {
+            (Some(latency), _) => println!("  DNS {} via {}: {:.2} ms ({})",
+                check.name,
+                server,
+                latency,
+                check.addresses.join(", ")
+            ),
+            (None, Some(error)) => println!("  DNS {} via {}: failed, {}", check.name, server, error),
+            (None, None) => println!("  DNS {} via {}: failed", check.name, server),
+        }
+    }
+    for count in &metrics.log_pattern_counts {
+        match count.matches {
+            Some(matches) => println!("  Log {} /{}/: {} new matching lines", count.path, count.pattern, matches),
+            None => println!("  Log {} /{}/: N/A", count.path, count.pattern),
+        }
+    }
+    for (name, value) in &metrics.custom_metrics {
+        println!("  {}: {}", name, value);
+    }
+    for usage in &metrics.top_users {
+        println!("  User {}: {} processes, {:.2}% CPU, {:.2} MB RSS",
+            usage.user,
+            usage.process_count,
+            usage.cpu_usage_percent,
+            usage.rss_bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+    println!("  Processes: {}", metrics.process_metrics.total_processes);
+    for process in &metrics.process_metrics.top_by_memory {
+        println!("    {:>8} {:<24} {:.2} MB RSS",
+            process.pid,
+            process.name,
+            process.rss_bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
 
     Ok(())
 }
@@ -301,7 +1460,11 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
         sys.refresh_cpu_all();
         sys.refresh_memory();
         cpu_usage_samples.push(sys.global_cpu_usage());
-        memory_usage_samples.push(sys.used_memory());
+        // Inside a memory-limited container, size for the container's working set, not the host's
+        let cgroup_memory = monitor::cgroup::read_cgroup_stats(sys.total_memory())
+            .filter(|cgroup| cgroup.memory_limit.is_some())
+            .and_then(|cgroup| cgroup.memory_usage);
+        memory_usage_samples.push(cgroup_memory.unwrap_or_else(|| monitor::used_memory_excluding_cache(&sys)));
         tokio::time::sleep(sleep_interval).await;
     }
 
@@ -309,7 +1472,11 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
     let avg_mem_used_bytes = memory_usage_samples.iter().sum::<u64>() / memory_usage_samples.len() as u64;
     let avg_mem_used_gb = avg_mem_used_bytes as f32 / (1024.0 * 1024.0 * 1024.0);
     
-    let physical_cpu_cores = System::physical_core_count().unwrap_or_else(|| sys.cpus().len()) as u32;
+    let mut physical_cpu_cores = System::physical_core_count().unwrap_or_else(|| sys.cpus().len()) as u32;
+    // Global CPU usage is measured against all host cores; a CPU quota caps what this workload can use
+    if let Some(limit_cores) = monitor::cgroup::read_cgroup_stats(sys.total_memory()).and_then(|c| c.cpu_limit_cores) {
+        physical_cpu_cores = physical_cpu_cores.min(limit_cores.ceil() as u32);
+    }
 
     println!("\n--- Usage Analysis Complete ---");
     println!("Average CPU Usage: {:.2}%", avg_cpu_usage);
@@ -323,6 +1490,22 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
         Err(e) => return Err(anyhow::anyhow!("Failed to load VM dataset: {}", e)),
     };
 
+    // What this VM costs now, to show what each recommendation saves
+    let cloud_provider = match config::load_config() {
+        Ok(config) => config.cloud_provider,
+        Err(_) => config::detect_cloud_provider().await,
+    };
+    let cloud_instance = monitor::detect_cloud_instance(&cloud_provider).await;
+    let current = cloud_instance.as_ref().and_then(|instance| recommend::current_instance(&dataset, instance));
+    if let Some(current) = current {
+        println!(
+            "Current instance: {} ({}, {}) at ${:.4}/hour",
+            current.instance_name, current.provider, current.region, current.hourly_cost
+        );
+    } else if let Some(instance_type) = cloud_instance.as_ref().and_then(|instance| instance.instance_type.as_deref()) {
+        println!("Current instance type {} isn't in the dataset, so savings can't be estimated.", instance_type);
+    }
+
     println!("Finding recommendations...");
     let recommendations = recommend::recommend_vms(
         &dataset,
@@ -353,6 +1536,8 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
         hourly_cost: String,
         #[table(title = "Efficiency Score")]
         score: String,
+        #[table(title = "Hourly Savings ($)")]
+        savings: String,
     }
 
     let table_data: Vec<RecommendationRow> = recommendations.iter().map(|rec| {
@@ -364,6 +1549,7 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
             memory_gb: rec.instance.memory_gb,
             hourly_cost: format!("{:.4}", rec.instance.hourly_cost), // Format cost
             score: format!("{:.6}", rec.cost_per_needed_resource), // Format score
+            savings: current.map_or_else(|| "-".to_string(), |current| format!("{:.4}", current.hourly_cost - rec.instance.hourly_cost)),
         }
     }).collect();
 
@@ -377,16 +1563,21 @@ async fn handle_recommend(duration_secs: u64, region: Option<String>) -> anyhow:
 async fn main() -> anyhow::Result<()> {
     // Setup logging: RUST_LOG=info vm-monitor ...
     // Default to `info` if RUST_LOG is not set.
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    syslog::EventLogger::init(env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build());
 
     let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_config_path(path);
+    }
 
     match cli.command {
-        Commands::Init { api_url, name, interval, batch_size } => {
-            handle_init(api_url, name, interval, batch_size).await?
-        }
-        Commands::Start { interval } => handle_start(interval).await?,
+        Commands::Init(args) => handle_init(*args).await?,
+        Commands::Start { interval, listen, stdout, no_api } => handle_start(interval, listen, stdout, no_api).await?,
         Commands::Status => handle_status().await?,
+        Commands::Inventory { dry_run } => handle_inventory(dry_run).await?,
+        Commands::RotateKey { keyring } => handle_rotate_key(keyring).await?,
+        Commands::ReRegister { new_identity } => handle_re_register(new_identity).await?,
+        Commands::Config { action } => handle_config(action).await?,
         Commands::Recommend { duration, region } => {
             handle_recommend(duration, region).await?
         }
@@ -1,13 +1,111 @@
+use crate::config::{CloudProvider, MonitoringSettings, PingProtocol};
 use chrono::{DateTime, Utc};
+use regex::Regex;
 use serde::Serialize;
-use sysinfo::{System, Disks, Networks};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use sysinfo::{Disks, Networks, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind, Users};
 use uuid::Uuid;
 
+pub(crate) mod certs;
+pub mod cgroup;
+mod clock;
+mod custom;
+mod dns;
+#[cfg(unix)]
+mod docker;
+mod environment;
+#[cfg(target_os = "freebsd")]
+mod freebsd;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod http_check;
+mod inventory;
+mod kubernetes;
+mod logwatch;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(windows)]
+mod perfcounters;
+mod ping;
+mod power;
+mod procfs;
+mod smart;
+mod sockets;
+#[cfg(target_os = "freebsd")]
+mod rcd;
+#[cfg(not(any(windows, target_os = "freebsd")))]
+mod systemd;
+#[cfg(windows)]
+mod winsvc;
+
+// Share of CPU time spent in each state over the last interval, from /proc/stat.
+#[derive(Serialize, Debug)]
+pub struct CpuTimeBreakdown {
+    pub user_percent: f32,
+    pub nice_percent: f32,
+    pub system_percent: f32,
+    pub idle_percent: f32,
+    pub iowait_percent: f32,
+    pub irq_percent: f32,
+    pub softirq_percent: f32,
+    pub steal_percent: f32, // Time the hypervisor gave our vCPUs to someone else
+}
+
 #[derive(Serialize, Debug)]
 pub struct CpuMetrics {
     pub usage_percent: f32,
     pub core_count: usize,
     pub per_core_usage: Vec<f32>,
+    pub limit_cores: Option<f64>, // cgroup CPU quota; when set, usage_percent is relative to it
+    pub time_breakdown: Option<CpuTimeBreakdown>, // Linux only, `None` on the first sample
+}
+
+// Scheduler activity over the last interval, from /proc/stat.
+#[derive(Serialize, Debug)]
+pub struct KernelMetrics {
+    pub context_switches: u64,
+    pub interrupts: u64,
+    pub context_switches_per_sec: f64,
+    pub interrupts_per_sec: f64,
+    pub procs_running: u64,
+    pub procs_blocked: u64,
+}
+
+// Finer-grained memory accounting from /proc/meminfo, in bytes.
+#[derive(Serialize, Debug)]
+pub struct MemoryBreakdown {
+    pub used_excluding_cache: u64, // MemTotal - MemAvailable
+    pub anonymous: u64,
+    pub cached: u64,
+    pub buffers: u64,
+    pub shared: u64,
+    pub slab: u64,
+    pub slab_reclaimable: u64,
+    pub dirty: u64,
+    pub writeback: u64,
+}
+
+// FreeBSD page queues, in bytes.
+#[derive(Serialize, Debug)]
+pub struct FreebsdMemory {
+    pub used_excluding_cache: u64, // Wired (minus ARC) + active + laundry
+    pub wired: u64,
+    pub active: u64,
+    pub inactive: u64,
+    pub laundry: u64, // Dirty pages queued for writeback to swap
+    pub free: u64,
+    pub zfs_arc: Option<u64>, // None without ZFS
+}
+
+#[derive(Serialize, Debug)]
+pub struct NumaNodeMemory {
+    pub node: u32,
+    pub total_memory: u64,
+    pub free_memory: u64,
+    pub used_memory: u64,
 }
 
 #[derive(Serialize, Debug)]
@@ -17,6 +115,28 @@ pub struct MemoryMetrics {
     pub available_memory: u64,
     pub total_swap: u64,
     pub used_swap: u64,
+    pub limit_bytes: Option<u64>, // cgroup memory limit; when set, the totals above reflect the container
+    pub breakdown: Option<MemoryBreakdown>, // Linux only
+    pub freebsd_breakdown: Option<FreebsdMemory>, // FreeBSD only
+    pub numa_nodes: Vec<NumaNodeMemory>, // Only populated on multi-node (NUMA) Linux machines
+    // Paging activity from /proc/vmstat (Linux only); `None` on the first sample
+    pub swap_in_pages_per_sec: Option<f64>,
+    pub swap_out_pages_per_sec: Option<f64>,
+    pub major_faults_per_sec: Option<f64>,
+}
+
+// SMART attributes change slowly and smartctl is expensive, so results are cached.
+const SMART_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SmartHealth {
+    pub device: String,
+    pub model: Option<String>,
+    pub passed: bool,
+    pub temperature_celsius: Option<i64>,
+    pub power_on_hours: Option<u64>,
+    pub reallocated_sectors: Option<u64>, // NVMe: media errors
+    pub wear_level_percent: Option<u64>, // Share of rated endurance used, SSDs only
 }
 
 #[derive(Serialize, Debug)]
@@ -28,6 +148,22 @@ pub struct DiskMetric {
     pub filesystem: String,
     pub total_written_bytes: u64,
     pub total_read_bytes: u64,
+    // Interval rates since the previous sample; `None` on the first sample for a disk.
+    pub read_bytes_per_sec: Option<f64>,
+    pub write_bytes_per_sec: Option<f64>,
+    pub smart: Option<SmartHealth>, // Only when `collect_smart` is enabled
+    // From /proc/diskstats deltas (Linux only); `None` on the first sample
+    pub read_latency_ms: Option<f64>,
+    pub write_latency_ms: Option<f64>,
+    pub avg_queue_depth: Option<f64>,
+    pub io_in_flight: Option<u64>,
+}
+
+// Lifetime I/O counters for one mount, kept between samples to compute interval rates.
+#[derive(Debug, Clone, Copy, Default)]
+struct DiskCounters {
+    read_bytes: u64,
+    written_bytes: u64,
 }
 
 #[derive(Serialize, Debug)]
@@ -35,6 +171,280 @@ pub struct NetworkMetric {
     pub interface_name: String,
     pub received_bytes_total: u64,
     pub transmitted_bytes_total: u64,
+    // Interval values since the previous sample; `None` on the first sample for an interface.
+    pub received_bytes_per_sec: Option<f64>,
+    pub transmitted_bytes_per_sec: Option<f64>,
+    pub received_packets: Option<u64>,
+    pub transmitted_packets: Option<u64>,
+    pub receive_errors: Option<u64>,
+    pub transmit_errors: Option<u64>,
+    pub receive_drops: Option<u64>,
+    pub transmit_drops: Option<u64>,
+}
+
+// Lifetime counters for one interface, kept between samples to compute interval deltas.
+#[derive(Debug, Clone, Copy, Default)]
+struct NetworkCounters {
+    received_bytes: u64,
+    transmitted_bytes: u64,
+    received_packets: u64,
+    transmitted_packets: u64,
+    receive_errors: u64,
+    transmit_errors: u64,
+    receive_drops: u64,
+    transmit_drops: u64,
+}
+
+// Socket counts by TCP state across IPv4 and IPv6.
+#[derive(Serialize, Debug, Default)]
+pub struct TcpMetrics {
+    pub total: u64,
+    pub established: u64,
+    pub syn_sent: u64,
+    pub syn_recv: u64,
+    pub fin_wait1: u64,
+    pub fin_wait2: u64,
+    pub time_wait: u64,
+    pub close: u64,
+    pub close_wait: u64,
+    pub last_ack: u64,
+    pub listen: u64,
+    pub closing: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ContainerMetric {
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub state: String,
+    pub cpu_usage_percent: Option<f64>, // `None` on the first sample for a container
+    pub memory_usage: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub restart_count: u64,
+}
+
+// Performance counters without a portable equivalent, read through PDH.
+#[derive(Serialize, Debug)]
+pub struct WindowsMetrics {
+    pub disk_queue_length: Option<f64>, // All physical disks
+    pub pages_per_sec: Option<f64>, // Hard page faults resolved from disk
+    pub page_file_usage_percent: Option<f64>,
+    pub handle_count: Option<u64>, // Open handles across all processes
+}
+
+// Signals macOS uses instead of Linux's PSI and thermal zones.
+#[derive(Serialize, Debug)]
+pub struct MacosMetrics {
+    pub memory_pressure: Option<String>, // "normal", "warning" or "critical"
+    pub memory_free_percent: Option<u32>, // As used by the kernel to decide the pressure level
+    pub thermal_warning_level: Option<u32>, // 0 when no warning has been recorded
+    pub cpu_speed_limit_percent: Option<u32>, // Below 100 when thermally throttled, Intel Macs only
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    pub restart_count: Option<u64>,
+    pub healthy: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ListeningSocket {
+    pub protocol: String, // tcp, tcp6, udp, udp6
+    pub address: String,
+    pub port: u16,
+    pub pid: Option<u32>, // Only resolvable for other users' processes when running as root
+    pub process_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatteryMetric {
+    pub name: String,
+    pub capacity_percent: Option<u8>,
+    pub status: String, // Charging, Discharging, Full, Not charging, Unknown
+    pub power_draw_watts: Option<f64>,
+    pub energy_now_wh: Option<f64>,
+    pub energy_full_wh: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PowerMetrics {
+    pub on_ac_power: Option<bool>,
+    pub batteries: Vec<BatteryMetric>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FdMetrics {
+    pub system_in_use: u64,
+    pub system_max: u64,
+    pub system_usage_percent: f64,
+    pub agent_open: Option<u64>,
+    pub agent_limit: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConntrackMetrics {
+    pub entries: u64,
+    pub max_entries: u64,
+    pub usage_percent: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GpuMetric {
+    pub index: u32,
+    pub name: String,
+    pub uuid: String,
+    pub utilization_percent: Option<u32>,
+    pub memory_utilization_percent: Option<u32>,
+    pub memory_used: Option<u64>,
+    pub memory_total: Option<u64>,
+    pub temperature_celsius: Option<u32>,
+    pub power_draw_watts: Option<f32>,
+    pub processes: Vec<GpuProcess>, // Processes with a context on this GPU
+}
+
+#[derive(Serialize, Debug)]
+pub struct GpuProcess {
+    pub pid: u32,
+    pub name: Option<String>,
+    pub kind: String, // "compute" or "graphics"
+    pub used_memory: Option<u64>, // Not available under Windows WDDM
+    pub sm_utilization_percent: Option<u32>, // Averaged since the previous sample
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub cmdline: String,
+    pub cpu_usage_percent: f32,
+    pub rss_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ProcessMetrics {
+    pub total_processes: usize,
+    pub top_by_cpu: Vec<ProcessInfo>,
+    pub top_by_memory: Vec<ProcessInfo>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct KubernetesContext {
+    pub role: String, // "pod" or "node"
+    pub node_name: Option<String>,
+    pub namespace: Option<String>,
+    pub pod_name: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CertificateExpiry {
+    pub source: String, // File path or host:port endpoint
+    pub subject: Option<String>, // Of the certificate in the chain that expires first
+    pub not_after: Option<DateTime<Utc>>,
+    pub days_remaining: Option<f64>, // Negative once expired
+    pub error: Option<String>, // Why the certificate couldn't be read
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HttpCheckResult {
+    pub name: String,
+    pub url: String,
+    pub status_code: Option<u16>, // None when no response was received
+    pub latency_ms: Option<f64>,
+    pub tls_days_remaining: Option<f64>, // HTTPS only
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PingResult {
+    pub host: String,
+    pub protocol: PingProtocol,
+    pub port: Option<u16>,
+    pub reachable: bool,
+    pub rtt_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DnsCheckResult {
+    pub name: String,
+    pub server: Option<String>, // None when the system resolver was used
+    pub success: bool,
+    pub latency_ms: Option<f64>,
+    pub addresses: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LogPatternCount {
+    pub path: String,
+    pub pattern: String,
+    pub matches: Option<u64>, // Lines matched since the last sample; None on the first sample or if the file is unreadable
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserUsage {
+    pub user: String, // Falls back to the numeric uid (or SID) when the name can't be resolved
+    pub process_count: usize,
+    pub cpu_usage_percent: f32,
+    pub rss_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WatchedProcess {
+    pub pattern: String,
+    pub running: bool,
+    pub process_count: usize,
+    pub pids: Vec<u32>,
+    pub cpu_usage_percent: f32, // Summed across matching processes
+    pub rss_bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed, // Requests go through
+    Open, // Requests fail fast until the cool-down ends
+    HalfOpen, // Cool-down over, the next request probes the health endpoint first
+}
+
+// The agent's own view of its delivery pipeline.
+#[derive(Serialize, Debug)]
+pub struct AgentMetrics {
+    pub api_circuit_state: CircuitState,
+    pub api_consecutive_failures: u32,
+    pub spooled_bytes: u64, // Unsent batches waiting in the spool
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    BareMetal,
+    VirtualMachine,
+    Wsl,
+    Docker,
+    Lxc,
+    Container, // Other runtimes: Podman, containerd, systemd-nspawn
+    Unknown,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Virtualization {
+    None, // Bare metal
+    Kvm,
+    Xen,
+    Vmware,
+    HyperV,
+    VirtualBox,
+    Qemu, // QEMU machine without a KVM signature: emulated (TCG), or KVM seen only through DMI
+    Other,
 }
 
 #[derive(Serialize, Debug)]
@@ -44,77 +454,991 @@ pub struct SystemInfo {
     pub os_version: String,
     pub kernel_version: String,
     pub uptime: u64, // seconds
+    pub kubernetes: Option<KubernetesContext>,
+    pub environment: Environment,
+    pub virtualization: Option<Virtualization>, // None when it can't be determined
+    pub cloud_instance: Option<CloudInstance>, // None outside a known cloud, or when its metadata service is unreachable
+    // Local clock minus NTP time, refreshed every `clock_check_interval_seconds`.
+    // Request signatures are timestamp-based, so large values also break API auth.
+    pub clock_offset_ms: Option<f64>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct SystemMetrics {
     pub timestamp: DateTime<Utc>,
     pub instance_id: Uuid,
+    pub tags: BTreeMap<String, String>, // From `tags` in the config
     pub cpu_metrics: CpuMetrics,
     pub memory_metrics: MemoryMetrics,
+    pub kernel_metrics: Option<KernelMetrics>, // Linux only, `None` on the first sample
     pub disk_metrics: Vec<DiskMetric>,
     pub network_metrics: Vec<NetworkMetric>,
+    pub tcp_metrics: Option<TcpMetrics>, // Linux only
+    // Attached every `listening_ports_interval_seconds` rather than on every sample
+    pub listening_sockets: Option<Vec<ListeningSocket>>,
+    pub process_metrics: ProcessMetrics,
+    pub watched_processes: Vec<WatchedProcess>, // One entry per `watch_processes` pattern
+    pub top_users: Vec<UserUsage>, // Users ranked by CPU usage, at most `top_users` entries
+    pub gpu_metrics: Vec<GpuMetric>, // Empty unless built with the `gpu` feature and an NVIDIA driver is present
+    pub fd_metrics: Option<FdMetrics>, // Linux only
+    pub conntrack_metrics: Option<ConntrackMetrics>, // Linux only, None when nf_conntrack isn't loaded
+    pub entropy_available: Option<u64>, // Linux only, bits
+    pub power_metrics: Option<PowerMetrics>, // Only when `collect_power` is enabled
+    pub container_metrics: Vec<ContainerMetric>, // Empty unless `collect_docker` is enabled
+    pub service_metrics: Vec<ServiceStatus>, // One entry per `watch_services` unit
+    pub windows_metrics: Option<WindowsMetrics>, // Windows only
+    pub macos_metrics: Option<MacosMetrics>, // macOS only
+    pub certificate_expiry: Vec<CertificateExpiry>, // One entry per configured certificate file and endpoint
+    pub http_checks: Vec<HttpCheckResult>, // Latest result of each configured probe
+    pub ping_results: Vec<PingResult>, // One entry per `ping_targets` entry, probed every cycle
+    pub dns_checks: Vec<DnsCheckResult>, // One entry per `dns_checks` entry, resolved every cycle
+    pub log_pattern_counts: Vec<LogPatternCount>, // One entry per watched file and pattern
+    // Latest values from user-defined collectors ("<collector>.<metric>")
+    // and the textfile directory ("textfile.<file>.<metric>")
+    pub custom_metrics: BTreeMap<String, f64>,
+    pub agent_metrics: Option<AgentMetrics>, // Filled in by the `start` loop, which owns the API client and spool
     pub system_info: SystemInfo,
 }
 
-pub fn collect_metrics(instance_id: Uuid, sys: &mut System) -> SystemMetrics {
-    sys.refresh_cpu_all();
-    sys.refresh_memory();
+#[derive(Serialize, Debug)]
+pub struct CpuInventory {
+    pub brand: String,
+    pub vendor_id: String,
+    pub architecture: String,
+    pub physical_cores: Option<usize>,
+    pub logical_cores: usize,
+    pub frequency_mhz: u64,
+}
 
-    let disks = Disks::new_with_refreshed_list();
-    let networks = Networks::new_with_refreshed_list();
+#[derive(Serialize, Debug)]
+pub struct DiskInventory {
+    pub name: String,
+    pub model: Option<String>,
+    pub size: u64, // bytes
+    pub kind: String, // "ssd", "hdd" or "unknown"
+}
 
-    let cpu_metrics = CpuMetrics {
-        usage_percent: sys.global_cpu_usage(),
-        core_count: sys.cpus().len(),
-        per_core_usage: sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect(),
-    };
+#[derive(Serialize, Debug)]
+pub struct NetworkInterfaceInventory {
+    pub name: String,
+    pub mac_address: String,
+    pub addresses: Vec<String>, // CIDR notation
+    pub mtu: u64,
+    pub speed_mbps: Option<u64>, // Linux only, `None` while the link is down
+}
+
+/// Static hardware and software details, sent once at registration and by the
+/// `inventory` command rather than with every metrics sample.
+#[derive(Serialize, Debug)]
+pub struct Inventory {
+    pub collected_at: DateTime<Utc>,
+    pub instance_id: Uuid,
+    pub agent_version: String,
+    pub hostname: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub long_os_version: String,
+    pub distribution_id: String,
+    pub kernel_version: String,
+    pub boot_time: u64, // Unix timestamp
+    pub cpu: CpuInventory,
+    pub memory_total: u64, // bytes visible to the OS
+    pub memory_installed: Option<u64>, // Sum of DIMM sizes from firmware, `None` when unreadable
+    pub swap_total: u64,
+    pub disks: Vec<DiskInventory>,
+    pub network_interfaces: Vec<NetworkInterfaceInventory>,
+    pub environment: Environment,
+    pub virtualization: Option<Virtualization>,
+}
+
+pub fn collect_inventory(instance_id: Uuid) -> Inventory {
+    inventory::collect_inventory(instance_id)
+}
+
+// The instance as its cloud's metadata service describes it. Fields the provider doesn't
+// report, such as the zone of a non-zonal Azure VM, are None.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CloudInstance {
+    pub instance_type: Option<String>, // e.g. "t3.medium", "e2-medium", "Standard_B2s"
+    pub region: Option<String>,
+    pub availability_zone: Option<String>,
+    pub instance_id: Option<String>, // The provider's, e.g. "i-0abc123", not the agent's own
+    pub image: Option<String>, // AMI ID, GCE image, or Azure image ID or publisher:offer:sku:version
+}
 
-    let memory_metrics = MemoryMetrics {
-        total_memory: sys.total_memory(),
-        used_memory: sys.used_memory(),
-        available_memory: sys.available_memory(),
-        total_swap: sys.total_swap(),
-        used_swap: sys.used_swap(),
+/// Fetched from the metadata service once per call, so callers keep the result.
+pub async fn detect_cloud_instance(provider: &CloudProvider) -> Option<CloudInstance> {
+    let instance = match provider {
+        CloudProvider::AWS => crate::aws::Aws::new().instance().await,
+        CloudProvider::GCP => crate::gcp::instance().await,
+        CloudProvider::Azure => crate::azure::ManagedIdentity::new(None).compute().await.map(|compute| compute.cloud_instance()),
+        CloudProvider::Unknown(_) => return None,
     };
+    instance.inspect_err(|e| log::warn!("Failed to read instance details from the {:?} metadata service: {}", provider, e)).ok()
+}
+
+/// Hypervisor the agent runs under, see `SystemInfo::virtualization`.
+pub fn detect_virtualization() -> Option<Virtualization> {
+    environment::detect_virtualization()
+}
+
+/// Memory in use by workloads, excluding reclaimable page cache. sysinfo's
+/// `used_memory` has counted cache differently across versions, so on Linux
+/// this is derived from MemAvailable instead, and on FreeBSD the ZFS ARC is excluded.
+pub fn used_memory_excluding_cache(sys: &System) -> u64 {
+    procfs::read_memory_breakdown()
+        .map(|breakdown| breakdown.used_excluding_cache)
+        .or_else(|| read_freebsd_memory().map(|memory| memory.used_excluding_cache))
+        .unwrap_or_else(|| sys.used_memory())
+}
+
+#[cfg(target_os = "freebsd")]
+fn read_freebsd_memory() -> Option<FreebsdMemory> {
+    freebsd::read_freebsd_memory()
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn read_freebsd_memory() -> Option<FreebsdMemory> {
+    None
+}
+
+fn refresh_processes(sys: &mut System) {
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing()
+            .with_cpu()
+            .with_memory()
+            .with_cmd(UpdateKind::OnlyIfNotSet)
+            .with_user(UpdateKind::OnlyIfNotSet),
+    );
+}
 
-    let disk_metrics: Vec<DiskMetric> = disks
+fn process_cmdline(process: &sysinfo::Process) -> String {
+    process
+        .cmd()
         .iter()
-        .map(|disk| DiskMetric {
-            name: disk.name().to_string_lossy().into_owned(),
-            mount_point: disk.mount_point().to_string_lossy().into_owned(),
-            total_space: disk.total_space(),
-            available_space: disk.available_space(),
-            filesystem: disk.file_system().to_string_lossy().into_owned(),
-            total_written_bytes: disk.usage().total_written_bytes,
-            total_read_bytes: disk.usage().total_read_bytes,
-        })
-        .collect();
-      
-    let network_metrics: Vec<NetworkMetric> = networks
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Aggregates every process whose name or command line matches each watchlist pattern.
+// Patterns are reported even when nothing matches, so stopped services show up as not running.
+fn collect_watched_processes(sys: &System, watchlist: &[(String, Regex)]) -> Vec<WatchedProcess> {
+    watchlist
         .iter()
-        .map(|(name, data)| NetworkMetric {
-            interface_name: name.clone(),
-            received_bytes_total: data.total_received(),
-            transmitted_bytes_total: data.total_transmitted(),
+        .map(|(pattern, regex)| {
+            let matching: Vec<&sysinfo::Process> = sys
+                .processes()
+                .values()
+                .filter(|process| process.thread_kind().is_none())
+                .filter(|process| {
+                    regex.is_match(&process.name().to_string_lossy()) || regex.is_match(&process_cmdline(process))
+                })
+                .collect();
+            let mut pids: Vec<u32> = matching.iter().map(|p| p.pid().as_u32()).collect();
+            pids.sort_unstable();
+            WatchedProcess {
+                pattern: pattern.clone(),
+                running: !matching.is_empty(),
+                process_count: matching.len(),
+                pids,
+                cpu_usage_percent: matching.iter().map(|p| p.cpu_usage()).sum(),
+                rss_bytes: matching.iter().map(|p| p.memory()).sum(),
+            }
+        })
+        .collect()
+}
+
+// Sums CPU and memory per process owner and keeps the `limit` busiest users by CPU.
+fn collect_user_usage(sys: &System, users: &Users, limit: usize) -> Vec<UserUsage> {
+    if limit == 0 {
+        return Vec::new();
+    }
+
+    let mut by_user: HashMap<String, UserUsage> = HashMap::new();
+    for process in sys.processes().values().filter(|p| p.thread_kind().is_none()) {
+        let user = match process.user_id() {
+            Some(uid) => users
+                .get_user_by_id(uid)
+                .map(|user| user.name().to_string())
+                .unwrap_or_else(|| (**uid).to_string()),
+            None => "unknown".to_string(),
+        };
+        let usage = by_user.entry(user.clone()).or_insert(UserUsage {
+            user,
+            process_count: 0,
+            cpu_usage_percent: 0.0,
+            rss_bytes: 0,
+        });
+        usage.process_count += 1;
+        usage.cpu_usage_percent += process.cpu_usage();
+        usage.rss_bytes += process.memory();
+    }
+
+    let mut top_users: Vec<UserUsage> = by_user.into_values().collect();
+    top_users.sort_by(|a, b| {
+        b.cpu_usage_percent
+            .total_cmp(&a.cpu_usage_percent)
+            .then(b.rss_bytes.cmp(&a.rss_bytes))
+    });
+    top_users.truncate(limit);
+    top_users
+}
+
+// Ranks processes by CPU and resident memory, keeping the top `limit` of each.
+// CPU usage is relative to the previous refresh of `sys`, so the first sample
+// after creating a `System` reports 0% for every process.
+fn collect_process_metrics(sys: &System, limit: usize) -> ProcessMetrics {
+    if limit == 0 {
+        return ProcessMetrics::default();
+    }
+
+    let mut processes: Vec<ProcessInfo> = sys
+        .processes()
+        .values()
+        .filter(|process| process.thread_kind().is_none()) // Skip Linux threads, they duplicate their parent
+        .map(|process| ProcessInfo {
+            pid: process.pid().as_u32(),
+            name: process.name().to_string_lossy().into_owned(),
+            cmdline: process_cmdline(process),
+            cpu_usage_percent: process.cpu_usage(),
+            rss_bytes: process.memory(),
         })
         .collect();
+    let total_processes = processes.len();
+
+    processes.sort_by_key(|p| std::cmp::Reverse(p.rss_bytes));
+    let top_by_memory: Vec<ProcessInfo> = processes.iter().take(limit).cloned().collect();
+
+    processes.sort_by(|a, b| b.cpu_usage_percent.total_cmp(&a.cpu_usage_percent));
+    processes.truncate(limit);
+
+    ProcessMetrics {
+        total_processes,
+        top_by_cpu: processes,
+        top_by_memory,
+    }
+}
 
-    let system_info = SystemInfo {
-        hostname: System::host_name().unwrap_or_else(|| "N/A".to_string()),
-        os_name: System::name().unwrap_or_else(|| "N/A".to_string()),
-        os_version: System::os_version().unwrap_or_else(|| "N/A".to_string()),
-        kernel_version: System::kernel_version().unwrap_or_else(|| "N/A".to_string()),
-        uptime: System::uptime(),
+// Difference between two readings of a monotonically increasing counter.
+// A smaller current value means the counter was reset (driver reload, interface
+// re-created), in which case everything counted since the reset is the delta.
+// Runs `run` on every item, each on its own thread of `scope`.
+fn spawn_each<'scope, 'env, T: Sync, R: Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    items: &'env [T],
+    run: impl Fn(&'env T) -> R + Send + Copy + 'env,
+) -> Vec<thread::ScopedJoinHandle<'scope, R>> {
+    items.iter().map(|item| scope.spawn(move || run(item))).collect()
+}
+
+fn join_each<R>(handles: Vec<thread::ScopedJoinHandle<'_, R>>) -> Vec<R> {
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+        .collect()
+}
+
+fn counter_delta(current: u64, previous: u64) -> u64 {
+    if current >= previous { current - previous } else { current }
+}
+
+// sysinfo doesn't expose drop counters, read them from sysfs where available.
+#[cfg(target_os = "linux")]
+fn read_interface_drops(interface: &str) -> (u64, u64) {
+    let read_stat = |stat: &str| {
+        std::fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", interface, stat))
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)
     };
+    (read_stat("rx_dropped"), read_stat("tx_dropped"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_interface_drops(_interface: &str) -> (u64, u64) {
+    (0, 0)
+}
+
+fn compile_patterns(patterns: &[String], kind: &str) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(e) => {
+                log::error!("Ignoring invalid {} pattern '{}': {}", kind, pattern, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn process_watchlist(settings: &MonitoringSettings) -> Vec<(String, Regex)> {
+    compile_patterns(&settings.watch_processes, "process watchlist")
+        .into_iter()
+        .map(|regex| (regex.as_str().to_string(), regex))
+        .collect()
+}
+
+fn log_watchers(settings: &MonitoringSettings) -> Vec<logwatch::LogWatcher> {
+    settings
+        .log_watches
+        .iter()
+        .map(|watch| logwatch::LogWatcher::new(watch.path.clone(), compile_patterns(&watch.patterns, "log watch")))
+        .collect()
+}
+
+// Compiled include/exclude patterns for network interface names.
+struct InterfaceFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl InterfaceFilter {
+    fn new(settings: &MonitoringSettings) -> Self {
+        InterfaceFilter {
+            include: compile_patterns(&settings.network_include, "network interface"),
+            exclude: compile_patterns(&settings.network_exclude, "network interface"),
+        }
+    }
+
+    fn matches(&self, interface: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|r| r.is_match(interface)))
+            && !self.exclude.iter().any(|r| r.is_match(interface))
+    }
+}
+
+/// Owns the sysinfo handles and the counters from the previous sample so that
+/// rate metrics can be derived across collection cycles.
+pub struct MetricsCollector {
+    instance_id: Uuid,
+    settings: MonitoringSettings,
+    sys: System,
+    disks: Disks,
+    networks: Networks,
+    interface_filter: InterfaceFilter,
+    process_watchlist: Vec<(String, Regex)>,
+    users: Users,
+    log_watchers: Vec<logwatch::LogWatcher>,
+    previous_disks: HashMap<String, DiskCounters>,
+    previous_network: HashMap<String, NetworkCounters>,
+    previous_cgroup_cpu_usec: Option<u64>,
+    previous_proc_stat: Option<procfs::ProcStat>,
+    previous_diskstats: HashMap<String, procfs::DiskStats>,
+    previous_vmstat: Option<procfs::VmStat>,
+    smart_cache: HashMap<String, (Instant, Option<SmartHealth>)>,
+    last_socket_inventory: Option<Instant>,
+    clock_offset: Option<(Instant, Option<f64>)>,
+    certificate_checks: Option<(Instant, Vec<CertificateExpiry>)>,
+    custom_results: HashMap<String, (Instant, BTreeMap<String, f64>)>, // Last run and values, by collector name
+    http_check_results: HashMap<String, (Instant, HttpCheckResult)>, // Last run and result, by check name
+    http_check_tls: Option<Result<Arc<rustls::ClientConfig>, String>>, // Built for the first probe; `api_tls` only changes on restart
+    kubernetes: Option<KubernetesContext>, // Detected once, it can't change while running
+    environment: Environment, // Likewise
+    virtualization: Option<Virtualization>,
+    cloud_instance: Option<CloudInstance>, // Set by `start`, which fetches it once
+    #[cfg(unix)]
+    previous_container_cpu: HashMap<String, docker::ContainerCpuCounters>,
+    #[cfg(feature = "gpu")]
+    gpu_samples_seen: HashMap<u32, u64>, // Newest per-process utilization sample, by GPU index
+    #[cfg(windows)]
+    perf_counters: Option<perfcounters::PerfCounters>,
+    last_sample: Option<Instant>,
+}
+
+impl MetricsCollector {
+    pub fn new(instance_id: Uuid, settings: MonitoringSettings) -> Self {
+        MetricsCollector {
+            instance_id,
+            interface_filter: InterfaceFilter::new(&settings),
+            process_watchlist: process_watchlist(&settings),
+            log_watchers: log_watchers(&settings),
+            settings,
+            sys: System::new_all(),
+            users: Users::new(),
+            disks: Disks::new_with_refreshed_list(),
+            networks: Networks::new_with_refreshed_list(),
+            previous_disks: HashMap::new(),
+            previous_network: HashMap::new(),
+            previous_cgroup_cpu_usec: None,
+            previous_proc_stat: None,
+            previous_diskstats: HashMap::new(),
+            previous_vmstat: None,
+            smart_cache: HashMap::new(),
+            last_socket_inventory: None,
+            clock_offset: None,
+            certificate_checks: None,
+            custom_results: HashMap::new(),
+            http_check_results: HashMap::new(),
+            http_check_tls: None,
+            kubernetes: kubernetes::detect_kubernetes_context(),
+            environment: environment::detect_environment(),
+            virtualization: environment::detect_virtualization(),
+            cloud_instance: None,
+            #[cfg(unix)]
+            previous_container_cpu: HashMap::new(),
+            #[cfg(feature = "gpu")]
+            gpu_samples_seen: HashMap::new(),
+            #[cfg(windows)]
+            perf_counters: None,
+            last_sample: None,
+        }
+    }
+
+    pub fn set_cloud_instance(&mut self, cloud_instance: Option<CloudInstance>) {
+        self.cloud_instance = cloud_instance;
+    }
+
+    /// Switches to settings changed while running, from the next sample. Log watches that are
+    /// still configured keep their place in the file.
+    pub fn update_settings(&mut self, settings: MonitoringSettings) {
+        self.interface_filter = InterfaceFilter::new(&settings);
+        self.process_watchlist = process_watchlist(&settings);
+        if settings.log_watches != self.settings.log_watches {
+            self.log_watchers = log_watchers(&settings);
+        }
+        self.settings = settings;
+    }
+
+    pub fn collect(&mut self) -> SystemMetrics {
+        let now = Instant::now();
+        let elapsed_secs = self.last_sample.map(|last| now.duration_since(last).as_secs_f64());
+        self.last_sample = Some(now);
+
+        self.sys.refresh_cpu_all();
+        self.sys.refresh_memory();
+        if self.settings.collect_disks {
+            self.disks.refresh(true);
+        } else {
+            self.previous_disks.clear(); // Rates start over when switched back on
+            self.previous_diskstats.clear();
+        }
+        if self.settings.collect_network {
+            self.networks.refresh(true);
+        } else {
+            self.previous_network.clear();
+        }
+
+        let proc_stat = procfs::read_proc_stat();
+        let previous_proc_stat = std::mem::replace(&mut self.previous_proc_stat, proc_stat);
+        let (time_breakdown, kernel_metrics) = match (&proc_stat, &previous_proc_stat, elapsed_secs) {
+            (Some(current), Some(previous), Some(secs)) => (
+                current.cpu.breakdown_since(&previous.cpu),
+                Some(current.kernel_metrics_since(previous, secs)).filter(|_| self.settings.collect_kernel),
+            ),
+            _ => (None, None),
+        };
+
+        let vmstat = procfs::read_vmstat();
+        let previous_vmstat = std::mem::replace(&mut self.previous_vmstat, vmstat);
+        let paging_rates = match (&vmstat, &previous_vmstat, elapsed_secs) {
+            (Some(current), Some(previous), Some(secs)) => Some(current.rates_since(previous, secs)),
+            _ => None,
+        };
+
+        let mut cpu_metrics = CpuMetrics {
+            usage_percent: self.sys.global_cpu_usage(),
+            core_count: self.sys.cpus().len(),
+            per_core_usage: if self.settings.collect_per_core {
+                self.sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect()
+            } else {
+                Vec::new()
+            },
+            limit_cores: None,
+            time_breakdown,
+        };
+
+        let mut memory_metrics = MemoryMetrics {
+            total_memory: self.sys.total_memory(),
+            used_memory: self.sys.used_memory(),
+            available_memory: self.sys.available_memory(),
+            total_swap: self.sys.total_swap(),
+            used_swap: self.sys.used_swap(),
+            limit_bytes: None,
+            breakdown: procfs::read_memory_breakdown(),
+            freebsd_breakdown: read_freebsd_memory(),
+            numa_nodes: procfs::read_numa_memory(),
+            swap_in_pages_per_sec: paging_rates.map(|(swap_in, _, _)| swap_in),
+            swap_out_pages_per_sec: paging_rates.map(|(_, swap_out, _)| swap_out),
+            major_faults_per_sec: paging_rates.map(|(_, _, major_faults)| major_faults),
+        };
+
+        if let Some(cgroup) = cgroup::read_cgroup_stats(memory_metrics.total_memory) {
+            self.apply_cgroup_limits(&cgroup, elapsed_secs, &mut cpu_metrics, &mut memory_metrics);
+        }
+
+        let disk_metrics = if self.settings.collect_disks { self.collect_disk_metrics(elapsed_secs) } else { Vec::new() };
+        let network_metrics = if self.settings.collect_network { self.collect_network_metrics(elapsed_secs) } else { Vec::new() };
+        let tcp_metrics = if self.settings.collect_network { procfs::read_tcp_metrics() } else { None };
+        let listening_sockets = self.collect_listening_sockets();
+        let processes = self.settings.collect_processes;
+        let (top_processes, top_users) = if processes { (self.settings.top_processes, self.settings.top_users) } else { (0, 0) };
+        if top_processes > 0 || top_users > 0 || (processes && !self.process_watchlist.is_empty()) {
+            refresh_processes(&mut self.sys);
+        }
+        if top_users > 0 {
+            self.users.refresh(); // Picks up accounts created since the last sample
+        }
+        let process_metrics = collect_process_metrics(&self.sys, top_processes);
+        let watched_processes = if processes { collect_watched_processes(&self.sys, &self.process_watchlist) } else { Vec::new() };
+        let top_users = collect_user_usage(&self.sys, &self.users, top_users);
+        let gpu_metrics = if self.settings.collect_gpu { self.collect_gpu_metrics() } else { Vec::new() };
+        let kernel = self.settings.collect_kernel;
+        let fd_metrics = if kernel { procfs::read_fd_metrics() } else { None };
+        let conntrack_metrics = if self.settings.collect_network { procfs::read_conntrack_metrics() } else { None };
+        let entropy_available = if kernel { procfs::read_entropy_available() } else { None };
+        let power_metrics = if self.settings.collect_power { power::read_power_metrics() } else { None };
+        let container_metrics = self.collect_container_metrics();
+        #[cfg(not(any(windows, target_os = "freebsd")))]
+        let service_metrics = systemd::collect_service_statuses(&self.settings.watch_services);
+        #[cfg(target_os = "freebsd")]
+        let service_metrics = rcd::collect_service_statuses(&self.settings.watch_services);
+        #[cfg(windows)]
+        let service_metrics = winsvc::collect_service_statuses(&self.settings.watch_services);
+        #[cfg(windows)]
+        let windows_metrics = perfcounters::read_windows_metrics(&mut self.perf_counters);
+        #[cfg(not(windows))]
+        let windows_metrics = None;
+        #[cfg(target_os = "macos")]
+        let macos_metrics = Some(macos::read_macos_metrics());
+        #[cfg(not(target_os = "macos"))]
+        let macos_metrics = None;
+        let certificate_expiry = self.certificate_expiry();
+        let (http_checks, ping_results, dns_checks) = self.run_probes();
+        let log_pattern_counts = self.collect_log_pattern_counts();
+        let custom_metrics = self.collect_custom_metrics();
+
+        let system_info = SystemInfo {
+            hostname: System::host_name().unwrap_or_else(|| "N/A".to_string()),
+            os_name: System::name().unwrap_or_else(|| "N/A".to_string()),
+            os_version: System::os_version().unwrap_or_else(|| "N/A".to_string()),
+            kernel_version: System::kernel_version().unwrap_or_else(|| "N/A".to_string()),
+            uptime: System::uptime(),
+            kubernetes: self.kubernetes.clone(),
+            environment: self.environment,
+            virtualization: self.virtualization,
+            cloud_instance: self.cloud_instance.clone(),
+            clock_offset_ms: self.clock_offset_ms(),
+        };
+
+        SystemMetrics {
+            timestamp: Utc::now(),
+            instance_id: self.instance_id,
+            tags: self.settings.tags.clone(),
+            cpu_metrics,
+            memory_metrics,
+            kernel_metrics,
+            disk_metrics,
+            network_metrics,
+            tcp_metrics,
+            listening_sockets,
+            process_metrics,
+            watched_processes,
+            top_users,
+            gpu_metrics,
+            fd_metrics,
+            conntrack_metrics,
+            entropy_available,
+            power_metrics,
+            container_metrics,
+            service_metrics,
+            windows_metrics,
+            macos_metrics,
+            certificate_expiry,
+            http_checks,
+            ping_results,
+            dns_checks,
+            log_pattern_counts,
+            custom_metrics,
+            agent_metrics: None,
+            system_info,
+        }
+    }
+
+    // Re-reads the configured certificates once the check interval has passed.
+    // Days remaining is recomputed on every sample from the cached expiry dates.
+    fn certificate_expiry(&mut self) -> Vec<CertificateExpiry> {
+        let files = &self.settings.certificate_files;
+        let endpoints = &self.settings.certificate_endpoints;
+        if files.is_empty() && endpoints.is_empty() {
+            return Vec::new();
+        }
+
+        let interval = Duration::from_secs(self.settings.certificate_check_interval_seconds);
+        let due = self
+            .certificate_checks
+            .as_ref()
+            .is_none_or(|(checked_at, _)| checked_at.elapsed() >= interval);
+        if due {
+            let results: Vec<CertificateExpiry> = files
+                .iter()
+                .map(|path| certs::check_certificate_file(path))
+                .chain(endpoints.iter().map(|endpoint| certs::check_certificate_endpoint(endpoint)))
+                .collect();
+            for result in &results {
+                if let Some(error) = &result.error {
+                    log::warn!("Certificate check for {} failed: {}", result.source, error);
+                }
+            }
+            self.certificate_checks = Some((Instant::now(), results));
+        }
 
-    SystemMetrics {
-        timestamp: Utc::now(),
-        instance_id,
-        cpu_metrics,
-        memory_metrics,
-        disk_metrics,
-        network_metrics,
-        system_info,
+        let now = Utc::now();
+        let mut results = self.certificate_checks.as_ref().map(|(_, results)| results.clone()).unwrap_or_default();
+        for result in &mut results {
+            result.days_remaining = result
+                .not_after
+                .map(|not_after| (not_after - now).num_seconds() as f64 / 86_400.0);
+        }
+        results
     }
-}
\ No newline at end of file
+
+    // Returns the last measured clock offset, re-measuring once the check interval has passed.
+    fn clock_offset_ms(&mut self) -> Option<f64> {
+        let interval = Duration::from_secs(self.settings.clock_check_interval_seconds);
+        if interval.is_zero() || self.settings.ntp_server.is_empty() {
+            return None;
+        }
+        if let Some((checked_at, offset)) = self.clock_offset
+            && checked_at.elapsed() < interval
+        {
+            return offset;
+        }
+
+        let offset = match clock::measure_clock_offset_ms(&self.settings.ntp_server) {
+            Ok(offset) => {
+                if offset.abs() > 60_000.0 {
+                    log::warn!("Local clock is off by {:.0} ms, signed API requests may be rejected.", offset);
+                }
+                Some(offset)
+            }
+            Err(e) => {
+                log::warn!("Failed to measure clock offset: {}", e);
+                None
+            }
+        };
+        self.clock_offset = Some((Instant::now(), offset));
+        offset
+    }
+
+    // Runs the custom collectors that are due, side by side, and merges the latest result of
+    // each, plus whatever is currently in the textfile directory.
+    // A collector that fails keeps reporting its previous values until it succeeds again.
+    fn collect_custom_metrics(&mut self) -> BTreeMap<String, f64> {
+        let due: Vec<_> = self
+            .settings
+            .custom_collectors
+            .iter()
+            .filter(|collector| {
+                let interval = Duration::from_secs(collector.interval_seconds);
+                self.custom_results.get(&collector.name).is_none_or(|(ran_at, _)| ran_at.elapsed() >= interval)
+            })
+            .collect();
+        let results = thread::scope(|scope| join_each(spawn_each(scope, &due, |collector| custom::run_custom_collector(collector))));
+        for (collector, result) in due.iter().zip(results) {
+            let previous = self.custom_results.remove(&collector.name).map(|(_, values)| values);
+            let values = match result {
+                Ok(values) => values,
+                Err(e) => {
+                    log::warn!("Custom collector '{}' failed: {}", collector.name, e);
+                    previous.unwrap_or_default()
+                }
+            };
+            // Failed runs also reset the timer so a broken command isn't retried every cycle.
+            self.custom_results.insert(collector.name.clone(), (Instant::now(), values));
+        }
+
+        let mut metrics: BTreeMap<String, f64> =
+            self.custom_results.values().flat_map(|(_, values)| values.clone()).collect();
+        if let Some(directory) = &self.settings.textfile_directory {
+            metrics.extend(custom::read_textfile_directory(std::path::Path::new(directory)));
+        }
+        metrics
+    }
+
+    // Runs the HTTP checks that are due, the pings and the DNS checks, and returns the latest
+    // result of every HTTP check, in config order. Probes wait on the network, so they run
+    // side by side rather than adding up their timeouts.
+    fn run_probes(&mut self) -> (Vec<HttpCheckResult>, Vec<PingResult>, Vec<DnsCheckResult>) {
+        let due: Vec<_> = self
+            .settings
+            .http_checks
+            .iter()
+            .filter(|check| {
+                let interval = Duration::from_secs(check.interval_seconds);
+                self.http_check_results.get(&check.name).is_none_or(|(ran_at, _)| ran_at.elapsed() >= interval)
+            })
+            .collect();
+        if !due.is_empty() && self.http_check_tls.is_none() {
+            self.http_check_tls = Some(certs::verifying_tls_config(self.settings.api_tls.ca_file.as_deref()));
+        }
+
+        let (http_results, ping_results, dns_checks) = thread::scope(|scope| {
+            let http_results = match &self.http_check_tls {
+                Some(tls) => spawn_each(scope, &due, move |check| http_check::run_http_check(check, tls)),
+                None => Vec::new(),
+            };
+            let ping_results = spawn_each(scope, &self.settings.ping_targets, ping::ping);
+            let dns_checks = spawn_each(scope, &self.settings.dns_checks, dns::run_dns_check);
+            (join_each(http_results), join_each(ping_results), join_each(dns_checks))
+        });
+        for (check, result) in due.iter().zip(http_results) {
+            if let Some(error) = &result.error {
+                log::warn!("HTTP check '{}' failed: {}", check.name, error);
+            }
+            self.http_check_results.insert(check.name.clone(), (Instant::now(), result));
+        }
+
+        let http_checks = self
+            .settings
+            .http_checks
+            .iter()
+            .filter_map(|check| self.http_check_results.get(&check.name).map(|(_, result)| result.clone()))
+            .collect();
+        (http_checks, ping_results, dns_checks)
+    }
+
+    fn collect_log_pattern_counts(&mut self) -> Vec<LogPatternCount> {
+        let mut counts = Vec::new();
+        for watcher in &mut self.log_watchers {
+            let matches = watcher.poll().unwrap_or_else(|e| {
+                log::warn!("Log watch failed: {}", e);
+                None
+            });
+            for (index, pattern) in watcher.patterns.iter().enumerate() {
+                counts.push(LogPatternCount {
+                    path: watcher.path.clone(),
+                    pattern: pattern.as_str().to_string(),
+                    matches: matches.as_ref().map(|matches| matches[index]),
+                });
+            }
+        }
+        counts
+    }
+
+    fn collect_listening_sockets(&mut self) -> Option<Vec<ListeningSocket>> {
+        let interval = Duration::from_secs(self.settings.listening_ports_interval_seconds);
+        if interval.is_zero() || self.last_socket_inventory.is_some_and(|last| last.elapsed() < interval) {
+            return None;
+        }
+        self.last_socket_inventory = Some(Instant::now());
+        Some(sockets::collect_listening_sockets())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn collect_gpu_metrics(&mut self) -> Vec<GpuMetric> {
+        let mut gpu_metrics = gpu::collect_gpu_metrics(&mut self.gpu_samples_seen);
+        for process in gpu_metrics.iter_mut().flat_map(|gpu| gpu.processes.iter_mut()) {
+            process.name = self
+                .sys
+                .process(sysinfo::Pid::from_u32(process.pid))
+                .map(|p| p.name().to_string_lossy().into_owned());
+        }
+        gpu_metrics
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    fn collect_gpu_metrics(&mut self) -> Vec<GpuMetric> {
+        Vec::new()
+    }
+
+    #[cfg(unix)]
+    fn collect_container_metrics(&mut self) -> Vec<ContainerMetric> {
+        if !self.settings.collect_docker {
+            return Vec::new();
+        }
+        docker::collect_container_metrics(&self.settings.docker_socket, &mut self.previous_container_cpu)
+    }
+
+    #[cfg(not(unix))]
+    fn collect_container_metrics(&mut self) -> Vec<ContainerMetric> {
+        if self.settings.collect_docker {
+            log::warn!("Docker metrics are only supported on Unix-like systems.");
+        }
+        Vec::new()
+    }
+
+    // Rewrites host-wide CPU/memory figures in terms of the container's cgroup limits.
+    fn apply_cgroup_limits(
+        &mut self,
+        cgroup: &cgroup::CgroupStats,
+        elapsed_secs: Option<f64>,
+        cpu_metrics: &mut CpuMetrics,
+        memory_metrics: &mut MemoryMetrics,
+    ) {
+        log::debug!("Applying cgroup {:?} limits: {:?}", cgroup.version, cgroup);
+        let previous_cpu_usec = self.previous_cgroup_cpu_usec;
+        self.previous_cgroup_cpu_usec = cgroup.cpu_usage_usec;
+
+        if let Some(limit_cores) = cgroup.cpu_limit_cores {
+            cpu_metrics.limit_cores = Some(limit_cores);
+            cpu_metrics.usage_percent = match (cgroup.cpu_usage_usec, previous_cpu_usec, elapsed_secs) {
+                (Some(current), Some(previous), Some(secs)) if secs > 0.0 => {
+                    let used_cores = counter_delta(current, previous) as f64 / (secs * 1_000_000.0);
+                    (used_cores / limit_cores * 100.0) as f32
+                }
+                _ => 0.0, // No baseline yet, like sysinfo's own first sample
+            };
+        }
+
+        if let Some(limit) = cgroup.memory_limit {
+            let used = cgroup.memory_usage.unwrap_or(memory_metrics.used_memory).min(limit);
+            memory_metrics.limit_bytes = Some(limit);
+            memory_metrics.total_memory = limit;
+            memory_metrics.used_memory = used;
+            memory_metrics.available_memory = limit - used;
+        }
+    }
+
+    // Returns the cached SMART summary for the disk backing `disk_name`, refreshing it hourly.
+    fn smart_health(&mut self, disk_name: &str) -> Option<SmartHealth> {
+        if !self.settings.collect_smart {
+            return None;
+        }
+        let device = smart::base_device(disk_name)?;
+        if let Some((checked_at, health)) = self.smart_cache.get(&device)
+            && checked_at.elapsed() < SMART_REFRESH_INTERVAL
+        {
+            return health.clone();
+        }
+        let health = smart::read_smart_health(&device);
+        self.smart_cache.insert(device, (Instant::now(), health.clone()));
+        health
+    }
+
+    fn is_disk_excluded(settings: &MonitoringSettings, disk: &sysinfo::Disk) -> bool {
+        let filesystem = disk.file_system().to_string_lossy();
+        if settings.disk_exclude_fs_types.iter().any(|fs| fs.eq_ignore_ascii_case(&filesystem)) {
+            return true;
+        }
+        let mount_point = disk.mount_point();
+        settings
+            .disk_exclude_mounts
+            .iter()
+            .any(|excluded| mount_point.starts_with(excluded)) // Path::starts_with matches whole components
+    }
+
+    fn collect_disk_metrics(&mut self, elapsed_secs: Option<f64>) -> Vec<DiskMetric> {
+        let mut current_disks = HashMap::new();
+
+        let diskstats = procfs::read_diskstats();
+
+        let disk_names: Vec<String> = self
+            .disks
+            .iter()
+            .filter(|disk| !Self::is_disk_excluded(&self.settings, disk))
+            .map(|disk| disk.name().to_string_lossy().into_owned())
+            .collect();
+        let smart: HashMap<String, Option<SmartHealth>> = disk_names
+            .into_iter()
+            .map(|name| {
+                let health = self.smart_health(&name);
+                (name, health)
+            })
+            .collect();
+
+        let disk_metrics = self
+            .disks
+            .iter()
+            .filter(|disk| !Self::is_disk_excluded(&self.settings, disk))
+            .map(|disk| {
+                let mount_point = disk.mount_point().to_string_lossy().into_owned();
+                let usage = disk.usage();
+                let counters = DiskCounters {
+                    read_bytes: usage.total_read_bytes,
+                    written_bytes: usage.total_written_bytes,
+                };
+                current_disks.insert(mount_point.clone(), counters);
+
+                // Keyed by mount point: the same device can be mounted more than once.
+                let previous = self.previous_disks.get(&mount_point);
+                let rate = |field: fn(&DiskCounters) -> u64| match (previous, elapsed_secs) {
+                    (Some(prev), Some(secs)) if secs > 0.0 => {
+                        Some(counter_delta(field(&counters), field(prev)) as f64 / secs)
+                    }
+                    _ => None,
+                };
+
+                let name = disk.name().to_string_lossy().into_owned();
+                let latency = procfs::diskstats_name(&name).and_then(|device| {
+                    let current = diskstats.get(&device)?;
+                    let previous = self.previous_diskstats.get(&device)?;
+                    Some(current.latency_since(previous, elapsed_secs?))
+                });
+                DiskMetric {
+                    read_latency_ms: latency.as_ref().and_then(|l| l.read_latency_ms),
+                    write_latency_ms: latency.as_ref().and_then(|l| l.write_latency_ms),
+                    avg_queue_depth: latency.as_ref().map(|l| l.avg_queue_depth),
+                    io_in_flight: latency.as_ref().map(|l| l.in_flight),
+                    smart: smart.get(&name).cloned().flatten(),
+                    name,
+                    total_space: disk.total_space(),
+                    available_space: disk.available_space(),
+                    filesystem: disk.file_system().to_string_lossy().into_owned(),
+                    total_written_bytes: counters.written_bytes,
+                    total_read_bytes: counters.read_bytes,
+                    read_bytes_per_sec: rate(|c| c.read_bytes),
+                    write_bytes_per_sec: rate(|c| c.written_bytes),
+                    mount_point,
+                }
+            })
+            .collect();
+
+        self.previous_disks = current_disks;
+        self.previous_diskstats = diskstats;
+        disk_metrics
+    }
+
+    fn collect_network_metrics(&mut self, elapsed_secs: Option<f64>) -> Vec<NetworkMetric> {
+        let mut current_network = HashMap::new();
+
+        let network_metrics = self
+            .networks
+            .iter()
+            .filter(|(name, _)| self.interface_filter.matches(name))
+            .map(|(name, data)| {
+                let (receive_drops, transmit_drops) = read_interface_drops(name);
+                let counters = NetworkCounters {
+                    received_bytes: data.total_received(),
+                    transmitted_bytes: data.total_transmitted(),
+                    received_packets: data.total_packets_received(),
+                    transmitted_packets: data.total_packets_transmitted(),
+                    receive_errors: data.total_errors_on_received(),
+                    transmit_errors: data.total_errors_on_transmitted(),
+                    receive_drops,
+                    transmit_drops,
+                };
+                current_network.insert(name.clone(), counters);
+
+                let previous = self.previous_network.get(name);
+                let delta = |field: fn(&NetworkCounters) -> u64| {
+                    previous.map(|prev| counter_delta(field(&counters), field(prev)))
+                };
+                let rate = |field: fn(&NetworkCounters) -> u64| match (delta(field), elapsed_secs) {
+                    (Some(bytes), Some(secs)) if secs > 0.0 => Some(bytes as f64 / secs),
+                    _ => None,
+                };
+
+                NetworkMetric {
+                    interface_name: name.clone(),
+                    received_bytes_total: counters.received_bytes,
+                    transmitted_bytes_total: counters.transmitted_bytes,
+                    received_bytes_per_sec: rate(|c| c.received_bytes),
+                    transmitted_bytes_per_sec: rate(|c| c.transmitted_bytes),
+                    received_packets: delta(|c| c.received_packets),
+                    transmitted_packets: delta(|c| c.transmitted_packets),
+                    receive_errors: delta(|c| c.receive_errors),
+                    transmit_errors: delta(|c| c.transmit_errors),
+                    receive_drops: delta(|c| c.receive_drops),
+                    transmit_drops: delta(|c| c.transmit_drops),
+                }
+            })
+            .collect();
+
+        // Replacing the map also forgets interfaces that disappeared since the last sample.
+        self.previous_network = current_network;
+        network_metrics
+    }
+}
@@ -0,0 +1,161 @@
+// Expiry checks for certificates on disk and certificates served by TLS endpoints.
+use super::CertificateExpiry;
+use chrono::{DateTime, Utc};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName, StreamOwned};
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use x509_parser::pem::Pem;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// We only read the peer's certificates, so expired or untrusted ones must not
+// abort the handshake: those are exactly the ones worth reporting. Also backs
+// `insecure_skip_verify` for the metrics stream.
+pub(crate) struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// The certificate that expires first decides when the chain stops working.
+fn earliest_expiry<'a>(source: &str, ders: impl Iterator<Item = &'a [u8]>) -> CertificateExpiry {
+    let mut earliest: Option<(DateTime<Utc>, String)> = None;
+    for der in ders {
+        let Ok((_, cert)) = X509Certificate::from_der(der) else {
+            continue;
+        };
+        let Some(not_after) = DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0) else {
+            continue;
+        };
+        if earliest.as_ref().is_none_or(|(current, _)| not_after < *current) {
+            earliest = Some((not_after, cert.subject().to_string()));
+        }
+    }
+
+    match earliest {
+        Some((not_after, subject)) => CertificateExpiry {
+            source: source.to_string(),
+            subject: Some(subject),
+            not_after: Some(not_after),
+            days_remaining: None,
+            error: None,
+        },
+        None => failed(source, "no parsable certificate found".to_string()),
+    }
+}
+
+fn failed(source: &str, error: String) -> CertificateExpiry {
+    CertificateExpiry {
+        source: source.to_string(),
+        subject: None,
+        not_after: None,
+        days_remaining: None,
+        error: Some(error),
+    }
+}
+
+/// Checks a PEM (possibly a bundle) or DER certificate file.
+pub fn check_certificate_file(path: &str) -> CertificateExpiry {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) => return failed(path, format!("failed to read {}: {}", path, e)),
+    };
+
+    if content.starts_with(b"-----BEGIN") {
+        let blocks: Vec<Pem> = Pem::iter_from_buffer(&content)
+            .filter_map(Result::ok)
+            .filter(|pem| pem.label == "CERTIFICATE")
+            .collect();
+        earliest_expiry(path, blocks.iter().map(|pem| pem.contents.as_slice()))
+    } else {
+        earliest_expiry(path, std::iter::once(content.as_slice()))
+    }
+}
+
+/// Performs a TLS handshake over `stream` without validating the server's
+/// certificate, so callers can inspect the chain it presents.
+pub fn tls_connect(host: &str, stream: TcpStream) -> Result<StreamOwned<ClientConnection, TcpStream>, String> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+    tls_connect_with(host, stream, Arc::new(config))
+}
+
+/// Trusts the built-in roots and those in `ca_file`, the API's private CA, which often
+/// signs the other internal services on the VM's network too.
+pub fn verifying_tls_config(ca_file: Option<&str>) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    if let Some(ca_file) = ca_file {
+        let content = fs::read(ca_file).map_err(|e| format!("failed to read CA file {}: {}", ca_file, e))?;
+        for pem in Pem::iter_from_buffer(&content) {
+            let pem = pem.map_err(|e| format!("invalid PEM in {}: {}", ca_file, e))?;
+            roots.add(&Certificate(pem.contents)).map_err(|e| format!("invalid certificate in {}: {}", ca_file, e))?;
+        }
+    }
+    Ok(Arc::new(ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth()))
+}
+
+/// Performs a TLS handshake over `stream`, verifying the server as `config` says.
+pub fn tls_connect_with(host: &str, mut stream: TcpStream, config: Arc<ClientConfig>) -> Result<StreamOwned<ClientConnection, TcpStream>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host).map_err(|e| format!("invalid host '{}': {}", host, e))?;
+    let mut connection = ClientConnection::new(config, server_name).map_err(|e| e.to_string())?;
+
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut stream)
+            .map_err(|e| format!("TLS handshake with {} failed: {}", host, e))?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// Expiry of the chain presented on an established TLS connection.
+pub fn peer_certificate_expiry(source: &str, connection: &ClientConnection) -> CertificateExpiry {
+    match connection.peer_certificates() {
+        Some(certs) => earliest_expiry(source, certs.iter().map(|cert| cert.0.as_slice())),
+        None => failed(source, "no certificate presented".to_string()),
+    }
+}
+
+/// Connects to `address`, applying `timeout` to the connect and to every read and write.
+pub fn connect_with_timeout(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let socket_address = address
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {}: {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("no address found for {}", address))?;
+    let stream = TcpStream::connect_timeout(&socket_address, timeout)
+        .map_err(|e| format!("failed to connect to {}: {}", address, e))?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Connects to a `host:port` TLS endpoint and checks the chain it presents.
+pub fn check_certificate_endpoint(endpoint: &str) -> CertificateExpiry {
+    let Some((host, _)) = endpoint.rsplit_once(':') else {
+        return failed(endpoint, format!("expected host:port, got '{}'", endpoint));
+    };
+    match connect_with_timeout(endpoint, CONNECT_TIMEOUT).and_then(|stream| tls_connect(host, stream)) {
+        Ok(tls) => peer_certificate_expiry(endpoint, &tls.conn),
+        Err(e) => failed(endpoint, e),
+    }
+}
@@ -0,0 +1,133 @@
+// cgroup v1/v2 detection so containerized agents report their own limits
+// rather than the host totals sysinfo sees.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone)]
+pub struct CgroupStats {
+    pub version: CgroupVersion,
+    pub memory_limit: Option<u64>, // None when unlimited
+    pub memory_usage: Option<u64>, // Working set: usage minus inactive page cache
+    pub cpu_limit_cores: Option<f64>, // None when unlimited
+    pub cpu_usage_usec: Option<u64>, // Lifetime CPU time consumed by the cgroup
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    read_trimmed(path)?.parse().ok()
+}
+
+// Value of `key` in a flat keyed file such as memory.stat or cpu.stat.
+fn read_keyed(path: &Path, key: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        (name == key).then(|| value.trim().parse().ok()).flatten()
+    })
+}
+
+// Resolves the directory of this process's cgroup for `controller` (empty for v2).
+// With a cgroup namespace the path in /proc/self/cgroup is "/", and without one the
+// host path may not be mounted inside the container, so fall back to the mount root.
+fn cgroup_dir(mount: &Path, controller: &str) -> PathBuf {
+    let relative = fs::read_to_string("/proc/self/cgroup").ok().and_then(|content| {
+        content.lines().find_map(|line| {
+            let mut parts = line.splitn(3, ':');
+            let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+            let matches = if controller.is_empty() {
+                controllers.is_empty()
+            } else {
+                controllers.split(',').any(|c| c == controller)
+            };
+            matches.then(|| path.trim_start_matches('/').to_string())
+        })
+    });
+
+    match relative {
+        Some(relative) if mount.join(&relative).is_dir() => mount.join(relative),
+        _ => mount.to_path_buf(),
+    }
+}
+
+fn read_v2() -> CgroupStats {
+    let dir = cgroup_dir(Path::new(CGROUP_ROOT), "");
+
+    let memory_limit = read_trimmed(&dir.join("memory.max")).and_then(|v| v.parse().ok()); // "max" = unlimited
+    let memory_usage = read_u64(&dir.join("memory.current")).map(|current| {
+        let inactive_file = read_keyed(&dir.join("memory.stat"), "inactive_file").unwrap_or(0);
+        current.saturating_sub(inactive_file)
+    });
+
+    // cpu.max: "<quota|max> <period>"
+    let cpu_limit_cores = read_trimmed(&dir.join("cpu.max")).and_then(|value| {
+        let (quota, period) = value.split_once(' ')?;
+        let (quota, period): (f64, f64) = (quota.parse().ok()?, period.parse().ok()?);
+        (period > 0.0).then_some(quota / period)
+    });
+    let cpu_usage_usec = read_keyed(&dir.join("cpu.stat"), "usage_usec");
+
+    CgroupStats {
+        version: CgroupVersion::V2,
+        memory_limit,
+        memory_usage,
+        cpu_limit_cores,
+        cpu_usage_usec,
+    }
+}
+
+fn read_v1() -> CgroupStats {
+    let root = Path::new(CGROUP_ROOT);
+    let memory_dir = cgroup_dir(&root.join("memory"), "memory");
+    let cpu_dir = cgroup_dir(&root.join("cpu"), "cpu");
+    let cpuacct_dir = cgroup_dir(&root.join("cpuacct"), "cpuacct");
+
+    let memory_limit = read_u64(&memory_dir.join("memory.limit_in_bytes"));
+    let memory_usage = read_u64(&memory_dir.join("memory.usage_in_bytes")).map(|usage| {
+        let inactive_file = read_keyed(&memory_dir.join("memory.stat"), "total_inactive_file").unwrap_or(0);
+        usage.saturating_sub(inactive_file)
+    });
+
+    let quota = read_trimmed(&cpu_dir.join("cpu.cfs_quota_us")).and_then(|v| v.parse::<i64>().ok());
+    let period = read_u64(&cpu_dir.join("cpu.cfs_period_us"));
+    let cpu_limit_cores = match (quota, period) {
+        (Some(quota), Some(period)) if quota > 0 && period > 0 => Some(quota as f64 / period as f64),
+        _ => None, // -1 = unlimited
+    };
+    let cpu_usage_usec = read_u64(&cpuacct_dir.join("cpuacct.usage")).map(|ns| ns / 1000);
+
+    CgroupStats {
+        version: CgroupVersion::V1,
+        memory_limit,
+        memory_usage,
+        cpu_limit_cores,
+        cpu_usage_usec,
+    }
+}
+
+/// Reads the limits of the cgroup this process runs in. Memory limits at or above
+/// `host_total_memory` (v1 reports unlimited as a huge number) are treated as unlimited.
+pub fn read_cgroup_stats(host_total_memory: u64) -> Option<CgroupStats> {
+    let root = Path::new(CGROUP_ROOT);
+    let mut stats = if root.join("cgroup.controllers").exists() {
+        read_v2()
+    } else if root.join("memory").is_dir() || root.join("cpu").is_dir() {
+        read_v1()
+    } else {
+        return None;
+    };
+
+    if stats.memory_limit.is_some_and(|limit| limit >= host_total_memory) {
+        stats.memory_limit = None;
+    }
+    Some(stats)
+}
@@ -0,0 +1,46 @@
+// Minimal SNTP (RFC 4330) client used to measure local clock offset.
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NTP_PORT: u16 = 123;
+const NTP_UNIX_EPOCH_DELTA: f64 = 2_208_988_800.0; // Seconds between 1900-01-01 and 1970-01-01
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn now_unix_secs() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+fn ntp_timestamp_to_unix(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64 / 4_294_967_296.0;
+    seconds - NTP_UNIX_EPOCH_DELTA + fraction
+}
+
+/// Returns how far the local clock is ahead of `server`, in milliseconds
+/// (negative when it is behind).
+pub fn measure_clock_offset_ms(server: &str) -> Result<f64, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("failed to bind UDP socket: {}", e))?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|e| e.to_string())?;
+    socket
+        .connect((server, NTP_PORT))
+        .map_err(|e| format!("failed to resolve {}: {}", server, e))?;
+
+    let mut request = [0u8; 48];
+    request[0] = 0x1B; // LI = 0, version = 3, mode = 3 (client)
+
+    let originate = now_unix_secs();
+    socket.send(&request).map_err(|e| format!("failed to send NTP request: {}", e))?;
+    let mut response = [0u8; 48];
+    let received = socket.recv(&mut response).map_err(|e| format!("no NTP response from {}: {}", server, e))?;
+    let destination = now_unix_secs();
+
+    if received < 48 || response[0] & 0x07 != 4 {
+        return Err(format!("invalid NTP response from {}", server));
+    }
+
+    let server_receive = ntp_timestamp_to_unix(&response[32..40]);
+    let server_transmit = ntp_timestamp_to_unix(&response[40..48]);
+    // Standard NTP offset: how far the server is ahead of us, with network delay averaged out.
+    let server_ahead = ((server_receive - originate) + (server_transmit - destination)) / 2.0;
+    Ok(-server_ahead * 1000.0)
+}
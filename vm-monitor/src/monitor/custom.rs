@@ -0,0 +1,144 @@
+// User-provided metrics: commands whose JSON stdout becomes named custom metrics,
+// and a node_exporter-style textfile directory other programs can drop files into.
+use crate::config::CustomCollectorConfig;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Runs the command, killing it if it exceeds `timeout`.
+fn run_with_timeout(config: &CustomCollectorConfig, timeout: Duration) -> Result<String, String> {
+    let mut child = Command::new(&config.command)
+        .args(&config.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("failed to start '{}': {}", config.command, e))?;
+
+    // Drain stdout on a separate thread so a chatty command can't block on a full pipe.
+    let mut stdout = child.stdout.take().ok_or("stdout not captured")?;
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {}s", timeout.as_secs()));
+            }
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    };
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+
+    reader
+        .join()
+        .map_err(|_| "stdout reader panicked".to_string())?
+        .map_err(|e| format!("failed to read stdout: {}", e))
+}
+
+/// Flattens numeric (and boolean) leaves of a JSON document into `prefix.key.subkey` names.
+pub fn flatten_json_metrics(prefix: &str, value: &Value, out: &mut BTreeMap<String, f64>) {
+    match value {
+        Value::Number(n) => {
+            if let Some(n) = n.as_f64() {
+                out.insert(prefix.to_string(), n);
+            }
+        }
+        Value::Bool(b) => {
+            out.insert(prefix.to_string(), if *b { 1.0 } else { 0.0 });
+        }
+        Value::Object(map) => {
+            for (key, nested) in map {
+                flatten_json_metrics(&format!("{}.{}", prefix, key), nested, out);
+            }
+        }
+        _ => {} // Strings, arrays and nulls have no numeric meaning
+    }
+}
+
+pub fn run_custom_collector(config: &CustomCollectorConfig) -> Result<BTreeMap<String, f64>, String> {
+    let stdout = run_with_timeout(config, Duration::from_secs(config.timeout_seconds))?;
+    let value: Value = serde_json::from_str(&stdout).map_err(|e| format!("stdout is not valid JSON: {}", e))?;
+    let mut metrics = BTreeMap::new();
+    flatten_json_metrics(&config.name, &value, &mut metrics);
+    Ok(metrics)
+}
+
+// Parses Prometheus text exposition lines (`name{labels} value [timestamp]`).
+// Label sets are kept verbatim in the metric name so series stay distinct.
+fn parse_prometheus_text(prefix: &str, content: &str, out: &mut BTreeMap<String, f64>) {
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Label values may contain spaces, so split after the closing brace when there is one.
+        let (series, rest) = match line.rfind('}') {
+            Some(end) if line.contains('{') => (&line[..=end], &line[end + 1..]),
+            _ => match line.split_once(char::is_whitespace) {
+                Some((series, rest)) => (series, rest),
+                None => continue,
+            },
+        };
+        let Some(value) = rest.split_whitespace().next().and_then(|v| v.parse::<f64>().ok()) else {
+            log::debug!("Skipping unparsable textfile line: {}", line);
+            continue;
+        };
+        out.insert(format!("{}.{}", prefix, series), value);
+    }
+}
+
+/// Reads every `.prom` and `.json` file in `directory`. Files are left in place,
+/// writers are expected to replace them atomically (write to a temp file, then rename).
+pub fn read_textfile_directory(directory: &Path) -> BTreeMap<String, f64> {
+    let mut metrics = BTreeMap::new();
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to read textfile directory {}: {}", directory.display(), e);
+            return metrics;
+        }
+    };
+
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let prefix = format!("textfile.{}", stem);
+        let extension = path.extension().and_then(|e| e.to_str());
+        if !matches!(extension, Some("prom") | Some("json")) {
+            continue;
+        }
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("Failed to read textfile {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        if extension == Some("prom") {
+            parse_prometheus_text(&prefix, &content, &mut metrics);
+        } else {
+            match serde_json::from_str::<Value>(&content) {
+                Ok(value) => flatten_json_metrics(&prefix, &value, &mut metrics),
+                Err(e) => log::warn!("Invalid JSON in textfile {}: {}", path.display(), e),
+            }
+        }
+    }
+    metrics
+}
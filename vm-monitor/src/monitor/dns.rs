@@ -0,0 +1,129 @@
+// DNS resolution probes, either through the system resolver (what applications on
+// this VM see) or as a single A query sent straight to a given nameserver.
+use super::DnsCheckResult;
+use crate::config::DnsCheck;
+use std::net::{Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+const DNS_PORT: u16 = 53;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // Standard query, recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // One question, no other records
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name '{}'", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+// Returns the offset just past the (possibly compressed) name starting at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(offset)?;
+        match length {
+            0 => return Some(offset + 1),
+            l if l & 0xC0 == 0xC0 => return Some(offset + 2), // Compression pointer
+            l => offset += l as usize + 1,
+        }
+    }
+}
+
+fn parse_response(id: u16, response: &[u8]) -> Result<Vec<String>, String> {
+    if response.len() < 12 || response[0..2] != id.to_be_bytes() {
+        return Err("invalid DNS response".to_string());
+    }
+    match response[3] & 0x0F {
+        0 => {}
+        2 => return Err("server failure (SERVFAIL)".to_string()),
+        3 => return Err("name does not exist (NXDOMAIN)".to_string()),
+        5 => return Err("query refused (REFUSED)".to_string()),
+        rcode => return Err(format!("query failed with rcode {}", rcode)),
+    }
+
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(response, offset).ok_or("truncated question")? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(response, offset).ok_or("truncated answer")?;
+        let header = response.get(offset..offset + 10).ok_or("truncated answer")?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = response.get(offset + 10..offset + 10 + length).ok_or("truncated answer")?;
+        if record_type == TYPE_A && length == 4 {
+            addresses.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]).to_string());
+        }
+        offset += 10 + length;
+    }
+    Ok(addresses)
+}
+
+fn query_server(server: &str, name: &str) -> Result<Vec<String>, String> {
+    let address = (server, DNS_PORT)
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {}: {}", server, e))?
+        .next()
+        .ok_or_else(|| format!("{} didn't resolve", server))?;
+    let socket = UdpSocket::bind(if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .map_err(|e| format!("failed to bind UDP socket: {}", e))?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|e| e.to_string())?;
+    socket.connect(address).map_err(|e| format!("failed to connect to {}: {}", server, e))?;
+
+    let id: u16 = rand::random();
+    socket
+        .send(&build_query(id, name)?)
+        .map_err(|e| format!("failed to send DNS query: {}", e))?;
+    let mut response = [0u8; 512];
+    let received = socket
+        .recv(&mut response)
+        .map_err(|e| format!("no DNS response from {}: {}", server, e))?;
+    parse_response(id, &response[..received])
+}
+
+fn query_system_resolver(name: &str) -> Result<Vec<String>, String> {
+    (name, 0)
+        .to_socket_addrs()
+        .map(|addresses| addresses.map(|address| address.ip().to_string()).collect())
+        .map_err(|e| e.to_string())
+}
+
+/// Resolves the configured name and times the lookup. A lookup that returns
+/// no addresses counts as a failure.
+pub fn run_dns_check(check: &DnsCheck) -> DnsCheckResult {
+    let started = Instant::now();
+    let outcome = match &check.server {
+        Some(server) => query_server(server, &check.name),
+        None => query_system_resolver(&check.name),
+    };
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    let (addresses, error) = match outcome {
+        Ok(addresses) if addresses.is_empty() => (addresses, Some("no addresses returned".to_string())),
+        Ok(addresses) => (addresses, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    DnsCheckResult {
+        name: check.name.clone(),
+        server: check.server.clone(),
+        success: error.is_none(),
+        latency_ms: error.is_none().then_some(latency_ms),
+        addresses,
+        error,
+    }
+}
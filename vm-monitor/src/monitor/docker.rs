@@ -0,0 +1,133 @@
+// Per-container metrics from the local Docker Engine API over its Unix socket.
+use super::{ContainerMetric, counter_delta};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const API_VERSION: &str = "v1.41";
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+// CPU counters for one container, kept between samples to compute usage.
+#[derive(Debug, Clone, Copy)]
+pub struct ContainerCpuCounters {
+    container_usage: u64,
+    system_usage: u64,
+}
+
+// Issues a GET over HTTP/1.0 so the daemon answers with a plain (non-chunked)
+// body and closes the connection, which keeps parsing trivial.
+fn docker_get(socket_path: &str, path: &str) -> Result<Value, String> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| format!("failed to connect to {}: {}", socket_path, e))?;
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let request = format!("GET /{}{} HTTP/1.0\r\nHost: docker\r\n\r\n", API_VERSION, path);
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|e| e.to_string())?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "malformed HTTP response".to_string())?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("GET {} returned '{}'", path, status_line));
+    }
+    serde_json::from_str(body).map_err(|e| format!("invalid JSON from GET {}: {}", path, e))
+}
+
+fn container_metric(
+    socket_path: &str,
+    summary: &Value,
+    previous_cpu: &HashMap<String, ContainerCpuCounters>,
+    current_cpu: &mut HashMap<String, ContainerCpuCounters>,
+) -> Result<ContainerMetric, String> {
+    let id = summary["Id"].as_str().unwrap_or_default().to_string();
+    let name = summary["Names"][0].as_str().unwrap_or_default().trim_start_matches('/').to_string();
+
+    // one-shot skips the daemon's own 1s pre-sample; CPU deltas are computed across our cycles instead.
+    let stats = docker_get(socket_path, &format!("/containers/{}/stats?stream=false&one-shot=true", id))?;
+    let inspect = docker_get(socket_path, &format!("/containers/{}/json", id))?;
+
+    let cpu = &stats["cpu_stats"];
+    let cpu_usage_percent = match (cpu["cpu_usage"]["total_usage"].as_u64(), cpu["system_cpu_usage"].as_u64()) {
+        (Some(container_usage), Some(system_usage)) => {
+            current_cpu.insert(id.clone(), ContainerCpuCounters { container_usage, system_usage });
+            previous_cpu.get(&id).and_then(|prev| {
+                let container_delta = counter_delta(container_usage, prev.container_usage) as f64;
+                let system_delta = counter_delta(system_usage, prev.system_usage) as f64;
+                let online_cpus = cpu["online_cpus"].as_u64().unwrap_or(1) as f64;
+                (system_delta > 0.0).then(|| container_delta / system_delta * online_cpus * 100.0)
+            })
+        }
+        _ => None,
+    };
+
+    // Match `docker stats`: exclude inactive page cache from memory usage.
+    let memory = &stats["memory_stats"];
+    let inactive_file = memory["stats"]["inactive_file"]
+        .as_u64()
+        .or_else(|| memory["stats"]["total_inactive_file"].as_u64())
+        .unwrap_or(0);
+    let memory_usage = memory["usage"].as_u64().map(|usage| usage.saturating_sub(inactive_file));
+
+    let (network_rx_bytes, network_tx_bytes) = stats["networks"]
+        .as_object()
+        .map(|networks| {
+            networks.values().fold((0, 0), |(rx, tx), net| {
+                (rx + net["rx_bytes"].as_u64().unwrap_or(0), tx + net["tx_bytes"].as_u64().unwrap_or(0))
+            })
+        })
+        .unwrap_or((0, 0));
+
+    Ok(ContainerMetric {
+        id: id.chars().take(12).collect(),
+        name,
+        image: summary["Image"].as_str().unwrap_or_default().to_string(),
+        state: summary["State"].as_str().unwrap_or_default().to_string(),
+        cpu_usage_percent,
+        memory_usage,
+        memory_limit: memory["limit"].as_u64(),
+        network_rx_bytes,
+        network_tx_bytes,
+        restart_count: inspect["RestartCount"].as_u64().unwrap_or(0),
+    })
+}
+
+/// Collects metrics for all running containers. `previous_cpu` is replaced with
+/// this sample's counters so the next call can compute CPU usage.
+pub fn collect_container_metrics(
+    socket_path: &str,
+    previous_cpu: &mut HashMap<String, ContainerCpuCounters>,
+) -> Vec<ContainerMetric> {
+    let containers = match docker_get(socket_path, "/containers/json") {
+        Ok(Value::Array(containers)) => containers,
+        Ok(_) => {
+            log::warn!("Unexpected response listing Docker containers");
+            return Vec::new();
+        }
+        Err(e) => {
+            log::warn!("Failed to list Docker containers: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut current_cpu = HashMap::new();
+    let metrics = containers
+        .iter()
+        .filter_map(|summary| match container_metric(socket_path, summary, previous_cpu, &mut current_cpu) {
+            Ok(metric) => Some(metric),
+            Err(e) => {
+                log::debug!("Skipping container {}: {}", summary["Id"], e);
+                None
+            }
+        })
+        .collect();
+
+    *previous_cpu = current_cpu;
+    metrics
+}
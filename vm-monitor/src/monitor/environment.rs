@@ -0,0 +1,133 @@
+// Classifies where the agent runs (bare metal, a VM, WSL, or a container runtime)
+// and which hypervisor, if any, it runs under.
+use super::{Environment, Virtualization};
+use std::fs;
+use std::path::Path;
+
+fn read_lowercase(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|content| content.trim().to_lowercase())
+}
+
+fn detect_wsl() -> bool {
+    std::env::var_os("WSL_DISTRO_NAME").is_some()
+        || read_lowercase("/proc/sys/kernel/osrelease").is_some_and(|release| release.contains("microsoft"))
+}
+
+fn detect_container() -> Option<Environment> {
+    // systemd and most runtimes record the container type for PID 1.
+    let container_type = read_lowercase("/run/systemd/container").or_else(|| {
+        fs::read("/proc/1/environ").ok().and_then(|environ| {
+            environ
+                .split(|byte| *byte == 0)
+                .find_map(|entry| entry.strip_prefix(b"container="))
+                .map(|value| String::from_utf8_lossy(value).to_lowercase())
+        })
+    });
+    match container_type.as_deref() {
+        Some("docker") => return Some(Environment::Docker),
+        Some(kind) if kind.starts_with("lxc") => return Some(Environment::Lxc),
+        Some(_) => return Some(Environment::Container),
+        None => {}
+    }
+
+    if Path::new("/.dockerenv").exists() {
+        return Some(Environment::Docker);
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some(Environment::Container); // Podman
+    }
+    let cgroup = read_lowercase("/proc/1/cgroup").unwrap_or_default();
+    if cgroup.contains("/docker") {
+        Some(Environment::Docker)
+    } else if cgroup.contains("/lxc") {
+        Some(Environment::Lxc)
+    } else if cgroup.contains("kubepods") || cgroup.contains("containerd") {
+        Some(Environment::Container)
+    } else {
+        None
+    }
+}
+
+// CPUID leaf 1, ECX bit 31 is reserved for hypervisors to announce themselves, and
+// leaf 0x40000000 then carries a 12-byte vendor signature in EBX, ECX and EDX.
+#[cfg(target_arch = "x86_64")]
+fn cpuid_virtualization() -> Option<Virtualization> {
+    use std::arch::x86_64::__cpuid;
+
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return Some(Virtualization::None);
+    }
+    let leaf = __cpuid(0x4000_0000);
+    let signature: Vec<u8> = [leaf.ebx, leaf.ecx, leaf.edx].iter().flat_map(|r| r.to_le_bytes()).collect();
+    match &signature[..] {
+        b"KVMKVMKVM\0\0\0" => Some(Virtualization::Kvm),
+        b"XenVMMXenVMM" => Some(Virtualization::Xen),
+        b"VMwareVMware" => Some(Virtualization::Vmware),
+        b"Microsoft Hv" => Some(Virtualization::HyperV),
+        b"VBoxVBoxVBox" => Some(Virtualization::VirtualBox),
+        b"TCGTCGTCGTCG" => Some(Virtualization::Qemu),
+        _ => None, // Unknown signature, let DMI decide
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpuid_virtualization() -> Option<Virtualization> {
+    None
+}
+
+// Firmware strings, which also cover ARM guests and hypervisors hiding their CPUID signature.
+fn dmi_virtualization() -> Option<Virtualization> {
+    if read_lowercase("/sys/hypervisor/type").as_deref() == Some("xen") {
+        return Some(Virtualization::Xen);
+    }
+    let vendor = read_lowercase("/sys/class/dmi/id/sys_vendor")?;
+    let product = read_lowercase("/sys/class/dmi/id/product_name").unwrap_or_default();
+    if product.contains("kvm") || vendor == "amazon ec2" || vendor == "google" {
+        Some(Virtualization::Kvm) // Nitro and GCE are KVM-based
+    } else if vendor.contains("qemu") {
+        Some(Virtualization::Qemu)
+    } else if vendor.contains("vmware") {
+        Some(Virtualization::Vmware)
+    } else if vendor.contains("xen") {
+        Some(Virtualization::Xen)
+    } else if vendor == "microsoft corporation" && product.contains("virtual machine") {
+        Some(Virtualization::HyperV)
+    } else if vendor.contains("innotek") || product.contains("virtualbox") {
+        Some(Virtualization::VirtualBox)
+    } else {
+        None
+    }
+}
+
+/// Identifies the hypervisor this system runs under, `Virtualization::None` on bare
+/// metal, or `None` when it can't be determined.
+pub fn detect_virtualization() -> Option<Virtualization> {
+    if let Some(kind) = cpuid_virtualization() {
+        return Some(kind);
+    }
+    if let Some(kind) = dmi_virtualization() {
+        return Some(kind);
+    }
+    // Without CPUID or a recognizable vendor, fall back to the kernel's "hypervisor" CPU flag.
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    Some(if cpuinfo.split_whitespace().any(|flag| flag == "hypervisor") {
+        Virtualization::Other
+    } else {
+        Virtualization::None
+    })
+}
+
+pub fn detect_environment() -> Environment {
+    // WSL 2 is itself a Hyper-V VM, so it's checked before the hypervisor.
+    if detect_wsl() {
+        return Environment::Wsl;
+    }
+    if let Some(container) = detect_container() {
+        return container;
+    }
+    match detect_virtualization() {
+        Some(Virtualization::None) => Environment::BareMetal,
+        Some(_) => Environment::VirtualMachine,
+        None => Environment::Unknown,
+    }
+}
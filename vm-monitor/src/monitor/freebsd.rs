@@ -0,0 +1,48 @@
+// FreeBSD page queue sizes and ZFS ARC from sysctl. sysinfo folds these into a single
+// used figure, which on ZFS storage boxes is dominated by the (reclaimable) ARC.
+use super::FreebsdMemory;
+use std::collections::HashMap;
+use std::process::Command;
+
+const SYSCTLS: [&str; 7] = [
+    "hw.pagesize",
+    "vm.stats.vm.v_wire_count",
+    "vm.stats.vm.v_active_count",
+    "vm.stats.vm.v_inactive_count",
+    "vm.stats.vm.v_laundry_count",
+    "vm.stats.vm.v_free_count",
+    "kstat.zfs.misc.arcstats.size", // Absent when ZFS isn't loaded
+];
+
+// `sysctl -i` skips unknown names and prints "name: value" for the rest.
+fn read_sysctls() -> Option<HashMap<String, u64>> {
+    let output = Command::new("sysctl").arg("-i").args(SYSCTLS).output().ok()?;
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .filter_map(|(name, value)| Some((name.to_string(), value.trim().parse().ok()?)))
+            .collect(),
+    )
+}
+
+pub fn read_freebsd_memory() -> Option<FreebsdMemory> {
+    let values = read_sysctls()?;
+    let page_size = *values.get("hw.pagesize")?;
+    let pages = |name: &str| values.get(name).map_or(0, |count| count * page_size);
+
+    let wired = pages("vm.stats.vm.v_wire_count");
+    let zfs_arc = values.get("kstat.zfs.misc.arcstats.size").copied();
+    Some(FreebsdMemory {
+        // The ARC is wired but shrinks under pressure, so it counts as cache.
+        used_excluding_cache: wired.saturating_sub(zfs_arc.unwrap_or(0))
+            + pages("vm.stats.vm.v_active_count")
+            + pages("vm.stats.vm.v_laundry_count"),
+        wired,
+        active: pages("vm.stats.vm.v_active_count"),
+        inactive: pages("vm.stats.vm.v_inactive_count"),
+        laundry: pages("vm.stats.vm.v_laundry_count"),
+        free: pages("vm.stats.vm.v_free_count"),
+        zfs_arc,
+    })
+}
@@ -0,0 +1,102 @@
+use super::{GpuMetric, GpuProcess};
+use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+use nvml_wrapper::enums::device::UsedGpuMemory;
+use nvml_wrapper::{Device, Nvml};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// NVML initialization loads the driver library and is comparatively expensive,
+// so it is done once per process. `None` means no usable NVIDIA driver was found.
+static NVML: OnceLock<Option<Nvml>> = OnceLock::new();
+
+fn nvml() -> Option<&'static Nvml> {
+    NVML.get_or_init(|| match Nvml::init() {
+        Ok(nvml) => Some(nvml),
+        Err(e) => {
+            log::warn!("GPU metrics unavailable, failed to initialize NVML: {}", e);
+            None
+        }
+    })
+    .as_ref()
+}
+
+// Processes holding a context on the device, with their memory and their average
+// SM utilization across the driver's samples newer than `last_seen` (µs timestamp).
+fn collect_gpu_processes(device: &Device, last_seen: &mut u64) -> Vec<GpuProcess> {
+    let mut utilization: HashMap<u32, (u64, u32)> = HashMap::new(); // Sum and count of samples, by PID
+    if let Ok(samples) = device.process_utilization_stats(*last_seen) {
+        for sample in samples {
+            *last_seen = (*last_seen).max(sample.timestamp);
+            let entry = utilization.entry(sample.pid).or_default();
+            entry.0 += sample.sm_util as u64;
+            entry.1 += 1;
+        }
+    }
+
+    let compute = device.running_compute_processes().unwrap_or_default().into_iter().map(|p| (p, "compute"));
+    let graphics = device.running_graphics_processes().unwrap_or_default().into_iter().map(|p| (p, "graphics"));
+    let mut processes: Vec<GpuProcess> = Vec::new();
+    for (info, kind) in compute.chain(graphics) {
+        if processes.iter().any(|p| p.pid == info.pid) {
+            continue; // Listed as both; the compute entry is kept
+        }
+        processes.push(GpuProcess {
+            pid: info.pid,
+            name: None,
+            kind: kind.to_string(),
+            used_memory: match info.used_gpu_memory {
+                UsedGpuMemory::Used(bytes) => Some(bytes),
+                UsedGpuMemory::Unavailable => None,
+            },
+            sm_utilization_percent: utilization
+                .get(&info.pid)
+                .map(|(sum, count)| (*sum / *count as u64) as u32),
+        });
+    }
+    processes
+}
+
+/// Collects metrics for every NVIDIA GPU. `last_seen_samples` keeps, per device
+/// index, the newest per-process utilization sample already accounted for.
+pub fn collect_gpu_metrics(last_seen_samples: &mut HashMap<u32, u64>) -> Vec<GpuMetric> {
+    let Some(nvml) = nvml() else {
+        return Vec::new();
+    };
+
+    let device_count = match nvml.device_count() {
+        Ok(count) => count,
+        Err(e) => {
+            log::warn!("Failed to query NVML device count: {}", e);
+            return Vec::new();
+        }
+    };
+
+    (0..device_count)
+        .filter_map(|index| match nvml.device_by_index(index) {
+            Ok(device) => Some((index, device)),
+            Err(e) => {
+                log::debug!("Failed to open GPU {}: {}", index, e);
+                None
+            }
+        })
+        .map(|(index, device)| {
+            // Individual queries are optional: consumer cards and vGPUs often
+            // don't support power or temperature readings.
+            let utilization = device.utilization_rates().ok();
+            let memory = device.memory_info().ok();
+            let processes = collect_gpu_processes(&device, last_seen_samples.entry(index).or_default());
+            GpuMetric {
+                index,
+                name: device.name().unwrap_or_else(|_| "N/A".to_string()),
+                uuid: device.uuid().unwrap_or_else(|_| "N/A".to_string()),
+                utilization_percent: utilization.as_ref().map(|u| u.gpu),
+                memory_utilization_percent: utilization.as_ref().map(|u| u.memory),
+                memory_used: memory.as_ref().map(|m| m.used),
+                memory_total: memory.as_ref().map(|m| m.total),
+                temperature_celsius: device.temperature(TemperatureSensor::Gpu).ok(),
+                power_draw_watts: device.power_usage().ok().map(|mw| mw as f32 / 1000.0),
+                processes,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,97 @@
+// Synthetic HTTP(S) probes issued from the VM itself. Requests are written by hand over
+// a blocking socket (like the Docker client) because collection runs synchronously.
+use super::{HttpCheckResult, certs};
+use crate::config::HttpCheckConfig;
+use reqwest::Url;
+use rustls::ClientConfig;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Sends the request and returns the status code from the response's status line.
+fn send_request<S: Read + Write>(stream: &mut S, method: &str, url: &Url) -> Result<u16, String> {
+    let host = url.host_str().unwrap_or_default();
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: vm-monitor/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        method,
+        target,
+        host_header,
+        env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| format!("failed to send request: {}", e))?;
+
+    // "HTTP/1.1 200 OK"
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("failed to read response: {}", e))?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("malformed status line '{}'", status_line.trim()))
+}
+
+/// Runs one probe. Latency covers connect, TLS handshake and time to the status line.
+/// Redirects are reported as-is rather than followed. HTTPS servers are verified with `tls`;
+/// one that fails verification gets no request, but its certificate's expiry is still read.
+pub fn run_http_check(check: &HttpCheckConfig, tls: &Result<Arc<ClientConfig>, String>) -> HttpCheckResult {
+    let mut result = HttpCheckResult {
+        name: check.name.clone(),
+        url: check.url.clone(),
+        status_code: None,
+        latency_ms: None,
+        tls_days_remaining: None,
+        error: None,
+    };
+
+    let started = Instant::now();
+    let outcome = Url::parse(&check.url).map_err(|e| format!("invalid URL: {}", e)).and_then(|url| {
+        let host = url.host_str().ok_or("URL has no host")?.to_string();
+        let port = url.port_or_known_default().ok_or("URL has no port")?;
+        let address = format!("{}:{}", host, port);
+        let timeout = Duration::from_secs(check.timeout_seconds);
+        let mut stream = certs::connect_with_timeout(&address, timeout)?;
+        let days_remaining = |connection| {
+            certs::peer_certificate_expiry(&check.url, connection)
+                .not_after
+                .map(|not_after| (not_after - chrono::Utc::now()).num_seconds() as f64 / 86_400.0)
+        };
+        match url.scheme() {
+            "https" => {
+                let config = tls.clone()?;
+                let mut verified = match certs::tls_connect_with(&host, stream, config) {
+                    Ok(verified) => verified,
+                    Err(e) => {
+                        // Over a second connection that trusts anything, only to see what's presented
+                        if let Ok(unverified) = certs::connect_with_timeout(&address, timeout).and_then(|stream| certs::tls_connect(&host, stream)) {
+                            result.tls_days_remaining = days_remaining(&unverified.conn);
+                        }
+                        return Err(e);
+                    }
+                };
+                result.tls_days_remaining = days_remaining(&verified.conn);
+                send_request(&mut verified, &check.method, &url)
+            }
+            "http" => send_request(&mut stream, &check.method, &url),
+            scheme => Err(format!("unsupported scheme '{}'", scheme)),
+        }
+    });
+
+    match outcome {
+        Ok(status_code) => {
+            result.status_code = Some(status_code);
+            result.latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+        }
+        Err(e) => result.error = Some(e),
+    }
+    result
+}
@@ -0,0 +1,168 @@
+// One-off hardware and software inventory. Unlike the rest of the collectors this
+// runs only at registration or on request, so it can afford to shell out freely.
+use super::{CpuInventory, DiskInventory, Inventory, NetworkInterfaceInventory};
+use chrono::Utc;
+use std::process::Command;
+use sysinfo::{CpuRefreshKind, MemoryRefreshKind, Networks, RefreshKind, System};
+use uuid::Uuid;
+
+fn command_stdout(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// SMBIOS memory devices (type 17). dmidecode needs root to read the tables.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn installed_memory() -> Option<u64> {
+    let output = command_stdout("dmidecode", &["-t", "17"])?;
+    let sizes: Vec<u64> = output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Size:")) // Skips "Volatile Size:" and friends
+        .filter_map(|size| {
+            let (value, unit) = size.trim().split_once(' ')?;
+            let value: u64 = value.parse().ok()?; // "No Module Installed" fails here
+            match unit {
+                "kB" => Some(value << 10),
+                "MB" => Some(value << 20),
+                "GB" => Some(value << 30),
+                "TB" => Some(value << 40),
+                _ => None,
+            }
+        })
+        .collect();
+    (!sizes.is_empty()).then(|| sizes.iter().sum())
+}
+
+#[cfg(target_os = "macos")]
+fn installed_memory() -> Option<u64> {
+    command_stdout("sysctl", &["-n", "hw.memsize"])?.trim().parse().ok()
+}
+
+#[cfg(windows)]
+fn installed_memory() -> Option<u64> {
+    let script = "(Get-CimInstance Win32_PhysicalMemory | Measure-Object -Property Capacity -Sum).Sum";
+    command_stdout("powershell", &["-NoProfile", "-Command", script])?.trim().parse().ok()
+}
+
+// Block devices backed by hardware; virtual ones (loop, dm, zram) have no `device` link.
+#[cfg(target_os = "linux")]
+fn collect_disks() -> Vec<DiskInventory> {
+    use std::fs;
+    use std::path::Path;
+
+    let read = |path: &Path| fs::read_to_string(path).ok().map(|value| value.trim().to_string());
+    let Ok(entries) = fs::read_dir("/sys/block") else {
+        return Vec::new();
+    };
+    let mut disks: Vec<DiskInventory> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join("device").exists())
+        .map(|path| DiskInventory {
+            name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            model: read(&path.join("device/model")).filter(|model| !model.is_empty()),
+            // sysfs reports the size in 512-byte sectors regardless of the device's block size.
+            size: read(&path.join("size")).and_then(|sectors| sectors.parse::<u64>().ok()).unwrap_or(0) * 512,
+            kind: match read(&path.join("queue/rotational")).as_deref() {
+                Some("0") => "ssd",
+                Some("1") => "hdd",
+                _ => "unknown",
+            }
+            .to_string(),
+        })
+        .collect();
+    disks.sort_by(|a, b| a.name.cmp(&b.name));
+    disks
+}
+
+// Elsewhere sysinfo only lists mounted filesystems, so report each backing device once.
+#[cfg(not(target_os = "linux"))]
+fn collect_disks() -> Vec<DiskInventory> {
+    use sysinfo::{DiskKind, Disks};
+
+    let mut disks: Vec<DiskInventory> = Vec::new();
+    for disk in Disks::new_with_refreshed_list().list() {
+        let name = disk.name().to_string_lossy().into_owned();
+        if disks.iter().any(|known| known.name == name) {
+            continue;
+        }
+        disks.push(DiskInventory {
+            name,
+            model: None,
+            size: disk.total_space(),
+            kind: match disk.kind() {
+                DiskKind::SSD => "ssd",
+                DiskKind::HDD => "hdd",
+                DiskKind::Unknown(_) => "unknown",
+            }
+            .to_string(),
+        });
+    }
+    disks
+}
+
+#[cfg(target_os = "linux")]
+fn link_speed_mbps(interface: &str) -> Option<u64> {
+    // Reads fail with EINVAL while the link is down, and some drivers report -1.
+    std::fs::read_to_string(format!("/sys/class/net/{}/speed", interface)).ok()?.trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_speed_mbps(_interface: &str) -> Option<u64> {
+    None
+}
+
+fn collect_network_interfaces() -> Vec<NetworkInterfaceInventory> {
+    let networks = Networks::new_with_refreshed_list();
+    let mut interfaces: Vec<NetworkInterfaceInventory> = networks
+        .iter()
+        .filter(|(_, data)| !data.mac_address().is_unspecified()) // Loopback and tunnels
+        .map(|(name, data)| NetworkInterfaceInventory {
+            name: name.clone(),
+            mac_address: data.mac_address().to_string(),
+            addresses: data.ip_networks().iter().map(|network| format!("{}/{}", network.addr, network.prefix)).collect(),
+            mtu: data.mtu(),
+            speed_mbps: link_speed_mbps(name),
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+pub fn collect_inventory(instance_id: Uuid) -> Inventory {
+    let sys = System::new_with_specifics(
+        RefreshKind::nothing()
+            .with_cpu(CpuRefreshKind::nothing().with_frequency())
+            .with_memory(MemoryRefreshKind::everything()),
+    );
+    let first_cpu = sys.cpus().first();
+    let cpu = CpuInventory {
+        brand: first_cpu.map(|cpu| cpu.brand().trim().to_string()).unwrap_or_else(|| "N/A".to_string()),
+        vendor_id: first_cpu.map(|cpu| cpu.vendor_id().to_string()).unwrap_or_else(|| "N/A".to_string()),
+        architecture: System::cpu_arch(),
+        physical_cores: System::physical_core_count(),
+        logical_cores: sys.cpus().len(),
+        frequency_mhz: first_cpu.map_or(0, |cpu| cpu.frequency()),
+    };
+
+    Inventory {
+        collected_at: Utc::now(),
+        instance_id,
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        hostname: System::host_name().unwrap_or_else(|| "N/A".to_string()),
+        os_name: System::name().unwrap_or_else(|| "N/A".to_string()),
+        os_version: System::os_version().unwrap_or_else(|| "N/A".to_string()),
+        long_os_version: System::long_os_version().unwrap_or_else(|| "N/A".to_string()),
+        distribution_id: System::distribution_id(),
+        kernel_version: System::kernel_version().unwrap_or_else(|| "N/A".to_string()),
+        boot_time: System::boot_time(),
+        cpu,
+        memory_total: sys.total_memory(),
+        memory_installed: installed_memory(),
+        swap_total: sys.total_swap(),
+        disks: collect_disks(),
+        network_interfaces: collect_network_interfaces(),
+        environment: super::environment::detect_environment(),
+        virtualization: super::environment::detect_virtualization(),
+    }
+}
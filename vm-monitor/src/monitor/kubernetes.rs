@@ -0,0 +1,50 @@
+// Detects whether the agent runs inside a Kubernetes pod or directly on a node.
+use super::KubernetesContext;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const KUBELET_MARKERS: [&str; 3] = [
+    "/var/lib/kubelet/config.yaml",
+    "/etc/kubernetes/kubelet.conf",
+    "/var/lib/kubelet/kubeconfig",
+];
+
+fn non_empty_env(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+pub fn detect_kubernetes_context() -> Option<KubernetesContext> {
+    // NODE_NAME/POD_NAME/POD_NAMESPACE are the conventional downward API variable names.
+    let node_name = non_empty_env("NODE_NAME").or_else(|| non_empty_env("KUBERNETES_NODE_NAME"));
+
+    let in_pod = non_empty_env("KUBERNETES_SERVICE_HOST").is_some() || Path::new(SERVICE_ACCOUNT_DIR).is_dir();
+    if in_pod {
+        let namespace = non_empty_env("POD_NAMESPACE").or_else(|| {
+            fs::read_to_string(Path::new(SERVICE_ACCOUNT_DIR).join("namespace"))
+                .ok()
+                .map(|ns| ns.trim().to_string())
+        });
+        // A pod's hostname defaults to its name.
+        let pod_name = non_empty_env("POD_NAME").or_else(|| non_empty_env("HOSTNAME"));
+        return Some(KubernetesContext {
+            role: "pod".to_string(),
+            node_name,
+            namespace,
+            pod_name,
+        });
+    }
+
+    if KUBELET_MARKERS.iter().any(|marker| Path::new(marker).exists()) {
+        return Some(KubernetesContext {
+            role: "node".to_string(),
+            // Kubelet registers nodes under their hostname unless overridden.
+            node_name: node_name.or_else(sysinfo::System::host_name),
+            namespace: None,
+            pod_name: None,
+        });
+    }
+
+    None
+}
@@ -0,0 +1,70 @@
+// Tails log files and counts lines matching regex patterns between samples.
+use regex::Regex;
+use std::fs::{self, File, Metadata};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+#[cfg(unix)]
+fn file_identity(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+// Without inodes, rotation is only noticed when the file shrinks.
+#[cfg(not(unix))]
+fn file_identity(_metadata: &Metadata) -> u64 {
+    0
+}
+
+pub struct LogWatcher {
+    pub path: String,
+    pub patterns: Vec<Regex>,
+    position: Option<(u64, u64)>, // File identity and offset of the next unread line
+}
+
+impl LogWatcher {
+    pub fn new(path: String, patterns: Vec<Regex>) -> Self {
+        LogWatcher { path, patterns, position: None }
+    }
+
+    /// Counts matches per pattern in the lines appended since the last call.
+    /// The first call only records the end of the file and returns `None`, so
+    /// existing history isn't reported as a burst. A rotated or truncated file
+    /// is read from the start.
+    pub fn poll(&mut self) -> Result<Option<Vec<u64>>, String> {
+        let metadata = fs::metadata(&self.path).map_err(|e| format!("failed to stat {}: {}", self.path, e))?;
+        let identity = file_identity(&metadata);
+
+        let Some((previous_identity, previous_offset)) = self.position else {
+            self.position = Some((identity, metadata.len()));
+            return Ok(None);
+        };
+        let start = if identity != previous_identity || metadata.len() < previous_offset { 0 } else { previous_offset };
+
+        let mut file = File::open(&self.path).map_err(|e| format!("failed to open {}: {}", self.path, e))?;
+        file.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(file);
+
+        let mut counts = vec![0; self.patterns.len()];
+        let mut offset = start;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line).map_err(|e| format!("failed to read {}: {}", self.path, e))?;
+            // Leave a trailing partial line for the next call, once the writer has finished it.
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            offset += read as u64;
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\n', '\r']);
+            for (count, pattern) in counts.iter_mut().zip(&self.patterns) {
+                if pattern.is_match(text) {
+                    *count += 1;
+                }
+            }
+        }
+
+        self.position = Some((identity, offset));
+        Ok(Some(counts))
+    }
+}
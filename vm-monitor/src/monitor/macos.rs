@@ -0,0 +1,56 @@
+// macOS-specific readings from `sysctl` and `pmset`, which need no entitlements.
+use super::MacosMetrics;
+use std::process::Command;
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn sysctl_u32(name: &str) -> Option<u32> {
+    run("sysctl", &["-n", name])?.trim().parse().ok()
+}
+
+// Same levels as the kernel's dispatch source: 1 = normal, 2 = warning, 4 = critical.
+fn memory_pressure() -> Option<String> {
+    match sysctl_u32("kern.memorystatus_vm_pressure_level")? {
+        1 => Some("normal".to_string()),
+        2 => Some("warning".to_string()),
+        4 => Some("critical".to_string()),
+        level => Some(format!("unknown ({})", level)),
+    }
+}
+
+// `pmset -g therm` prints "No thermal warning level has been recorded" or
+// "Thermal warning level set to N.", and on Intel Macs a CPU power section with
+// "CPU_Speed_Limit = 100" (percent of full speed allowed).
+fn thermal_state() -> (Option<u32>, Option<u32>) {
+    let Some(output) = run("pmset", &["-g", "therm"]) else {
+        return (None, None);
+    };
+
+    let warning_level = if output.contains("No thermal warning level") {
+        Some(0)
+    } else {
+        output
+            .lines()
+            .find_map(|line| line.split_once("Thermal warning level set to"))
+            .and_then(|(_, level)| level.trim().trim_end_matches('.').parse().ok())
+    };
+    let speed_limit = output
+        .lines()
+        .find(|line| line.trim_start().starts_with("CPU_Speed_Limit"))
+        .and_then(|line| line.split_once('='))
+        .and_then(|(_, value)| value.trim().parse().ok());
+    (warning_level, speed_limit)
+}
+
+pub fn read_macos_metrics() -> MacosMetrics {
+    let (thermal_warning_level, cpu_speed_limit_percent) = thermal_state();
+    MacosMetrics {
+        memory_pressure: memory_pressure(),
+        memory_free_percent: sysctl_u32("kern.memorystatus_level"),
+        thermal_warning_level,
+        cpu_speed_limit_percent,
+    }
+}
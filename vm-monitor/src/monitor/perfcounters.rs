@@ -0,0 +1,88 @@
+// Windows performance counters read through PDH. One query stays open across samples, since
+// rate counters like Pages/sec are computed between two collections; the first sample reports
+// them as `None`. Counter paths are English names, which PDH resolves on localized installs too.
+use super::WindowsMetrics;
+use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+use windows_sys::Win32::System::Performance::{
+    PDH_FMT_COUNTERVALUE, PDH_FMT_DOUBLE, PdhAddEnglishCounterW, PdhCloseQuery, PdhCollectQueryData,
+    PdhGetFormattedCounterValue, PdhOpenQueryW,
+};
+
+const COUNTERS: [&str; 4] = [
+    r"\PhysicalDisk(_Total)\Current Disk Queue Length",
+    r"\Memory\Pages/sec",
+    r"\Paging File(_Total)\% Usage",
+    r"\Process(_Total)\Handle Count",
+];
+
+pub struct PerfCounters {
+    query: isize,
+    counters: Vec<Option<isize>>, // By position in COUNTERS, `None` for those PDH couldn't add
+}
+
+impl PerfCounters {
+    pub fn open() -> Option<Self> {
+        let mut query = 0;
+        // SAFETY: a null data source means live counters; `query` outlives the call.
+        let status = unsafe { PdhOpenQueryW(std::ptr::null(), 0, &mut query) };
+        if status != ERROR_SUCCESS {
+            log::warn!("Failed to open a performance counter query: PDH error {:#x}", status);
+            return None;
+        }
+        let counters = COUNTERS
+            .iter()
+            .map(|path| {
+                let wide: Vec<u16> = path.encode_utf16().chain([0]).collect();
+                let mut counter = 0;
+                // SAFETY: `wide` is NUL-terminated and both outlive the call.
+                let status = unsafe { PdhAddEnglishCounterW(query, wide.as_ptr(), 0, &mut counter) };
+                if status != ERROR_SUCCESS {
+                    log::debug!("Performance counter {} isn't available: PDH error {:#x}", path, status);
+                    return None;
+                }
+                Some(counter)
+            })
+            .collect();
+        Some(PerfCounters { query, counters })
+    }
+
+    fn value(&self, index: usize) -> Option<f64> {
+        let counter = self.counters.get(index).copied().flatten()?;
+        // SAFETY: PDH fills in the value, which is plain data, so zeroed is a valid start.
+        let mut value: PDH_FMT_COUNTERVALUE = unsafe { std::mem::zeroed() };
+        // SAFETY: `counter` belongs to the open query and `value` outlives the call.
+        let status = unsafe { PdhGetFormattedCounterValue(counter, PDH_FMT_DOUBLE, std::ptr::null_mut(), &mut value) };
+        // SAFETY: PDH_FMT_DOUBLE asks for the double member, which is zero if PDH didn't set it.
+        (status == ERROR_SUCCESS).then_some(unsafe { value.Anonymous.doubleValue })
+    }
+
+    pub fn read(&self) -> Option<WindowsMetrics> {
+        // SAFETY: the query stays open until `self` is dropped.
+        let status = unsafe { PdhCollectQueryData(self.query) };
+        if status != ERROR_SUCCESS {
+            log::warn!("Failed to collect performance counters: PDH error {:#x}", status);
+            return None;
+        }
+        Some(WindowsMetrics {
+            disk_queue_length: self.value(0),
+            pages_per_sec: self.value(1),
+            page_file_usage_percent: self.value(2),
+            handle_count: self.value(3).map(|count| count as u64),
+        })
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        // SAFETY: the query was opened by `open` and isn't used after this.
+        unsafe { PdhCloseQuery(self.query) };
+    }
+}
+
+// Opens the query on first use, and again after it failed to open.
+pub fn read_windows_metrics(counters: &mut Option<PerfCounters>) -> Option<WindowsMetrics> {
+    if counters.is_none() {
+        *counters = PerfCounters::open();
+    }
+    counters.as_ref()?.read()
+}
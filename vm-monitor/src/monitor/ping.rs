@@ -0,0 +1,125 @@
+// Reachability probes: ICMP echo and TCP connect, timed from this VM.
+use super::PingResult;
+use crate::config::{PingProtocol, PingTarget};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, String> {
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("failed to resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("no address found for {}", host))
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Prefers unprivileged ICMP datagram sockets (Linux `net.ipv4.ping_group_range`),
+// falling back to raw sockets when running as root or with CAP_NET_RAW.
+fn open_icmp_socket(address: &IpAddr) -> io::Result<(Socket, bool)> {
+    let (domain, protocol) = match address {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => Ok((socket, false)),
+        Err(_) => Socket::new(domain, Type::RAW, Some(protocol)).map(|socket| (socket, true)),
+    }
+}
+
+fn icmp_echo(address: IpAddr, timeout: Duration) -> Result<Duration, String> {
+    let (mut socket, raw) = open_icmp_socket(&address).map_err(|e| format!("failed to open ICMP socket: {}", e))?;
+    socket
+        .connect(&SockAddr::from(SocketAddr::new(address, 0)))
+        .map_err(|e| format!("failed to connect ICMP socket: {}", e))?;
+
+    let (request_type, reply_type) = match address {
+        IpAddr::V4(_) => (ICMP_ECHO_REQUEST, ICMP_ECHO_REPLY),
+        IpAddr::V6(_) => (ICMPV6_ECHO_REQUEST, ICMPV6_ECHO_REPLY),
+    };
+    let identifier = std::process::id() as u16; // Datagram sockets replace this with their own
+    let sequence: u16 = rand::random();
+    let mut packet = [0u8; 16];
+    packet[0] = request_type;
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    packet[8..].copy_from_slice(b"vmmonitr");
+    if address.is_ipv4() {
+        // The kernel computes ICMPv6 checksums itself.
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let started = Instant::now();
+    socket.send(&packet).map_err(|e| format!("failed to send echo request: {}", e))?;
+
+    let mut buffer = [0u8; 1500];
+    loop {
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(format!("no echo reply within {}s", timeout.as_secs()));
+        }
+        socket.set_read_timeout(Some(remaining)).map_err(|e| e.to_string())?;
+        let received = match socket.read(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(format!("no echo reply within {}s", timeout.as_secs()));
+            }
+            Err(e) => return Err(format!("failed to read echo reply: {}", e)),
+        };
+
+        // Raw IPv4 sockets deliver the IP header too; everything else starts at the ICMP header.
+        let offset = if raw && address.is_ipv4() { (buffer[0] & 0x0F) as usize * 4 } else { 0 };
+        let reply = &buffer[offset.min(received)..received];
+        if reply.len() >= 8 && reply[0] == reply_type && reply[6..8] == sequence.to_be_bytes() {
+            return Ok(started.elapsed());
+        }
+    }
+}
+
+fn tcp_connect(address: SocketAddr, timeout: Duration) -> Result<Duration, String> {
+    let started = Instant::now();
+    TcpStream::connect_timeout(&address, timeout).map_err(|e| format!("failed to connect to {}: {}", address, e))?;
+    Ok(started.elapsed())
+}
+
+/// Probes one target. TCP targets measure the time to complete the handshake.
+pub fn ping(target: &PingTarget) -> PingResult {
+    let timeout = Duration::from_secs(target.timeout_seconds);
+    let outcome = match target.protocol {
+        PingProtocol::Icmp => resolve(&target.host, 0).and_then(|address| icmp_echo(address.ip(), timeout)),
+        PingProtocol::Tcp => match target.port {
+            Some(port) => resolve(&target.host, port).and_then(|address| tcp_connect(address, timeout)),
+            None => Err("TCP targets need a port".to_string()),
+        },
+    };
+
+    let (rtt_ms, error) = match outcome {
+        Ok(rtt) => (Some(rtt.as_secs_f64() * 1000.0), None),
+        Err(e) => (None, Some(e)),
+    };
+    PingResult {
+        host: target.host.clone(),
+        protocol: target.protocol,
+        port: target.port,
+        reachable: rtt_ms.is_some(),
+        rtt_ms,
+        error,
+    }
+}
@@ -0,0 +1,63 @@
+// Battery and power-supply readings from /sys/class/power_supply (Linux).
+use super::{BatteryMetric, PowerMetrics};
+use std::fs;
+use std::path::Path;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr)).ok().map(|s| s.trim().to_string())
+}
+
+// sysfs reports power in µW, energy in µWh, current in µA and voltage in µV.
+fn read_micro(dir: &Path, attr: &str) -> Option<f64> {
+    read_attr(dir, attr)?.parse::<f64>().ok().map(|v| v / 1_000_000.0)
+}
+
+fn battery_metric(name: String, dir: &Path) -> BatteryMetric {
+    let voltage = read_micro(dir, "voltage_now");
+    // Some drivers only expose current, so derive power from current * voltage.
+    let power_draw_watts = read_micro(dir, "power_now").or_else(|| Some(read_micro(dir, "current_now")? * voltage?));
+    // Charge-based batteries (µAh) can be converted to energy with the current voltage.
+    let energy = |energy_attr: &str, charge_attr: &str| {
+        read_micro(dir, energy_attr).or_else(|| Some(read_micro(dir, charge_attr)? * voltage?))
+    };
+
+    BatteryMetric {
+        name,
+        capacity_percent: read_attr(dir, "capacity").and_then(|c| c.parse().ok()),
+        status: read_attr(dir, "status").unwrap_or_else(|| "Unknown".to_string()),
+        power_draw_watts,
+        energy_now_wh: energy("energy_now", "charge_now"),
+        energy_full_wh: energy("energy_full", "charge_full"),
+    }
+}
+
+/// Returns `None` when the machine has no power supply information at all
+/// (typical for servers and VMs).
+pub fn read_power_metrics() -> Option<PowerMetrics> {
+    let entries = fs::read_dir(POWER_SUPPLY_DIR).ok()?;
+    let mut on_ac_power = None;
+    let mut batteries = Vec::new();
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let dir = entry.path();
+        match read_attr(&dir, "type").as_deref() {
+            Some("Mains") => {
+                let online = read_attr(&dir, "online").is_some_and(|v| v == "1");
+                on_ac_power = Some(on_ac_power.unwrap_or(false) || online);
+            }
+            Some("Battery") => {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                batteries.push(battery_metric(name, &dir));
+            }
+            _ => {} // USB, UPS and peripheral batteries are not reported
+        }
+    }
+
+    if on_ac_power.is_none() && batteries.is_empty() {
+        return None;
+    }
+    batteries.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(PowerMetrics { on_ac_power, batteries })
+}
@@ -0,0 +1,390 @@
+// Readers for Linux procfs/sysfs files that sysinfo doesn't cover.
+// On other platforms the files don't exist and every reader returns `None`.
+use super::{ConntrackMetrics, CpuTimeBreakdown, FdMetrics, KernelMetrics, MemoryBreakdown, NumaNodeMemory, TcpMetrics, counter_delta};
+use std::collections::HashMap;
+use std::fs;
+
+fn read_u64(path: &str) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+pub fn read_fd_metrics() -> Option<FdMetrics> {
+    // file-nr: "<allocated> <allocated but unused> <max>"
+    let file_nr = fs::read_to_string("/proc/sys/fs/file-nr").ok()?;
+    let fields: Vec<u64> = file_nr
+        .split_whitespace()
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    let [allocated, unused, max] = fields[..] else {
+        return None;
+    };
+
+    let agent_open = fs::read_dir("/proc/self/fd").map(|dir| dir.count() as u64).ok();
+    // "Max open files            1024                 4096                 files"
+    let agent_limit = fs::read_to_string("/proc/self/limits").ok().and_then(|limits| {
+        limits
+            .lines()
+            .find(|line| line.starts_with("Max open files"))
+            .and_then(|line| line.split_whitespace().nth(3))
+            .and_then(|soft_limit| soft_limit.parse().ok())
+    });
+
+    let system_in_use = allocated.saturating_sub(unused);
+    Some(FdMetrics {
+        system_in_use,
+        system_max: max,
+        system_usage_percent: if max > 0 { system_in_use as f64 / max as f64 * 100.0 } else { 0.0 },
+        agent_open,
+        agent_limit,
+    })
+}
+
+
+pub fn read_tcp_metrics() -> Option<TcpMetrics> {
+    let mut metrics = TcpMetrics::default();
+    let mut found_any = false;
+
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = fs::read_to_string(path) else {
+            continue;
+        };
+        found_any = true;
+
+        // "  sl  local_address rem_address   st ..." - the state is the 4th column, in hex
+        for line in table.lines().skip(1) {
+            let Some(state) = line.split_whitespace().nth(3) else {
+                continue;
+            };
+            let counter = match state {
+                "01" => &mut metrics.established,
+                "02" => &mut metrics.syn_sent,
+                "03" => &mut metrics.syn_recv,
+                "04" => &mut metrics.fin_wait1,
+                "05" => &mut metrics.fin_wait2,
+                "06" => &mut metrics.time_wait,
+                "07" => &mut metrics.close,
+                "08" => &mut metrics.close_wait,
+                "09" => &mut metrics.last_ack,
+                "0A" => &mut metrics.listen,
+                "0B" => &mut metrics.closing,
+                _ => continue,
+            };
+            *counter += 1;
+            metrics.total += 1;
+        }
+    }
+
+    found_any.then_some(metrics)
+}
+
+// Aggregate CPU time counters from the "cpu" line of /proc/stat, in clock ticks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    user: u64,
+    nice: u64,
+    system: u64,
+    idle: u64,
+    iowait: u64,
+    irq: u64,
+    softirq: u64,
+    steal: u64,
+}
+
+impl CpuTimes {
+    // guest/guest_nice are already included in user/nice, so they are not summed again.
+    fn total(&self) -> u64 {
+        self.user + self.nice + self.system + self.idle + self.iowait + self.irq + self.softirq + self.steal
+    }
+
+    /// Share of each CPU state over the interval since `previous`, in percent.
+    pub fn breakdown_since(&self, previous: &CpuTimes) -> Option<CpuTimeBreakdown> {
+        let total = counter_delta(self.total(), previous.total());
+        if total == 0 {
+            return None;
+        }
+        let percent = |current: u64, previous: u64| counter_delta(current, previous) as f32 / total as f32 * 100.0;
+        Some(CpuTimeBreakdown {
+            user_percent: percent(self.user, previous.user),
+            nice_percent: percent(self.nice, previous.nice),
+            system_percent: percent(self.system, previous.system),
+            idle_percent: percent(self.idle, previous.idle),
+            iowait_percent: percent(self.iowait, previous.iowait),
+            irq_percent: percent(self.irq, previous.irq),
+            softirq_percent: percent(self.softirq, previous.softirq),
+            steal_percent: percent(self.steal, previous.steal),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcStat {
+    pub cpu: CpuTimes,
+    context_switches: u64,
+    interrupts: u64,
+    procs_running: u64,
+    procs_blocked: u64,
+}
+
+impl ProcStat {
+    pub fn kernel_metrics_since(&self, previous: &ProcStat, elapsed_secs: f64) -> KernelMetrics {
+        let context_switches = counter_delta(self.context_switches, previous.context_switches);
+        let interrupts = counter_delta(self.interrupts, previous.interrupts);
+        let per_sec = |count: u64| if elapsed_secs > 0.0 { count as f64 / elapsed_secs } else { 0.0 };
+        KernelMetrics {
+            context_switches,
+            interrupts,
+            context_switches_per_sec: per_sec(context_switches),
+            interrupts_per_sec: per_sec(interrupts),
+            procs_running: self.procs_running,
+            procs_blocked: self.procs_blocked,
+        }
+    }
+}
+
+pub fn read_proc_stat() -> Option<ProcStat> {
+    let content = fs::read_to_string("/proc/stat").ok()?;
+    let mut stat = ProcStat::default();
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let first_value = |fields: &mut std::str::SplitWhitespace| {
+            fields.next().and_then(|v| v.parse().ok()).unwrap_or(0)
+        };
+        match fields.next() {
+            Some("cpu") => {
+                let values: Vec<u64> = fields.filter_map(|v| v.parse().ok()).collect();
+                let value = |index: usize| values.get(index).copied().unwrap_or(0);
+                stat.cpu = CpuTimes {
+                    user: value(0),
+                    nice: value(1),
+                    system: value(2),
+                    idle: value(3),
+                    iowait: value(4),
+                    irq: value(5),
+                    softirq: value(6),
+                    steal: value(7),
+                };
+            }
+            Some("ctxt") => stat.context_switches = first_value(&mut fields),
+            Some("intr") => stat.interrupts = first_value(&mut fields), // First value is the total across all IRQs
+            Some("procs_running") => stat.procs_running = first_value(&mut fields),
+            Some("procs_blocked") => stat.procs_blocked = first_value(&mut fields),
+            _ => {}
+        }
+    }
+
+    Some(stat)
+}
+
+/// Per-node memory from /sys/devices/system/node. Empty on single-node machines,
+/// where it would just repeat the system-wide figures.
+pub fn read_numa_memory() -> Vec<NumaNodeMemory> {
+    let Ok(entries) = fs::read_dir("/sys/devices/system/node") else {
+        return Vec::new();
+    };
+
+    let mut nodes: Vec<NumaNodeMemory> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let node: u32 = entry.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            let meminfo = fs::read_to_string(entry.path().join("meminfo")).ok()?;
+            // "Node 0 MemTotal:        6158152 kB"
+            let value = |key: &str| {
+                meminfo.lines().find_map(|line| {
+                    let mut fields = line.split_whitespace().skip(2);
+                    if fields.next()? != key {
+                        return None;
+                    }
+                    fields.next()?.parse::<u64>().ok().map(|kb| kb * 1024)
+                })
+            };
+            let total_memory = value("MemTotal:")?;
+            let free_memory = value("MemFree:")?;
+            Some(NumaNodeMemory {
+                node,
+                total_memory,
+                free_memory,
+                used_memory: value("MemUsed:").unwrap_or(total_memory.saturating_sub(free_memory)),
+            })
+        })
+        .collect();
+
+    if nodes.len() < 2 {
+        return Vec::new();
+    }
+    nodes.sort_by_key(|n| n.node);
+    nodes
+}
+
+// Connection tracking table fill level; new flows are dropped once it is full.
+pub fn read_conntrack_metrics() -> Option<ConntrackMetrics> {
+    let entries = read_u64("/proc/sys/net/netfilter/nf_conntrack_count")?;
+    let max_entries = read_u64("/proc/sys/net/netfilter/nf_conntrack_max")?;
+    Some(ConntrackMetrics {
+        entries,
+        max_entries,
+        usage_percent: if max_entries > 0 { entries as f64 / max_entries as f64 * 100.0 } else { 0.0 },
+    })
+}
+
+// Bits of entropy in the kernel pool. Kernels >= 5.18 always report 256.
+pub fn read_entropy_available() -> Option<u64> {
+    read_u64("/proc/sys/kernel/random/entropy_avail")
+}
+
+// Cumulative counters for one block device from /proc/diskstats.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskStats {
+    reads_completed: u64,
+    read_time_ms: u64,
+    writes_completed: u64,
+    write_time_ms: u64,
+    in_flight: u64,
+    weighted_io_time_ms: u64,
+}
+
+// Interval-derived latency figures for one device.
+pub struct DiskLatency {
+    pub read_latency_ms: Option<f64>, // None when there were no reads in the interval
+    pub write_latency_ms: Option<f64>,
+    pub avg_queue_depth: f64,
+    pub in_flight: u64,
+}
+
+impl DiskStats {
+    pub fn latency_since(&self, previous: &DiskStats, elapsed_secs: f64) -> DiskLatency {
+        let average = |time: u64, prev_time: u64, ops: u64, prev_ops: u64| {
+            let ops = counter_delta(ops, prev_ops);
+            (ops > 0).then(|| counter_delta(time, prev_time) as f64 / ops as f64)
+        };
+        let elapsed_ms = elapsed_secs * 1000.0;
+        DiskLatency {
+            read_latency_ms: average(self.read_time_ms, previous.read_time_ms, self.reads_completed, previous.reads_completed),
+            write_latency_ms: average(
+                self.write_time_ms,
+                previous.write_time_ms,
+                self.writes_completed,
+                previous.writes_completed,
+            ),
+            // Weighted I/O time grows by the number of in-flight requests every millisecond.
+            avg_queue_depth: if elapsed_ms > 0.0 {
+                counter_delta(self.weighted_io_time_ms, previous.weighted_io_time_ms) as f64 / elapsed_ms
+            } else {
+                0.0
+            },
+            in_flight: self.in_flight,
+        }
+    }
+}
+
+/// Reads /proc/diskstats, keyed by kernel device name (sda, nvme0n1p1, dm-0).
+pub fn read_diskstats() -> HashMap<String, DiskStats> {
+    let Ok(content) = fs::read_to_string("/proc/diskstats") else {
+        return HashMap::new();
+    };
+
+    content
+        .lines()
+        .filter_map(|line| {
+            // "major minor name reads merged sectors read_ms writes merged sectors write_ms in_flight io_ms weighted_ms ..."
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 14 {
+                return None;
+            }
+            let field = |index: usize| fields[index].parse::<u64>().unwrap_or(0);
+            Some((
+                fields[2].to_string(),
+                DiskStats {
+                    reads_completed: field(3),
+                    read_time_ms: field(6),
+                    writes_completed: field(7),
+                    write_time_ms: field(10),
+                    in_flight: field(11),
+                    weighted_io_time_ms: field(13),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Maps a device path such as /dev/sda1 or /dev/mapper/vg-root to its
+/// /proc/diskstats name, following symlinks (device-mapper names point to dm-N).
+pub fn diskstats_name(device: &str) -> Option<String> {
+    let resolved = fs::canonicalize(device).ok()?;
+    resolved.file_name()?.to_str().map(str::to_string)
+}
+
+// Cumulative paging counters from /proc/vmstat.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VmStat {
+    swap_in_pages: u64,
+    swap_out_pages: u64,
+    major_faults: u64,
+}
+
+impl VmStat {
+    /// (swap-in, swap-out, major faults) per second since `previous`.
+    pub fn rates_since(&self, previous: &VmStat, elapsed_secs: f64) -> (f64, f64, f64) {
+        let rate = |current: u64, prev: u64| {
+            if elapsed_secs > 0.0 { counter_delta(current, prev) as f64 / elapsed_secs } else { 0.0 }
+        };
+        (
+            rate(self.swap_in_pages, previous.swap_in_pages),
+            rate(self.swap_out_pages, previous.swap_out_pages),
+            rate(self.major_faults, previous.major_faults),
+        )
+    }
+}
+
+pub fn read_vmstat() -> Option<VmStat> {
+    let content = fs::read_to_string("/proc/vmstat").ok()?;
+    let mut vmstat = VmStat::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        let value = value.trim().parse().unwrap_or(0);
+        match key {
+            "pswpin" => vmstat.swap_in_pages = value,
+            "pswpout" => vmstat.swap_out_pages = value,
+            "pgmajfault" => vmstat.major_faults = value,
+            _ => {}
+        }
+    }
+    Some(vmstat)
+}
+
+/// Reads /proc/meminfo into a map of field name to bytes.
+fn read_meminfo() -> Option<HashMap<String, u64>> {
+    let content = fs::read_to_string("/proc/meminfo").ok()?;
+    Some(
+        content
+            .lines()
+            .filter_map(|line| {
+                // "Cached:          3393348 kB"
+                let (key, rest) = line.split_once(':')?;
+                let kb: u64 = rest.split_whitespace().next()?.parse().ok()?;
+                Some((key.to_string(), kb * 1024))
+            })
+            .collect(),
+    )
+}
+
+pub fn read_memory_breakdown() -> Option<MemoryBreakdown> {
+    let meminfo = read_meminfo()?;
+    let field = |key: &str| meminfo.get(key).copied().unwrap_or(0);
+    let total = field("MemTotal");
+    Some(MemoryBreakdown {
+        // MemAvailable already discounts reclaimable cache, which makes this
+        // stable across kernel and sysinfo versions.
+        used_excluding_cache: total.saturating_sub(meminfo.get("MemAvailable").copied().unwrap_or(field("MemFree"))),
+        anonymous: field("AnonPages"),
+        cached: field("Cached"),
+        buffers: field("Buffers"),
+        shared: field("Shmem"),
+        slab: field("Slab"),
+        slab_reclaimable: field("SReclaimable"),
+        dirty: field("Dirty"),
+        writeback: field("Writeback"),
+    })
+}
@@ -0,0 +1,32 @@
+// State of watched FreeBSD rc.d services, queried through `service <name> status`.
+// rc.d only knows running or not, which is mapped onto systemd's vocabulary.
+use super::ServiceStatus;
+use std::process::Command;
+
+pub fn collect_service_statuses(services: &[String]) -> Vec<ServiceStatus> {
+    services
+        .iter()
+        .map(|service| {
+            let (load_state, active_state) = match Command::new("service").args([service, "status"]).output() {
+                Ok(output) if output.status.success() => ("loaded", "active"),
+                // rc.d scripts exit non-zero both when stopped and when disabled in rc.conf.
+                Ok(output) if String::from_utf8_lossy(&output.stderr).contains("does not exist") => {
+                    ("not-found", "inactive")
+                }
+                Ok(_) => ("loaded", "inactive"),
+                Err(e) => {
+                    log::warn!("Failed to query rc.d service {}: {}", service, e);
+                    ("unknown", "unknown")
+                }
+            };
+            ServiceStatus {
+                name: service.clone(),
+                load_state: load_state.to_string(),
+                active_state: active_state.to_string(),
+                sub_state: if active_state == "active" { "running" } else { "dead" }.to_string(),
+                restart_count: None,
+                healthy: active_state == "active",
+            }
+        })
+        .collect()
+}
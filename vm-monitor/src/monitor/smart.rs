@@ -0,0 +1,76 @@
+// Disk health from smartmontools' JSON output (`smartctl -j`, smartmontools >= 7.0).
+use super::SmartHealth;
+use serde_json::Value;
+use std::process::Command;
+
+// ATA attributes whose normalized value counts down from 100 as an SSD wears out.
+const ATA_WEAR_ATTRIBUTES: [u64; 3] = [
+    177, // Wear_Leveling_Count
+    231, // SSD_Life_Left
+    233, // Media_Wearout_Indicator
+];
+const ATA_REALLOCATED_SECTORS: u64 = 5;
+
+/// Maps a partition such as /dev/sda1 or /dev/nvme0n1p2 to its whole-disk device,
+/// which is what SMART is queried on. Returns `None` for non-block devices
+/// (overlay, tmpfs, device-mapper volumes).
+pub fn base_device(disk_name: &str) -> Option<String> {
+    let device = disk_name.strip_prefix("/dev/")?;
+    if device.starts_with("mapper/") || device.starts_with("loop") {
+        return None;
+    }
+    let base = if device.starts_with("nvme") || device.starts_with("mmcblk") {
+        // nvme0n1p2 -> nvme0n1, mmcblk0p1 -> mmcblk0
+        match device.rsplit_once('p') {
+            Some((disk, partition)) if !partition.is_empty() && partition.chars().all(|c| c.is_ascii_digit()) => disk,
+            _ => device,
+        }
+    } else {
+        device.trim_end_matches(|c: char| c.is_ascii_digit())
+    };
+    Some(format!("/dev/{}", base))
+}
+
+pub fn read_smart_health(device: &str) -> Option<SmartHealth> {
+    let output = match Command::new("smartctl").args(["-j", "-a", device]).output() {
+        Ok(output) => output,
+        Err(e) => {
+            log::warn!("Failed to run smartctl for {}: {}", device, e);
+            return None;
+        }
+    };
+    // smartctl encodes warnings in its exit status bits, so a non-zero exit
+    // doesn't mean the JSON is unusable.
+    let report: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let Some(passed) = report["smart_status"]["passed"].as_bool() else {
+        log::debug!("No SMART status for {} (exit status {})", device, output.status);
+        return None;
+    };
+
+    let ata_attributes = report["ata_smart_attributes"]["table"].as_array();
+    let ata_attribute = |id: u64| {
+        ata_attributes.and_then(|table| table.iter().find(|attr| attr["id"].as_u64() == Some(id)))
+    };
+    let nvme_log = &report["nvme_smart_health_information_log"];
+
+    let reallocated_sectors = ata_attribute(ATA_REALLOCATED_SECTORS)
+        .and_then(|attr| attr["raw"]["value"].as_u64())
+        .or_else(|| nvme_log["media_errors"].as_u64());
+    let wear_level_percent = nvme_log["percentage_used"].as_u64().or_else(|| {
+        ATA_WEAR_ATTRIBUTES
+            .iter()
+            .find_map(|id| ata_attribute(*id))
+            .and_then(|attr| attr["value"].as_u64())
+            .map(|remaining| 100u64.saturating_sub(remaining))
+    });
+
+    Some(SmartHealth {
+        device: device.to_string(),
+        model: report["model_name"].as_str().map(str::to_string),
+        passed,
+        temperature_celsius: report["temperature"]["current"].as_i64(),
+        power_on_hours: report["power_on_time"]["hours"].as_u64(),
+        reallocated_sectors,
+        wear_level_percent,
+    })
+}
@@ -0,0 +1,101 @@
+// Inventory of listening TCP/UDP sockets and their owning processes, from procfs.
+use super::ListeningSocket;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const TCP_LISTEN: &str = "0A";
+const UDP_UNCONNECTED: &str = "07"; // Bound UDP sockets with no peer report TCP_CLOSE
+
+// procfs prints addresses as raw in-memory 32-bit words in host byte order,
+// so converting each word back with `to_ne_bytes` recovers the network-order octets.
+fn parse_address(hex: &str) -> Option<(String, u16)> {
+    let (addr, port) = hex.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let octets: Vec<u8> = (0..addr.len() / 8)
+        .map(|i| u32::from_str_radix(&addr[i * 8..i * 8 + 8], 16).map(u32::to_ne_bytes))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?
+        .concat();
+
+    let address = match octets.len() {
+        4 => Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?).to_string(),
+        16 => Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?).to_string(),
+        _ => return None,
+    };
+    Some((address, port))
+}
+
+// Maps socket inodes to the owning pid by scanning /proc/<pid>/fd. Without root
+// only the agent user's own processes are visible.
+fn socket_owners() -> HashMap<u64, u32> {
+    let mut owners = HashMap::new();
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return owners;
+    };
+
+    for process in processes.filter_map(|entry| entry.ok()) {
+        let Some(pid) = process.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.filter_map(|entry| entry.ok()) {
+            // Socket fds link to "socket:[<inode>]"
+            let inode = fs::read_link(fd.path()).ok().and_then(|target| {
+                target
+                    .to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse::<u64>()
+                    .ok()
+            });
+            if let Some(inode) = inode {
+                owners.insert(inode, pid);
+            }
+        }
+    }
+    owners
+}
+
+fn process_name(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid)).ok().map(|name| name.trim().to_string())
+}
+
+pub fn collect_listening_sockets() -> Vec<ListeningSocket> {
+    let owners = socket_owners();
+    let mut sockets = Vec::new();
+
+    for (protocol, listening_state) in [
+        ("tcp", TCP_LISTEN),
+        ("tcp6", TCP_LISTEN),
+        ("udp", UDP_UNCONNECTED),
+        ("udp6", UDP_UNCONNECTED),
+    ] {
+        let Ok(table) = fs::read_to_string(format!("/proc/net/{}", protocol)) else {
+            continue;
+        };
+        // "sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode"
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != listening_state {
+                continue;
+            }
+            let Some((address, port)) = parse_address(fields[1]) else {
+                continue;
+            };
+            let pid = fields[9].parse::<u64>().ok().and_then(|inode| owners.get(&inode).copied());
+            sockets.push(ListeningSocket {
+                protocol: protocol.to_string(),
+                address,
+                port,
+                pid,
+                process_name: pid.and_then(process_name),
+            });
+        }
+    }
+
+    sockets.sort_by(|a, b| (&a.protocol, a.port, &a.address).cmp(&(&b.protocol, b.port, &b.address)));
+    sockets
+}
@@ -0,0 +1,66 @@
+// State of watched systemd units, queried through `systemctl show`.
+use super::ServiceStatus;
+use std::collections::HashMap;
+use std::process::Command;
+
+const PROPERTIES: &str = "LoadState,ActiveState,SubState,NRestarts";
+
+fn unit_name(service: &str) -> String {
+    if service.contains('.') {
+        service.to_string()
+    } else {
+        format!("{}.service", service)
+    }
+}
+
+fn query_unit(unit: &str) -> Result<HashMap<String, String>, String> {
+    let output = Command::new("systemctl")
+        .args(["show", unit, "--property", PROPERTIES])
+        .output()
+        .map_err(|e| format!("failed to run systemctl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    // Output is one "Key=Value" pair per line.
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+pub fn collect_service_statuses(services: &[String]) -> Vec<ServiceStatus> {
+    services
+        .iter()
+        .map(|service| {
+            let unit = unit_name(service);
+            match query_unit(&unit) {
+                Ok(properties) => {
+                    let property = |key: &str| properties.get(key).cloned().unwrap_or_else(|| "unknown".to_string());
+                    let active_state = property("ActiveState");
+                    ServiceStatus {
+                        healthy: active_state == "active",
+                        name: unit,
+                        load_state: property("LoadState"),
+                        active_state,
+                        sub_state: property("SubState"),
+                        // NRestarts needs systemd >= 235
+                        restart_count: properties.get("NRestarts").and_then(|n| n.parse().ok()),
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to query systemd unit {}: {}", unit, e);
+                    ServiceStatus {
+                        name: unit,
+                        load_state: "unknown".to_string(),
+                        active_state: "unknown".to_string(),
+                        sub_state: "unknown".to_string(),
+                        restart_count: None,
+                        healthy: false,
+                    }
+                }
+            }
+        })
+        .collect()
+}
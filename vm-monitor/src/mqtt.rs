@@ -0,0 +1,119 @@
+// Publishes every sample to an MQTT broker, for edge gateways that keep an MQTT
+// connection but can't reach the HTTP API. rumqttc reconnects on its own as long as
+// its event loop is polled, which a background task does for the agent's lifetime.
+use crate::config::{Configuration, MqttSettings};
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use rumqttc::{AsyncClient, ClientError, EventLoop, MqttOptions, QoS, Transport};
+use std::fs;
+use std::time::Duration;
+
+const QUEUE_CAPACITY: usize = 100; // Publishes waiting for the connection
+const MAX_PACKET_BYTES: usize = 4 * 1024 * 1024; // rumqttc defaults to 10 KiB, less than one sample
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn mqtt_error(e: impl std::fmt::Display) -> VmMonitorError {
+    VmMonitorError::SinkError(format!("MQTT: {}", e))
+}
+
+pub struct MqttSink {
+    client: AsyncClient,
+    topic: String,
+    qos: QoS,
+}
+
+impl MqttSink {
+    pub fn new(config: &Configuration, settings: &MqttSettings) -> Result<Self, VmMonitorError> {
+        let qos = match settings.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            other => return Err(VmMonitorError::ConfigError(format!("Invalid MQTT QoS {} (expected 0, 1 or 2)", other))),
+        };
+        let client_id = settings.client_id.clone().unwrap_or_else(|| format!("vm-monitor-{}", config.instance_id));
+        let port = settings.port.unwrap_or(if settings.tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(client_id, settings.host.clone(), port);
+        options
+            .set_keep_alive(Duration::from_secs(settings.keep_alive_seconds))
+            .set_max_packet_size(MAX_PACKET_BYTES, MAX_PACKET_BYTES);
+        if let Some(username) = &settings.username {
+            options.set_credentials(username.clone(), settings.password.clone().unwrap_or_default());
+        }
+        if settings.tls {
+            let read = |path: &str| fs::read(path).map_err(|e| VmMonitorError::ConfigError(format!("Failed to read {}: {}", path, e)));
+            let client_auth = match (&settings.client_cert_file, &settings.client_key_file) {
+                (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+                (None, None) => None,
+                _ => return Err(VmMonitorError::ConfigError("MQTT client_cert_file and client_key_file must be set together".to_string())),
+            };
+            options.set_transport(match &settings.ca_file {
+                Some(ca_file) => Transport::tls(read(ca_file)?, client_auth, None),
+                None if client_auth.is_none() => Transport::tls_with_default_config(), // System trust store
+                None => return Err(VmMonitorError::ConfigError("MQTT client certificates need ca_file to be set".to_string())),
+            });
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        tokio::spawn(drive(eventloop, settings.host.clone()));
+        Ok(MqttSink {
+            client,
+            topic: expand_topic(&settings.topic, config),
+            qos,
+        })
+    }
+
+    // Queues a sample for publishing. Fails instead of waiting when the queue is full,
+    // which happens while the broker is unreachable.
+    fn publish(&self, metrics: &SystemMetrics) -> Result<(), VmMonitorError> {
+        let payload = serde_json::to_vec(metrics)?;
+        self.client.try_publish(&self.topic, self.qos, false, payload).map_err(|e| match e {
+            ClientError::TryRequest(_) => mqtt_error("publish queue is full, broker unreachable"),
+            e => mqtt_error(e),
+        })
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        let result = metrics.iter().try_for_each(|sample| self.publish(sample));
+        Box::pin(async { result })
+    }
+}
+
+// "{instance_id}" and "{instance_name}" in the configured topic are replaced. The name
+// is stripped of the MQTT wildcards and separator so it stays a single topic level.
+fn expand_topic(template: &str, config: &Configuration) -> String {
+    let instance_name: String = config
+        .instance_name
+        .chars()
+        .map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c })
+        .collect();
+    template
+        .replace("{instance_id}", &config.instance_id.to_string())
+        .replace("{instance_name}", &instance_name)
+}
+
+async fn drive(mut eventloop: EventLoop, host: String) {
+    let mut failing = false; // Logs each outage once rather than on every reconnect attempt
+    loop {
+        match eventloop.poll().await {
+            Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                log::info!("Connected to MQTT broker {}", host);
+                failing = false;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if !failing {
+                    log::warn!("MQTT broker {} unreachable, retrying every {}s: {}", host, RECONNECT_DELAY.as_secs(), e);
+                }
+                failing = true;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+// Sends every batch to the New Relic Metric API as gauges. What describes the host (hostname,
+// OS, environment, Kubernetes placement...) goes into each sample's common attributes, so
+// metrics can be faceted by it in NRQL.
+use crate::config::{Configuration, NewRelicSettings};
+use crate::errors::VmMonitorError;
+use crate::gauges::gauges;
+use crate::monitor::{SystemInfo, SystemMetrics};
+use crate::sink::{Delivery, Sink, SinkFuture};
+use reqwest::Client;
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+
+// Enums serialize to their snake_case names, as the API receives them.
+fn label(value: impl serde::Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+fn host_attributes(info: &SystemInfo) -> Map<String, Value> {
+    let mut attributes = Map::new();
+    attributes.insert("host.name".to_string(), json!(info.hostname));
+    attributes.insert("os.name".to_string(), json!(info.os_name));
+    attributes.insert("os.version".to_string(), json!(info.os_version));
+    attributes.insert("kernel.version".to_string(), json!(info.kernel_version));
+    attributes.insert("environment".to_string(), label(info.environment));
+    if let Some(virtualization) = info.virtualization {
+        attributes.insert("virtualization".to_string(), label(virtualization));
+    }
+    if let Some(kubernetes) = &info.kubernetes {
+        attributes.insert("k8s.role".to_string(), json!(kubernetes.role));
+        for (key, value) in [
+            ("k8s.node.name", &kubernetes.node_name),
+            ("k8s.namespace.name", &kubernetes.namespace),
+            ("k8s.pod.name", &kubernetes.pod_name),
+        ] {
+            if let Some(value) = value {
+                attributes.insert(key.to_string(), json!(value));
+            }
+        }
+    }
+    attributes
+}
+
+pub struct NewRelicSink {
+    client: Client,
+    settings: NewRelicSettings,
+    instance_id: String,
+    instance_name: String,
+}
+
+impl NewRelicSink {
+    pub fn new(config: &Configuration, settings: &NewRelicSettings) -> Result<Self, VmMonitorError> {
+        if settings.license_key.is_empty() {
+            return Err(VmMonitorError::ConfigError("New Relic license_key is empty".to_string()));
+        }
+        Ok(NewRelicSink {
+            client: Client::builder().timeout(Duration::from_secs(settings.timeout_seconds)).build()?,
+            settings: settings.clone(),
+            instance_id: config.instance_id.to_string(),
+            instance_name: config.instance_name.clone(),
+        })
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        match self.settings.prefix.trim_end_matches('.') {
+            "" => name.to_string(),
+            prefix => format!("{}.{}", prefix, name),
+        }
+    }
+
+    // One entry per sample, its host attributes and timestamp shared by all its metrics.
+    fn payload(&self, metrics: &[SystemMetrics]) -> Value {
+        metrics
+            .iter()
+            .map(|sample| {
+                let mut common = host_attributes(&sample.system_info);
+                common.insert("instance.id".to_string(), json!(self.instance_id));
+                common.insert("instance.name".to_string(), json!(self.instance_name));
+                common.extend(sample.tags.iter().map(|(key, value)| (key.clone(), json!(value))));
+                let metrics: Vec<Value> = gauges(sample)
+                    .into_iter()
+                    .map(|gauge| {
+                        let name = self.metric_name(&gauge.path("."));
+                        let attributes: Map<String, Value> =
+                            gauge.dimension.into_iter().map(|(key, value)| (key.to_string(), json!(value))).collect();
+                        json!({"name": name, "type": "gauge", "value": gauge.value, "attributes": attributes})
+                    })
+                    .collect();
+                json!({
+                    "common": {"timestamp": sample.timestamp.timestamp_millis(), "attributes": common},
+                    "metrics": metrics,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Sink for NewRelicSink {
+    fn name(&self) -> &'static str {
+        "newrelic"
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Batch
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.settings.endpoint)
+                .header("Api-Key", &self.settings.license_key)
+                .json(&self.payload(metrics))
+                .send()
+                .await?;
+            let status = response.status();
+            if !status.is_success() {
+                let text = response.text().await.unwrap_or_default();
+                return Err(VmMonitorError::ApiError(format!("New Relic returned {}: {}", status, text)));
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,83 @@
+// Bearer tokens from an OAuth2 token endpoint through the client credentials grant, cached
+// and refreshed shortly before they expire.
+use crate::config::OAuth2Settings;
+use crate::errors::VmMonitorError;
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+const REFRESH_BEFORE_EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>, // Seconds; without it the token is used until the API rejects it
+}
+
+struct Token {
+    access_token: String,
+    refresh_at: Option<Instant>,
+}
+
+pub struct TokenSource {
+    client: Client,
+    settings: OAuth2Settings,
+    token: Mutex<Option<Token>>, // Held while fetching, so concurrent requests share one fetch
+}
+
+impl TokenSource {
+    pub fn new(client: Client, settings: OAuth2Settings) -> Self {
+        TokenSource { client, settings, token: Mutex::new(None) }
+    }
+
+    pub async fn token(&self) -> Result<String, VmMonitorError> {
+        let mut token = self.token.lock().await;
+        if let Some(current) = token.as_ref()
+            && current.refresh_at.is_none_or(|refresh_at| Instant::now() < refresh_at)
+        {
+            return Ok(current.access_token.clone());
+        }
+        let fetched = self.fetch().await?;
+        let access_token = fetched.access_token.clone();
+        *token = Some(fetched);
+        Ok(access_token)
+    }
+
+    /// Drops the cached token after the API rejected it. Returns whether there was one,
+    /// in which case the request is worth retrying with a fresh token.
+    pub async fn invalidate(&self) -> bool {
+        self.token.lock().await.take().is_some()
+    }
+
+    async fn fetch(&self) -> Result<Token, VmMonitorError> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.settings.scope {
+            form.push(("scope", scope));
+        }
+        if let Some(audience) = &self.settings.audience {
+            form.push(("audience", audience));
+        }
+        let response = self
+            .client
+            .post(&self.settings.token_url)
+            .basic_auth(&self.settings.client_id, Some(&self.settings.client_secret))
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(VmMonitorError::AuthError(format!("Token endpoint {} returned {}: {}", self.settings.token_url, status, text)));
+        }
+        let response: TokenResponse = response.json().await?;
+        log::debug!("Got an OAuth2 access token, expires in {:?}s", response.expires_in);
+        Ok(Token {
+            access_token: response.access_token,
+            refresh_at: response
+                .expires_in
+                .map(|expires_in| Instant::now() + Duration::from_secs(expires_in).saturating_sub(REFRESH_BEFORE_EXPIRY)),
+        })
+    }
+}
@@ -0,0 +1,359 @@
+// Exports every sample to an OpenTelemetry collector over OTLP/HTTP, using the JSON
+// encoding so no protobuf toolchain is needed. Metric and attribute names follow the
+// OpenTelemetry semantic conventions where one exists, and `vm_monitor.*` otherwise.
+use crate::config::{CloudProvider, Configuration, OtlpSettings};
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use reqwest::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::future::Future;
+use std::time::Duration;
+
+const SCOPE_NAME: &str = "vm-monitor";
+const CUMULATIVE: u8 = 2; // AggregationTemporality
+
+type Attributes = Vec<(&'static str, String)>;
+
+enum Number {
+    Int(u64),
+    Double(f64),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportResponse {
+    #[serde(default)]
+    partial_success: Option<PartialSuccess>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PartialSuccess {
+    #[serde(default)]
+    rejected_data_points: Option<Value>, // int64, which the JSON mapping allows as a string or a number
+    #[serde(default)]
+    error_message: String,
+}
+
+fn attributes(attributes: &[(&str, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+struct MetricsBuilder {
+    time: String, // Nanoseconds since the epoch, as a string per the JSON mapping of fixed64
+    start_time: String, // Boot time, the start of the kernel's cumulative counters
+    metrics: Vec<Value>,
+}
+
+impl MetricsBuilder {
+    fn data_point(&self, point_attributes: &[(&str, String)], value: Number) -> Value {
+        let mut point = json!({
+            "attributes": attributes(point_attributes),
+            "startTimeUnixNano": self.start_time,
+            "timeUnixNano": self.time,
+        });
+        match value {
+            Number::Int(value) => point["asInt"] = json!(value.to_string()),
+            Number::Double(value) => point["asDouble"] = json!(value),
+        }
+        point
+    }
+
+    fn points(&self, points: impl IntoIterator<Item = (Attributes, Number)>) -> Vec<Value> {
+        points.into_iter().map(|(point_attributes, value)| self.data_point(&point_attributes, value)).collect()
+    }
+
+    fn gauge(&mut self, name: &str, unit: &str, points: impl IntoIterator<Item = (Attributes, Number)>) {
+        let points = self.points(points);
+        if !points.is_empty() {
+            self.metrics.push(json!({"name": name, "unit": unit, "gauge": {"dataPoints": points}}));
+        }
+    }
+
+    fn counter(&mut self, name: &str, unit: &str, points: impl IntoIterator<Item = (Attributes, Number)>) {
+        let points = self.points(points);
+        if !points.is_empty() {
+            self.metrics.push(json!({
+                "name": name,
+                "unit": unit,
+                "sum": {"dataPoints": points, "aggregationTemporality": CUMULATIVE, "isMonotonic": true},
+            }));
+        }
+    }
+}
+
+fn build_metrics(metrics: &SystemMetrics) -> Vec<Value> {
+    let nanos = |time: chrono::DateTime<chrono::Utc>| time.timestamp_nanos_opt().unwrap_or_default().to_string();
+    let boot_time = metrics.timestamp - chrono::Duration::seconds(metrics.system_info.uptime as i64);
+    let mut builder = MetricsBuilder {
+        time: nanos(metrics.timestamp),
+        start_time: nanos(boot_time),
+        metrics: Vec::new(),
+    };
+    let single = |value: Number| [(Attributes::new(), value)];
+
+    let cpu = &metrics.cpu_metrics;
+    builder.gauge("system.cpu.utilization", "1", single(Number::Double(cpu.usage_percent as f64 / 100.0)));
+    builder.gauge("system.cpu.logical.count", "{cpu}", single(Number::Int(cpu.core_count as u64)));
+    if let Some(times) = &cpu.time_breakdown {
+        builder.gauge(
+            "vm_monitor.cpu.mode.utilization",
+            "1",
+            [
+                ("user", times.user_percent),
+                ("nice", times.nice_percent),
+                ("system", times.system_percent),
+                ("idle", times.idle_percent),
+                ("iowait", times.iowait_percent),
+                ("interrupt", times.irq_percent + times.softirq_percent),
+                ("steal", times.steal_percent),
+            ]
+            .map(|(mode, percent)| (vec![("cpu.mode", mode.to_string())], Number::Double(percent as f64 / 100.0))),
+        );
+    }
+
+    let memory = &metrics.memory_metrics;
+    let state = |key: &'static str, state: &str| vec![(key, state.to_string())];
+    builder.gauge(
+        "system.memory.usage",
+        "By",
+        [
+            (state("system.memory.state", "used"), Number::Int(memory.used_memory)),
+            (state("system.memory.state", "free"), Number::Int(memory.available_memory)),
+        ],
+    );
+    builder.gauge("system.memory.limit", "By", single(Number::Int(memory.total_memory)));
+    builder.gauge(
+        "system.paging.usage",
+        "By",
+        [
+            (state("system.paging.state", "used"), Number::Int(memory.used_swap)),
+            (state("system.paging.state", "free"), Number::Int(memory.total_swap.saturating_sub(memory.used_swap))),
+        ],
+    );
+
+    let filesystem = |disk: &crate::monitor::DiskMetric, state: &str| {
+        vec![
+            ("system.device", disk.name.clone()),
+            ("system.filesystem.mountpoint", disk.mount_point.clone()),
+            ("system.filesystem.type", disk.filesystem.clone()),
+            ("system.filesystem.state", state.to_string()),
+        ]
+    };
+    builder.gauge(
+        "system.filesystem.usage",
+        "By",
+        metrics.disk_metrics.iter().flat_map(|disk| {
+            [
+                (filesystem(disk, "used"), Number::Int(disk.total_space.saturating_sub(disk.available_space))),
+                (filesystem(disk, "free"), Number::Int(disk.available_space)),
+            ]
+        }),
+    );
+    let direction = |device: &str, direction: &str| vec![("system.device", device.to_string()), ("disk.io.direction", direction.to_string())];
+    builder.counter(
+        "system.disk.io",
+        "By",
+        metrics.disk_metrics.iter().flat_map(|disk| {
+            [
+                (direction(&disk.name, "read"), Number::Int(disk.total_read_bytes)),
+                (direction(&disk.name, "write"), Number::Int(disk.total_written_bytes)),
+            ]
+        }),
+    );
+
+    let direction = |interface: &str, direction: &str| {
+        vec![("network.interface.name", interface.to_string()), ("network.io.direction", direction.to_string())]
+    };
+    builder.counter(
+        "system.network.io",
+        "By",
+        metrics.network_metrics.iter().flat_map(|network| {
+            [
+                (direction(&network.interface_name, "receive"), Number::Int(network.received_bytes_total)),
+                (direction(&network.interface_name, "transmit"), Number::Int(network.transmitted_bytes_total)),
+            ]
+        }),
+    );
+    if let Some(tcp) = &metrics.tcp_metrics {
+        builder.gauge(
+            "system.network.connections",
+            "{connection}",
+            [
+                ("established", tcp.established),
+                ("syn_sent", tcp.syn_sent),
+                ("syn_recv", tcp.syn_recv),
+                ("fin_wait_1", tcp.fin_wait1),
+                ("fin_wait_2", tcp.fin_wait2),
+                ("time_wait", tcp.time_wait),
+                ("close", tcp.close),
+                ("close_wait", tcp.close_wait),
+                ("last_ack", tcp.last_ack),
+                ("listen", tcp.listen),
+                ("closing", tcp.closing),
+            ]
+            .map(|(state, count)| {
+                (
+                    vec![("network.transport", "tcp".to_string()), ("network.connection.state", state.to_string())],
+                    Number::Int(count),
+                )
+            }),
+        );
+    }
+
+    builder.gauge("system.process.count", "{process}", single(Number::Int(metrics.process_metrics.total_processes as u64)));
+    builder.gauge("system.uptime", "s", single(Number::Int(metrics.system_info.uptime)));
+
+    let container = |name: &str, image: &str| vec![("container.name", name.to_string()), ("container.image.name", image.to_string())];
+    builder.gauge(
+        "container.cpu.utilization",
+        "1",
+        metrics.container_metrics.iter().filter_map(|c| {
+            Some((container(&c.name, &c.image), Number::Double(c.cpu_usage_percent? / 100.0)))
+        }),
+    );
+    builder.gauge(
+        "container.memory.usage",
+        "By",
+        metrics.container_metrics.iter().filter_map(|c| Some((container(&c.name, &c.image), Number::Int(c.memory_usage?)))),
+    );
+
+    builder.gauge(
+        "vm_monitor.http_check.duration",
+        "s",
+        metrics.http_checks.iter().filter_map(|check| {
+            Some((vec![("vm_monitor.check.name", check.name.clone())], Number::Double(check.latency_ms? / 1000.0)))
+        }),
+    );
+    builder.gauge(
+        "vm_monitor.http_check.status_code",
+        "1",
+        metrics.http_checks.iter().filter_map(|check| {
+            Some((vec![("vm_monitor.check.name", check.name.clone())], Number::Int(check.status_code? as u64)))
+        }),
+    );
+    if let Some(agent) = &metrics.agent_metrics {
+        builder.gauge("vm_monitor.api.consecutive_failures", "{failure}", single(Number::Int(agent.api_consecutive_failures as u64)));
+        builder.gauge("vm_monitor.spool.size", "By", single(Number::Int(agent.spooled_bytes)));
+    }
+    // Textfile keys can carry Prometheus labels, which aren't valid metric names, so keep them as an attribute.
+    builder.gauge(
+        "vm_monitor.custom",
+        "1",
+        metrics.custom_metrics.iter().map(|(name, value)| (vec![("vm_monitor.custom.name", name.clone())], Number::Double(*value))),
+    );
+    builder.metrics
+}
+
+#[derive(Clone)]
+pub struct OtlpExporter {
+    client: Client,
+    url: String,
+    headers: HeaderMap,
+    resource: Attributes,
+}
+
+impl OtlpExporter {
+    pub fn new(config: &Configuration, settings: &OtlpSettings) -> Result<Self, VmMonitorError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &settings.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| VmMonitorError::ConfigError(format!("Invalid OTLP header name {:?}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| VmMonitorError::ConfigError(format!("Invalid value for OTLP header {}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+
+        let mut resource = vec![
+            ("service.name", SCOPE_NAME.to_string()),
+            ("service.version", env!("CARGO_PKG_VERSION").to_string()),
+            ("service.instance.id", config.instance_id.to_string()),
+            ("host.id", config.instance_id.to_string()),
+            ("vm_monitor.instance.name", config.instance_name.clone()),
+            ("os.type", match std::env::consts::OS {
+                "macos" => "darwin".to_string(),
+                os => os.to_string(),
+            }),
+        ];
+        let cloud_provider = match &config.cloud_provider {
+            CloudProvider::AWS => Some("aws"),
+            CloudProvider::GCP => Some("gcp"),
+            CloudProvider::Azure => Some("azure"),
+            CloudProvider::Unknown(_) => None,
+        };
+        if let Some(cloud_provider) = cloud_provider {
+            resource.push(("cloud.provider", cloud_provider.to_string()));
+        }
+
+        Ok(OtlpExporter {
+            client: Client::builder().timeout(Duration::from_secs(settings.timeout_seconds)).build()?,
+            url: format!("{}/v1/metrics", settings.endpoint.trim_end_matches('/')),
+            headers,
+            resource,
+        })
+    }
+
+    /// Builds the export request for one sample. The returned future doesn't borrow the
+    /// exporter or the sample, so it can be spawned without holding up collection.
+    pub fn export(&self, metrics: &SystemMetrics) -> impl Future<Output = Result<(), VmMonitorError>> + use<> {
+        let mut resource: Vec<(&str, String)> = self.resource.clone();
+        resource.push(("host.name", metrics.system_info.hostname.clone()));
+        resource.extend(metrics.tags.iter().map(|(key, value)| (key.as_str(), value.clone())));
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": {"attributes": attributes(&resource)},
+                "scopeMetrics": [{
+                    "scope": {"name": SCOPE_NAME, "version": env!("CARGO_PKG_VERSION")},
+                    "metrics": build_metrics(metrics),
+                }],
+            }],
+        });
+        let request = self.client.post(&self.url).headers(self.headers.clone()).json(&body);
+
+        async move {
+            let response = request.send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                let text = response.text().await.unwrap_or_default();
+                return Err(VmMonitorError::ApiError(format!("OTLP collector returned {}: {}", status, text)));
+            }
+            // A collector that dropped some points still answers 200, with the details in the body.
+            if let Some(partial) = response.json::<ExportResponse>().await.ok().and_then(|response| response.partial_success) {
+                let rejected: u64 = match &partial.rejected_data_points {
+                    Some(Value::String(count)) => count.parse().unwrap_or_default(),
+                    Some(Value::Number(count)) => count.as_u64().unwrap_or_default(),
+                    _ => 0,
+                };
+                if rejected > 0 || !partial.error_message.is_empty() {
+                    log::warn!("OTLP collector rejected {} data points: {}", rejected, partial.error_message);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Sink for OtlpExporter {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    // Spawned, so a slow collector doesn't hold up collection or the other sinks.
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        for sample in metrics {
+            let export = self.export(sample);
+            tokio::spawn(async move {
+                if let Err(e) = export.await {
+                    log::warn!("Failed to export metrics over OTLP: {}", e);
+                }
+            });
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
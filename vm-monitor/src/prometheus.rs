@@ -0,0 +1,434 @@
+// Optional scrape endpoint serving the latest sample in the Prometheus text format, so
+// an existing Prometheus can pull from the agent alongside the push API. The server is
+// deliberately minimal: one GET per connection, no keep-alive, no TLS.
+use crate::errors::VmMonitorError;
+use crate::monitor::{CircuitState, SystemMetrics};
+use crate::sink::{Sink, SinkFuture};
+use std::fmt::Write as _;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+const MAX_REQUEST_HEAD_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+type Labels<'a> = Vec<(&'a str, String)>;
+
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    // Families without samples are left out entirely rather than exposed with only HELP/TYPE.
+    fn family<'a>(&mut self, name: &str, kind: &str, help: &str, samples: impl IntoIterator<Item = (Labels<'a>, f64)>) {
+        let samples: Vec<(Labels<'a>, f64)> = samples.into_iter().collect();
+        if samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(self.out, "# HELP vm_monitor_{} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE vm_monitor_{} {}", name, kind);
+        for (labels, value) in samples {
+            self.out.push_str("vm_monitor_");
+            self.out.push_str(name);
+            if !labels.is_empty() {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                    .collect();
+                let _ = write!(self.out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(self.out, " {}", format_value(value));
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: Option<f64>) {
+        self.family(name, "gauge", help, value.map(|value| (Vec::new(), value)));
+    }
+}
+
+// Label names are limited to [a-zA-Z_][a-zA-Z0-9_]*, so a tag like "cost-center" becomes cost_center.
+// Names starting with "__" are reserved, so an empty tag key or one that would become such a name
+// has none; the config check refuses those, and keys that end up with the same name.
+pub fn label_name(key: &str) -> Option<String> {
+    let name: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    let name = if name.starts_with(|c: char| c.is_ascii_digit()) { format!("_{}", name) } else { name };
+    (!name.is_empty() && !name.starts_with("__")).then_some(name)
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn flag(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Renders one sample in the Prometheus text exposition format (version 0.0.4).
+/// Durations and latencies are converted to seconds, following Prometheus naming conventions.
+pub fn render(metrics: &SystemMetrics) -> String {
+    let mut exp = Exposition { out: String::new() };
+    let info = &metrics.system_info;
+
+    exp.family("info", "gauge", "Agent and host details, always 1.", [(
+        vec![
+            ("instance_id", metrics.instance_id.to_string()),
+            ("hostname", info.hostname.clone()),
+            ("os_name", info.os_name.clone()),
+            ("os_version", info.os_version.clone()),
+            ("kernel_version", info.kernel_version.clone()),
+            ("agent_version", env!("CARGO_PKG_VERSION").to_string()),
+        ],
+        1.0,
+    )]);
+    // Joined onto other series in queries, e.g. `* on(instance) group_left(env) vm_monitor_tags`
+    let mut tag_names: Vec<(String, &String)> = Vec::new();
+    for (key, value) in &metrics.tags {
+        if let Some(name) = label_name(key)
+            && !tag_names.iter().any(|(taken, _)| *taken == name)
+        {
+            tag_names.push((name, value));
+        }
+    }
+    let tags: Labels = tag_names.iter().map(|(name, value)| (name.as_str(), (*value).clone())).collect();
+    if !tags.is_empty() {
+        exp.family("tags", "gauge", "Tags from the agent's config as labels, always 1.", [(tags, 1.0)]);
+    }
+    exp.gauge("uptime_seconds", "Time since boot.", Some(info.uptime as f64));
+    exp.gauge("clock_offset_seconds", "Local clock minus NTP time.", info.clock_offset_ms.map(|ms| ms / 1000.0));
+
+    // CPU
+    let cpu = &metrics.cpu_metrics;
+    exp.gauge("cpu_usage_percent", "Overall CPU usage, relative to the cgroup quota when one is set.", Some(cpu.usage_percent as f64));
+    exp.gauge("cpu_cores", "Number of logical CPUs.", Some(cpu.core_count as f64));
+    exp.gauge("cpu_limit_cores", "cgroup CPU quota in cores.", cpu.limit_cores);
+    exp.family(
+        "cpu_core_usage_percent",
+        "gauge",
+        "Usage of each logical CPU.",
+        cpu.per_core_usage.iter().enumerate().map(|(core, usage)| (vec![("core", core.to_string())], *usage as f64)),
+    );
+    if let Some(times) = &cpu.time_breakdown {
+        exp.family(
+            "cpu_time_percent",
+            "gauge",
+            "Share of CPU time spent in each mode over the last interval.",
+            [
+                ("user", times.user_percent),
+                ("nice", times.nice_percent),
+                ("system", times.system_percent),
+                ("idle", times.idle_percent),
+                ("iowait", times.iowait_percent),
+                ("irq", times.irq_percent),
+                ("softirq", times.softirq_percent),
+                ("steal", times.steal_percent),
+            ]
+            .map(|(mode, percent)| (vec![("mode", mode.to_string())], percent as f64)),
+        );
+    }
+    if let Some(kernel) = &metrics.kernel_metrics {
+        exp.gauge("context_switches_per_second", "Context switches per second.", Some(kernel.context_switches_per_sec));
+        exp.gauge("interrupts_per_second", "Interrupts per second.", Some(kernel.interrupts_per_sec));
+        exp.gauge("procs_running", "Processes in a runnable state.", Some(kernel.procs_running as f64));
+        exp.gauge("procs_blocked", "Processes blocked on I/O.", Some(kernel.procs_blocked as f64));
+    }
+
+    // Memory
+    let memory = &metrics.memory_metrics;
+    exp.gauge("memory_total_bytes", "Total memory, or the cgroup limit when one is set.", Some(memory.total_memory as f64));
+    exp.gauge("memory_used_bytes", "Memory in use.", Some(memory.used_memory as f64));
+    exp.gauge("memory_available_bytes", "Memory available for new workloads.", Some(memory.available_memory as f64));
+    exp.gauge("memory_limit_bytes", "cgroup memory limit.", memory.limit_bytes.map(|bytes| bytes as f64));
+    exp.gauge("swap_total_bytes", "Total swap space.", Some(memory.total_swap as f64));
+    exp.gauge("swap_used_bytes", "Swap space in use.", Some(memory.used_swap as f64));
+    exp.gauge("swap_in_pages_per_second", "Pages swapped in per second.", memory.swap_in_pages_per_sec);
+    exp.gauge("swap_out_pages_per_second", "Pages swapped out per second.", memory.swap_out_pages_per_sec);
+    exp.gauge("major_faults_per_second", "Major page faults per second.", memory.major_faults_per_sec);
+
+    // Disks
+    let disk_labels = |name: &str, mount_point: &str, filesystem: &str| -> Labels<'static> {
+        vec![
+            ("device", name.to_string()),
+            ("mountpoint", mount_point.to_string()),
+            ("fstype", filesystem.to_string()),
+        ]
+    };
+    let disks = &metrics.disk_metrics;
+    let labelled = |value: fn(&crate::monitor::DiskMetric) -> Option<f64>| {
+        disks
+            .iter()
+            .filter_map(move |disk| Some((disk_labels(&disk.name, &disk.mount_point, &disk.filesystem), value(disk)?)))
+    };
+    exp.family("disk_total_bytes", "gauge", "Filesystem size.", labelled(|disk| Some(disk.total_space as f64)));
+    exp.family("disk_available_bytes", "gauge", "Filesystem space available.", labelled(|disk| Some(disk.available_space as f64)));
+    exp.family("disk_read_bytes_total", "counter", "Bytes read from the device.", labelled(|disk| Some(disk.total_read_bytes as f64)));
+    exp.family("disk_written_bytes_total", "counter", "Bytes written to the device.", labelled(|disk| Some(disk.total_written_bytes as f64)));
+    exp.family("disk_read_bytes_per_second", "gauge", "Read throughput over the last interval.", labelled(|disk| disk.read_bytes_per_sec));
+    exp.family("disk_written_bytes_per_second", "gauge", "Write throughput over the last interval.", labelled(|disk| disk.write_bytes_per_sec));
+    exp.family("disk_read_latency_seconds", "gauge", "Average read latency over the last interval.", labelled(|disk| disk.read_latency_ms.map(|ms| ms / 1000.0)));
+    exp.family("disk_write_latency_seconds", "gauge", "Average write latency over the last interval.", labelled(|disk| disk.write_latency_ms.map(|ms| ms / 1000.0)));
+    exp.family("disk_queue_depth", "gauge", "Average I/O queue depth over the last interval.", labelled(|disk| disk.avg_queue_depth));
+    exp.family("disk_io_in_flight", "gauge", "I/O requests currently in flight.", labelled(|disk| disk.io_in_flight.map(|count| count as f64)));
+    // SMART is per drive, so every partition on one carries the same report: one series each.
+    let mut drives: Vec<_> = disks.iter().filter_map(|disk| disk.smart.as_ref()).collect();
+    drives.sort_by(|a, b| a.device.cmp(&b.device));
+    drives.dedup_by(|a, b| a.device == b.device);
+    let smart_labels = |device: &str| vec![("device", device.to_string())];
+    exp.family(
+        "disk_smart_healthy",
+        "gauge",
+        "1 when the SMART self-assessment passed.",
+        drives.iter().map(|smart| (smart_labels(&smart.device), flag(smart.passed))),
+    );
+    exp.family(
+        "disk_temperature_celsius",
+        "gauge",
+        "Drive temperature reported by SMART.",
+        drives
+            .iter()
+            .filter_map(|smart| Some((smart_labels(&smart.device), smart.temperature_celsius? as f64))),
+    );
+
+    // Network
+    let interfaces = &metrics.network_metrics;
+    let labelled = |value: fn(&crate::monitor::NetworkMetric) -> Option<f64>| {
+        interfaces
+            .iter()
+            .filter_map(move |network| Some((vec![("interface", network.interface_name.clone())], value(network)?)))
+    };
+    exp.family("network_received_bytes_total", "counter", "Bytes received.", labelled(|network| Some(network.received_bytes_total as f64)));
+    exp.family("network_transmitted_bytes_total", "counter", "Bytes transmitted.", labelled(|network| Some(network.transmitted_bytes_total as f64)));
+    exp.family("network_received_bytes_per_second", "gauge", "Receive throughput over the last interval.", labelled(|network| network.received_bytes_per_sec));
+    exp.family("network_transmitted_bytes_per_second", "gauge", "Transmit throughput over the last interval.", labelled(|network| network.transmitted_bytes_per_sec));
+    exp.family("network_receive_errors", "gauge", "Receive errors over the last interval.", labelled(|network| network.receive_errors.map(|count| count as f64)));
+    exp.family("network_transmit_errors", "gauge", "Transmit errors over the last interval.", labelled(|network| network.transmit_errors.map(|count| count as f64)));
+    exp.family("network_receive_drops", "gauge", "Dropped incoming packets over the last interval.", labelled(|network| network.receive_drops.map(|count| count as f64)));
+    exp.family("network_transmit_drops", "gauge", "Dropped outgoing packets over the last interval.", labelled(|network| network.transmit_drops.map(|count| count as f64)));
+    if let Some(tcp) = &metrics.tcp_metrics {
+        exp.family(
+            "tcp_connections",
+            "gauge",
+            "TCP sockets by state.",
+            [
+                ("established", tcp.established),
+                ("syn_sent", tcp.syn_sent),
+                ("syn_recv", tcp.syn_recv),
+                ("fin_wait1", tcp.fin_wait1),
+                ("fin_wait2", tcp.fin_wait2),
+                ("time_wait", tcp.time_wait),
+                ("close", tcp.close),
+                ("close_wait", tcp.close_wait),
+                ("last_ack", tcp.last_ack),
+                ("listen", tcp.listen),
+                ("closing", tcp.closing),
+            ]
+            .map(|(state, count)| (vec![("state", state.to_string())], count as f64)),
+        );
+    }
+    if let Some(conntrack) = &metrics.conntrack_metrics {
+        exp.gauge("conntrack_entries", "Connection tracking table entries.", Some(conntrack.entries as f64));
+        exp.gauge("conntrack_max_entries", "Connection tracking table size.", Some(conntrack.max_entries as f64));
+    }
+
+    // Processes and system resources
+    exp.gauge("processes", "Number of processes.", Some(metrics.process_metrics.total_processes as f64));
+    let watched = &metrics.watched_processes;
+    let pattern_labels = |pattern: &str| vec![("pattern", pattern.to_string())];
+    exp.family("watched_process_running", "gauge", "1 when at least one process matches the pattern.", watched.iter().map(|process| (pattern_labels(&process.pattern), flag(process.running))));
+    exp.family("watched_process_count", "gauge", "Processes matching the pattern.", watched.iter().map(|process| (pattern_labels(&process.pattern), process.process_count as f64)));
+    exp.family("watched_process_cpu_usage_percent", "gauge", "CPU usage summed across matching processes.", watched.iter().map(|process| (pattern_labels(&process.pattern), process.cpu_usage_percent as f64)));
+    exp.family("watched_process_rss_bytes", "gauge", "Resident memory summed across matching processes.", watched.iter().map(|process| (pattern_labels(&process.pattern), process.rss_bytes as f64)));
+    if let Some(fds) = &metrics.fd_metrics {
+        exp.gauge("open_fds", "File descriptors allocated system-wide.", Some(fds.system_in_use as f64));
+        exp.gauge("max_fds", "System-wide file descriptor limit.", Some(fds.system_max as f64));
+    }
+    exp.gauge("entropy_available_bits", "Entropy available to the kernel's random pool.", metrics.entropy_available.map(|bits| bits as f64));
+
+    // Containers and services
+    let containers = &metrics.container_metrics;
+    let container_labels = |name: &str, image: &str| vec![("name", name.to_string()), ("image", image.to_string())];
+    exp.family("container_cpu_usage_percent", "gauge", "Container CPU usage.", containers.iter().filter_map(|container| Some((container_labels(&container.name, &container.image), container.cpu_usage_percent?))));
+    exp.family("container_memory_usage_bytes", "gauge", "Container memory usage.", containers.iter().filter_map(|container| Some((container_labels(&container.name, &container.image), container.memory_usage? as f64))));
+    exp.family("container_memory_limit_bytes", "gauge", "Container memory limit.", containers.iter().filter_map(|container| Some((container_labels(&container.name, &container.image), container.memory_limit? as f64))));
+    exp.family("container_restarts_total", "counter", "Container restarts.", containers.iter().map(|container| (container_labels(&container.name, &container.image), container.restart_count as f64)));
+    exp.family("service_healthy", "gauge", "1 when the watched service is running.", metrics.service_metrics.iter().map(|service| (vec![("name", service.name.clone())], flag(service.healthy))));
+    exp.family("service_restarts_total", "counter", "Service restarts.", metrics.service_metrics.iter().filter_map(|service| Some((vec![("name", service.name.clone())], service.restart_count? as f64))));
+
+    // GPUs
+    let gpus = &metrics.gpu_metrics;
+    let gpu_labels = |index: u32, name: &str| vec![("index", index.to_string()), ("name", name.to_string())];
+    exp.family("gpu_utilization_percent", "gauge", "GPU utilization.", gpus.iter().filter_map(|gpu| Some((gpu_labels(gpu.index, &gpu.name), gpu.utilization_percent? as f64))));
+    exp.family("gpu_memory_used_bytes", "gauge", "GPU memory in use.", gpus.iter().filter_map(|gpu| Some((gpu_labels(gpu.index, &gpu.name), gpu.memory_used? as f64))));
+    exp.family("gpu_memory_total_bytes", "gauge", "GPU memory size.", gpus.iter().filter_map(|gpu| Some((gpu_labels(gpu.index, &gpu.name), gpu.memory_total? as f64))));
+    exp.family("gpu_temperature_celsius", "gauge", "GPU temperature.", gpus.iter().filter_map(|gpu| Some((gpu_labels(gpu.index, &gpu.name), gpu.temperature_celsius? as f64))));
+    exp.family("gpu_power_draw_watts", "gauge", "GPU power draw.", gpus.iter().filter_map(|gpu| Some((gpu_labels(gpu.index, &gpu.name), gpu.power_draw_watts? as f64))));
+
+    // Power
+    if let Some(power) = &metrics.power_metrics {
+        exp.gauge("on_ac_power", "1 when running on mains power.", power.on_ac_power.map(flag));
+        exp.family(
+            "battery_capacity_percent",
+            "gauge",
+            "Battery charge.",
+            power.batteries.iter().filter_map(|battery| Some((vec![("name", battery.name.clone())], battery.capacity_percent? as f64))),
+        );
+    }
+
+    // Checks
+    exp.family(
+        "certificate_expiry_timestamp_seconds",
+        "gauge",
+        "When the first certificate in the chain expires.",
+        metrics
+            .certificate_expiry
+            .iter()
+            .filter_map(|cert| Some((vec![("source", cert.source.clone())], cert.not_after?.timestamp() as f64))),
+    );
+    let checks = &metrics.http_checks;
+    let check_labels = |name: &str, url: &str| vec![("name", name.to_string()), ("url", url.to_string())];
+    exp.family("http_check_up", "gauge", "1 when the probe got a response without error.", checks.iter().map(|check| (check_labels(&check.name, &check.url), flag(check.status_code.is_some() && check.error.is_none()))));
+    exp.family("http_check_status_code", "gauge", "HTTP status code of the last probe.", checks.iter().filter_map(|check| Some((check_labels(&check.name, &check.url), check.status_code? as f64))));
+    exp.family("http_check_duration_seconds", "gauge", "Duration of the last probe.", checks.iter().filter_map(|check| Some((check_labels(&check.name, &check.url), check.latency_ms? / 1000.0))));
+    let pings = &metrics.ping_results;
+    let ping_labels = |host: &str, port: Option<u16>| vec![("host", host.to_string()), ("port", port.map(|port| port.to_string()).unwrap_or_default())];
+    exp.family("ping_up", "gauge", "1 when the target answered.", pings.iter().map(|ping| (ping_labels(&ping.host, ping.port), flag(ping.reachable))));
+    exp.family("ping_rtt_seconds", "gauge", "Round-trip time of the last probe.", pings.iter().filter_map(|ping| Some((ping_labels(&ping.host, ping.port), ping.rtt_ms? / 1000.0))));
+    let lookups = &metrics.dns_checks;
+    let dns_labels = |name: &str, server: &Option<String>| vec![("name", name.to_string()), ("server", server.clone().unwrap_or_default())];
+    exp.family("dns_check_up", "gauge", "1 when the name resolved.", lookups.iter().map(|lookup| (dns_labels(&lookup.name, &lookup.server), flag(lookup.success))));
+    exp.family("dns_check_duration_seconds", "gauge", "Duration of the last lookup.", lookups.iter().filter_map(|lookup| Some((dns_labels(&lookup.name, &lookup.server), lookup.latency_ms? / 1000.0))));
+    exp.family(
+        "log_pattern_matches",
+        "gauge",
+        "Lines matching the pattern since the previous sample.",
+        metrics
+            .log_pattern_counts
+            .iter()
+            .filter_map(|count| Some((vec![("path", count.path.clone()), ("pattern", count.pattern.clone())], count.matches? as f64))),
+    );
+    exp.family(
+        "custom_metric",
+        "untyped",
+        "Values from custom collectors and the textfile directory.",
+        metrics.custom_metrics.iter().map(|(name, value)| (vec![("name", name.clone())], *value)),
+    );
+
+    // The agent itself
+    if let Some(agent) = &metrics.agent_metrics {
+        exp.family(
+            "api_circuit_state",
+            "gauge",
+            "1 for the current state of the API circuit breaker.",
+            [(CircuitState::Closed, "closed"), (CircuitState::Open, "open"), (CircuitState::HalfOpen, "half_open")]
+                .map(|(state, name)| (vec![("state", name.to_string())], flag(agent.api_circuit_state == state))),
+        );
+        exp.gauge("api_consecutive_failures", "API requests failed in a row.", Some(agent.api_consecutive_failures as f64));
+        exp.gauge("spooled_bytes", "Unsent batches waiting in the spool.", Some(agent.spooled_bytes as f64));
+    }
+    exp.out
+}
+
+pub struct PrometheusSink {
+    latest: watch::Sender<String>, // Rendered exposition of the newest sample
+}
+
+/// Binds the scrape endpoint and serves `/metrics` in the background, always with the
+/// newest sample sent to the returned sink. Until the first one, scrapes get a 503.
+pub async fn start_server(address: &str) -> Result<PrometheusSink, VmMonitorError> {
+    let listener = TcpListener::bind(address).await?;
+    log::info!("Serving Prometheus metrics at http://{}/metrics", listener.local_addr()?);
+    let (latest, receiver) = watch::channel(String::new());
+    tokio::spawn(serve(listener, receiver));
+    Ok(PrometheusSink { latest })
+}
+
+impl Sink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        if let Some(newest) = metrics.last() {
+            self.latest.send_replace(render(newest));
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+async fn serve(listener: TcpListener, latest: watch::Receiver<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let latest = latest.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, latest).await {
+                        log::debug!("Metrics scrape from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::warn!("Failed to accept metrics scrape connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await; // e.g. out of file descriptors
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, latest: watch::Receiver<String>) -> std::io::Result<()> {
+    // Only the request line matters; headers are read up to the blank line and ignored.
+    let mut head = Vec::new();
+    let read_head = async {
+        let mut chunk = [0u8; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD_BYTES {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            head.extend_from_slice(&chunk[..read]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read_head)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default().split('?').next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET" | "HEAD", "/metrics") => {
+            let text = latest.borrow().clone();
+            if text.is_empty() {
+                ("503 Service Unavailable", "text/plain; charset=utf-8", "No metrics collected yet.\n".to_string())
+            } else {
+                ("200 OK", CONTENT_TYPE, text)
+            }
+        }
+        ("GET" | "HEAD", _) => ("404 Not Found", "text/plain; charset=utf-8", "Metrics are served at /metrics.\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "Only GET is supported.\n".to_string()),
+    };
+    let response_head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(response_head.as_bytes()).await?;
+    if method != "HEAD" {
+        stream.write_all(body.as_bytes()).await?;
+    }
+    stream.shutdown().await
+}
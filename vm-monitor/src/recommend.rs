@@ -1,3 +1,4 @@
+use crate::monitor::CloudInstance;
 use serde::Deserialize;
 
 // Struct to represent a row in our instances.csv dataset
@@ -25,6 +26,14 @@ pub fn load_vm_dataset() -> Result<Vec<VmInstance>, csv::Error> {
     reader.deserialize().collect()
 }
 
+// The dataset's entry for the type this VM runs as, from its own region when that's listed.
+pub fn current_instance<'a>(dataset: &'a [VmInstance], instance: &CloudInstance) -> Option<&'a VmInstance> {
+    let instance_type = instance.instance_type.as_deref()?;
+    let mut same_type = dataset.iter().filter(|vm| vm.instance_name.eq_ignore_ascii_case(instance_type));
+    let region = instance.region.as_deref().unwrap_or_default();
+    same_type.clone().find(|vm| vm.region.eq_ignore_ascii_case(region)).or_else(|| same_type.next())
+}
+
 pub fn recommend_vms(
     dataset: &[VmInstance],
     avg_cpu_usage_percent: f32,
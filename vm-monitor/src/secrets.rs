@@ -0,0 +1,92 @@
+// Secrets kept outside the config file. Keyring entries live under the "vm-monitor" service
+// in the platform keyring: Secret Service on Linux and FreeBSD, the Keychain on macOS and the
+// Credential Manager on Windows. Vault secrets are fetched by `vault.rs`. Key files keep the
+// API key of the system-wide config, which other users read, to root alone.
+use crate::errors::VmMonitorError;
+use std::io::Write;
+use std::path::Path;
+
+pub const KEYRING_PREFIX: &str = "keyring:"; // Marks an `api_key` that names a keyring entry
+pub const VAULT_PREFIX: &str = "vault:"; // Marks an `api_key` that names a Vault secret
+pub const KEY_FILE_PREFIX: &str = "file:"; // Marks an `api_key` that names a key file
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "vm-monitor";
+
+#[cfg(feature = "keyring")]
+fn keyring_entry(account: &str) -> Result<keyring::Entry, VmMonitorError> {
+    keyring::Entry::new(KEYRING_SERVICE, account).map_err(|e| VmMonitorError::KeyringError(e.to_string()))
+}
+
+#[cfg(feature = "keyring")]
+pub fn keyring_load(account: &str) -> Result<String, VmMonitorError> {
+    keyring_entry(account)?
+        .get_password()
+        .map_err(|e| VmMonitorError::KeyringError(format!("Failed to read the API key ({}): {}", account, e)))
+}
+
+// Saving the config rewrites the key each time, so unchanged keys are left alone.
+#[cfg(feature = "keyring")]
+pub fn keyring_store(account: &str, secret: &str) -> Result<(), VmMonitorError> {
+    let entry = keyring_entry(account)?;
+    if entry.get_password().is_ok_and(|stored| stored == secret) {
+        return Ok(());
+    }
+    entry
+        .set_password(secret)
+        .map_err(|e| VmMonitorError::KeyringError(format!("Failed to store the API key ({}): {}", account, e)))
+}
+
+// For an entry left behind when the key moved to another account. One that's already gone is fine.
+#[cfg(feature = "keyring")]
+pub fn keyring_delete(account: &str) -> Result<(), VmMonitorError> {
+    match keyring_entry(account)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(VmMonitorError::KeyringError(format!("Failed to delete the API key ({}): {}", account, e))),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn keyring_load(_account: &str) -> Result<String, VmMonitorError> {
+    Err(VmMonitorError::KeyringError("The API key is in the OS keyring, which needs a build with the `keyring` feature".to_string()))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn keyring_store(_account: &str, _secret: &str) -> Result<(), VmMonitorError> {
+    Err(VmMonitorError::KeyringError("The OS keyring needs a build with the `keyring` feature".to_string()))
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn keyring_delete(_account: &str) -> Result<(), VmMonitorError> {
+    Err(VmMonitorError::KeyringError("The OS keyring needs a build with the `keyring` feature".to_string()))
+}
+
+/// Whether an `api_key` read from a config file names where the key is, rather than being it.
+pub fn is_reference(api_key: &str) -> bool {
+    [KEYRING_PREFIX, VAULT_PREFIX, KEY_FILE_PREFIX].iter().any(|prefix| api_key.starts_with(prefix))
+}
+
+pub fn key_file_load(path: &str) -> Result<String, VmMonitorError> {
+    let key = std::fs::read_to_string(path)
+        .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read the API key from {}: {}", path, e)))?;
+    Ok(key.trim().to_string())
+}
+
+// Replaced whole like the config, and readable by its owner only.
+pub fn key_file_store(path: &str, secret: &str) -> Result<(), VmMonitorError> {
+    if key_file_load(path).is_ok_and(|stored| stored == secret) {
+        return Ok(());
+    }
+    let path = Path::new(path);
+    let temp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp_path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?; // Also when a stale temp file was there
+    writeln!(file, "{}", secret)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+// Destinations for collected metrics. `start` fans every sample out to each configured sink,
+// either as it's collected or in batches of `batch_size`. Sinks run concurrently and fail
+// independently: an error is logged and the others still get their data. Sinks that keep undelivered data for a
+// later attempt (the API's spool, Kafka's pending queue) do so themselves.
+use crate::errors::VmMonitorError;
+use crate::monitor::{CircuitState, SystemMetrics};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+// Boxed so sinks can be held as trait objects.
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), VmMonitorError>> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Sample, // Every sample as soon as it's collected
+    Batch, // `batch_size` samples at a time
+}
+
+// A sink's own view of its delivery, reported in the agent's self-metrics.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct SinkHealth {
+    pub circuit_state: CircuitState, // Closed for sinks without a circuit breaker
+    pub consecutive_failures: u32,
+    pub backlog_bytes: u64, // Undelivered data kept on disk
+}
+
+impl Default for SinkHealth {
+    fn default() -> Self {
+        SinkHealth { circuit_state: CircuitState::Closed, consecutive_failures: 0, backlog_bytes: 0 }
+    }
+}
+
+pub trait Sink: Send {
+    fn name(&self) -> &'static str;
+
+    fn delivery(&self) -> Delivery {
+        Delivery::Sample
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a>;
+
+    /// Called once per collection cycle, for sinks that signal liveness or have
+    /// periodic work of their own. Sinks decide themselves when a heartbeat is due.
+    fn send_heartbeat(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn health(&self) -> SinkHealth {
+        SinkHealth::default()
+    }
+
+    /// Delivers data held back for a later attempt right away, when the API asks for it.
+    fn drain_backlog(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Delivers anything still queued, on shutdown.
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+pub fn report(name: &str, action: &str, result: Result<(), VmMonitorError>) {
+    match result {
+        Ok(()) => {}
+        // Already logged when the circuit opened or the backoff started, or expected while rate limited
+        Err(e @ (VmMonitorError::CircuitOpen(_) | VmMonitorError::Throttled(_) | VmMonitorError::RateLimited(_))) => {
+            log::debug!("Not {} {}: {}", action, name, e)
+        }
+        Err(e) => log::warn!("Error {} {}: {}", action, name, e),
+    }
+}
+
+// Runs `call` on the sinks `filter` picks, all at once so a slow sink doesn't hold up the
+// others. One still running after `timeout` is cut off where it was; sinks that keep
+// undelivered data still have it for their next attempt.
+async fn each<'a>(
+    sinks: &'a mut [Box<dyn Sink>],
+    timeout: Option<Duration>,
+    filter: impl Fn(&dyn Sink) -> bool,
+    call: impl Fn(&'a mut Box<dyn Sink>) -> SinkFuture<'a>,
+) -> Vec<(&'static str, Result<(), VmMonitorError>)> {
+    let calls = sinks.iter_mut().filter(|sink| filter(sink.as_ref())).map(|sink| {
+        let name = sink.name();
+        let future = call(sink);
+        async move {
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, future)
+                    .await
+                    .unwrap_or_else(|_| Err(VmMonitorError::SinkError(format!("gave up after {}s", timeout.as_secs())))),
+                None => future.await,
+            };
+            (name, result)
+        }
+    });
+    join_all(calls).await
+}
+
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+    batch: Vec<SystemMetrics>, // Samples waiting for the batch sinks
+    batch_size: usize,
+    timeout: Option<Duration>, // For each sink's part of a send, heartbeat or flush
+}
+
+impl Sinks {
+    pub fn new(batch_size: usize, timeout_seconds: u64) -> Self {
+        let timeout = (timeout_seconds > 0).then(|| Duration::from_secs(timeout_seconds));
+        Sinks { sinks: Vec::new(), batch: Vec::new(), batch_size: batch_size.max(1), timeout }
+    }
+
+    pub fn add(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    pub fn health(&self, name: &str) -> Option<SinkHealth> {
+        self.sinks.iter().find(|sink| sink.name() == name).map(|sink| sink.health())
+    }
+
+    pub fn healths(&self) -> Vec<(&'static str, SinkHealth)> {
+        self.sinks.iter().map(|sink| (sink.name(), sink.health())).collect()
+    }
+
+    /// Sends the partial batch now instead of waiting for it to fill up.
+    pub async fn send_pending(&mut self) {
+        if !self.batch.is_empty() {
+            self.send_batch().await;
+        }
+    }
+
+    /// Drains every sink's backlog, returning the errors of those that couldn't.
+    pub async fn drain_backlogs(&mut self) -> Vec<String> {
+        each(&mut self.sinks, self.timeout, |_| true, |sink| sink.drain_backlog())
+            .await
+            .into_iter()
+            .filter_map(|(name, result)| result.err().map(|e| format!("{}: {}", name, e)))
+            .collect()
+    }
+
+    // Takes effect from the next batch; samples already collected stay in the current one.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    pub async fn record(&mut self, metrics: SystemMetrics) {
+        let sample = std::slice::from_ref(&metrics);
+        let sample_sinks = |sink: &dyn Sink| sink.delivery() == Delivery::Sample;
+        for (name, result) in each(&mut self.sinks, self.timeout, sample_sinks, |sink| sink.send_batch(sample)).await {
+            report(name, "sending metrics to", result);
+        }
+        if !self.sinks.iter().any(|sink| sink.delivery() == Delivery::Batch) {
+            return;
+        }
+        self.batch.push(metrics);
+        log::info!("Collected metrics. Batch size: {}", self.batch.len());
+        if self.batch.len() >= self.batch_size {
+            log::info!("Batch limit reached ({} items). Sending metrics...", self.batch.len());
+            self.send_batch().await;
+        }
+    }
+
+    async fn send_batch(&mut self) {
+        let batch = &self.batch;
+        let batch_sinks = |sink: &dyn Sink| sink.delivery() == Delivery::Batch;
+        for (name, result) in each(&mut self.sinks, self.timeout, batch_sinks, |sink| sink.send_batch(batch)).await {
+            report(name, "sending metrics batch to", result);
+        }
+        self.batch.clear();
+    }
+
+    pub async fn heartbeat(&mut self) {
+        for (name, result) in each(&mut self.sinks, self.timeout, |_| true, |sink| sink.send_heartbeat()).await {
+            report(name, "sending heartbeat to", result);
+        }
+    }
+
+    /// Sends the partial batch and flushes every sink, on shutdown.
+    pub async fn flush(&mut self) {
+        let final_batch = !self.batch.is_empty();
+        if final_batch {
+            log::info!("Sending remaining {} metrics before shutdown...", self.batch.len());
+            self.send_batch().await;
+        }
+        // Sending the batch already tried everything the batch sinks had queued
+        let unflushed = |sink: &dyn Sink| !(final_batch && sink.delivery() == Delivery::Batch);
+        for (name, result) in each(&mut self.sinks, self.timeout, unflushed, |sink| sink.flush()).await {
+            report(name, "flushing metrics to", result);
+        }
+    }
+}
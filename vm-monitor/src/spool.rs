@@ -0,0 +1,263 @@
+// Durable queue for metrics batches the API didn't accept. Each batch is appended as one
+// JSON line to numbered segment files, which are replayed oldest first once the API is
+// reachable again and deleted when fully sent.
+use crate::errors::VmMonitorError;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const SEGMENT_MAX_BYTES: u64 = 4 * 1024 * 1024;
+const CURSOR_FILE_NAME: &str = "cursor"; // "<segment> <offset>" of the next unsent batch
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segments: VecDeque<u64>, // Sequence numbers, oldest first
+    next_segment: u64,
+    // Size of the newest segment while it accepts appends. `None` forces a new segment,
+    // so a line left half-written by a crash never gets glued to the next batch.
+    writable_size: Option<u64>,
+    cursor: u64, // Offset of the next unsent batch in the oldest segment
+    pending_cursor: Option<u64>, // Offset after the batch returned by `next_batch`
+}
+
+impl Spool {
+    pub fn open(dir: PathBuf, max_bytes: u64) -> Result<Self, VmMonitorError> {
+        fs::create_dir_all(&dir)?;
+        let mut segments: Vec<u64> = fs::read_dir(&dir)?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect();
+        segments.sort_unstable();
+
+        let cursor = fs::read_to_string(dir.join(CURSOR_FILE_NAME))
+            .ok()
+            .and_then(|content| {
+                let (segment, offset) = content.trim().split_once(' ')?;
+                if segment.parse::<u64>().ok()? != *segments.first()? {
+                    return None; // Left behind by an already deleted segment
+                }
+                offset.parse().ok()
+            })
+            .unwrap_or(0);
+
+        let spool = Spool {
+            next_segment: segments.last().map_or(1, |last| last + 1),
+            segments: segments.into(),
+            dir,
+            max_bytes,
+            writable_size: None,
+            cursor,
+            pending_cursor: None,
+        };
+        if !spool.is_empty() {
+            log::info!(
+                "Found {} bytes of unsent metrics in {} spool segment(s).",
+                spool.size_bytes(),
+                spool.segments.len()
+            );
+        }
+        Ok(spool)
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{:010}.{}", segment, SEGMENT_EXTENSION))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.segments
+            .iter()
+            .filter_map(|segment| fs::metadata(self.segment_path(*segment)).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+            .saturating_sub(self.cursor)
+    }
+
+    pub fn append<T: Serialize>(&mut self, batch: &[T]) -> Result<(), VmMonitorError> {
+        let mut line = serde_json::to_string(batch)?;
+        line.push('\n');
+
+        let (segment, size) = match (self.writable_size, self.segments.back()) {
+            (Some(size), Some(&newest)) if size < SEGMENT_MAX_BYTES => (newest, size),
+            _ => {
+                let segment = self.next_segment;
+                self.segments.push_back(segment);
+                self.next_segment += 1;
+                (segment, 0)
+            }
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(self.segment_path(segment))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.writable_size = Some(size + line.len() as u64);
+
+        self.enforce_size_limit();
+        Ok(())
+    }
+
+    // Drops whole segments, oldest first, but never the one being written.
+    fn enforce_size_limit(&mut self) {
+        while self.segments.len() > 1 && self.size_bytes() > self.max_bytes {
+            log::warn!(
+                "Metrics spool exceeds {} bytes, dropping its oldest segment.",
+                self.max_bytes
+            );
+            self.remove_oldest_segment();
+        }
+    }
+
+    fn remove_oldest_segment(&mut self) {
+        if let Some(oldest) = self.segments.pop_front()
+            && let Err(e) = fs::remove_file(self.segment_path(oldest))
+        {
+            log::warn!("Failed to remove spool segment {}: {}", oldest, e);
+        }
+        if self.segments.is_empty() {
+            self.writable_size = None;
+        }
+        self.cursor = 0;
+        self.pending_cursor = None;
+        let _ = fs::remove_file(self.dir.join(CURSOR_FILE_NAME));
+    }
+
+    /// The oldest unsent batch, as JSON values since `SystemMetrics` is serialize-only.
+    /// Call `commit` once it has been delivered.
+    pub fn next_batch(&mut self) -> Result<Option<Vec<serde_json::Value>>, VmMonitorError> {
+        while let Some(&oldest) = self.segments.front() {
+            let mut reader = BufReader::new(File::open(self.segment_path(oldest))?);
+            reader.seek(SeekFrom::Start(self.cursor))?;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 {
+                    break;
+                }
+                let end = self.cursor + read as u64;
+                match serde_json::from_str(&line) {
+                    Ok(batch) => {
+                        self.pending_cursor = Some(end);
+                        return Ok(Some(batch));
+                    }
+                    Err(e) => {
+                        log::warn!("Skipping unreadable batch in spool segment {}: {}", oldest, e);
+                        self.cursor = end;
+                    }
+                }
+            }
+            self.remove_oldest_segment(); // Fully sent
+        }
+        Ok(None)
+    }
+
+    pub fn commit(&mut self) -> Result<(), VmMonitorError> {
+        let (Some(cursor), Some(oldest)) = (self.pending_cursor.take(), self.segments.front()) else {
+            return Ok(());
+        };
+        self.cursor = cursor;
+        fs::write(self.dir.join(CURSOR_FILE_NAME), format!("{} {}", oldest, cursor))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn batch(n: u64) -> Vec<Value> {
+        vec![json!({ "n": n })]
+    }
+
+    fn segment_files(dir: &std::path::Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == SEGMENT_EXTENSION))
+            .count()
+    }
+
+    #[test]
+    fn reopens_after_a_partial_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        spool.append(&batch(1)).unwrap();
+        spool.append(&batch(2)).unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(1)));
+        spool.commit().unwrap();
+        // A crash halfway through the next append
+        let mut file = OpenOptions::new().append(true).open(spool.segment_path(1)).unwrap();
+        file.write_all(br#"[{"n":"#).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        spool.append(&batch(3)).unwrap();
+        assert_eq!(segment_files(dir.path()), 2, "appends after reopening go to a new segment");
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(2)));
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(3)));
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), None);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn replays_oldest_first_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        spool.append(&batch(1)).unwrap();
+        spool.append(&batch(2)).unwrap();
+        drop(spool);
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        spool.append(&batch(3)).unwrap();
+        assert_eq!(segment_files(dir.path()), 2);
+
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(1)));
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(1)), "not committed, so offered again");
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(2)));
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(3)));
+        assert_eq!(segment_files(dir.path()), 1, "the first segment is deleted once fully sent");
+        spool.commit().unwrap();
+
+        // The cursor survives a restart
+        spool.append(&batch(4)).unwrap();
+        spool.append(&batch(5)).unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(4)));
+        spool.commit().unwrap();
+        drop(spool);
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(5)));
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), None);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn evicting_the_pending_segment_drops_its_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        spool.append(&batch(1)).unwrap();
+        spool.append(&batch(2)).unwrap();
+        drop(spool);
+        let mut spool = Spool::open(dir.path().to_path_buf(), 1).unwrap();
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(1)));
+
+        spool.append(&batch(3)).unwrap(); // Over the limit, so the first segment goes
+        assert_eq!(segment_files(dir.path()), 1);
+        spool.commit().unwrap(); // Must not skip anything in the remaining segment
+        assert_eq!(spool.next_batch().unwrap(), Some(batch(3)));
+        spool.commit().unwrap();
+        assert_eq!(spool.next_batch().unwrap(), None);
+    }
+}
@@ -0,0 +1,103 @@
+// Sends every sample as StatsD gauges over UDP, for shops with an existing StatsD or
+// Telegraf relay. Fire-and-forget: lost datagrams are simply gaps in the graphs.
+use crate::config::StatsdSettings;
+use crate::errors::VmMonitorError;
+use crate::gauges;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use tokio::net::{UdpSocket, lookup_host};
+
+// Stays under a 1500-byte Ethernet MTU after IP and UDP headers, as the StatsD docs recommend.
+const MAX_DATAGRAM_BYTES: usize = 1432;
+
+// Label values become part of the metric path, so dots and separators StatsD
+// uses (':', '|', '@') are replaced. A mount point of "/" becomes "root".
+fn sanitize(component: &str) -> String {
+    let component = component.trim_matches('/');
+    if component.is_empty() {
+        return "root".to_string();
+    }
+    component
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+// Dimensions go into the path, e.g. "disk.root.total_bytes"; custom metrics are already dotted,
+// "custom.<collector>.<metric>".
+fn gauges(metrics: &SystemMetrics) -> Vec<(String, f64)> {
+    gauges::gauges(metrics)
+        .into_iter()
+        .map(|gauge| {
+            let name = match &gauge.dimension {
+                None => gauge.path("."),
+                Some((_, value)) if gauge.name.is_empty() => {
+                    format!("{}.{}", gauge.group, value.split('.').map(sanitize).collect::<Vec<_>>().join("."))
+                }
+                Some((_, value)) => format!("{}.{}.{}", gauge.group, sanitize(value), gauge.name),
+            };
+            (name, gauge.value)
+        })
+        .collect()
+}
+
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdSink {
+    // The relay's address is resolved once; restart the agent if it moves.
+    pub async fn connect(settings: &StatsdSettings) -> Result<Self, VmMonitorError> {
+        let address = lookup_host(&settings.address)
+            .await?
+            .next()
+            .ok_or_else(|| VmMonitorError::ConfigError(format!("StatsD address {} didn't resolve", settings.address)))?;
+        let socket = UdpSocket::bind(if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(address).await?;
+        Ok(StatsdSink {
+            socket,
+            prefix: settings.prefix.trim_end_matches('.').to_string(),
+        })
+    }
+
+    async fn send(&self, metrics: &SystemMetrics) -> Result<(), VmMonitorError> {
+        let mut datagram = String::new();
+        for (name, value) in gauges(metrics) {
+            let name = if self.prefix.is_empty() { name } else { format!("{}.{}", self.prefix, name) };
+            // A signed gauge value means "adjust by", so negative values are set by zeroing first.
+            let line = if value < 0.0 {
+                format!("{name}:0|g\n{name}:{value}|g")
+            } else {
+                format!("{name}:{value}|g")
+            };
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_BYTES {
+                self.socket.send(datagram.as_bytes()).await?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for sample in metrics {
+                self.send(sample).await?;
+            }
+            Ok(())
+        })
+    }
+}
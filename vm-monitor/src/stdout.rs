@@ -0,0 +1,39 @@
+// `start --stdout`: every sample as one JSON line on stdout, for piping into jq, Vector,
+// Fluent Bit and the like. Logs go to stderr, so the stream stays pure JSON.
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use std::io::{ErrorKind, Write};
+
+#[derive(Default)]
+pub struct StdoutSink {
+    closed: bool, // The reader went away; the agent keeps running for its other sinks
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            if self.closed {
+                return Ok(());
+            }
+            let mut lines = Vec::new();
+            for sample in metrics {
+                serde_json::to_writer(&mut lines, sample)?;
+                lines.push(b'\n');
+            }
+            // Flushed every time, since a pipe would otherwise hold lines back in the buffer.
+            let mut stdout = std::io::stdout().lock();
+            match stdout.write_all(&lines).and_then(|()| stdout.flush()) {
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                    log::warn!("stdout was closed, no longer printing metrics.");
+                    self.closed = true;
+                    Ok(())
+                }
+                result => Ok(result?),
+            }
+        })
+    }
+}
@@ -0,0 +1,351 @@
+// RFC 5424 syslog output: a one-line summary of every sample, plus the agent's own
+// warnings and errors, for environments where everything has to go through syslog.
+// Writes are synchronous because agent events arrive through the `log` facade. They never
+// connect, though: a TCP connection that's down queues them, and the sink reconnects.
+use crate::config::{SyslogSettings, SyslogTransport};
+use crate::errors::VmMonitorError;
+use crate::monitor::SystemMetrics;
+use crate::sink::{Sink, SinkFuture};
+use chrono::{SecondsFormat, Utc};
+use log::{Level, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+// RFC 5612's documentation enterprise number; the project has no registered one.
+const SD_ID: &str = "vm-monitor@32473";
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_QUEUED: usize = 1000; // Messages kept while the TCP connection is down; the oldest go first
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+enum Connection {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpConnection),
+}
+
+struct TcpConnection {
+    stream: Option<TcpStream>, // `None` after a failed write, until `SyslogWriter::reconnect`
+    queued: VecDeque<String>, // Framed messages written meanwhile, sent once reconnected
+    retry_at: Instant,
+    backoff: Duration, // Doubled after each failed reconnect
+}
+
+impl TcpConnection {
+    fn write(&mut self, framed: String) {
+        if let Some(stream) = &mut self.stream {
+            if stream.write_all(framed.as_bytes()).is_ok() {
+                return;
+            }
+            self.stream = None; // The message goes out again whole, in case part of it was sent
+        }
+        if self.queued.len() >= MAX_QUEUED {
+            self.queued.pop_front();
+        }
+        self.queued.push_back(framed);
+    }
+}
+
+pub struct SyslogWriter {
+    settings: SyslogSettings,
+    connection: Mutex<Connection>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+}
+
+fn facility_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "kern" => 0,
+        "user" => 1,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    })
+}
+
+// Header fields are printable ASCII without spaces, "-" when empty (RFC 5424 section 6.2).
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max_len).collect();
+    if value.is_empty() { "-".to_string() } else { value }
+}
+
+fn escape_param_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+fn connect_tcp(address: &str) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, TCP_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} has no address", address))))
+}
+
+impl SyslogWriter {
+    pub fn new(settings: &SyslogSettings) -> Result<Self, VmMonitorError> {
+        let facility = facility_code(&settings.facility)
+            .ok_or_else(|| VmMonitorError::ConfigError(format!("Unknown syslog facility {:?}", settings.facility)))?;
+        let remote_address = || {
+            settings
+                .address
+                .clone()
+                .ok_or_else(|| VmMonitorError::ConfigError("Syslog over UDP or TCP needs an address (host:port)".to_string()))
+        };
+        let connection = match settings.transport {
+            #[cfg(unix)]
+            SyslogTransport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                let path = settings.address.clone().unwrap_or_else(|| {
+                    if cfg!(target_os = "macos") { "/var/run/syslog" } else { "/dev/log" }.to_string()
+                });
+                socket.connect(&path)?;
+                Connection::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTransport::Unix => {
+                return Err(VmMonitorError::ConfigError("Syslog over a local socket is only available on Unix".to_string()));
+            }
+            SyslogTransport::Udp => {
+                let address = remote_address()?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&address)?;
+                Connection::Udp(socket)
+            }
+            SyslogTransport::Tcp => Connection::Tcp(TcpConnection {
+                stream: Some(connect_tcp(&remote_address()?)?),
+                queued: VecDeque::new(),
+                retry_at: Instant::now(),
+                backoff: MIN_BACKOFF,
+            }),
+        };
+        Ok(SyslogWriter {
+            settings: settings.clone(),
+            connection: Mutex::new(connection),
+            facility,
+            hostname: header_field(&sysinfo::System::host_name().unwrap_or_default(), 255),
+            app_name: header_field(&settings.app_name, 48),
+        })
+    }
+
+    fn send(&self, severity: u8, msg_id: &str, structured_data: &str, message: &str) -> std::io::Result<()> {
+        let line = format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            self.facility * 8 + severity,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            msg_id,
+            structured_data,
+            message
+        );
+        let mut connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *connection {
+            #[cfg(unix)]
+            Connection::Unix(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Connection::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Connection::Tcp(tcp) => {
+                // Octet-counting framing (RFC 6587), so messages may contain newlines.
+                tcp.write(format!("{} {}", line.len(), line));
+                Ok(())
+            }
+        }
+    }
+
+    // Reconnects a TCP connection that's down, once its backoff is over, and sends what was
+    // queued meanwhile. Connects without holding the connection, so messages logged in the
+    // meantime are queued rather than waiting, and returns errors instead of logging them,
+    // since a log record sent from here would need the connection too.
+    fn reconnect(&self) -> Result<(), VmMonitorError> {
+        {
+            let connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match &*connection {
+                Connection::Tcp(tcp) if tcp.stream.is_none() && Instant::now() >= tcp.retry_at => {}
+                _ => return Ok(()),
+            }
+        }
+        let address = self.settings.address.as_deref().unwrap_or_default();
+        let connected = connect_tcp(address);
+        let mut connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Connection::Tcp(tcp) = &mut *connection else {
+            return Ok(());
+        };
+        let failed = |tcp: &mut TcpConnection, e: std::io::Error| {
+            tcp.retry_at = Instant::now() + tcp.backoff;
+            let error = VmMonitorError::SinkError(format!("Can't reach {}, retrying in {}s: {}", address, tcp.backoff.as_secs(), e));
+            tcp.backoff = (tcp.backoff * 2).min(MAX_BACKOFF);
+            Err(error)
+        };
+        let mut stream = match connected {
+            Ok(stream) => stream,
+            Err(e) => return failed(tcp, e),
+        };
+        while let Some(framed) = tcp.queued.front() {
+            if let Err(e) = stream.write_all(framed.as_bytes()) {
+                return failed(tcp, e);
+            }
+            tcp.queued.pop_front();
+        }
+        tcp.stream = Some(stream);
+        tcp.backoff = MIN_BACKOFF;
+        Ok(())
+    }
+
+    // Logs a summary of one sample at informational severity, with the
+    // values also carried as structured data for parsing downstream.
+    fn write_summary(&self, metrics: &SystemMetrics) -> Result<(), VmMonitorError> {
+        let memory = &metrics.memory_metrics;
+        let percent = |used: u64, total: u64| if total > 0 { used as f64 / total as f64 * 100.0 } else { 0.0 };
+        let memory_percent = percent(memory.used_memory, memory.total_memory);
+        let swap_percent = percent(memory.used_swap, memory.total_swap);
+        let fullest_disk = metrics
+            .disk_metrics
+            .iter()
+            .map(|disk| (disk, percent(disk.total_space.saturating_sub(disk.available_space), disk.total_space)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        // Folded from 0.0 because an empty f64 `sum()` is -0.0, which would print as "-0".
+        let received = metrics.network_metrics.iter().filter_map(|network| network.received_bytes_per_sec).fold(0.0, |a, b| a + b);
+        let transmitted = metrics.network_metrics.iter().filter_map(|network| network.transmitted_bytes_per_sec).fold(0.0, |a, b| a + b);
+        let unhealthy_services: Vec<&str> = metrics
+            .service_metrics
+            .iter()
+            .filter(|service| !service.healthy)
+            .map(|service| service.name.as_str())
+            .collect();
+
+        let mut params = vec![
+            ("instance_id", metrics.instance_id.to_string()),
+            ("cpu_percent", format!("{:.1}", metrics.cpu_metrics.usage_percent)),
+            ("memory_percent", format!("{:.1}", memory_percent)),
+            ("swap_percent", format!("{:.1}", swap_percent)),
+            ("rx_bytes_per_sec", format!("{:.0}", received)),
+            ("tx_bytes_per_sec", format!("{:.0}", transmitted)),
+            ("processes", metrics.process_metrics.total_processes.to_string()),
+        ];
+        if let Some((disk, used)) = fullest_disk {
+            params.push(("fullest_mount", disk.mount_point.clone()));
+            params.push(("fullest_mount_percent", format!("{:.1}", used)));
+        }
+        if !unhealthy_services.is_empty() {
+            params.push(("unhealthy_services", unhealthy_services.join(",")));
+        }
+        let structured_data = format!(
+            "[{} {}]",
+            SD_ID,
+            params
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_param_value(value)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        let mut message = format!(
+            "cpu {:.1}%, memory {:.1}%, swap {:.1}%, network rx {:.0} B/s tx {:.0} B/s",
+            metrics.cpu_metrics.usage_percent, memory_percent, swap_percent, received, transmitted
+        );
+        if let Some((disk, used)) = fullest_disk {
+            message.push_str(&format!(", fullest disk {} at {:.1}%", disk.mount_point, used));
+        }
+        if !unhealthy_services.is_empty() {
+            message.push_str(&format!(", unhealthy services: {}", unhealthy_services.join(", ")));
+        }
+        self.send(6, "metrics", &structured_data, &message)?; // Informational
+        Ok(())
+    }
+}
+
+// Shared with the event logger below, which writes through the same connection.
+impl Sink for Arc<SyslogWriter> {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        let result = metrics.iter().try_for_each(|sample| self.write_summary(sample));
+        Box::pin(async { result })
+    }
+
+    // Each cycle, on a blocking thread as connecting can take up to TCP_TIMEOUT.
+    fn send_heartbeat(&mut self) -> SinkFuture<'_> {
+        let writer = Arc::clone(self);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || writer.reconnect())
+                .await
+                .map_err(|e| VmMonitorError::SinkError(e.to_string()))?
+        })
+    }
+}
+
+// Set by `start` once a syslog sink is configured, read by the logger below.
+static EVENTS: OnceLock<(Arc<SyslogWriter>, Level)> = OnceLock::new();
+
+/// Forwards agent log records at `level` and above to `writer`, in addition to stderr.
+pub fn forward_events(writer: Arc<SyslogWriter>, level: Level) {
+    if EVENTS.set((writer, level)).is_ok() && log::max_level() < level {
+        log::set_max_level(level.to_level_filter());
+    }
+}
+
+/// env_logger, plus forwarding to syslog once `forward_events` has been called.
+pub struct EventLogger {
+    inner: env_logger::Logger,
+}
+
+impl EventLogger {
+    pub fn init(inner: env_logger::Logger) {
+        log::set_max_level(inner.filter());
+        // Only fails if a logger is already installed, and then there's nothing to wrap.
+        let _ = log::set_boxed_logger(Box::new(EventLogger { inner }));
+    }
+}
+
+impl Log for EventLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || EVENTS.get().is_some_and(|(_, level)| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+        if let Some((writer, level)) = EVENTS.get()
+            && record.level() <= *level
+        {
+            let severity = match record.level() {
+                Level::Error => 3,
+                Level::Warn => 4,
+                Level::Info => 6,
+                Level::Debug | Level::Trace => 7,
+            };
+            let structured_data = format!("[{} module=\"{}\"]", SD_ID, escape_param_value(record.target()));
+            // Errors can't be logged from inside the logger; the record still reached stderr.
+            let _ = writer.send(severity, "event", &structured_data, &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
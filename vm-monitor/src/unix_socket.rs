@@ -0,0 +1,89 @@
+// API requests over a Unix socket (`api_url` of `unix:///run/collector.sock`), for hosts where
+// only a local relay may reach the network. Like the Docker client, requests go out as
+// HTTP/1.0 so the relay answers with a plain body and closes the connection.
+use crate::api::RequestFailure;
+use crate::config::ApiTimeouts;
+use crate::errors::VmMonitorError;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::fmt::Display;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+// Connection trouble is worth retrying, like a failed TCP connection.
+fn failure(socket_path: &str, e: impl Display) -> RequestFailure {
+    RequestFailure {
+        error: VmMonitorError::ApiError(format!("API request over {} failed: {}", socket_path, e)),
+        transient: true,
+        retry_after: None,
+    }
+}
+
+fn parse_response(socket_path: &str, response: &[u8]) -> Result<Response, RequestFailure> {
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| failure(socket_path, "malformed HTTP response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| failure(socket_path, "malformed HTTP status line"))?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.trim().as_bytes()), HeaderValue::from_str(value.trim())) {
+            headers.append(name, value);
+        }
+    }
+    let body = String::from_utf8_lossy(&response[split + 4..]).into_owned();
+    Ok(Response { status, headers, body })
+}
+
+async fn exchange(socket_path: &str, request: &reqwest::Request, timeouts: &ApiTimeouts) -> Result<Response, RequestFailure> {
+    let connect = UnixStream::connect(socket_path);
+    let mut stream = match timeouts.connect() {
+        Some(connect_timeout) => timeout(connect_timeout, connect).await.map_err(|e| failure(socket_path, e))?,
+        None => connect.await,
+    }
+    .map_err(|e| failure(socket_path, e))?;
+
+    let url = request.url();
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: localhost\r\nContent-Length: {}\r\n", request.method(), target, body.len());
+    for (name, value) in request.headers() {
+        let value = value.to_str().map_err(|e| VmMonitorError::ApiError(format!("Invalid header {}: {}", name, e)))?;
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut message = head.into_bytes();
+    message.extend_from_slice(body);
+    stream.write_all(&message).await.map_err(|e| failure(socket_path, e))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(|e| failure(socket_path, e))?;
+    parse_response(socket_path, &response)
+}
+
+/// Sends a request built for `http://localhost` to the API behind `socket_path`.
+pub async fn send(socket_path: &str, request: reqwest::Request, timeouts: &ApiTimeouts) -> Result<Response, RequestFailure> {
+    match timeouts.request() {
+        Some(request_timeout) => timeout(request_timeout, exchange(socket_path, &request, timeouts))
+            .await
+            .map_err(|e| failure(socket_path, e))?,
+        None => exchange(socket_path, &request, timeouts).await,
+    }
+}
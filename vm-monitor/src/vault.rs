@@ -0,0 +1,234 @@
+// Secrets fetched from HashiCorp Vault at startup: the API key and the client certificate for
+// mutual TLS. The agent logs in, reads them into memory and revokes its token, so none of it
+// is ever written to disk.
+use crate::aws::Aws;
+use crate::azure::ManagedIdentity;
+use crate::config::{ApiKeyStore, ClientIdentity, Configuration, VaultAuth, VaultSettings};
+use crate::errors::VmMonitorError;
+use crate::gcp::IdentityTokens;
+use crate::secrets::VAULT_PREFIX;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+
+const DEFAULT_FIELD: &str = "api_key";
+const STS_URL: &str = "https://sts.amazonaws.com/";
+const STS_BODY: &str = "Action=GetCallerIdentity&Version=2011-06-15";
+const STS_CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+#[derive(Deserialize)]
+struct SecretResponse {
+    data: Map<String, Value>,
+}
+
+fn error(message: impl std::fmt::Display) -> VmMonitorError {
+    VmMonitorError::VaultError(message.to_string())
+}
+
+fn take_field(secret: &mut Map<String, Value>, path: &str, field: &str) -> Result<String, VmMonitorError> {
+    match secret.remove(field) {
+        Some(Value::String(value)) => Ok(value),
+        _ => Err(error(format!("No field {:?} in {}", field, path))),
+    }
+}
+
+// "secret/data/vm-monitor#api_key" is the path as in the HTTP API, then the field.
+fn parse_reference(reference: &str) -> (&str, &str) {
+    let reference = reference.strip_prefix(VAULT_PREFIX).unwrap_or(reference);
+    match reference.split_once('#') {
+        Some((path, field)) => (path.trim_matches('/'), field),
+        None => (reference.trim_matches('/'), DEFAULT_FIELD),
+    }
+}
+
+// The parts of an Azure VM's or scale set's resource ID that Vault's Azure auth checks.
+fn azure_vm(resource_id: &str) -> Result<Map<String, Value>, VmMonitorError> {
+    let parts: Vec<&str> = resource_id.trim_matches('/').split('/').collect();
+    let after = |key: &str| {
+        parts.iter().position(|part| part.eq_ignore_ascii_case(key)).and_then(|i| parts.get(i + 1)).map(|value| json!(value))
+    };
+    let mut fields = Map::new();
+    fields.insert("subscription_id".to_string(), after("subscriptions").ok_or_else(|| error("No subscription in the VM's resource ID"))?);
+    fields.insert("resource_group_name".to_string(), after("resourceGroups").ok_or_else(|| error("No resource group in the VM's resource ID"))?);
+    if let Some(vmss) = after("virtualMachineScaleSets") {
+        fields.insert("vmss_name".to_string(), vmss);
+    } else if let Some(vm) = after("virtualMachines") {
+        fields.insert("vm_name".to_string(), vm);
+    }
+    Ok(fields)
+}
+
+struct Vault {
+    client: Client,
+    settings: VaultSettings,
+    token: String,
+}
+
+impl Vault {
+    fn request(client: &Client, settings: &VaultSettings, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut request = client.request(method, format!("{}/v1/{}", settings.address.trim_end_matches('/'), path));
+        if let Some(namespace) = &settings.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        request
+    }
+
+    async fn login(settings: &VaultSettings) -> Result<Self, VmMonitorError> {
+        let mut builder = Client::builder().timeout(Duration::from_secs(settings.timeout_seconds));
+        if let Some(ca_file) = &settings.ca_file {
+            let pem = std::fs::read(ca_file)
+                .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read Vault CA file {}: {}", ca_file, e)))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        let client = builder.build()?;
+
+        let (method, body) = match &settings.auth {
+            VaultAuth::Approle { role_id, secret_id, secret_id_file } => {
+                let secret_id = match (secret_id, secret_id_file) {
+                    (Some(secret_id), _) => Some(secret_id.clone()),
+                    (None, Some(path)) => Some(
+                        std::fs::read_to_string(path)
+                            .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read {}: {}", path, e)))?
+                            .trim()
+                            .to_string(),
+                    ),
+                    (None, None) => None, // For roles that bind to CIDRs instead
+                };
+                ("approle", json!({"role_id": role_id, "secret_id": secret_id}))
+            }
+            // A signed sts:GetCallerIdentity request, which Vault replays to learn the role's ARN.
+            VaultAuth::Aws { role, server_id } => {
+                let aws = Aws::new();
+                let credentials = aws.credentials().await?;
+                let mut headers = vec![("content-type", STS_CONTENT_TYPE.to_string())];
+                if let Some(server_id) = server_id {
+                    headers.push(("x-vault-aws-iam-server-id", server_id.clone()));
+                }
+                let url = Url::parse(STS_URL).expect("valid URL");
+                let signed_headers: Vec<(&str, &str)> = headers.iter().map(|(name, value)| (*name, value.as_str())).collect();
+                let signature = credentials.sign(chrono::Utc::now(), "us-east-1", "sts", "POST", &url, &signed_headers, STS_BODY.as_bytes());
+                let all_headers: Map<String, Value> =
+                    headers.into_iter().chain(signature).map(|(name, value)| (name.to_string(), json!([value]))).collect();
+                (
+                    "aws",
+                    json!({
+                        "role": role,
+                        "iam_http_request_method": "POST",
+                        "iam_request_url": STANDARD.encode(STS_URL),
+                        "iam_request_body": STANDARD.encode(STS_BODY),
+                        "iam_request_headers": STANDARD.encode(Value::Object(all_headers).to_string()),
+                    }),
+                )
+            }
+            VaultAuth::Gcp { role, service_account } => {
+                let jwt = IdentityTokens::new(service_account, &format!("http://vault/{}", role)).token().await?;
+                ("gcp", json!({"role": role, "jwt": jwt}))
+            }
+            VaultAuth::Azure { role, resource, client_id } => {
+                let identity = ManagedIdentity::new(client_id.clone());
+                let jwt = identity.token(resource).await?;
+                let mut body = azure_vm(&identity.compute().await?.resource_id)?;
+                body.insert("role".to_string(), json!(role));
+                body.insert("jwt".to_string(), json!(jwt));
+                ("azure", Value::Object(body))
+            }
+        };
+
+        let mount = settings.auth_mount.as_deref().unwrap_or(method).trim_matches('/');
+        let response = Self::request(&client, settings, reqwest::Method::POST, &format!("auth/{}/login", mount))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| error(format!("{} unreachable: {}", settings.address, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(error(format!("Login with {} auth returned {}: {}", method, status, text)));
+        }
+        let response: LoginResponse = response.json().await?;
+        log::info!("Logged in to Vault at {} with {} auth", settings.address, method);
+        Ok(Vault { client, settings: settings.clone(), token: response.auth.client_token })
+    }
+
+    // KV version 2 nests the fields under another `data`.
+    async fn read(&self, path: &str) -> Result<Map<String, Value>, VmMonitorError> {
+        let response = Self::request(&self.client, &self.settings, reqwest::Method::GET, path)
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(error(format!("Reading {} returned {}: {}", path, status, text)));
+        }
+        let response: SecretResponse = response.json().await?;
+        match response.data.get("data") {
+            Some(Value::Object(data)) if response.data.contains_key("metadata") => Ok(data.clone()),
+            _ => Ok(response.data),
+        }
+    }
+
+
+    // Nothing is read after startup, so the token needn't outlive it.
+    async fn revoke(&self) {
+        let revoked = Self::request(&self.client, &self.settings, reqwest::Method::POST, "auth/token/revoke-self")
+            .header("X-Vault-Token", &self.token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = revoked {
+            log::debug!("Failed to revoke the Vault token: {}", e);
+        }
+    }
+}
+
+/// Fetches the API key and client certificate into `config`, when they're kept in Vault.
+pub async fn resolve(config: &mut Configuration) -> Result<(), VmMonitorError> {
+    let reference = match &config.api_key_store {
+        ApiKeyStore::Vault(reference) => Some(reference.clone()),
+        _ => None,
+    };
+    let Some(settings) = config.vault.clone() else {
+        return match reference {
+            Some(reference) => Err(VmMonitorError::ConfigError(format!("The API key is in Vault ({}), but `vault` isn't configured", reference))),
+            None => Ok(()),
+        };
+    };
+    if reference.is_none() && settings.client_certificate.is_none() {
+        return Ok(());
+    }
+
+    let vault = Vault::login(&settings).await?;
+    let fetched = async {
+        if let Some(reference) = &reference {
+            let (path, field) = parse_reference(reference);
+            config.api_key = take_field(&mut vault.read(path).await?, path, field)?;
+        }
+        if let Some(path) = &settings.client_certificate {
+            let path = path.trim_matches('/');
+            let mut secret = vault.read(path).await?;
+            config.monitoring_settings.api_tls.vault_client_identity = Some(ClientIdentity {
+                cert: take_field(&mut secret, path, "certificate")?.into_bytes(),
+                key: take_field(&mut secret, path, "private_key")?.into_bytes(),
+            });
+        }
+        Ok(())
+    }
+    .await;
+    vault.revoke().await;
+    fetched
+}
@@ -0,0 +1,313 @@
+// Streams every sample to the API over one persistent WebSocket (`stream_metrics`), for short
+// intervals where setting up a request per batch costs more than the data itself. The stream
+// reconnects by itself with a growing delay, handing samples to the HTTP API sink meanwhile
+// (which spools them if the API is down too), and carries messages from the API back to the agent.
+use crate::api::ApiSink;
+use crate::auth;
+use crate::config::{ApiEndpoint, ApiTlsSettings, Configuration};
+use crate::errors::VmMonitorError;
+use crate::monitor::certs::AcceptAnyCertificate;
+use crate::monitor::{CircuitState, SystemMetrics};
+use crate::sink::{Sink, SinkFuture, SinkHealth};
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, timeout};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use x509_parser::pem::Pem;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_INTERVAL: Duration = Duration::from_secs(30); // Keeps proxies and NAT from dropping a quiet stream
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// Agent-to-API frames are JSON text: `{"type": "metrics", "sample": {...}}`, the sample in
+// the same shape as in an HTTP metrics batch. Built around the sample's own text, which is
+// what the fallback gets too.
+fn frame(sample: &str) -> String {
+    format!(r#"{{"type":"metrics","sample":{}}}"#, sample)
+}
+
+struct Connection {
+    writer: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    open: Arc<AtomicBool>, // Cleared by the reader when the stream ends
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort(); // Otherwise it would keep the socket open
+    }
+}
+
+fn stream_url(api_url: &str, path: &str) -> String {
+    let url = match api_url.split_once("://") {
+        Some(("https", rest)) => format!("wss://{}", rest),
+        Some(("http", rest)) => format!("ws://{}", rest),
+        _ => api_url.to_string(),
+    };
+    format!("{}{}", url, path)
+}
+
+fn parse_pem(data: &[u8], source: &str) -> Result<Vec<Pem>, VmMonitorError> {
+    Pem::iter_from_buffer(data)
+        .map(|pem| pem.map_err(|e| VmMonitorError::ConfigError(format!("Invalid PEM in {}: {}", source, e))))
+        .collect()
+}
+
+// Only needed with a custom CA, `insecure_skip_verify` or a client certificate; tungstenite's
+// defaults apply otherwise.
+fn tls_connector(tls: &ApiTlsSettings) -> Result<Option<Connector>, VmMonitorError> {
+    let client_auth = tls.client_identity()?;
+    if tls.ca_file.is_none() && !tls.insecure_skip_verify && client_auth.is_none() {
+        return Ok(None);
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    if let Some(ca_file) = &tls.ca_file {
+        let data = std::fs::read(ca_file)
+            .map_err(|e| VmMonitorError::ConfigError(format!("Failed to read CA file {}: {}", ca_file, e)))?;
+        for pem in parse_pem(&data, ca_file)? {
+            roots
+                .add(&rustls::Certificate(pem.contents))
+                .map_err(|e| VmMonitorError::ConfigError(format!("Invalid certificate in {}: {}", ca_file, e)))?;
+        }
+    }
+    let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+    let mut config = match client_auth {
+        Some(identity) => {
+            let chain = parse_pem(&identity.cert, "client_cert_file")?.into_iter().map(|pem| rustls::Certificate(pem.contents)).collect();
+            let key = parse_pem(&identity.key, "client_key_file")?
+                .into_iter()
+                .find(|pem| pem.label.ends_with("PRIVATE KEY"))
+                .ok_or_else(|| VmMonitorError::ConfigError("No private key found in client_key_file".to_string()))?;
+            builder
+                .with_client_auth_cert(chain, rustls::PrivateKey(key.contents))
+                .map_err(|e| VmMonitorError::ConfigError(format!("Invalid client certificate or key: {}", e)))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    if tls.insecure_skip_verify {
+        config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    }
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+pub struct WebSocketSink {
+    config: Configuration,
+    path: String,
+    url: String,
+    tls: Option<Connector>,
+    connection: Option<Connection>,
+    queued: Vec<String>, // Samples not yet sent, as JSON, oldest first
+    fallback: ApiSink, // Gets the samples while the stream is down
+    failures: u32, // Connection attempts that failed in a row
+    clock: auth::ClockSkew,
+    auth: auth::Authenticator,
+    reconnect_at: Instant,
+    last_ping: Instant,
+}
+
+impl WebSocketSink {
+    pub fn new(config: Configuration, fallback: ApiSink) -> Result<Self, VmMonitorError> {
+        if config.api_socket_path().is_some() {
+            return Err(VmMonitorError::ConfigError("stream_metrics isn't supported with a Unix socket api_url".to_string()));
+        }
+        let path = config.monitoring_settings.api_paths.path(ApiEndpoint::Stream);
+        let auth = auth::Authenticator::new(&config, crate::api::http_client(&config.monitoring_settings)?);
+        Ok(WebSocketSink {
+            url: stream_url(&config.api_url, &path),
+            path,
+            tls: tls_connector(&config.monitoring_settings.api_tls)?,
+            config,
+            connection: None,
+            queued: Vec::new(),
+            fallback,
+            failures: 0,
+            clock: auth::ClockSkew::default(),
+            auth,
+            reconnect_at: Instant::now(),
+            last_ping: Instant::now(),
+        })
+    }
+
+    fn error(&self, e: impl Display) -> VmMonitorError {
+        VmMonitorError::ApiError(format!("Metrics stream to {} failed: {}", self.url, e))
+    }
+
+    // Authenticated like an HTTP request: a GET of the stream path with an empty body.
+    async fn connect(&self) -> Result<Connection, VmMonitorError> {
+        let mut request = self.url.as_str().into_client_request().map_err(|e| self.error(e))?;
+        let mut headers = self.auth.headers(&self.clock, &auth::RequestParts { method: "GET", url: &self.url, path: &self.path, body: "", payload: &[] }).await?;
+        headers.push(("x-instance-id", self.config.instance_id.to_string()));
+        for (name, value) in headers {
+            request.headers_mut().insert(name, HeaderValue::from_str(&value).map_err(|e| self.error(e))?);
+        }
+        let connect = tokio_tungstenite::connect_async_tls_with_config(request, None, false, self.tls.clone());
+        let result = match self.config.monitoring_settings.api_timeouts.connect() {
+            Some(connect_timeout) => timeout(connect_timeout, connect).await.map_err(|e| self.error(e))?,
+            None => connect.await,
+        };
+        // A rejected handshake still tells the API's time, for the next attempt.
+        let headers = match &result {
+            Ok((_, response)) => Some(response.headers()),
+            Err(tungstenite::Error::Http(response)) => Some(response.headers()),
+            Err(_) => None,
+        };
+        if let Some(date) = headers.and_then(|headers| headers.get("date")?.to_str().ok()) {
+            self.clock.observe(date);
+        }
+        let (stream, _) = result.map_err(|e| self.error(e))?;
+
+        let (writer, mut reader) = stream.split();
+        let open = Arc::new(AtomicBool::new(true));
+        let reader_open = open.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(message) = reader.next().await {
+                match message {
+                    Ok(Message::Text(text)) => log::info!("Message from the API: {}", text),
+                    Ok(Message::Close(frame)) => {
+                        log::warn!("API closed the metrics stream{}", frame.map(|f| format!(": {}", f)).unwrap_or_default());
+                        break;
+                    }
+                    Ok(_) => {} // Pings are answered by tungstenite itself
+                    Err(e) => {
+                        log::warn!("Metrics stream from the API failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            reader_open.store(false, Ordering::Relaxed);
+        });
+        Ok(Connection { writer, open, reader })
+    }
+
+    async fn ensure_connected(&mut self) -> Result<(), VmMonitorError> {
+        if self.connection.as_ref().is_some_and(|connection| !connection.open.load(Ordering::Relaxed)) {
+            self.connection = None;
+        }
+        if self.connection.is_none() {
+            let now = Instant::now();
+            if now < self.reconnect_at {
+                return Err(VmMonitorError::CircuitOpen((self.reconnect_at - now).as_secs().max(1)));
+            }
+            match self.connect().await {
+                Ok(connection) => {
+                    log::info!("Streaming metrics to {}", self.url);
+                    self.failures = 0;
+                    self.last_ping = Instant::now();
+                    self.connection = Some(connection);
+                }
+                Err(e) => {
+                    self.failures += 1;
+                    let delay = Duration::from_secs(1u64 << (self.failures - 1).min(6)).min(MAX_RECONNECT_DELAY);
+                    self.reconnect_at = Instant::now() + delay;
+                    log::debug!("Reconnecting the metrics stream in {}s.", delay.as_secs());
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Sends queued frames in order. A failed send drops the connection; the frame stays
+    // queued and the next sample reconnects right away.
+    async fn deliver(&mut self) -> Result<(), VmMonitorError> {
+        self.ensure_connected().await?;
+        while let Some(sample) = self.queued.first() {
+            let message = Message::Text(frame(sample));
+            let connection = self.connection.as_mut().expect("connected above");
+            let result = match timeout(SEND_TIMEOUT, connection.writer.send(message)).await {
+                Ok(result) => result.map_err(|e| self.error(e)),
+                Err(e) => Err(self.error(e)),
+            };
+            if let Err(e) = result {
+                self.connection = None;
+                return Err(e);
+            }
+            self.queued.remove(0);
+        }
+        Ok(())
+    }
+
+    // Streams what's queued, or sends it over HTTP when the stream is down.
+    async fn deliver_or_fall_back(&mut self) -> Result<(), VmMonitorError> {
+        match self.deliver().await {
+            Ok(()) => return Ok(()),
+            Err(e @ VmMonitorError::CircuitOpen(_)) => log::debug!("Not streaming: {}", e),
+            Err(e) => log::warn!("{}. Sending metrics over HTTP until it's back.", e),
+        }
+        let samples = self.queued.drain(..).map(|sample| serde_json::from_str(&sample)).collect::<Result<_, _>>()?;
+        self.fallback.send_samples(samples).await
+    }
+}
+
+impl Sink for WebSocketSink {
+    fn name(&self) -> &'static str {
+        "api"
+    }
+
+    fn send_batch<'a>(&'a mut self, metrics: &'a [SystemMetrics]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for sample in metrics {
+                self.queued.push(serde_json::to_string(sample)?);
+            }
+            self.deliver_or_fall_back().await
+        })
+    }
+
+    // Also replays the fallback's spool, over HTTP.
+    fn send_heartbeat(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            self.fallback.send_heartbeat().await?;
+            if self.last_ping.elapsed() < PING_INTERVAL {
+                return Ok(());
+            }
+            let Some(connection) = &mut self.connection else {
+                return Ok(());
+            };
+            self.last_ping = Instant::now();
+            let result = match timeout(SEND_TIMEOUT, connection.writer.send(Message::Ping(Vec::new()))).await {
+                Ok(result) => result.map_err(|e| self.error(e)),
+                Err(e) => Err(self.error(e)),
+            };
+            if result.is_err() {
+                self.connection = None;
+            }
+            result
+        })
+    }
+
+    fn health(&self) -> SinkHealth {
+        SinkHealth {
+            circuit_state: if self.failures > 0 { CircuitState::Open } else { CircuitState::Closed },
+            consecutive_failures: self.failures,
+            backlog_bytes: self.fallback.health().backlog_bytes,
+        }
+    }
+
+    fn drain_backlog(&mut self) -> SinkFuture<'_> {
+        self.fallback.drain_backlog()
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_> {
+        Box::pin(async move {
+            let result = if self.queued.is_empty() { Ok(()) } else { self.deliver_or_fall_back().await };
+            let result = result.and(self.fallback.flush().await);
+            if let Some(mut connection) = self.connection.take() {
+                let _ = timeout(SEND_TIMEOUT, connection.writer.close()).await;
+            }
+            result
+        })
+    }
+}
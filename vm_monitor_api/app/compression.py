@@ -0,0 +1,38 @@
+import gzip
+from typing import Callable
+import zstandard
+from fastapi import HTTPException, Request, Response, status
+from fastapi.routing import APIRoute
+
+
+class DecompressingRequest(Request):
+    """
+    Request whose body is transparently decoded according to Content-Encoding.
+    Agents sign the uncompressed JSON, so signature checks see the decoded body too.
+    """
+
+    async def body(self) -> bytes:
+        if not hasattr(self, "_decoded_body"):
+            body = await super().body()
+            encoding = self.headers.get("content-encoding", "identity").lower()
+            if encoding == "gzip":
+                body = gzip.decompress(body)
+            elif encoding == "zstd":
+                body = zstandard.ZstdDecompressor().decompressobj().decompress(body)
+            elif encoding != "identity":
+                raise HTTPException(
+                    status_code=status.HTTP_415_UNSUPPORTED_MEDIA_TYPE,
+                    detail=f"Unsupported Content-Encoding '{encoding}'.",
+                )
+            self._decoded_body = body
+        return self._decoded_body
+
+
+class DecompressingRoute(APIRoute):
+    def get_route_handler(self) -> Callable:
+        original_route_handler = super().get_route_handler()
+
+        async def route_handler(request: Request) -> Response:
+            return await original_route_handler(DecompressingRequest(request.scope, request.receive))
+
+        return route_handler
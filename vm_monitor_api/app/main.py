@@ -1,14 +1,25 @@
-from fastapi import FastAPI, HTTPException, Depends, status
+from fastapi import FastAPI, HTTPException, Depends, Header, Query, Request, Response, WebSocket, WebSocketDisconnect, status
 from fastapi.middleware.cors import CORSMiddleware
-from typing import List, Dict
+from typing import List, Dict, Optional
+from collections import OrderedDict
 from datetime import datetime, timezone
 from contextlib import asynccontextmanager
+from pydantic import ValidationError
 from . import models
 from . import security
+from . import compression
+import json
 import uuid
 
 db_agents: Dict[uuid.UUID, models.StoredAgent] = {}
 db_metrics: Dict[uuid.UUID, List[models.StoredMetricsBatch]] = {}
+db_inventory: Dict[uuid.UUID, models.InventoryPayload] = {}
+# Recently processed X-Batch-Id values per agent, to acknowledge resubmitted batches without storing them twice.
+RECENT_BATCH_IDS_LIMIT = 1000
+recent_batch_ids: Dict[uuid.UUID, OrderedDict] = {}
+# Commands queued by an admin until the agent next polls, and the results it reported.
+db_commands: Dict[uuid.UUID, List[models.AgentCommand]] = {}
+db_command_results: Dict[uuid.UUID, List[models.StoredCommandResult]] = {}
 
 @asynccontextmanager
 async def lifespan(app: FastAPI):
@@ -23,6 +34,9 @@ app = FastAPI(
     lifespan=lifespan
 )
 
+# Must be set before any route is declared.
+app.router.route_class = compression.DecompressingRoute
+
 app.add_middleware(
     CORSMiddleware,
     allow_origins=["*"],
@@ -44,12 +58,23 @@ async def health_check():
 
 # chicken and egg
 @app.post("/v1/agent/register", response_model=models.AgentRegistrationResponse, status_code=status.HTTP_201_CREATED, tags=["Agent"])
-async def register_agent(payload: models.AgentRegistrationPayload):
+async def register_agent(
+    payload: models.AgentRegistrationPayload,
+    request: Request,
+    x_request_timestamp: Optional[str] = Header(None, alias="X-Request-Timestamp"),
+    x_request_nonce: Optional[str] = Header(None, alias="X-Request-Nonce"),
+    x_request_signature: Optional[str] = Header(None, alias="X-Request-Signature"),
+):
     """
     Register a new vm-monitor agent.
     The agent sends its self-generated API key, which the server stores.
+    An instance that's already registered must sign the request with its current key, so
+    knowing an instance ID isn't enough to take it over.
     """
-    if payload.instance_id in db_agents:
+    if str(payload.instance_id) in security.AGENT_API_KEYS:
+        await security.verify_agent_request(
+            request, str(payload.instance_id), x_request_timestamp, x_request_nonce, x_request_signature
+        )
         print(f"Agent {payload.instance_id} is re-registering.")
     else:
         print(f"New agent registration: {payload.instance_id}")
@@ -59,14 +84,23 @@ async def register_agent(payload: models.AgentRegistrationPayload):
         instance_name=payload.instance_name,
         cloud_provider=payload.cloud_provider,
         agent_api_key=payload.agent_api_key,
+        virtualization=payload.virtualization,
+        signature_algorithm=payload.signature_algorithm,
+        tags=payload.tags,
+        cloud_instance=payload.cloud_instance,
         registered_at=datetime.now(timezone.utc)
     )
     db_agents[payload.instance_id] = stored_agent
-    security.AGENT_API_KEYS[str(payload.instance_id)] = payload.agent_api_key
-
     db_metrics.setdefault(payload.instance_id, [])
 
-    print(f"Agent '{payload.instance_name}' ({payload.instance_id}) registered with API key prefix: {payload.agent_api_key[:8]}...")
+    if payload.agent_api_key:
+        security.AGENT_API_KEYS[str(payload.instance_id)] = payload.agent_api_key
+        security.AGENT_SIGNATURE_ALGORITHMS[str(payload.instance_id)] = payload.signature_algorithm
+        security.PENDING_API_KEYS.pop(str(payload.instance_id), None)
+        print(f"Agent '{payload.instance_name}' ({payload.instance_id}) registered with API key prefix: {payload.agent_api_key[:8]}...")
+    else:
+        # Authenticated by the gateway in front of this API (OAuth2, SigV4, cloud identity), not by a key of its own
+        print(f"Agent '{payload.instance_name}' ({payload.instance_id}) registered without an API key")
     return {
         "message": "Agent registered successfully",
         "instance_id": payload.instance_id
@@ -74,36 +108,131 @@ async def register_agent(payload: models.AgentRegistrationPayload):
 
 AuthenticatedAgent = Depends(security.authenticate_agent)
 
-@app.post("/v1/agent/metrics", response_model=models.MessageResponse, status_code=status.HTTP_202_ACCEPTED, tags=["Agent"])
+@app.post(
+    "/v1/agent/metrics",
+    response_model=models.MetricsBatchResponse,
+    status_code=status.HTTP_202_ACCEPTED,
+    responses={status.HTTP_207_MULTI_STATUS: {"model": models.MetricsBatchResponse}},
+    tags=["Agent"],
+)
 async def receive_metrics(
     payload_wrapper: models.MetricsBatchWrapper,
-    authenticated_agent_data: dict = AuthenticatedAgent
+    response: Response,
+    authenticated_agent_data: dict = AuthenticatedAgent,
+    x_batch_id: Optional[str] = Header(None, alias="X-Batch-Id"),
 ):
     """
     Receive a batch of metrics from an authenticated agent.
+    Invalid samples are reported individually with a 207 response; the rest are stored.
     """
     instance_id_from_auth = uuid.UUID(authenticated_agent_data["instance_id"])
-    metrics_batch = payload_wrapper.metrics
 
-    if not metrics_batch:
+    if not payload_wrapper.metrics:
         raise HTTPException(status_code=status.HTTP_400_BAD_REQUEST, detail="Empty metrics batch received.")
 
-    for metric in metrics_batch:
+    seen_batches = recent_batch_ids.setdefault(instance_id_from_auth, OrderedDict())
+    if x_batch_id and x_batch_id in seen_batches:
+        print(f"Duplicate metrics batch {x_batch_id} from agent {instance_id_from_auth}, ignoring.")
+        previous = seen_batches[x_batch_id]
+        if previous.rejected:
+            response.status_code = status.HTTP_207_MULTI_STATUS
+        return previous
+
+    accepted: List[models.SystemMetricsPayload] = []
+    rejected: List[models.RejectedMetric] = []
+    for index, item in enumerate(payload_wrapper.metrics):
+        try:
+            metric = models.SystemMetricsPayload.model_validate(item)
+        except ValidationError as e:
+            rejected.append(models.RejectedMetric(index=index, error=str(e)))
+            continue
         if metric.instance_id != instance_id_from_auth:
-            raise HTTPException(
-                status_code=status.HTTP_400_BAD_REQUEST,
-                detail=f"Mismatch in instance_id in metrics payload ({metric.instance_id}) and authenticated agent ({instance_id_from_auth})."
-            )
-
-    batch_to_store = models.StoredMetricsBatch(
-        received_at=datetime.now(timezone.utc),
-        instance_id=instance_id_from_auth,
-        metrics=metrics_batch
+            rejected.append(models.RejectedMetric(
+                index=index,
+                error=f"Mismatch in instance_id in metrics payload ({metric.instance_id}) and authenticated agent ({instance_id_from_auth})."
+            ))
+            continue
+        accepted.append(metric)
+
+    if accepted:
+        db_metrics.setdefault(instance_id_from_auth, []).append(models.StoredMetricsBatch(
+            received_at=datetime.now(timezone.utc),
+            instance_id=instance_id_from_auth,
+            metrics=accepted
+        ))
+    if rejected:
+        response.status_code = status.HTTP_207_MULTI_STATUS
+
+    print(f"Received metrics batch (accepted: {len(accepted)}, rejected: {len(rejected)}) for agent {instance_id_from_auth}.")
+    result = models.MetricsBatchResponse(
+        message=f"Metrics batch for {instance_id_from_auth} processed.",
+        accepted=len(accepted),
+        rejected=rejected,
     )
-    db_metrics.setdefault(instance_id_from_auth, []).append(batch_to_store)
+    if x_batch_id:
+        seen_batches[x_batch_id] = result
+        while len(seen_batches) > RECENT_BATCH_IDS_LIMIT:
+            seen_batches.popitem(last=False)
+    return result
 
-    print(f"Received metrics batch (count: {len(metrics_batch)}) for agent {instance_id_from_auth}.")
-    return {"message": f"Metrics batch for {instance_id_from_auth} accepted."}
+
+@app.websocket("/v1/agent/stream")
+async def stream_metrics(websocket: WebSocket):
+    """
+    Receive samples from an agent with `stream_metrics` set, one `{"type": "metrics", "sample": {...}}`
+    frame each. Samples that don't validate are answered with a text message and dropped.
+    """
+    instance_id = security.verify_stream_handshake(websocket)
+    if instance_id is None:
+        await websocket.close(code=status.WS_1008_POLICY_VIOLATION)
+        return
+    await websocket.accept()
+    agent_id = uuid.UUID(instance_id)
+    print(f"Agent {agent_id} opened a metrics stream.")
+    try:
+        while True:
+            try:
+                frame = json.loads(await websocket.receive_text())
+            except ValueError:
+                continue
+            if not isinstance(frame, dict) or frame.get("type") != "metrics":
+                continue
+            try:
+                metric = models.SystemMetricsPayload.model_validate(frame.get("sample"))
+            except ValidationError as e:
+                await websocket.send_text(f"Rejected sample: {e}")
+                continue
+            if metric.instance_id != agent_id:
+                await websocket.send_text(f"Rejected sample for instance {metric.instance_id}")
+                continue
+            db_metrics.setdefault(agent_id, []).append(models.StoredMetricsBatch(
+                received_at=datetime.now(timezone.utc),
+                instance_id=agent_id,
+                metrics=[metric]
+            ))
+    except WebSocketDisconnect:
+        print(f"Agent {agent_id} closed its metrics stream.")
+
+
+@app.post("/v1/agent/inventory", response_model=models.MessageResponse, tags=["Agent"])
+async def receive_inventory(
+    payload: models.InventoryPayload,
+    authenticated_agent_data: dict = AuthenticatedAgent
+):
+    """
+    Receive the hardware/software inventory of an authenticated agent, replacing any previous one.
+    """
+    instance_id_from_auth = uuid.UUID(authenticated_agent_data["instance_id"])
+
+    if payload.instance_id != instance_id_from_auth:
+        raise HTTPException(
+            status_code=status.HTTP_400_BAD_REQUEST,
+            detail="Mismatch in instance_id in inventory payload and authenticated agent."
+        )
+
+    db_inventory[instance_id_from_auth] = payload
+    print(f"Inventory received from agent {instance_id_from_auth} (agent version {payload.agent_version}).")
+    return {"message": "Inventory stored"}
 
 
 @app.post("/v1/agent/heartbeat", response_model=models.MessageResponse, tags=["Agent"])
@@ -124,17 +253,117 @@ async def agent_heartbeat(
 
     if instance_id_from_auth in db_agents:
         db_agents[instance_id_from_auth].last_heartbeat_at = datetime.now(timezone.utc)
+        if payload.agent_version:
+            db_agents[instance_id_from_auth].agent_version = payload.agent_version
         print(f"Heartbeat received from agent {instance_id_from_auth}.")
         return {"message": "Heartbeat acknowledged"}
     else:
         raise HTTPException(status_code=status.HTTP_404_NOT_FOUND, detail="Agent not found for heartbeat.")
 
+@app.get("/v1/agent/commands", response_model=models.AgentCommandsResponse, tags=["Agent"])
+async def get_agent_commands(authenticated_agent_data: dict = AuthenticatedAgent):
+    """
+    Hand an authenticated agent the commands queued for it. Each is handed out once.
+    """
+    instance_id_from_auth = uuid.UUID(authenticated_agent_data["instance_id"])
+    return {"commands": db_commands.pop(instance_id_from_auth, [])}
+
+
+@app.post("/v1/agent/commands/result", response_model=models.MessageResponse, tags=["Agent"])
+async def receive_command_result(
+    payload: models.CommandResultPayload,
+    authenticated_agent_data: dict = AuthenticatedAgent
+):
+    """
+    Receive the outcome of a command from an authenticated agent.
+    """
+    instance_id_from_auth = uuid.UUID(authenticated_agent_data["instance_id"])
+
+    if payload.instance_id != instance_id_from_auth:
+        raise HTTPException(
+            status_code=status.HTTP_400_BAD_REQUEST,
+            detail="Mismatch in instance_id in command result and authenticated agent."
+        )
+
+    db_command_results.setdefault(instance_id_from_auth, []).append(models.StoredCommandResult(
+        **payload.model_dump(), received_at=datetime.now(timezone.utc)
+    ))
+    print(f"Command {payload.id} on agent {instance_id_from_auth} {'succeeded' if payload.success else f'failed: {payload.error}'}.")
+    return {"message": "Command result stored"}
+
+
+@app.post("/v1/agent/rotate-key", response_model=models.MessageResponse, tags=["Agent"])
+async def rotate_agent_key(
+    payload: models.RotateKeyPayload,
+    authenticated_agent_data: dict = AuthenticatedAgent
+):
+    """
+    Replace an authenticated agent's API key. The current key stays valid until the agent
+    signs a request with the new one, so a rotation whose response was lost can be repeated.
+    """
+    instance_id_from_auth = uuid.UUID(authenticated_agent_data["instance_id"])
+
+    if payload.instance_id != instance_id_from_auth:
+        raise HTTPException(
+            status_code=status.HTTP_400_BAD_REQUEST,
+            detail="Mismatch in instance_id in rotate-key payload and authenticated agent."
+        )
+
+    security.PENDING_API_KEYS[str(instance_id_from_auth)] = payload.new_api_key
+    if instance_id_from_auth in db_agents:
+        db_agents[instance_id_from_auth].agent_api_key = payload.new_api_key
+    print(f"Agent {instance_id_from_auth} is rotating to API key prefix: {payload.new_api_key[:8]}...")
+    return {"message": "API key rotation accepted"}
+
 @app.get("/admin/agents", response_model=Dict[uuid.UUID, models.StoredAgent], tags=["Admin"])
-async def get_all_agents():
+async def get_all_agents(tag: Optional[List[str]] = Query(None)):
+    """
+    (Admin) Get all registered agents, or those with every given tag, e.g. ?tag=env:prod&tag=team:payments.
+    """
+    wanted = [t.split(":", 1) for t in tag or []]
+    return {
+        instance_id: agent for instance_id, agent in db_agents.items()
+        if all(len(w) == 2 and agent.tags.get(w[0]) == w[1] for w in wanted)
+    }
+
+@app.post("/admin/commands/{instance_id_str}", response_model=models.AgentCommand, status_code=status.HTTP_201_CREATED, tags=["Admin"])
+async def queue_command_for_agent_admin(instance_id_str: str, request: models.CommandRequest):
+    """
+    (Admin) Queue a command for an agent, which picks it up on its next poll if it has `command_poll_seconds` set.
+    """
+    try:
+        instance_id = uuid.UUID(instance_id_str)
+    except ValueError:
+        raise HTTPException(status_code=status.HTTP_400_BAD_REQUEST, detail="Invalid instance_id format.")
+    if instance_id not in db_agents:
+        raise HTTPException(status_code=status.HTTP_404_NOT_FOUND, detail="Agent not found.")
+    command = models.AgentCommand(id=str(uuid.uuid4()), action=request.action)
+    db_commands.setdefault(instance_id, []).append(command)
+    return command
+
+@app.get("/admin/commands/{instance_id_str}", response_model=List[models.StoredCommandResult], tags=["Admin"])
+async def get_command_results_for_agent_admin(instance_id_str: str):
+    """
+    (Admin) Get the command results reported by a specific agent.
     """
-    (Admin) Get all registered agents.
+    try:
+        instance_id = uuid.UUID(instance_id_str)
+    except ValueError:
+        raise HTTPException(status_code=status.HTTP_400_BAD_REQUEST, detail="Invalid instance_id format.")
+    return db_command_results.get(instance_id, [])
+
+@app.get("/admin/inventory/{instance_id_str}", response_model=models.InventoryPayload, tags=["Admin"])
+async def get_inventory_for_agent_admin(instance_id_str: str):
     """
-    return db_agents
+    (Admin) Get the latest inventory reported by a specific agent.
+    """
+    try:
+        instance_id = uuid.UUID(instance_id_str)
+    except ValueError:
+        raise HTTPException(status_code=status.HTTP_400_BAD_REQUEST, detail="Invalid instance_id format.")
+    if instance_id not in db_inventory:
+        raise HTTPException(status_code=status.HTTP_404_NOT_FOUND, detail="No inventory found for this instance ID.")
+    return db_inventory[instance_id]
 
 @app.get("/admin/metrics/{instance_id_str}", response_model=List[models.StoredMetricsBatch], tags=["Admin"])
 async def get_metrics_for_agent_admin(instance_id_str: str):
@@ -1,13 +1,24 @@
 from pydantic import BaseModel, Field
-from typing import List, Optional
+from typing import Any, Dict, List, Literal, Optional
 from datetime import datetime
 import uuid
 
+class CloudInstance(BaseModel):
+    instance_type: Optional[str] = None
+    region: Optional[str] = None
+    availability_zone: Optional[str] = None
+    instance_id: Optional[str] = None  # The provider's, not the agent's
+    image: Optional[str] = None
+
 class AgentRegistrationPayload(BaseModel):
     instance_id: uuid.UUID
     instance_name: str
     cloud_provider: str
-    agent_api_key: str = Field(..., description="The API key generated by the agent, to be stored by the server")
+    agent_api_key: Optional[str] = Field(None, description="The API key generated by the agent, to be stored by the server; for ed25519, its public key. Absent for agents authenticated by a gateway (api_auth other than hmac)")
+    virtualization: Optional[str] = None
+    signature_algorithm: Literal["hmac_sha256", "hmac_sha512", "ed25519"] = "hmac_sha256"
+    tags: Dict[str, str] = {}
+    cloud_instance: Optional[CloudInstance] = None
 
 class CPUMetrics(BaseModel):
     usage_percent: float
@@ -41,10 +52,12 @@ class SystemInfo(BaseModel):
     os_version: str
     kernel_version: str
     uptime: int
+    cloud_instance: Optional[CloudInstance] = None
 
 class SystemMetricsPayload(BaseModel):
     timestamp: datetime
     instance_id: uuid.UUID
+    tags: Dict[str, str] = {}
     cpu_metrics: CPUMetrics
     memory_metrics: MemoryMetrics
     disk_metrics: List[DiskMetric]
@@ -52,10 +65,90 @@ class SystemMetricsPayload(BaseModel):
     system_info: SystemInfo
 
 class MetricsBatchWrapper(BaseModel):
-    metrics: List[SystemMetricsPayload]
+    metrics: List[Dict[str, Any]] # Validated one by one, so a bad sample doesn't sink the batch
+
+class RejectedMetric(BaseModel):
+    index: int
+    error: str
+    retryable: bool = False
+
+class MetricsBatchResponse(BaseModel):
+    message: str
+    accepted: int
+    rejected: List[RejectedMetric] = []
+
+class CPUInventory(BaseModel):
+    brand: str
+    vendor_id: str
+    architecture: str
+    physical_cores: Optional[int] = None
+    logical_cores: int
+    frequency_mhz: int
+
+class DiskInventory(BaseModel):
+    name: str
+    model: Optional[str] = None
+    size: int
+    kind: str
+
+class NetworkInterfaceInventory(BaseModel):
+    name: str
+    mac_address: str
+    addresses: List[str]
+    mtu: int
+    speed_mbps: Optional[int] = None
+
+class InventoryPayload(BaseModel):
+    collected_at: datetime
+    instance_id: uuid.UUID
+    agent_version: str
+    hostname: str
+    os_name: str
+    os_version: str
+    long_os_version: str
+    distribution_id: str
+    kernel_version: str
+    boot_time: int
+    cpu: CPUInventory
+    memory_total: int
+    memory_installed: Optional[int] = None
+    swap_total: int
+    disks: List[DiskInventory]
+    network_interfaces: List[NetworkInterfaceInventory]
+    environment: str
+    virtualization: Optional[str] = None
 
 class HeartbeatPayload(BaseModel):
     instance_id: uuid.UUID
+    agent_version: Optional[str] = None
+    uptime_seconds: Optional[int] = None
+    interval_seconds: Optional[int] = None
+
+CommandAction = Literal["snapshot", "flush_spool", "diagnostics"]
+
+class CommandRequest(BaseModel):
+    action: CommandAction
+
+class AgentCommand(BaseModel):
+    id: str
+    action: CommandAction
+
+class AgentCommandsResponse(BaseModel):
+    commands: List[AgentCommand] = []
+
+class CommandResultPayload(BaseModel):
+    instance_id: uuid.UUID
+    id: str
+    success: bool
+    output: Any = None  # The diagnostics report, otherwise null
+    error: Optional[str] = None
+
+class StoredCommandResult(CommandResultPayload):
+    received_at: datetime
+
+class RotateKeyPayload(BaseModel):
+    instance_id: uuid.UUID
+    new_api_key: str = Field(..., description="The agent's new API key; for ed25519, its public key")
 
 class AgentRegistrationResponse(BaseModel):
     message: str
@@ -73,9 +166,14 @@ class StoredAgent(BaseModel):
     instance_id: uuid.UUID
     instance_name: str
     cloud_provider: str
-    agent_api_key: str
+    agent_api_key: Optional[str] = None
+    virtualization: Optional[str] = None
+    signature_algorithm: str = "hmac_sha256"
+    tags: Dict[str, str] = {}
+    cloud_instance: Optional[CloudInstance] = None
     registered_at: datetime
     last_heartbeat_at: Optional[datetime] = None
+    agent_version: Optional[str] = None  # From the last heartbeat that had it
 
 class StoredMetricsBatch(BaseModel):
     received_at: datetime
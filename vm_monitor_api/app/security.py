@@ -1,23 +1,50 @@
 import hmac
 import hashlib
 import base64
+import time
 from datetime import datetime, timezone
-from fastapi import Request, HTTPException, status, Header
-from typing import Dict
+from cryptography.exceptions import InvalidSignature
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PublicKey
+from fastapi import Request, HTTPException, status, Header, WebSocket
+from typing import Dict, Optional
 
-AGENT_API_KEYS: Dict[str, str] = {}
+AGENT_API_KEYS: Dict[str, str] = {}  # For ed25519 agents, the base64 public key
+AGENT_SIGNATURE_ALGORITHMS: Dict[str, str] = {}
+# Keys from /v1/agent/rotate-key, which replace the agent's key once a request is signed with them.
+# Until then the old key still works, so an agent that never got the response can retry.
+PENDING_API_KEYS: Dict[str, str] = {}
 
 TIMESTAMP_VALIDITY_SECONDS = 300
+# Nonces of recently accepted requests per agent, with when they can be forgotten: once the
+# timestamp window has passed, a replayed request is rejected for its timestamp anyway.
+SEEN_NONCES: Dict[str, Dict[str, float]] = {}
 
-def verify_hmac_signature(
+
+def nonce_is_fresh(instance_id: str, nonce: str) -> bool:
+    """
+    Records a nonce, returning False if the agent already used it within the window.
+    """
+    now = time.monotonic()
+    seen = SEEN_NONCES.setdefault(instance_id, {})
+    for expired in [n for n, forget_at in seen.items() if forget_at <= now]:
+        del seen[expired]
+    if nonce in seen:
+        return False
+    seen[nonce] = now + 2 * TIMESTAMP_VALIDITY_SECONDS  # The window extends both ways
+    return True
+
+def verify_signature(
     api_key_secret: str,
+    algorithm: str,
     timestamp_str: str,
+    nonce: str,
     signature_from_request: str,
-    request: Request,
+    method: str,
+    path: str,
     body_bytes: bytes
 ) -> bool:
     """
-    Verifies the HMAC-SHA256 signature of a request.
+    Verifies the signature of a request: HMAC-SHA256, HMAC-SHA512 or Ed25519.
     """
     try:
         request_timestamp = datetime.fromtimestamp(int(timestamp_str), timezone.utc)
@@ -29,24 +56,49 @@ def verify_hmac_signature(
         print("Invalid timestamp format")
         return False
 
-    method = request.method.upper()
-    path = request.url.path
+    method = method.upper()
 
     body_str = body_bytes.decode('utf-8')
 
-    message_to_sign = f"{timestamp_str}\n{method}\n{path}\n{body_str}"
+    message_to_sign = f"{timestamp_str}\n{nonce}\n{method}\n{path}\n{body_str}"
 
-    mac = hmac.new(api_key_secret.encode('utf-8'), msg=message_to_sign.encode('utf-8'), digestmod=hashlib.sha256)
+    if algorithm == "ed25519":
+        try:
+            public_key = Ed25519PublicKey.from_public_bytes(base64.b64decode(api_key_secret))
+            public_key.verify(base64.b64decode(signature_from_request), message_to_sign.encode('utf-8'))
+            return True
+        except (InvalidSignature, ValueError):
+            return False
+
+    digestmod = hashlib.sha512 if algorithm == "hmac_sha512" else hashlib.sha256
+    mac = hmac.new(api_key_secret.encode('utf-8'), msg=message_to_sign.encode('utf-8'), digestmod=digestmod)
     expected_signature_bytes = mac.digest()
     expected_signature_base64 = base64.b64encode(expected_signature_bytes).decode('utf-8')
 
     return hmac.compare_digest(expected_signature_base64.encode('utf-8'), signature_from_request.encode('utf-8'))
 
 
+def verify_agent_signature(instance_id: str, **request) -> bool:
+    """
+    Verifies a request against the agent's key, then against its pending rotated key, which
+    becomes its key on first use.
+    """
+    algorithm = AGENT_SIGNATURE_ALGORITHMS.get(instance_id, "hmac_sha256")
+    if verify_signature(api_key_secret=AGENT_API_KEYS[instance_id], algorithm=algorithm, **request):
+        return True
+    pending_key = PENDING_API_KEYS.get(instance_id)
+    if pending_key and verify_signature(api_key_secret=pending_key, algorithm=algorithm, **request):
+        AGENT_API_KEYS[instance_id] = PENDING_API_KEYS.pop(instance_id)
+        print(f"Agent {instance_id} is now using its rotated API key.")
+        return True
+    return False
+
+
 async def authenticate_agent(
     request: Request,
     x_instance_id: str = Header(..., alias="X-Instance-Id"),
     x_request_timestamp: str = Header(..., alias="X-Request-Timestamp"),
+    x_request_nonce: str = Header(..., alias="X-Request-Nonce"),
     x_request_signature: str = Header(..., alias="X-Request-Signature"),
 ):
     """
@@ -54,6 +106,20 @@ async def authenticate_agent(
     The agent must send its `instance_id` in a header (e.g., X-Instance-Id)
     so the server can look up its specific API key.
     """
+    body_bytes = await verify_agent_request(request, x_instance_id, x_request_timestamp, x_request_nonce, x_request_signature)
+    return {"instance_id": x_instance_id, "raw_body_bytes": body_bytes}
+
+
+async def verify_agent_request(
+    request: Request,
+    x_instance_id: str,
+    x_request_timestamp: Optional[str],
+    x_request_nonce: Optional[str],
+    x_request_signature: Optional[str],
+) -> bytes:
+    """
+    Checks that a request is signed with the key registered for `x_instance_id`, returning its body.
+    """
     agent_secret_key = AGENT_API_KEYS.get(x_instance_id)
 
     if not agent_secret_key:
@@ -65,11 +131,13 @@ async def authenticate_agent(
 
     body_bytes = await request.body()
 
-    if not verify_hmac_signature(
-        api_key_secret=agent_secret_key,
+    if not (x_request_timestamp and x_request_nonce and x_request_signature) or not verify_agent_signature(
+        x_instance_id,
         timestamp_str=x_request_timestamp,
+        nonce=x_request_nonce,
         signature_from_request=x_request_signature,
-        request=request,
+        method=request.method,
+        path=request.url.path,
         body_bytes=body_bytes
     ):
         print(f"Authentication failed: HMAC signature verification failed for instance_id '{x_instance_id}'")
@@ -78,5 +146,37 @@ async def authenticate_agent(
             detail="Invalid signature or timestamp.",
             headers={"WWW-Authenticate": "Signature"},
         )
+    # Checked only once the signature is valid, so forged requests can't use up nonces.
+    if not nonce_is_fresh(x_instance_id, x_request_nonce):
+        print(f"Authentication failed: replayed nonce for instance_id '{x_instance_id}'")
+        raise HTTPException(
+            status_code=status.HTTP_401_UNAUTHORIZED,
+            detail="Request nonce already used.",
+            headers={"WWW-Authenticate": "Signature"},
+        )
     print(f"Agent {x_instance_id} authenticated successfully.")
-    return {"instance_id": x_instance_id, "raw_body_bytes": body_bytes}
\ No newline at end of file
+    return body_bytes
+
+
+def verify_stream_handshake(websocket: WebSocket) -> Optional[str]:
+    """
+    Checks a metrics stream's opening request, signed like a GET of the stream path with an
+    empty body. Returns the authenticated instance ID, or None.
+    """
+    headers = websocket.headers
+    instance_id = headers.get("x-instance-id", "")
+    timestamp, nonce, signature = (headers.get(name) for name in ("x-request-timestamp", "x-request-nonce", "x-request-signature"))
+    agent_secret_key = AGENT_API_KEYS.get(instance_id)
+    if not (agent_secret_key and timestamp and nonce and signature):
+        return None
+    if not verify_agent_signature(
+        instance_id,
+        timestamp_str=timestamp,
+        nonce=nonce,
+        signature_from_request=signature,
+        method="GET",
+        path=websocket.url.path,
+        body_bytes=b"",
+    ) or not nonce_is_fresh(instance_id, nonce):
+        return None
+    return instance_id